@@ -0,0 +1,555 @@
+// The tone channels, and the units they share that the frame counter clocks.
+
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+
+// indexed by the upper 5 bits of the length load registers
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// silences the channel once it reaches 0, unless halted
+pub struct LengthCounter
+{
+    enabled: bool,
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter
+{
+    pub fn new() -> LengthCounter { LengthCounter {enabled: false, halt: false, value: 0} }
+
+    pub fn value(&self) -> u8 { self.value }
+
+    // disabling the channel through $4015 clears the counter
+    pub fn set_enabled(&mut self, enabled: bool)
+    {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) { self.halt = halt }
+
+    // only while the channel is enabled
+    pub fn load(&mut self, data: u8)
+    {
+        if self.enabled {
+            self.value = LENGTH_TABLE[data as usize >> 3];
+        }
+    }
+
+    pub fn clock(&mut self)
+    {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bool(self.enabled);
+        state.write_bool(self.halt);
+        state.write_u8(self.value);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.enabled = state.read_bool()?;
+        self.halt = state.read_bool()?;
+        self.value = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// Decays from 15 to 0, one step per period + 1 quarter frames. The loop flag is
+// the length counter halt flag.
+pub struct Envelope
+{
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    // the constant volume, or the period of the divider
+    parameter: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope
+{
+    pub fn new() -> Envelope
+    {
+        Envelope {start: false, looping: false, constant_volume: false, parameter: 0, divider: 0, decay: 0}
+    }
+
+    // --LC VVVV, as in $4000, $4004 and $400C
+    pub fn write_control(&mut self, data: u8)
+    {
+        self.looping = data & 0x20 != 0;
+        self.constant_volume = data & 0x10 != 0;
+        self.parameter = data & 0x0F;
+    }
+
+    pub fn restart(&mut self) { self.start = true }
+
+    pub fn volume(&self) -> u8 { if self.constant_volume {self.parameter} else {self.decay} }
+
+    pub fn clock(&mut self)
+    {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.parameter;
+        } else if self.divider == 0 {
+            self.divider = self.parameter;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bool(self.start);
+        state.write_bool(self.looping);
+        state.write_bool(self.constant_volume);
+        state.write_u8(self.parameter);
+        state.write_u8(self.divider);
+        state.write_u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.start = state.read_bool()?;
+        self.looping = state.read_bool()?;
+        self.constant_volume = state.read_bool()?;
+        self.parameter = state.read_u8()?;
+        self.divider = state.read_u8()?;
+        self.decay = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// triangle only, a finer grained length counter clocked every quarter frame
+pub struct LinearCounter
+{
+    control: bool,
+    reload_value: u8,
+    reload: bool,
+    value: u8,
+}
+
+impl LinearCounter
+{
+    pub fn new() -> LinearCounter { LinearCounter {control: false, reload_value: 0, reload: false, value: 0} }
+
+    pub fn value(&self) -> u8 { self.value }
+
+    // CRRR RRRR, as in $4008, the control flag is also the length counter halt flag
+    pub fn write_control(&mut self, data: u8)
+    {
+        self.control = data & 0x80 != 0;
+        self.reload_value = data & 0x7F;
+    }
+
+    pub fn set_reload(&mut self) { self.reload = true }
+
+    pub fn clock(&mut self)
+    {
+        if self.reload {
+            self.value = self.reload_value;
+        } else if self.value > 0 {
+            self.value -= 1;
+        }
+        if !self.control {
+            self.reload = false;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bool(self.control);
+        state.write_u8(self.reload_value);
+        state.write_bool(self.reload);
+        state.write_u8(self.value);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.control = state.read_bool()?;
+        self.reload_value = state.read_u8()?;
+        self.reload = state.read_bool()?;
+        self.value = state.read_u8()?;
+        Ok(())
+    }
+}
+
+// Moves the pulse period every few half frames. The target period is computed
+// continuously, a target above $7FF mutes the channel even with the sweep disabled.
+pub struct Sweep
+{
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+    // pulse 1 negates with the ones' complement, pulse 2 with the twos' complement
+    ones_complement: bool,
+}
+
+impl Sweep
+{
+    fn new(ones_complement: bool) -> Sweep
+    {
+        Sweep {enabled: false, period: 0, negate: false, shift: 0, divider: 0, reload: false, ones_complement}
+    }
+
+    // EPPP NSSS, as in $4001 and $4005
+    fn write(&mut self, data: u8)
+    {
+        self.enabled = data & 0x80 != 0;
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0x08 != 0;
+        self.shift = data & 0x07;
+        self.reload = true;
+    }
+
+    fn target_period(&self, timer_period: u16) -> u16
+    {
+        let change = timer_period >> self.shift;
+        match (self.negate, self.ones_complement) {
+            (false, _) => timer_period + change,
+            (true, true) => timer_period.saturating_sub(change + 1),
+            (true, false) => timer_period.saturating_sub(change),
+        }
+    }
+
+    fn mutes(&self, timer_period: u16) -> bool { timer_period < 8 || self.target_period(timer_period) > 0x07FF }
+
+    // returns the new timer period
+    fn clock(&mut self, timer_period: u16) -> u16
+    {
+        let mut timer_period = timer_period;
+        if self.divider == 0 && self.enabled && self.shift > 0 && !self.mutes(timer_period) {
+            timer_period = self.target_period(timer_period);
+        }
+        if self.divider == 0 || self.reload {
+            self.divider = self.period;
+            self.reload = false;
+        } else {
+            self.divider -= 1;
+        }
+        timer_period
+    }
+
+    // the negation is fixed by the channel
+    fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bool(self.enabled);
+        state.write_u8(self.period);
+        state.write_bool(self.negate);
+        state.write_u8(self.shift);
+        state.write_u8(self.divider);
+        state.write_bool(self.reload);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.enabled = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.negate = state.read_bool()?;
+        self.shift = state.read_u8()? & 0x07;
+        self.divider = state.read_u8()?;
+        self.reload = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// one row per duty cycle, 12.5%, 25%, 50% and 25% negated
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+pub struct Pulse
+{
+    pub length_counter: LengthCounter,
+    pub envelope: Envelope,
+    sweep: Sweep,
+    duty: u8,
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+}
+
+impl Pulse
+{
+    // channel is 1 or 2
+    pub fn new(channel: u8) -> Pulse
+    {
+        Pulse {
+            length_counter: LengthCounter::new(),
+            envelope: Envelope::new(),
+            sweep: Sweep::new(channel == 1),
+            duty: 0,
+            sequence_step: 0,
+            timer_period: 0,
+            timer: 0,
+        }
+    }
+
+    // once per APU cycle, every other CPU cycle
+    pub fn clock_timer(&mut self)
+    {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_sweep(&mut self) { self.timer_period = self.sweep.clock(self.timer_period) }
+
+    // 0 to 15
+    pub fn output(&self) -> u8
+    {
+        let silenced = DUTY_TABLE[self.duty as usize][self.sequence_step as usize] == 0
+            || self.length_counter.value() == 0
+            || self.sweep.mutes(self.timer_period);
+        if silenced {0} else {self.envelope.volume()}
+    }
+
+    // register is 0 to 3, from $4000 or $4004
+    pub fn write_register(&mut self, register: u16, data: u8)
+    {
+        match register {
+            // DDLC VVVV
+            0 => {
+                self.duty = data >> 6;
+                self.length_counter.set_halt(data & 0x20 != 0);
+                self.envelope.write_control(data);
+            },
+            1 => self.sweep.write(data),
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            // LLLL LTTT, restarts the duty sequence
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (data as u16 & 0x07) << 8;
+                self.length_counter.load(data);
+                self.envelope.restart();
+                self.sequence_step = 0;
+            },
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        self.length_counter.save_state(state);
+        self.envelope.save_state(state);
+        self.sweep.save_state(state);
+        state.write_u8(self.duty);
+        state.write_u8(self.sequence_step);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.length_counter.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.sweep.load_state(state)?;
+        self.duty = state.read_u8()? & 0x03;
+        self.sequence_step = state.read_u8()? % 8;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        Ok(())
+    }
+}
+
+// 15 down to 0, then 0 up to 15
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct Triangle
+{
+    pub length_counter: LengthCounter,
+    pub linear_counter: LinearCounter,
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+}
+
+impl Triangle
+{
+    pub fn new() -> Triangle
+    {
+        Triangle {
+            length_counter: LengthCounter::new(),
+            linear_counter: LinearCounter::new(),
+            sequence_step: 0,
+            timer_period: 0,
+            timer: 0,
+        }
+    }
+
+    // once per CPU cycle, the sequence holds its step while either counter is 0
+    pub fn clock_timer(&mut self)
+    {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter.value() > 0 && self.length_counter.value() > 0 {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0 to 15, the channel is never silenced, it only stops moving
+    pub fn output(&self) -> u8 { TRIANGLE_SEQUENCE[self.sequence_step as usize] }
+
+    // register is 0 to 3, from $4008, $4009 is unused
+    pub fn write_register(&mut self, register: u16, data: u8)
+    {
+        match register {
+            0 => {
+                self.length_counter.set_halt(data & 0x80 != 0);
+                self.linear_counter.write_control(data);
+            },
+            1 => {},
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (data as u16 & 0x07) << 8;
+                self.length_counter.load(data);
+                self.linear_counter.set_reload();
+            },
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        self.length_counter.save_state(state);
+        self.linear_counter.save_state(state);
+        state.write_u8(self.sequence_step);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.length_counter.load_state(state)?;
+        self.linear_counter.load_state(state)?;
+        self.sequence_step = state.read_u8()? % 32;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        Ok(())
+    }
+}
+
+// NTSC periods, in CPU cycles
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+pub struct Noise
+{
+    pub length_counter: LengthCounter,
+    pub envelope: Envelope,
+    // short mode, the feedback taps bit 6 instead of bit 1
+    mode: bool,
+    period_index: u8,
+    timer: u16,
+    // 15 bits linear feedback shift register
+    shift_register: u16,
+}
+
+impl Noise
+{
+    pub fn new() -> Noise
+    {
+        Noise {
+            length_counter: LengthCounter::new(),
+            envelope: Envelope::new(),
+            mode: false,
+            period_index: 0,
+            timer: 0,
+            shift_register: 1,
+        }
+    }
+
+    // once per CPU cycle
+    pub fn clock_timer(&mut self)
+    {
+        if self.timer == 0 {
+            self.timer = NOISE_PERIODS[self.period_index as usize] - 1;
+            let tap = if self.mode {6} else {1};
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+            self.shift_register = (self.shift_register >> 1) | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0 to 15, silenced while bit 0 of the shift register is set
+    pub fn output(&self) -> u8
+    {
+        if self.shift_register & 0x01 != 0 || self.length_counter.value() == 0 {0} else {self.envelope.volume()}
+    }
+
+    // register is 0 to 3, from $400C, $400D is unused
+    pub fn write_register(&mut self, register: u16, data: u8)
+    {
+        match register {
+            // --LC VVVV
+            0 => {
+                self.length_counter.set_halt(data & 0x20 != 0);
+                self.envelope.write_control(data);
+            },
+            1 => {},
+            // M--- PPPP
+            2 => {
+                self.mode = data & 0x80 != 0;
+                self.period_index = data & 0x0F;
+            },
+            // LLLL L---
+            _ => {
+                self.length_counter.load(data);
+                self.envelope.restart();
+            },
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        self.length_counter.save_state(state);
+        self.envelope.save_state(state);
+        state.write_bool(self.mode);
+        state.write_u8(self.period_index);
+        state.write_u16(self.timer);
+        state.write_u16(self.shift_register);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.length_counter.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.mode = state.read_bool()?;
+        self.period_index = state.read_u8()? & 0x0F;
+        self.timer = state.read_u16()?;
+        self.shift_register = state.read_u16()?;
+        Ok(())
+    }
+}
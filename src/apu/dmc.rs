@@ -0,0 +1,192 @@
+// Delta modulation channel. Its memory reader is the only part of the APU that
+// reads the CPU bus: the CPU polls fetch_address() every cycle, reads the byte for
+// it and stalls while doing so.
+
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+
+// NTSC periods, in CPU cycles
+const RATE_TABLE: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+pub struct Dmc
+{
+    irq_enabled: bool,
+    looping: bool,
+    rate_index: u8,
+    irq_flag: bool,
+    // 7 bits, moved by 2 for every bit of the sample
+    output_level: u8,
+    // $C000 + A * 64, and L * 16 + 1 bytes
+    sample_address: u16,
+    sample_length: u16,
+    // memory reader
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    timer: u16,
+}
+
+impl Dmc
+{
+    pub fn new() -> Dmc
+    {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate_index: 0,
+            irq_flag: false,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            timer: 0,
+        }
+    }
+
+    // register is 0 to 3, from $4010
+    pub fn write_register(&mut self, register: u16, data: u8)
+    {
+        match register {
+            // IL-- RRRR, disabling the IRQ clears its flag
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.looping = data & 0x40 != 0;
+                self.rate_index = data & 0x0F;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+            },
+            // -DDD DDDD
+            1 => self.output_level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 1,
+        }
+    }
+
+    // bit 4 of $4015, which also acknowledges the IRQ
+    pub fn set_enabled(&mut self, enabled: bool)
+    {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self)
+    {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool { self.bytes_remaining > 0 }
+
+    pub fn irq_flag(&self) -> bool { self.irq_flag }
+
+    pub fn irq_enabled(&self) -> bool { self.irq_enabled }
+
+    pub fn output(&self) -> u8 { self.output_level }
+
+    // the address to read when the sample buffer waits for its next byte
+    pub fn fetch_address(&self) -> Option<u16>
+    {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {Some(self.current_address)} else {None}
+    }
+
+    pub fn fill_sample_buffer(&mut self, data: u8)
+    {
+        self.sample_buffer = Some(data);
+        // the address wraps from $FFFF to $8000
+        self.current_address = if self.current_address == 0xFFFF {0x8000} else {self.current_address + 1};
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // once per CPU cycle
+    pub fn clock_timer(&mut self)
+    {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return
+        }
+        self.timer = RATE_TABLE[self.rate_index as usize] - 1;
+
+        if !self.silence {
+            if self.shift_register & 0x01 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.shift_register = data;
+                    self.silence = false;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.looping);
+        state.write_u8(self.rate_index);
+        state.write_bool(self.irq_flag);
+        state.write_u8(self.output_level);
+        state.write_u16(self.sample_address);
+        state.write_u16(self.sample_length);
+        state.write_u16(self.current_address);
+        state.write_u16(self.bytes_remaining);
+        state.write_option_u8(self.sample_buffer);
+        state.write_u8(self.shift_register);
+        state.write_u8(self.bits_remaining);
+        state.write_bool(self.silence);
+        state.write_u16(self.timer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.irq_enabled = state.read_bool()?;
+        self.looping = state.read_bool()?;
+        self.rate_index = state.read_u8()? & 0x0F;
+        self.irq_flag = state.read_bool()?;
+        self.output_level = state.read_u8()?;
+        self.sample_address = state.read_u16()?;
+        self.sample_length = state.read_u16()?;
+        self.current_address = state.read_u16()?;
+        self.bytes_remaining = state.read_u16()?;
+        self.sample_buffer = state.read_option_u8()?;
+        self.shift_register = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?;
+        self.silence = state.read_bool()?;
+        self.timer = state.read_u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+
+// Frame sequencer, counted in CPU cycles since the last reset of the sequence.
+// The steps fall on APU half cycles, hence the odd numbers.
+const FIRST_STEP: u32 = 7457;
+const SECOND_STEP: u32 = 14913;
+const THIRD_STEP: u32 = 22371;
+// 4-step mode: the IRQ flag is set on the last three cycles of the sequence
+const FOUR_STEP_LAST_STEP: u32 = 29829;
+const FOUR_STEP_PERIOD: u32 = 29830;
+const FIVE_STEP_LAST_STEP: u32 = 37281;
+const FIVE_STEP_PERIOD: u32 = 37282;
+
+// which units the current cycle clocks
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct FrameTicks
+{
+    // envelopes and the triangle linear counter
+    pub quarter: bool,
+    // length counters and sweeps
+    pub half: bool,
+}
+
+impl FrameTicks
+{
+    const NONE: FrameTicks = FrameTicks {quarter: false, half: false};
+    const QUARTER: FrameTicks = FrameTicks {quarter: true, half: false};
+    const HALF: FrameTicks = FrameTicks {quarter: true, half: true};
+}
+
+pub struct FrameCounter
+{
+    five_step: bool,
+    irq_inhibit: bool,
+    irq_flag: bool,
+    cycle: u32,
+    // a $4017 write resets the sequence 3 or 4 CPU cycles later
+    reset_delay: Option<u8>,
+    odd_cycle: bool,
+}
+
+impl FrameCounter
+{
+    pub fn new() -> FrameCounter
+    {
+        FrameCounter {
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            reset_delay: None,
+            odd_cycle: false,
+        }
+    }
+
+    pub fn irq_flag(&self) -> bool { self.irq_flag }
+
+    pub fn clear_irq_flag(&mut self) { self.irq_flag = false }
+
+    // clocks to go through before the one setting the IRQ flag. A pending reset of the
+    // sequence only pushes it further.
+    pub fn irq_horizon(&self) -> Option<u32>
+    {
+        if self.five_step || self.irq_inhibit {
+            return None
+        }
+        Some((FOUR_STEP_LAST_STEP - 1).saturating_sub(self.cycle + 1))
+    }
+
+    // $4017: MI-- ----, 5-step mode and IRQ inhibit. The CPU writes before clocking the
+    // APU on the same cycle, so an inhibit written on the cycle that would set the flag
+    // keeps it clear, and one written later clears it right away.
+    pub fn write(&mut self, data: u8)
+    {
+        self.five_step = data & 0x80 != 0;
+        self.irq_inhibit = data & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        // 3 cycles when written during an APU cycle, 4 between two
+        self.reset_delay = Some(if self.odd_cycle {4} else {3});
+    }
+
+    // the reset line restarts the sequence as if $4017 was written again with its
+    // last value
+    pub fn reset(&mut self)
+    {
+        self.irq_flag = false;
+        self.reset_delay = Some(if self.odd_cycle {4} else {3});
+    }
+
+    pub fn clock(&mut self) -> FrameTicks
+    {
+        self.odd_cycle = !self.odd_cycle;
+        if let Some(delay) = self.reset_delay {
+            if delay == 1 {
+                self.reset_delay = None;
+                self.cycle = 0;
+                // entering 5-step mode clocks the units right away
+                return if self.five_step {FrameTicks::HALF} else {FrameTicks::NONE}
+            }
+            self.reset_delay = Some(delay - 1);
+        }
+
+        self.cycle += 1;
+        let four_step_irq = !self.five_step && self.cycle >= FOUR_STEP_LAST_STEP - 1;
+        if four_step_irq && !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+        let ticks = match (self.cycle, self.five_step) {
+            (FIRST_STEP, _) | (THIRD_STEP, _) => FrameTicks::QUARTER,
+            (SECOND_STEP, _) | (FOUR_STEP_LAST_STEP, false) | (FIVE_STEP_LAST_STEP, true) => FrameTicks::HALF,
+            _ => FrameTicks::NONE,
+        };
+        let period = if self.five_step {FIVE_STEP_PERIOD} else {FOUR_STEP_PERIOD};
+        if self.cycle == period {
+            self.cycle = 0;
+        }
+        ticks
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bool(self.five_step);
+        state.write_bool(self.irq_inhibit);
+        state.write_bool(self.irq_flag);
+        state.write_u32(self.cycle);
+        state.write_option_u8(self.reset_delay);
+        state.write_bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.five_step = state.read_bool()?;
+        self.irq_inhibit = state.read_bool()?;
+        self.irq_flag = state.read_bool()?;
+        self.cycle = state.read_u32()?;
+        self.reset_delay = state.read_option_u8()?;
+        self.odd_cycle = state.read_bool()?;
+        Ok(())
+    }
+}
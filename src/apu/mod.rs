@@ -0,0 +1,791 @@
+mod frame_counter;
+mod channels;
+mod dmc;
+
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+use crate::utils::Clocked;
+use frame_counter::FrameCounter;
+use channels::{
+    Noise,
+    Pulse,
+    Triangle,
+};
+use dmc::Dmc;
+
+pub const CPU_CLOCK_RATE: u32 = 1_789_773;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// Registers at $4000-$4013, $4015 and $4017, clocked once per CPU cycle. The
+// frame counter drives the length counters, envelopes, linear counter and sweeps.
+pub struct Apu
+{
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    // the pulse timers run at half the CPU clock
+    odd_cycle: bool,
+    // the mixer output is averaged over the CPU cycles of each sample
+    sample_rate: u32,
+    sample_phase: u32,
+    sample_sum: f32,
+    sample_cycles: u32,
+    sample_callback: Option<Box<dyn FnMut(f32)>>,
+}
+
+impl Apu
+{
+    pub fn new() -> Apu
+    {
+        Apu {
+            pulse_1: Pulse::new(1),
+            pulse_2: Pulse::new(2),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            odd_cycle: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_phase: 0,
+            sample_sum: 0.0,
+            sample_cycles: 0,
+            sample_callback: None,
+        }
+    }
+
+    // receives the samples, between 0.0 and 1.0
+    pub fn set_sample_callback(&mut self, callback: impl FnMut(f32) + 'static) { self.sample_callback = Some(Box::new(callback)) }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) { self.sample_rate = sample_rate }
+
+    // level of the frame counter IRQ output
+    pub fn frame_irq(&self) -> bool { self.frame_counter.irq_flag() }
+
+    // level of the DMC IRQ output, set when a sample ends without looping
+    pub fn dmc_irq(&self) -> bool { self.dmc.irq_flag() }
+
+    // the CPU reads this address for the DMC, then hands the byte to dmc_fill
+    // CPU cycles to go through before the one that could raise the IRQ line, None when
+    // only a register write could raise it
+    pub fn irq_horizon(&self) -> Option<u32>
+    {
+        // the sample ends on a byte fetch, which follows the output unit
+        if self.dmc.irq_enabled() && self.dmc.active() {
+            return Some(0)
+        }
+        self.frame_counter.irq_horizon()
+    }
+
+    // the CPU stalls for each sample byte while the DMC plays
+    pub fn dmc_active(&self) -> bool { self.dmc.active() }
+
+    pub fn dmc_fetch_address(&self) -> Option<u16> { self.dmc.fetch_address() }
+
+    pub fn dmc_fill(&mut self, data: u8) { self.dmc.fill_sample_buffer(data) }
+
+    // $4015: IF-D NT21, the frame IRQ flag is cleared by the read
+    pub fn read_status(&mut self) -> u8
+    {
+        let status = (self.dmc.irq_flag() as u8) << 7
+            | (self.frame_counter.irq_flag() as u8) << 6
+            | (self.dmc.active() as u8) << 4
+            | ((self.noise.length_counter.value() > 0) as u8) << 3
+            | ((self.triangle.length_counter.value() > 0) as u8) << 2
+            | ((self.pulse_2.length_counter.value() > 0) as u8) << 1
+            | (self.pulse_1.length_counter.value() > 0) as u8;
+        self.frame_counter.clear_irq_flag();
+        status
+    }
+
+    // register is the CPU address minus $4000
+    pub fn write_register(&mut self, register: u16, data: u8)
+    {
+        match register {
+            0x00..=0x03 => self.pulse_1.write_register(register, data),
+            0x04..=0x07 => self.pulse_2.write_register(register - 0x04, data),
+            0x08..=0x0B => self.triangle.write_register(register - 0x08, data),
+            0x0C..=0x0F => self.noise.write_register(register - 0x0C, data),
+            0x10..=0x13 => self.dmc.write_register(register - 0x10, data),
+            // ---D NT21, enables the channels
+            0x15 => {
+                self.pulse_1.length_counter.set_enabled(data & 0x01 != 0);
+                self.pulse_2.length_counter.set_enabled(data & 0x02 != 0);
+                self.triangle.length_counter.set_enabled(data & 0x04 != 0);
+                self.noise.length_counter.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            },
+            0x17 => self.frame_counter.write(data),
+            _ => {},
+        }
+    }
+
+    // the reset line silences the channels like a $4015 write of 0 and restarts the
+    // frame counter, the other registers keep their values
+    pub fn reset(&mut self)
+    {
+        self.write_register(0x15, 0x00);
+        self.frame_counter.reset();
+    }
+
+    fn clock_quarter_frame(&mut self)
+    {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.triangle.linear_counter.clock();
+        self.noise.envelope.clock();
+    }
+
+    fn clock_half_frame(&mut self)
+    {
+        self.pulse_1.length_counter.clock();
+        self.pulse_2.length_counter.clock();
+        self.triangle.length_counter.clock();
+        self.noise.length_counter.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    // the non-linear mixer
+    fn mix(&self) -> f32
+    {
+        let pulses = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+        let pulse_out = if pulses == 0.0 {0.0} else {95.88 / (8128.0 / pulses + 100.0)};
+        let tnd = self.triangle.output() as f32 / 8227.0 + self.noise.output() as f32 / 12241.0 + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {0.0} else {159.79 / (1.0 / tnd + 100.0)};
+        pulse_out + tnd_out
+    }
+
+    // a sample is due every CPU_CLOCK_RATE / sample_rate cycles, kept exact with an integer phase
+    fn output_sample(&mut self)
+    {
+        self.sample_sum += self.mix();
+        self.sample_cycles += 1;
+        self.sample_phase += self.sample_rate;
+        if self.sample_phase >= CPU_CLOCK_RATE {
+            self.sample_phase -= CPU_CLOCK_RATE;
+            let sample = self.sample_sum / self.sample_cycles as f32;
+            self.sample_sum = 0.0;
+            self.sample_cycles = 0;
+            if let Some(callback) = &mut self.sample_callback {
+                callback(sample);
+            }
+        }
+    }
+
+    // the sample rate and callback belong to the frontend, they are kept
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        self.frame_counter.save_state(state);
+        state.write_bool(self.odd_cycle);
+        state.write_u32(self.sample_phase);
+        state.write_f32(self.sample_sum);
+        state.write_u32(self.sample_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.frame_counter.load_state(state)?;
+        self.odd_cycle = state.read_bool()?;
+        self.sample_phase = state.read_u32()?;
+        self.sample_sum = state.read_f32()?;
+        self.sample_cycles = state.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Default for Apu
+{
+    fn default() -> Apu { Apu::new() }
+}
+
+impl Clocked for Apu
+{
+    fn clock(&mut self)
+    {
+        let ticks = self.frame_counter.clock();
+        if ticks.quarter {
+            self.clock_quarter_frame();
+        }
+        if ticks.half {
+            self.clock_half_frame();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.output_sample();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn run_cycles(apu: &mut Apu, cycles: u32)
+    {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+
+    // writes $4017 and waits out the reset delay
+    fn write_frame_counter(apu: &mut Apu, data: u8)
+    {
+        apu.write_register(0x17, data);
+        run_cycles(apu, 3);
+    }
+
+    #[test]
+    fn test_four_step_irq()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+
+        run_cycles(&mut apu, 29827);
+        assert_eq!(apu.frame_irq(), false);
+        apu.clock();
+        assert_eq!(apu.frame_irq(), true);
+
+        // reading $4015 reports and clears it
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert_eq!(apu.frame_irq(), false);
+        assert_eq!(apu.read_status() & 0x40, 0x00);
+    }
+
+    // the flag is set again on the two cycles after the read, then once per sequence
+    #[test]
+    fn test_four_step_irq_period()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+        run_cycles(&mut apu, 29828);
+        apu.read_status();
+        run_cycles(&mut apu, 2);
+        assert_eq!(apu.frame_irq(), true);
+        apu.read_status();
+
+        run_cycles(&mut apu, 29827);
+        assert_eq!(apu.frame_irq(), false);
+        apu.clock();
+        assert_eq!(apu.frame_irq(), true);
+    }
+
+    #[test]
+    fn test_irq_inhibit()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+        run_cycles(&mut apu, 29830);
+        assert_eq!(apu.frame_irq(), true);
+
+        // setting the inhibit flag clears it right away
+        apu.write_register(0x17, 0x40);
+        assert_eq!(apu.frame_irq(), false);
+        run_cycles(&mut apu, 2 * 29830);
+        assert_eq!(apu.frame_irq(), false);
+    }
+
+    // The inhibit write lands on the cycle before the one setting the flag, on it, and
+    // on the one after. Each cycle is a write, then a clock, like the CPU does.
+    #[test]
+    fn test_irq_inhibit_race()
+    {
+        for &(cycles, flag_before_write) in [(29826, false), (29827, false), (29828, true)].iter() {
+            let mut apu = Apu::new();
+            write_frame_counter(&mut apu, 0x00);
+            run_cycles(&mut apu, cycles);
+            assert_eq!(apu.frame_irq(), flag_before_write, "after {} cycles", cycles);
+
+            apu.write_register(0x17, 0x40);
+            assert_eq!(apu.frame_irq(), false, "after {} cycles", cycles);
+            apu.clock();
+            assert_eq!(apu.frame_irq(), false, "after {} cycles", cycles);
+            run_cycles(&mut apu, 29830);
+            assert_eq!(apu.read_status() & 0x40, 0x00, "after {} cycles", cycles);
+        }
+    }
+
+    #[test]
+    fn test_five_step_has_no_irq()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x80);
+        run_cycles(&mut apu, 2 * 37282);
+
+        assert_eq!(apu.frame_irq(), false);
+    }
+
+    // the sequence restarts 3 or 4 cycles after the write, depending on the cycle parity
+    #[test]
+    fn test_reset_delay()
+    {
+        for &(offset, delay) in [(0, 3), (1, 4)].iter() {
+            let mut apu = Apu::new();
+            run_cycles(&mut apu, offset);
+            apu.write_register(0x17, 0x00);
+
+            run_cycles(&mut apu, delay + 29827);
+            assert_eq!(apu.frame_irq(), false, "offset {}", offset);
+            apu.clock();
+            assert_eq!(apu.frame_irq(), true, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn test_length_counter_status()
+    {
+        let mut apu = Apu::new();
+        // loading a disabled channel has no effect
+        apu.write_register(0x03, 0x08);
+        assert_eq!(apu.read_status() & 0x0F, 0x00);
+
+        apu.write_register(0x15, 0x0F);
+        apu.write_register(0x03, 0x08);
+        apu.write_register(0x07, 0x08);
+        apu.write_register(0x0B, 0x08);
+        apu.write_register(0x0F, 0x08);
+        assert_eq!(apu.read_status() & 0x0F, 0x0F);
+
+        // disabling clears the counter
+        apu.write_register(0x15, 0x0D);
+        assert_eq!(apu.read_status() & 0x0F, 0x0D);
+    }
+
+    #[test]
+    fn test_reset()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+        apu.write_register(0x15, 0x0F);
+        apu.write_register(0x03, 0x08);
+        apu.write_register(0x0F, 0x08);
+        run_cycles(&mut apu, 29830);
+        assert_eq!(apu.frame_irq(), true);
+
+        apu.reset();
+        assert_eq!(apu.frame_irq(), false);
+        assert_eq!(apu.read_status(), 0x00);
+        // the channels stay disabled, loading them has no effect
+        apu.write_register(0x03, 0x08);
+        assert_eq!(apu.read_status() & 0x0F, 0x00);
+
+        // the sequence starts over, still in 4-step mode, 4 cycles later on an odd cycle
+        run_cycles(&mut apu, 4 + 29827);
+        assert_eq!(apu.frame_irq(), false);
+        apu.clock();
+        assert_eq!(apu.frame_irq(), true);
+    }
+
+    // two half frames per 4-step sequence
+    #[test]
+    fn test_length_counter_clocked_by_half_frames()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+        apu.write_register(0x15, 0x01);
+        // index 3 loads 2
+        apu.write_register(0x03, 0x18);
+
+        run_cycles(&mut apu, 14913 - 1);
+        assert_eq!(apu.pulse_1.length_counter.value(), 2);
+        apu.clock();
+        assert_eq!(apu.pulse_1.length_counter.value(), 1);
+        run_cycles(&mut apu, 29829 - 14913);
+        assert_eq!(apu.read_status() & 0x01, 0x00);
+    }
+
+    #[test]
+    fn test_length_counter_halt()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+        apu.write_register(0x15, 0x01);
+        apu.write_register(0x00, 0x20);
+        apu.write_register(0x03, 0x18);
+        run_cycles(&mut apu, 29830);
+
+        assert_eq!(apu.pulse_1.length_counter.value(), 2);
+    }
+
+    // switching to 5-step mode clocks the units once the write takes effect
+    #[test]
+    fn test_five_step_write_clocks_immediately()
+    {
+        let mut apu = Apu::new();
+        apu.write_register(0x15, 0x04);
+        apu.write_register(0x0B, 0x18);
+        apu.write_register(0x17, 0x80);
+        run_cycles(&mut apu, 2);
+        assert_eq!(apu.triangle.length_counter.value(), 2);
+        apu.clock();
+
+        assert_eq!(apu.triangle.length_counter.value(), 1);
+    }
+
+    #[test]
+    fn test_envelope_decay()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+        apu.write_register(0x15, 0x08);
+        // decaying envelope with a period of 1
+        apu.write_register(0x0C, 0x01);
+        apu.write_register(0x0F, 0x08);
+
+        // the first quarter frame restarts it at 15, then every 2 quarter frames
+        run_cycles(&mut apu, 7457);
+        assert_eq!(apu.noise.envelope.volume(), 15);
+        run_cycles(&mut apu, 22371 - 7457);
+        assert_eq!(apu.noise.envelope.volume(), 14);
+
+        apu.write_register(0x0C, 0x17);
+        assert_eq!(apu.noise.envelope.volume(), 7);
+    }
+
+    #[test]
+    fn test_linear_counter()
+    {
+        let mut apu = Apu::new();
+        write_frame_counter(&mut apu, 0x00);
+        apu.write_register(0x08, 0x05);
+        apu.write_register(0x0B, 0x00);
+
+        run_cycles(&mut apu, 7457);
+        assert_eq!(apu.triangle.linear_counter.value(), 5);
+        run_cycles(&mut apu, 14913 - 7457);
+        assert_eq!(apu.triangle.linear_counter.value(), 4);
+    }
+
+    fn pulse_level(volume: f32) -> f32 { 95.88 / (8128.0 / volume + 100.0) }
+
+    fn triangle_level(step: f32) -> f32 { 159.79 / (8227.0 / step + 100.0) }
+
+    // One sample per CPU cycle, collected in the returned buffer. The triangle never
+    // goes silent, it rests on step 15 until it is started.
+    fn recording_apu() -> (Apu, Rc<RefCell<Vec<f32>>>)
+    {
+        let samples = Rc::new(RefCell::new(Vec::new()));
+        let mut apu = Apu::new();
+        apu.set_sample_rate(CPU_CLOCK_RATE);
+        let buffer = Rc::clone(&samples);
+        let rest = triangle_level(15.0);
+        apu.set_sample_callback(move |sample| buffer.borrow_mut().push(sample - rest));
+        (apu, samples)
+    }
+
+    // lengths of the runs of non-zero samples that fit in the window
+    fn high_runs(samples: &[f32]) -> Vec<usize>
+    {
+        let high: Vec<bool> = samples.iter().map(|sample| sample.abs() > 1e-6).collect();
+        let mut runs = Vec::new();
+        let mut start = None;
+        for i in 1..high.len() {
+            if high[i] && !high[i - 1] {
+                start = Some(i);
+            }
+            if !high[i] && high[i - 1] {
+                if let Some(start) = start.take() {
+                    runs.push(i - start);
+                }
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn test_pulse_duty_and_amplitude()
+    {
+        for &(duty, high_steps) in [(0u8, 1), (1, 2), (2, 4), (3, 6)].iter() {
+            let (mut apu, samples) = recording_apu();
+            apu.write_register(0x15, 0x01);
+            // halted length counter, constant volume 15
+            apu.write_register(0x00, duty << 6 | 0x3F);
+            apu.write_register(0x02, 0xFF);
+            apu.write_register(0x03, 0x08);
+            run_cycles(&mut apu, 4 * 4096);
+
+            // 256 APU cycles per step, 8 steps
+            let samples = samples.borrow();
+            let runs = high_runs(&samples);
+            assert_eq!(runs.len() >= 3, true, "duty {}", duty);
+            for run in runs {
+                assert_eq!(run, high_steps * 512, "duty {}", duty);
+            }
+            for sample in samples.iter() {
+                assert_eq!(sample.abs() < 1e-6 || (*sample - pulse_level(15.0)).abs() < 1e-6, true);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pulse_silenced()
+    {
+        let (mut apu, samples) = recording_apu();
+        apu.write_register(0x15, 0x03);
+        apu.write_register(0x00, 0xBF);
+        // periods under 8 are muted
+        apu.write_register(0x02, 0x07);
+        apu.write_register(0x03, 0x08);
+        // a sweep target over $7FF mutes, even with the sweep disabled
+        apu.write_register(0x04, 0xBF);
+        apu.write_register(0x05, 0x00);
+        apu.write_register(0x06, 0x00);
+        apu.write_register(0x07, 0x0C);
+        run_cycles(&mut apu, 1000);
+
+        assert_eq!(samples.borrow().iter().all(|sample| sample.abs() < 1e-6), true);
+    }
+
+    #[test]
+    fn test_sweep_adds_on_half_frames()
+    {
+        let (mut apu, samples) = recording_apu();
+        write_frame_counter(&mut apu, 0x00);
+        apu.write_register(0x15, 0x02);
+        apu.write_register(0x04, 0xBF);
+        // enabled, divider period 0, shift 1
+        apu.write_register(0x05, 0x81);
+        apu.write_register(0x06, 0x00);
+        apu.write_register(0x07, 0x09);
+        run_cycles(&mut apu, 29829);
+
+        // $100, then $180 from the half frame at 14913
+        let samples = samples.borrow();
+        assert_eq!(high_runs(&samples[..14000]).iter().all(|run| *run == 0x101 * 8), true);
+        assert_eq!(high_runs(&samples[16000..]).iter().all(|run| *run == 0x181 * 8), true);
+    }
+
+    // pulse 1 subtracts one more when negating
+    #[test]
+    fn test_sweep_negate()
+    {
+        for &(register, period) in [(0x00, 0x7F), (0x04, 0x80)].iter() {
+            let (mut apu, samples) = recording_apu();
+            write_frame_counter(&mut apu, 0x00);
+            apu.write_register(0x15, 0x03);
+            apu.write_register(register, 0xBF);
+            apu.write_register(register + 1, 0x89);
+            apu.write_register(register + 2, 0x00);
+            apu.write_register(register + 3, 0x09);
+            run_cycles(&mut apu, 20000);
+
+            let runs = high_runs(&samples.borrow()[16000..]);
+            assert_eq!(runs.is_empty(), false);
+            assert_eq!(runs.iter().all(|run| *run == (period + 1) * 8), true, "register {:02X}", register);
+        }
+    }
+
+    #[test]
+    fn test_triangle_sequence()
+    {
+        let (mut apu, samples) = recording_apu();
+        write_frame_counter(&mut apu, 0x00);
+        apu.write_register(0x15, 0x04);
+        apu.write_register(0x08, 0xFF);
+        // 16 cycles per step, 32 steps
+        apu.write_register(0x0A, 0x0F);
+        apu.write_register(0x0B, 0x08);
+        run_cycles(&mut apu, 10000);
+
+        let samples = samples.borrow();
+        // held until the first quarter frame loads the linear counter
+        assert_eq!(samples[..7457].iter().all(|sample| *sample == 0.0), true);
+        for i in 8000..8512 {
+            assert_eq!(samples[i], samples[i + 512]);
+        }
+        let max = samples[8000..8512].iter().cloned().fold(-1.0, f32::max);
+        let min = samples[8000..8512].iter().cloned().fold(1.0, f32::min);
+        assert_eq!(max, 0.0);
+        assert_eq!((min + triangle_level(15.0)).abs() < 1e-6, true);
+    }
+
+    // the short mode sequence is 93 steps long
+    #[test]
+    fn test_noise_short_mode()
+    {
+        let (mut apu, samples) = recording_apu();
+        apu.write_register(0x15, 0x08);
+        apu.write_register(0x0C, 0x3F);
+        // short mode, 4 cycles per step
+        apu.write_register(0x0E, 0x80);
+        apu.write_register(0x0F, 0x08);
+        run_cycles(&mut apu, 2000);
+
+        let samples = samples.borrow();
+        for i in 100..500 {
+            assert_eq!(samples[i], samples[i + 93 * 4], "sample {}", i);
+        }
+        assert_eq!(samples[100..500].iter().any(|sample| sample.abs() < 1e-6), true);
+        assert_eq!(samples[100..500].iter().any(|sample| sample.abs() > 1e-6), true);
+    }
+
+    #[test]
+    fn test_noise_long_mode_does_not_repeat_early()
+    {
+        let (mut apu, samples) = recording_apu();
+        apu.write_register(0x15, 0x08);
+        apu.write_register(0x0C, 0x3F);
+        apu.write_register(0x0E, 0x00);
+        apu.write_register(0x0F, 0x08);
+        run_cycles(&mut apu, 2000);
+
+        let samples = samples.borrow();
+        assert_eq!((100..500).all(|i| samples[i] == samples[i + 93 * 4]), false);
+    }
+
+    #[test]
+    fn test_default_sample_rate()
+    {
+        let mut apu = Apu::new();
+        let count = Rc::new(RefCell::new(0));
+        let counter = Rc::clone(&count);
+        apu.set_sample_callback(move |_| *counter.borrow_mut() += 1);
+        run_cycles(&mut apu, CPU_CLOCK_RATE);
+
+        assert_eq!(*count.borrow(), DEFAULT_SAMPLE_RATE);
+    }
+
+    // reads the sample through fetch_address, as the CPU does
+    fn feed_dmc(apu: &mut Apu, memory: &dyn Fn(u16) -> u8) -> Vec<u16>
+    {
+        let mut addresses = Vec::new();
+        if let Some(address) = apu.dmc_fetch_address() {
+            addresses.push(address);
+            apu.dmc_fill(memory(address));
+        }
+        addresses
+    }
+
+    #[test]
+    fn test_dmc_reader()
+    {
+        let mut apu = Apu::new();
+        // $C040, 17 bytes
+        apu.write_register(0x12, 0x01);
+        apu.write_register(0x13, 0x01);
+        assert_eq!(apu.dmc_fetch_address(), None);
+        apu.write_register(0x15, 0x10);
+        assert_eq!(apu.read_status() & 0x10, 0x10);
+
+        let mut addresses = Vec::new();
+        for _ in 0..17 * 8 * 428 {
+            addresses.extend(feed_dmc(&mut apu, &|_| 0x00));
+            apu.clock();
+        }
+
+        assert_eq!(addresses, (0xC040..0xC051).collect::<Vec<u16>>());
+        assert_eq!(apu.read_status() & 0x10, 0x00);
+        assert_eq!(apu.dmc_irq(), false);
+    }
+
+    #[test]
+    fn test_dmc_address_wraps()
+    {
+        let mut apu = Apu::new();
+        // $FFC0, 65 bytes
+        apu.write_register(0x12, 0xFF);
+        apu.write_register(0x13, 0x04);
+        apu.write_register(0x15, 0x10);
+
+        let mut addresses = Vec::new();
+        for _ in 0..65 * 8 * 428 {
+            addresses.extend(feed_dmc(&mut apu, &|_| 0x00));
+            apu.clock();
+        }
+
+        assert_eq!(addresses.len(), 65);
+        assert_eq!(addresses[63], 0xFFFF);
+        assert_eq!(addresses[64], 0x8000);
+    }
+
+    #[test]
+    fn test_dmc_loop()
+    {
+        let mut apu = Apu::new();
+        // looping, fastest rate, 1 byte at $C000
+        apu.write_register(0x10, 0x4F);
+        apu.write_register(0x12, 0x00);
+        apu.write_register(0x13, 0x00);
+        apu.write_register(0x15, 0x10);
+
+        let mut addresses = Vec::new();
+        for _ in 0..4 * 8 * 54 {
+            addresses.extend(feed_dmc(&mut apu, &|_| 0x00));
+            apu.clock();
+        }
+
+        assert_eq!(addresses.len() >= 4, true);
+        assert_eq!(addresses.iter().all(|address| *address == 0xC000), true);
+        assert_eq!(apu.read_status() & 0x10, 0x10);
+    }
+
+    #[test]
+    fn test_dmc_irq()
+    {
+        let mut apu = Apu::new();
+        apu.write_register(0x10, 0x8F);
+        apu.write_register(0x13, 0x00);
+        apu.write_register(0x15, 0x10);
+        feed_dmc(&mut apu, &|_| 0x00);
+
+        assert_eq!(apu.dmc_irq(), true);
+        assert_eq!(apu.read_status() & 0x80, 0x80);
+        // not cleared by the read, but by $4015 writes
+        assert_eq!(apu.dmc_irq(), true);
+        apu.write_register(0x15, 0x00);
+        assert_eq!(apu.dmc_irq(), false);
+
+        // and by disabling the IRQ
+        let mut apu = Apu::new();
+        apu.write_register(0x10, 0x8F);
+        apu.write_register(0x13, 0x00);
+        apu.write_register(0x15, 0x10);
+        feed_dmc(&mut apu, &|_| 0x00);
+        assert_eq!(apu.dmc_irq(), true);
+        apu.write_register(0x10, 0x0F);
+        assert_eq!(apu.dmc_irq(), false);
+    }
+
+    // each bit moves the output level by 2, after the 8 silent bits of the empty unit
+    #[test]
+    fn test_dmc_output_level()
+    {
+        let mut apu = Apu::new();
+        apu.write_register(0x10, 0x0F);
+        apu.write_register(0x11, 0x40);
+        apu.write_register(0x13, 0x00);
+        apu.write_register(0x15, 0x10);
+        // 1 bit set, then 0s
+        feed_dmc(&mut apu, &|_| 0x0F);
+
+        run_cycles(&mut apu, 7 * 54 + 1);
+        assert_eq!(apu.dmc.output(), 0x40);
+        run_cycles(&mut apu, 4 * 54);
+        assert_eq!(apu.dmc.output(), 0x48);
+        run_cycles(&mut apu, 4 * 54);
+        assert_eq!(apu.dmc.output(), 0x40);
+    }
+}
@@ -1,128 +1,113 @@
 use super::Cpu;
-
-pub trait AddressSpace
-{
-    fn read(&self, cpu: &Cpu) -> u8;
-    fn write(&self, cpu: &mut Cpu, data: u8);
-}
-
-
-pub struct ZeroPageAddressSpace
-{
-    index: usize,
-}
-impl ZeroPageAddressSpace
-{
-    pub fn new(address: u8) -> ZeroPageAddressSpace { ZeroPageAddressSpace{index: address as usize} }
-}
-impl AddressSpace for ZeroPageAddressSpace
-{
-    fn read(&self, cpu: &Cpu) -> u8 { cpu.zero_page_ram[self.index] }
-    fn write(&self, cpu: &mut Cpu, data: u8) { cpu.zero_page_ram[self.index] = data; }
-}
-
-
-pub struct StackAddressSpace
-{
-    index: usize,
-}
-impl StackAddressSpace
-{
-    pub fn new(address: u8) -> StackAddressSpace { StackAddressSpace{index: address as usize} }
-}
-impl AddressSpace for StackAddressSpace
-{
-    fn read(&self, cpu: &Cpu) -> u8 { cpu.stack[self.index] }
-    fn write(&self, cpu: &mut Cpu, data: u8) { cpu.stack[self.index] = data }
+use super::cartridge::WriteOutcome;
+
+// Where an address of the CPU bus lands, decoded on every access without allocating.
+// Each variant holds the index or register inside its space.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AddressSpace
+{
+    ZeroPage(u8),
+    Stack(u8),
+    // internal ram after the stack, from $0200
+    Ram(u16),
+    PpuRegisters(u16),
+    ApuRegisters(u16),
+    IORegisters(u16),
+    Cartridge(u16),
+    Null,
+}
+
+impl AddressSpace
+{
+    #[inline]
+    pub fn decode(address: u16) -> AddressSpace
+    {
+        let first_nibble = (address >> 8) as u8;
+        let second_nibble = address as u8;
+
+        match (first_nibble, second_nibble) {
+            (x, index) if x <= 0x1F => match (x % 0x08, index) {
+                (0x00, index) => AddressSpace::ZeroPage(index),
+                (0x01, index) => AddressSpace::Stack(index),
+                (0x02..=0x07, _) => AddressSpace::Ram(address % 0x0800 - 0x0200),
+                (_, _) => AddressSpace::Null, // should never happen
+            },
+            (x, y) if x <= 0x3F => AddressSpace::PpuRegisters((y % 0x08) as u16),
+            (0x40, x) if x <= 0x13 || x == 0x15 => AddressSpace::ApuRegisters(x as u16),
+            (0x40, x) if x <= 0x17 => AddressSpace::IORegisters(x as u16),
+            (0x40, x) if x <= 0x1F => AddressSpace::Null, // unused APU and IO functionnalities
+            _ => AddressSpace::Cartridge(address)
+        }
+    }
+
+    #[inline]
+    pub fn read(self, cpu: &Cpu) -> u8
+    {
+        let open_bus = cpu.last_bus_value.get();
+        let data = match self {
+            AddressSpace::ZeroPage(index) => cpu.zero_page_ram[index as usize],
+            AddressSpace::Stack(index) => cpu.stack[index as usize],
+            AddressSpace::Ram(address) => cpu.internal_ram[address as usize],
+            // reading $2002 and $2007 changes the PPU state
+            AddressSpace::PpuRegisters(register) => cpu.ppu.borrow_mut().read_register(register),
+            // $4015 is inside the CPU and does not drive the external bus: bit 5 is
+            // what was left on it, and the bus keeps its value
+            AddressSpace::ApuRegisters(0x15) => return cpu.apu.borrow_mut().read_status() | open_bus & 0x20,
+            // the other APU registers are write-only
+            AddressSpace::ApuRegisters(_) => open_bus,
+            // the controllers only drive bits 0-4, usually the $40 of the address stays above
+            AddressSpace::IORegisters(0x16) => open_bus & 0xE0 | cpu.controllers.borrow_mut()[0].read(),
+            AddressSpace::IORegisters(0x17) => open_bus & 0xE0 | cpu.controllers.borrow_mut()[1].read(),
+            AddressSpace::IORegisters(_) => open_bus,
+            AddressSpace::Cartridge(address) => cpu.cartridge.borrow().read(address).unwrap_or(open_bus),
+            AddressSpace::Null => open_bus,
+        };
+        cpu.last_bus_value.set(data);
+        data
+    }
+
+    // a read without side effects, for debuggers and traces; the registers are not
+    // latched anywhere, so they show $FF like nestest.log does
+    pub fn peek(self, cpu: &Cpu) -> u8
+    {
+        match self {
+            AddressSpace::ZeroPage(index) => cpu.zero_page_ram[index as usize],
+            AddressSpace::Stack(index) => cpu.stack[index as usize],
+            AddressSpace::Ram(address) => cpu.internal_ram[address as usize],
+            AddressSpace::PpuRegisters(_) | AddressSpace::ApuRegisters(_) | AddressSpace::IORegisters(_) => 0xFF,
+            AddressSpace::Cartridge(address) => cpu.cartridge.borrow().read(address).unwrap_or(cpu.last_bus_value.get()),
+            AddressSpace::Null => cpu.last_bus_value.get(),
+        }
+    }
+
+    #[inline]
+    pub fn write(self, cpu: &mut Cpu, data: u8)
+    {
+        cpu.last_bus_value.set(data);
+        match self {
+            AddressSpace::ZeroPage(index) => cpu.zero_page_ram[index as usize] = data,
+            AddressSpace::Stack(index) => cpu.stack[index as usize] = data,
+            AddressSpace::Ram(address) => cpu.internal_ram[address as usize] = data,
+            AddressSpace::PpuRegisters(register) => cpu.ppu.get_mut().write_register(register, data),
+            AddressSpace::ApuRegisters(register) => cpu.apu.get_mut().write_register(register, data),
+            // OAMDMA, the copy happens once the writing instruction is over
+            AddressSpace::IORegisters(0x14) => cpu.oam_dma_page = Some(data),
+            // the strobe goes to both ports
+            AddressSpace::IORegisters(0x16) => {
+                for controller in cpu.controllers.get_mut().iter_mut() {
+                    controller.write_strobe(data);
+                }
+            },
+            // the APU frame counter, $4017 reads belong to the second controller
+            AddressSpace::IORegisters(0x17) => cpu.apu.get_mut().write_register(0x17, data),
+            AddressSpace::IORegisters(_) => {},
+            AddressSpace::Cartridge(address) => {
+                let outcome = cpu.cartridge.borrow_mut().write(address, data);
+                if let WriteOutcome::ReadOnly = outcome {
+                    cpu.rom_write(address, data);
+                }
+            },
+            AddressSpace::Null => {},
+        }
+    }
 }
-
-
-pub struct RamAddressSpace
-{
-    address: usize,
-}
-impl RamAddressSpace
-{
-    pub fn new(address: u16) -> RamAddressSpace { RamAddressSpace{address: address as usize} }
-}
-impl AddressSpace for RamAddressSpace
-{
-    fn read(&self, cpu: &Cpu) -> u8 { cpu.internal_ram[self.address] }
-    fn write(&self, cpu: &mut Cpu, data: u8) { cpu.internal_ram[self.address] = data; }
-}
-
-
-pub struct PpuRegistersAddressSpace
-{
-}
-impl PpuRegistersAddressSpace
-{
-    pub fn new(_address: u16) -> PpuRegistersAddressSpace { PpuRegistersAddressSpace{} }
-}
-impl AddressSpace for PpuRegistersAddressSpace
-{
-    fn read(&self, _cpu: &Cpu) -> u8 { 0 }
-    fn write(&self, _cpu: &mut Cpu, _data: u8) { }
-}
-
-
-pub struct ApuRegistersAddressSpace
-{
-}
-impl ApuRegistersAddressSpace
-{
-    pub fn new(_address: u16) -> ApuRegistersAddressSpace { ApuRegistersAddressSpace{} }
-}
-impl AddressSpace for ApuRegistersAddressSpace
-{
-    fn read(&self, _cpu: &Cpu) -> u8 { 0 }
-    fn write(&self, _cpu: &mut Cpu, _data: u8) { }
-}
-
-
-pub struct IORegistersAddressSpace
-{
-}
-impl IORegistersAddressSpace
-{
-    pub fn new(_address: u16) -> IORegistersAddressSpace { IORegistersAddressSpace{} }
-}
-impl AddressSpace for IORegistersAddressSpace
-{
-    fn read(&self, _cpu: &Cpu) -> u8 { 0 }
-    fn write(&self, _cpu: &mut Cpu, _data: u8) { }
-}
-
-pub struct CartridgeAddressSpace
-{
-    address: u16,
-}
-impl CartridgeAddressSpace
-{
-    pub fn new(address: u16) -> CartridgeAddressSpace { CartridgeAddressSpace{address} }
-}
-impl AddressSpace for CartridgeAddressSpace
-{
-    fn read(&self, cpu: &Cpu) -> u8 { cpu.cartridge.read(self.address) }
-    fn write(&self, cpu: &mut Cpu, data: u8) { cpu.cartridge.write(self.address, data) }
-}
-
-pub struct NullAddressSpace {}
-impl NullAddressSpace
-{
-    pub fn new() -> NullAddressSpace { NullAddressSpace{} }
-}
-impl AddressSpace for NullAddressSpace
-{
-    fn read(&self, _cpu: &Cpu) -> u8 { 0 }
-    fn write(&self, _cpu: &mut Cpu, _data: u8) { }
-}
-
-
-
-
-
-
-
-
-
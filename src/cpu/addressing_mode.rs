@@ -1,72 +1,92 @@
 use super::Cpu;
 
-pub trait AddressingMode
+// Decoded once per instruction, before it executes. The memory modes only differ by
+// how their address is computed, they share MemoryAccess.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AddressingMode
 {
-    fn read(&self, cpu: &Cpu) -> u8;
-    fn write(&self, cpu: &mut Cpu, data: u8);
-    fn address(&self) -> u16;
-    fn page_boundary_crossed(&self) -> bool;
+    Implicit,
+    Accumulator,
+    Immediate(u8),
+    // signed offset of the branch
+    Relative(u8),
+    Memory(MemoryAccess),
+    // the data latch of the cycle accurate mode, its micro-ops do the bus accesses
+    Latch,
 }
 
-pub struct Implicit;
-impl AddressingMode for Implicit
+impl AddressingMode
 {
-    fn read(&self, _cpu: &Cpu) -> u8 { 0 }
-    fn write(&self, _cpu: &mut Cpu, _data: u8) { }
-    fn address(&self) -> u16 { 0 }
-    fn page_boundary_crossed(&self) -> bool { false }
-}
+    pub fn immediate(cpu: &mut Cpu) -> AddressingMode { AddressingMode::Immediate(cpu.fetch()) }
 
-pub struct Accumulator;
-impl AddressingMode for Accumulator
-{
-    fn read(&self, cpu: &Cpu) -> u8 { cpu.registers.a }
-    fn write(&self, cpu: &mut Cpu, data: u8) { cpu.registers.a = data }
-    fn address(&self) -> u16 { 0 }
-    fn page_boundary_crossed(&self) -> bool { false }
-}
+    pub fn relative(cpu: &mut Cpu) -> AddressingMode { AddressingMode::Relative(cpu.fetch()) }
 
-pub struct Immediate
-{
-    value: u8,
-}
-impl Immediate
-{
-    pub fn new(cpu: &mut Cpu) -> Immediate
+    pub fn read(&self, cpu: &Cpu) -> u8
     {
-        Immediate {value: cpu.fetch()}
+        match self {
+            AddressingMode::Implicit => 0,
+            AddressingMode::Accumulator => cpu.registers.a,
+            AddressingMode::Immediate(value) => *value,
+            AddressingMode::Relative(offset) => *offset,
+            AddressingMode::Memory(access) => access.read(cpu),
+            AddressingMode::Latch => cpu.micro.data,
+        }
     }
-}
-impl AddressingMode for Immediate
-{
-    fn read(&self, _cpu: &Cpu) -> u8 { self.value }
-    fn write(&self, _cpu: &mut Cpu, _data: u8) { }
-    fn address(&self) -> u16 { 0 }
-    fn page_boundary_crossed(&self) -> bool { false }
-}
 
-pub struct Relative
-{
-    offset: u8,
-}
-impl Relative
-{
-    pub fn new(cpu: &mut Cpu) -> Relative
+    pub fn write(&self, cpu: &mut Cpu, data: u8)
     {
-        Relative {offset: cpu.fetch()}
+        match self {
+            AddressingMode::Accumulator => cpu.registers.a = data,
+            AddressingMode::Memory(access) => access.write(cpu, data),
+            AddressingMode::Latch => cpu.micro.data = data,
+            _ => {},
+        }
+    }
+
+    // read-modify-write instructions write the unmodified data back before the result
+    pub fn read_for_modify(&self, cpu: &Cpu) -> u8
+    {
+        match self {
+            AddressingMode::Memory(access) => access.read_for_modify(cpu),
+            _ => self.read(cpu),
+        }
+    }
+
+    pub fn write_modified(&self, cpu: &mut Cpu, data: u8, result: u8)
+    {
+        match self {
+            AddressingMode::Memory(access) => access.write_modified(cpu, data, result),
+            _ => self.write(cpu, result),
+        }
+    }
+
+    pub fn address(&self) -> u16
+    {
+        match self {
+            AddressingMode::Memory(access) => access.address,
+            _ => 0,
+        }
+    }
+
+    pub fn page_boundary_crossed(&self) -> bool
+    {
+        match self {
+            AddressingMode::Memory(access) => access.page_boundary_crossed,
+            _ => false,
+        }
     }
-}
-impl AddressingMode for Relative
-{
-    fn read(&self, _cpu: &Cpu) -> u8 { self.offset }
-    fn write(&self, _cpu: &mut Cpu, _data: u8) { }
-    fn address(&self) -> u16 { 0 }
-    fn page_boundary_crossed(&self) -> bool { false }
 }
 
+// destination of a branch, the offset is signed and relative to the next instruction
+pub fn branch_target(offset: u8, pc_after_operand: u16) -> u16 { pc_after_operand.wrapping_add(offset as i8 as u16) }
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct MemoryAccess
 {
     address: u16,
+    // The indexed modes first read from the base page, before the carry of the index is
+    // applied. Reads only do so when the page is crossed, writes and RMW always do.
+    uncorrected_address: Option<u16>,
     page_boundary_crossed: bool,
 }
 impl MemoryAccess
@@ -74,23 +94,21 @@ impl MemoryAccess
     // Zero Page
     pub fn new_zero_page(cpu: &mut Cpu) -> MemoryAccess
     {
-        MemoryAccess {address: cpu.fetch() as u16, page_boundary_crossed: false}
+        MemoryAccess {address: cpu.fetch() as u16, uncorrected_address: None, page_boundary_crossed: false}
     }
 
-    pub fn new_indexed_zero_page(cpu: &mut Cpu, index: u8) -> MemoryAccess { MemoryAccess {address: cpu.fetch().wrapping_add(index) as u16, page_boundary_crossed: false} }
+    pub fn new_indexed_zero_page(cpu: &mut Cpu, index: u8) -> MemoryAccess { MemoryAccess {address: cpu.fetch().wrapping_add(index) as u16, uncorrected_address: None, page_boundary_crossed: false} }
 
     // Absolute
     pub fn new_absolute(cpu: &mut Cpu) -> MemoryAccess
     {
-        MemoryAccess {address: cpu.fetch() as u16 | (cpu.fetch() as u16) << 8, page_boundary_crossed: false}
+        MemoryAccess {address: cpu.fetch() as u16 | (cpu.fetch() as u16) << 8, uncorrected_address: None, page_boundary_crossed: false}
     }
 
     pub fn new_indexed_absolute(cpu: &mut Cpu, index: u8) -> MemoryAccess
     {
         let address = cpu.fetch() as u16 | (cpu.fetch() as u16) << 8;
-        // page boundaries check;
-        let page_boundary_crossed = address.wrapping_add(index as u16) & 0xFF00 != address & 0xFF00;
-        MemoryAccess {address: address.wrapping_add(index as u16), page_boundary_crossed}
+        MemoryAccess::indexed(address, index)
     }
 
     // Indirect
@@ -98,8 +116,10 @@ impl MemoryAccess
     {
         let indirect_address = cpu.fetch() as u16 | (cpu.fetch() as u16) << 8;
         let address_lsb = cpu.load(indirect_address) as u16;
-        let address_msb = (cpu.load(indirect_address + 1) as u16) << 8;
-        MemoryAccess {address: address_lsb | address_msb, page_boundary_crossed: false}
+        // the pointer increment does not carry into the high byte: JMP ($02FF) reads $02FF and $0200
+        let msb_address = (indirect_address & 0xFF00) | (indirect_address as u8).wrapping_add(1) as u16;
+        let address_msb = (cpu.load(msb_address) as u16) << 8;
+        MemoryAccess {address: address_lsb | address_msb, uncorrected_address: None, page_boundary_crossed: false}
     }
 
     pub fn new_indexed_indirect(cpu: &mut Cpu, index: u8) -> MemoryAccess
@@ -107,7 +127,7 @@ impl MemoryAccess
         let indirect_address: u8 = cpu.fetch().wrapping_add(index);
         let address_lsb = cpu.load(indirect_address as u16) as u16;
         let address_msb = (cpu.load(indirect_address.wrapping_add(1)as u16) as u16) << 8;
-        MemoryAccess {address: address_lsb | address_msb, page_boundary_crossed: false}
+        MemoryAccess {address: address_lsb | address_msb, uncorrected_address: None, page_boundary_crossed: false}
     }
 
     pub fn new_indirect_indexed(cpu: &mut Cpu, index: u8) -> MemoryAccess
@@ -115,15 +135,52 @@ impl MemoryAccess
         let indirect_address: u8 = cpu.fetch();
         let address_lsb = cpu.load(indirect_address as u16) as u16;
         let address_msb = (cpu.load(indirect_address.wrapping_add(1) as u16) as u16) << 8;
-        // page boundaries check;
-        let page_boundary_crossed = (address_lsb | address_msb).wrapping_add(index as u16) & 0xFF00 != (address_lsb | address_msb) & 0xFF00;
-        MemoryAccess {address: (address_lsb | address_msb).wrapping_add(index as u16), page_boundary_crossed}
+        MemoryAccess::indexed(address_lsb | address_msb, index)
+    }
+
+    fn indexed(base_address: u16, index: u8) -> MemoryAccess
+    {
+        let address = base_address.wrapping_add(index as u16);
+        MemoryAccess {
+            address,
+            uncorrected_address: Some((base_address & 0xFF00) | (address & 0x00FF)),
+            // page boundaries check;
+            page_boundary_crossed: address & 0xFF00 != base_address & 0xFF00,
+        }
+    }
+
+    fn dummy_read(&self, cpu: &Cpu)
+    {
+        if let Some(address) = self.uncorrected_address {
+            cpu.load(address);
+        }
     }
 }
-impl AddressingMode for MemoryAccess
+impl MemoryAccess
 {
-    fn read(&self, cpu: &Cpu) -> u8 { cpu.load(self.address) }
-    fn write(&self, cpu: &mut Cpu, data: u8) { cpu.write(self.address, data); }
-    fn address(&self) -> u16 { self.address }
-    fn page_boundary_crossed(&self) -> bool { self.page_boundary_crossed }
+    pub fn read(&self, cpu: &Cpu) -> u8
+    {
+        if self.page_boundary_crossed {
+            self.dummy_read(cpu);
+        }
+        cpu.load(self.address)
+    }
+
+    pub fn write(&self, cpu: &mut Cpu, data: u8)
+    {
+        self.dummy_read(cpu);
+        cpu.write(self.address, data);
+    }
+
+    pub fn read_for_modify(&self, cpu: &Cpu) -> u8
+    {
+        self.dummy_read(cpu);
+        cpu.load(self.address)
+    }
+
+    pub fn write_modified(&self, cpu: &mut Cpu, data: u8, result: u8)
+    {
+        cpu.write(self.address, data);
+        cpu.write(self.address, result);
+    }
 }
@@ -0,0 +1,59 @@
+// Runs blargg's test ROMs, which report through the status byte at $6000 and the
+// message at $6004. They are registered fixtures, looked up under
+// NESQUICK_FIXTURES_DIR and laid out like the archives they come from
+// (instr_test-v5/rom_singles/01-basics.nes, ...).
+
+use super::{
+    load_cartridge_from_bytes,
+    Cpu,
+    StopCondition,
+    StopReason,
+};
+
+// a minute of emulated time, the slowest ROM needs about 20 seconds
+const CYCLE_CAP: u64 = 1_789_773 * 60;
+
+fn run_blargg(path: &str)
+{
+    let rom = fixture_or_skip!(path);
+    let cartridge = load_cartridge_from_bytes(&rom).unwrap_or_else(|e| panic!("could not load {}: {}", path, e));
+    let mut cpu = Cpu::new(cartridge);
+
+    let condition = StopCondition::Any(vec![StopCondition::StatusByteProtocol, StopCondition::CycleCount(CYCLE_CAP)]);
+    match cpu.run_until(&condition) {
+        StopReason::TestCompleted { result: 0, .. } => (),
+        StopReason::TestCompleted { result, message } => panic!("{} failed with code {}:\n{}", path, result, message),
+        _ => panic!("{} did not finish in {} cycles, status ${:02X}:\n{}", path, CYCLE_CAP, cpu.peek(0x6000), cpu.read_string(0x6004)),
+    }
+}
+
+macro_rules! blargg_tests {
+    ($($name:ident: $path:expr,)*) => {
+        $(
+            #[test]
+            fn $name() { run_blargg($path) }
+        )*
+    };
+}
+
+blargg_tests! {
+    instr_basics: "instr_test-v5/rom_singles/01-basics.nes",
+    instr_implied: "instr_test-v5/rom_singles/02-implied.nes",
+    instr_immediate: "instr_test-v5/rom_singles/03-immediate.nes",
+    instr_zero_page: "instr_test-v5/rom_singles/04-zero_page.nes",
+    instr_zp_xy: "instr_test-v5/rom_singles/05-zp_xy.nes",
+    instr_absolute: "instr_test-v5/rom_singles/06-absolute.nes",
+    instr_abs_xy: "instr_test-v5/rom_singles/07-abs_xy.nes",
+    instr_ind_x: "instr_test-v5/rom_singles/08-ind_x.nes",
+    instr_ind_y: "instr_test-v5/rom_singles/09-ind_y.nes",
+    instr_branches: "instr_test-v5/rom_singles/10-branches.nes",
+    instr_stack: "instr_test-v5/rom_singles/11-stack.nes",
+    instr_jmp_jsr: "instr_test-v5/rom_singles/12-jmp_jsr.nes",
+    instr_rts: "instr_test-v5/rom_singles/13-rts.nes",
+    instr_rti: "instr_test-v5/rom_singles/14-rti.nes",
+    instr_brk: "instr_test-v5/rom_singles/15-brk.nes",
+    instr_special: "instr_test-v5/rom_singles/16-special.nes",
+    cpu_dummy_reads: "cpu_dummy_reads/cpu_dummy_reads.nes",
+    instr_timing: "instr_timing/rom_singles/1-instr_timing.nes",
+    branch_timing: "instr_timing/rom_singles/2-branch_timing.nes",
+}
@@ -1,24 +1,325 @@
-use std::fs;
+use std::fmt;
+use std::fs::File;
+use std::io::{
+    self,
+    BufReader,
+    Read,
+};
+
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+
+pub enum WriteOutcome
+{
+    Handled,
+    ReadOnly, // the write hit ROM and was dropped
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mirroring
+{
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+impl Mirroring
+{
+    fn save_state(self, state: &mut StateWriter)
+    {
+        state.write_u8(match self {
+            Mirroring::Horizontal => 0,
+            Mirroring::Vertical => 1,
+            Mirroring::SingleScreenLower => 2,
+            Mirroring::SingleScreenUpper => 3,
+            Mirroring::FourScreen => 4,
+        })
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Mirroring, SaveStateError>
+    {
+        match state.read_u8()? {
+            0 => Ok(Mirroring::Horizontal),
+            1 => Ok(Mirroring::Vertical),
+            2 => Ok(Mirroring::SingleScreenLower),
+            3 => Ok(Mirroring::SingleScreenUpper),
+            4 => Ok(Mirroring::FourScreen),
+            _ => Err(SaveStateError::Mismatch("mirroring")),
+        }
+    }
+}
 
 pub trait Mapper
 {
-    fn read(&self, address: u16) -> u8;
-    fn write(&mut self, address: u16, data: u8);
+    // CPU side, $4020-$FFFF, None where the board leaves the bus open
+    fn read(&self, address: u16) -> Option<u8>;
+    fn write(&mut self, address: u16, data: u8) -> WriteOutcome;
+    // PPU side, pattern tables at $0000-$1FFF
+    fn chr_read(&self, address: u16) -> u8;
+    fn chr_write(&mut self, address: u16, data: u8);
+    fn mirroring(&self) -> Mirroring;
+    // called once per CPU cycle, for boards that track time
+    fn cpu_clock(&mut self) { }
+    // every address the PPU puts on its bus, for boards that watch it
+    fn ppu_address(&mut self, _address: u16) { }
+    // level of the cartridge IRQ output
+    fn irq(&self) -> bool { false }
+    // whether the IRQ output can rise without a CPU write
+    fn irq_enabled(&self) -> bool { false }
+    // registers and RAM for save states, boards without any keep the defaults. A
+    // state only loads into a board built from the same ROM.
+    fn save_state(&self, _state: &mut StateWriter) { }
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), SaveStateError> { Ok(()) }
+    // $6000-$7FFF RAM kept by a battery, only for headers with the battery flag
+    fn prg_ram(&self) -> Option<&[u8]> { None }
+    fn load_prg_ram(&mut self, _data: &[u8]) -> Result<(), CartridgeError> { Err(CartridgeError::NoBatteryRam) }
+}
+
+#[derive(Debug)]
+pub enum CartridgeError
+{
+    Io(io::Error),
+    BadMagic,
+    TruncatedHeader,
+    TruncatedTrainer,
+    TruncatedPrgRom { expected: usize, got: usize },
+    TruncatedChrRom { expected: usize, got: usize },
+    UnsupportedMapper(u16),
+    NoBatteryRam,
+    PrgRamSize { expected: usize, got: usize },
+}
+
+impl fmt::Display for CartridgeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            CartridgeError::Io(e) => write!(f, "{}", e),
+            CartridgeError::BadMagic => write!(f, "not an iNES file"),
+            CartridgeError::TruncatedHeader => write!(f, "truncated iNES header"),
+            CartridgeError::TruncatedTrainer => write!(f, "truncated trainer"),
+            CartridgeError::TruncatedPrgRom { expected, got } => write!(f, "truncated PRG ROM: expected {} bytes, got {}", expected, got),
+            CartridgeError::TruncatedChrRom { expected, got } => write!(f, "truncated CHR ROM: expected {} bytes, got {}", expected, got),
+            CartridgeError::UnsupportedMapper(mapper) => write!(f, "unsupported mapper {}", mapper),
+            CartridgeError::NoBatteryRam => write!(f, "the cartridge has no battery backed RAM"),
+            CartridgeError::PrgRamSize { expected, got } => write!(f, "wrong save RAM size: expected {} bytes, got {}", expected, got),
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError
+{
+    fn from(e: io::Error) -> CartridgeError { CartridgeError::Io(e) }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CartridgeHeader
+{
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub nes2: bool,
+}
+
+impl CartridgeHeader
+{
+    // the first 16 bytes of the file
+    pub fn parse(header: &[u8]) -> Result<CartridgeHeader, CartridgeError>
+    {
+        if header.len() < 16 {
+            return Err(CartridgeError::TruncatedHeader);
+        }
+        if header[0..4] != [0x4E, 0x45, 0x53, 0x1A] {
+            return Err(CartridgeError::BadMagic);
+        }
+        let nes2 = header[7] & 0b0000_1100 == 0b0000_1000;
+        let mut mapper = (header[6] >> 4) as u16;
+        let mut submapper = 0;
+        let (prg_rom_size, chr_rom_size) = if nes2 {
+            mapper |= (header[7] & 0xF0) as u16 | ((header[8] & 0x0F) as u16) << 8;
+            submapper = header[8] >> 4;
+            (nes2_rom_size(header[4], header[9] & 0x0F, 0x4000), nes2_rom_size(header[5], header[9] >> 4, 0x2000))
+        } else {
+            // old dumpers wrote their name over bytes 7-15, the upper mapper nibble is garbage then
+            if header[12..16] == [0; 4] {
+                mapper |= (header[7] & 0xF0) as u16;
+            }
+            (header[4] as usize * 0x4000, header[5] as usize * 0x2000)
+        };
+        let mirroring = match header[6] & 0b0000_1001 {
+            0b0000_0000 => Mirroring::Horizontal,
+            0b0000_0001 => Mirroring::Vertical,
+            _ => Mirroring::FourScreen,
+        };
+        Ok(CartridgeHeader {
+            prg_rom_size,
+            chr_rom_size,
+            mapper,
+            submapper,
+            mirroring,
+            battery: header[6] & 0b0000_0010 != 0,
+            trainer: header[6] & 0b0000_0100 != 0,
+            nes2,
+        })
+    }
+}
+
+// NES 2.0 sizes are a 12 bits count of units, or 2^E * (M * 2 + 1) bytes when the upper nibble is $F
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> usize
+{
+    if msb == 0x0F {
+        (1usize << (lsb >> 2)) * ((lsb & 0x03) as usize * 2 + 1)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+pub fn load_cartridge(filepath: &str) -> Result<Box<dyn Mapper>, CartridgeError>
+{
+    load_cartridge_from_reader(BufReader::new(File::open(filepath)?))
+}
+
+// for ROMs already in memory: embedded, downloaded, or read once to also compute a checksum
+pub fn load_cartridge_from_bytes(rom: &[u8]) -> Result<Box<dyn Mapper>, CartridgeError>
+{
+    load_cartridge_from_reader(rom)
+}
+
+// reads up to len bytes straight into an exactly sized buffer, returns how many were available on a short read
+fn read_section<R: Read>(reader: &mut R, len: usize) -> Result<Result<Vec<u8>, usize>, CartridgeError>
+{
+    let mut section = Vec::with_capacity(len);
+    reader.take(len as u64).read_to_end(&mut section)?;
+    Ok(if section.len() == len {Ok(section)} else {Err(section.len())})
+}
+
+// Reads the header first, then every section directly into its final buffer
+pub fn load_cartridge_from_reader<R: Read>(mut reader: R) -> Result<Box<dyn Mapper>, CartridgeError>
+{
+    let header = read_section(&mut reader, 16)?.map_err(|_| CartridgeError::TruncatedHeader)?;
+    let header = CartridgeHeader::parse(&header)?;
+    if header.trainer {
+        read_section(&mut reader, 512)?.map_err(|_| CartridgeError::TruncatedTrainer)?;
+    }
+    let prg_rom = read_section(&mut reader, header.prg_rom_size)?
+        .map_err(|got| CartridgeError::TruncatedPrgRom { expected: header.prg_rom_size, got })?;
+    let chr_rom = read_section(&mut reader, header.chr_rom_size)?
+        .map_err(|got| CartridgeError::TruncatedChrRom { expected: header.chr_rom_size, got })?;
+
+    let mirroring = header.mirroring;
+    // NES 2.0 submapper 2 is the bus conflicting variant, for UxROM and CNROM
+    let bus_conflicts = header.nes2 && header.submapper == 2;
+    Ok(match header.mapper {
+        0 => Box::new(NROM::new(prg_rom, chr_rom, mirroring).with_battery(header.battery)),
+        1 => Box::new(MMC1::new(prg_rom, chr_rom).with_battery(header.battery)),
+        2 => Box::new(UxROM::new(prg_rom, chr_rom, mirroring, bus_conflicts)),
+        3 => Box::new(CNROM::new(prg_rom, chr_rom, mirroring, bus_conflicts)),
+        4 => Box::new(MMC3::new(prg_rom, chr_rom, mirroring).with_battery(header.battery)),
+        mapper => return Err(CartridgeError::UnsupportedMapper(mapper)),
+    })
+}
+
+// ROM or RAM split in equally sized banks, mapped through consecutive windows of
+// the same size. Bank numbers wrap around the actual bank count, the way boards
+// leave the upper bank lines unconnected, so an out-of-range selection never panics.
+// Empty storage is open bus.
+pub struct BankedMemory
+{
+    data: Vec<u8>,
+    bank_size: usize,
+    selected: Vec<usize>,
+}
+
+impl BankedMemory
+{
+    pub fn new(data: Vec<u8>, bank_size: usize, windows: usize) -> BankedMemory
+    {
+        BankedMemory {
+            data,
+            bank_size,
+            selected: vec![0; windows],
+        }
+    }
+
+    pub fn bank_count(&self) -> usize { self.data.len() / self.bank_size }
+
+    // returns the bank actually mapped, None for open bus
+    pub fn select(&mut self, window: usize, bank: usize) -> Option<usize>
+    {
+        self.selected[window] = bank;
+        self.mapped_bank(window)
+    }
+
+    fn mapped_bank(&self, window: usize) -> Option<usize>
+    {
+        match self.bank_count() {
+            0 => None,
+            count => Some(self.selected[window] % count),
+        }
+    }
+
+    // offset from the start of the first window to offset in data
+    pub fn translate(&self, offset: usize) -> Option<usize>
+    {
+        let bank = self.mapped_bank(offset / self.bank_size % self.selected.len())?;
+        Some(bank * self.bank_size + offset % self.bank_size)
+    }
+
+    pub fn read(&self, offset: usize) -> Option<u8>
+    {
+        self.translate(offset).map(|index| self.data[index])
+    }
+
+    pub fn write(&mut self, offset: usize, data: u8)
+    {
+        if let Some(index) = self.translate(offset) {
+            self.data[index] = data;
+        }
+    }
+
+    // the selected banks, and the contents when they are RAM
+    pub fn save_state(&self, state: &mut StateWriter, is_ram: bool)
+    {
+        for bank in &self.selected {
+            state.write_u32(*bank as u32);
+        }
+        if is_ram {
+            state.write_bytes(&self.data);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, is_ram: bool) -> Result<(), SaveStateError>
+    {
+        for bank in self.selected.iter_mut() {
+            *bank = state.read_u32()? as usize;
+        }
+        if is_ram {
+            state.read_bytes(&mut self.data)?;
+        }
+        Ok(())
+    }
 }
 
-pub fn load_cartridge(filepath: &str) -> Box<dyn Mapper>
+// the .sav of a board must have the size of its RAM
+fn load_battery_ram(ram: &mut [u8], battery: bool, data: &[u8]) -> Result<(), CartridgeError>
 {
-    let rom_content = fs::read(filepath).expect(&format!("Could not load rom {}", filepath));
-    if !(rom_content[0] ==  0x4E &&
-        rom_content[1] == 0x45 &&
-        rom_content [2] == 0x53 &&
-        rom_content[3] ==  0x1A) {
-        panic!(format!("file {} isn't an INES file", filepath));
+    if !battery {
+        return Err(CartridgeError::NoBatteryRam)
     }
-    match (rom_content[6] & 0xF0 >> 4) | (rom_content[7] & 0xF0) {
-        0 => Box::new(NROM::new(rom_content)),
-        _ => Box::new(DummyMapper::new()),
+    if data.len() != ram.len() {
+        return Err(CartridgeError::PrgRamSize { expected: ram.len(), got: data.len() })
     }
+    ram.copy_from_slice(data);
+    Ok(())
 }
 
 pub struct DummyMapper {}
@@ -28,62 +329,622 @@ impl DummyMapper
 }
 impl Mapper for DummyMapper
 {
-    fn read(&self, address: u16) -> u8
+    fn read(&self, address: u16) -> Option<u8>
     {
         match address {
-            0xFFFE => 0x00,
-            0xFFFF => 0x80,
-            _ => 0,
+            0xFFFE => Some(0x00),
+            0xFFFF => Some(0x80),
+            _ => Some(0),
         }
     }
-    fn write(&mut self, _address: u16, _data: u8) {}
+    fn write(&mut self, _address: u16, _data: u8) -> WriteOutcome { WriteOutcome::Handled }
+    fn chr_read(&self, _address: u16) -> u8 { 0 }
+    fn chr_write(&mut self, _address: u16, _data: u8) { }
+    fn mirroring(&self) -> Mirroring { Mirroring::Horizontal }
 }
 
 pub struct NROM
 {
-    rom: [u8; 0x8000],
+    prg_rom: BankedMemory,
+    chr: BankedMemory,
+    // boards without CHR ROM have 8KB of CHR RAM instead
+    chr_is_ram: bool,
     ram: [u8; 0x2000],
-    rom_size: usize,
+    battery: bool,
+    mirroring: Mirroring,
 }
 impl NROM
 {
-    pub fn new(rom_content: Vec<u8>) -> NROM
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> NROM
     {
-        let rom_size = rom_content[4] as usize * 0x4000;
-        let mut rom = [0; 0x8000];
-        let rom_start = if (rom_content[6] & 0b0000_00100) >> 2 == 1 {528} else {16};
-        rom[..rom_size].copy_from_slice(&rom_content[rom_start..(rom_start + rom_size)]);
+        // NROM-128 has a single 16KB bank, $C000-$FFFF mirrors $8000-$BFFF
+        let mut prg_rom = BankedMemory::new(prg_rom, 0x4000, 2);
+        prg_rom.select(1, 1);
+        let chr_is_ram = chr_rom.is_empty();
+        let chr = if chr_is_ram {vec![0; 0x2000]} else {chr_rom};
         NROM{
-            rom,
+            prg_rom,
+            chr: BankedMemory::new(chr, 0x2000, 1),
+            chr_is_ram,
             ram: [0; 0x2000],
-            rom_size,
+            battery: false,
+            mirroring,
         }
     }
+
+    pub fn with_battery(mut self, battery: bool) -> NROM
+    {
+        self.battery = battery;
+        self
+    }
 }
 impl Mapper for NROM
 {
-    fn read(&self, address: u16) -> u8
+    fn read(&self, address: u16) -> Option<u8>
     {
-        if address < 0x6000 {
-            return 0
+        match address {
+            0x6000..=0x7FFF => Some(self.ram[(address - 0x6000) as usize]),
+            0x8000..=0xFFFF => self.prg_rom.read((address - 0x8000) as usize),
+            _ => None,
         }
-        let address = address - 0x6000;
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> WriteOutcome
+    {
         match address {
-            0x0000..=0x1FFF => self.ram[address as usize],
-            0x2000..=0x9FFF => self.rom[(address - 0x2000) as usize % self.rom_size],
-            _ => 0
+            0x6000..=0x7FFF => {
+                self.ram[(address - 0x6000) as usize] = data;
+                WriteOutcome::Handled
+            },
+            0x8000..=0xFFFF => WriteOutcome::ReadOnly,
+            _ => WriteOutcome::Handled,
         }
     }
 
-    fn write(&mut self, address: u16, data: u8)
+    fn chr_read(&self, address: u16) -> u8 { self.chr.read(address as usize).unwrap_or(0) }
+
+    fn chr_write(&mut self, address: u16, data: u8)
     {
-        if address >= 0x6000 {
-            let address = address - 0x6000;
-            match address {
-                0x00..=0x1FFF => self.ram[address as usize] = data,
-                0x2000..=0x9FFF => self.rom[(address as usize - 0x2000) % self.rom_size] = data,
-                _ => {}
+        if self.chr_is_ram {
+            self.chr.write(address as usize, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring { self.mirroring }
+
+    fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bytes(&self.ram);
+        self.chr.save_state(state, self.chr_is_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        state.read_bytes(&mut self.ram)?;
+        self.chr.load_state(state, self.chr_is_ram)
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> { if self.battery {Some(&self.ram)} else {None} }
+
+    fn load_prg_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> { load_battery_ram(&mut self.ram, self.battery, data) }
+}
+
+// SxROM boards. Registers are loaded one bit at a time through a 5 bits shift register,
+// the fifth write picks the register from bits 13-14 of its address.
+pub struct MMC1
+{
+    prg_rom: BankedMemory,
+    chr: BankedMemory,
+    chr_is_ram: bool,
+    // battery backed on most boards
+    ram: [u8; 0x2000],
+    battery: bool,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    // the board ignores a write on the cycle following another one, as done by the
+    // dummy write of read-modify-write instructions
+    cycle: u64,
+    last_write_cycle: Option<u64>,
+}
+impl MMC1
+{
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> MMC1
+    {
+        let chr_is_ram = chr_rom.is_empty();
+        let chr = if chr_is_ram {vec![0; 0x2000]} else {chr_rom};
+        let mut mmc1 = MMC1 {
+            prg_rom: BankedMemory::new(prg_rom, 0x4000, 2),
+            chr: BankedMemory::new(chr, 0x1000, 2),
+            chr_is_ram,
+            ram: [0; 0x2000],
+            battery: false,
+            shift: 0,
+            shift_count: 0,
+            // powers on with the last bank fixed at $C000
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_write_cycle: None,
+        };
+        mmc1.update_banks();
+        mmc1
+    }
+
+    pub fn with_battery(mut self, battery: bool) -> MMC1
+    {
+        self.battery = battery;
+        self
+    }
+
+    fn write_serial(&mut self, address: u16, data: u8)
+    {
+        let consecutive = matches!(self.last_write_cycle, Some(cycle) if self.cycle - cycle <= 1);
+        self.last_write_cycle = Some(self.cycle);
+        if consecutive {
+            return
+        }
+        // bit 7 resets the shift register and goes back to PRG mode 3
+        if data & 0x80 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            self.update_banks();
+            return
+        }
+        self.shift |= (data & 0x01) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return
+        }
+        match (address >> 13) & 0x03 {
+            0 => self.control = self.shift,
+            1 => self.chr_bank_0 = self.shift,
+            2 => self.chr_bank_1 = self.shift,
+            _ => self.prg_bank = self.shift,
+        }
+        self.shift = 0;
+        self.shift_count = 0;
+        self.update_banks();
+    }
+
+    fn update_banks(&mut self)
+    {
+        let prg_bank = (self.prg_bank & 0x0F) as usize;
+        let (low, high) = match (self.control >> 2) & 0x03 {
+            // 32KB, the low bit of the bank number is ignored
+            0 | 1 => (prg_bank & !1, prg_bank | 1),
+            // first bank fixed at $8000
+            2 => (0, prg_bank),
+            // last bank fixed at $C000
+            _ => (prg_bank, self.prg_rom.bank_count().saturating_sub(1)),
+        };
+        self.prg_rom.select(0, low);
+        self.prg_rom.select(1, high);
+
+        let (low, high) = if self.control & 0x10 != 0 {
+            (self.chr_bank_0 as usize, self.chr_bank_1 as usize)
+        } else {
+            // 8KB, the low bit of the bank number is ignored
+            (self.chr_bank_0 as usize & !1, self.chr_bank_0 as usize | 1)
+        };
+        self.chr.select(0, low);
+        self.chr.select(1, high);
+    }
+
+    fn ram_enabled(&self) -> bool { self.prg_bank & 0x10 == 0 }
+}
+impl Mapper for MMC1
+{
+    fn read(&self, address: u16) -> Option<u8>
+    {
+        match address {
+            0x6000..=0x7FFF if self.ram_enabled() => Some(self.ram[(address - 0x6000) as usize]),
+            0x8000..=0xFFFF => self.prg_rom.read((address - 0x8000) as usize),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> WriteOutcome
+    {
+        match address {
+            0x6000..=0x7FFF if self.ram_enabled() => self.ram[(address - 0x6000) as usize] = data,
+            0x8000..=0xFFFF => self.write_serial(address, data),
+            _ => {},
+        }
+        WriteOutcome::Handled
+    }
+
+    fn chr_read(&self, address: u16) -> u8 { self.chr.read(address as usize).unwrap_or(0) }
+
+    fn chr_write(&mut self, address: u16, data: u8)
+    {
+        if self.chr_is_ram {
+            self.chr.write(address as usize, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn cpu_clock(&mut self) { self.cycle += 1 }
+
+    fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bytes(&self.ram);
+        for register in [self.shift, self.shift_count, self.control, self.chr_bank_0, self.chr_bank_1, self.prg_bank] {
+            state.write_u8(register);
+        }
+        state.write_u64(self.cycle);
+        state.write_option_u64(self.last_write_cycle);
+        self.chr.save_state(state, self.chr_is_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        state.read_bytes(&mut self.ram)?;
+        for register in [&mut self.shift, &mut self.shift_count, &mut self.control, &mut self.chr_bank_0, &mut self.chr_bank_1, &mut self.prg_bank] {
+            *register = state.read_u8()?;
+        }
+        self.cycle = state.read_u64()?;
+        self.last_write_cycle = state.read_option_u64()?;
+        self.chr.load_state(state, self.chr_is_ram)?;
+        self.update_banks();
+        Ok(())
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> { if self.battery {Some(&self.ram)} else {None} }
+
+    fn load_prg_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> { load_battery_ram(&mut self.ram, self.battery, data) }
+}
+
+// UNROM and UOROM: a 16KB bank switched at $8000, the last bank fixed at $C000 and CHR RAM
+pub struct UxROM
+{
+    prg_rom: BankedMemory,
+    chr: BankedMemory,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    // without a buffer on the data bus, the ROM drives it too and a 0 bit wins
+    bus_conflicts: bool,
+}
+impl UxROM
+{
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring, bus_conflicts: bool) -> UxROM
+    {
+        let mut prg_rom = BankedMemory::new(prg_rom, 0x4000, 2);
+        let last_bank = prg_rom.bank_count().saturating_sub(1);
+        prg_rom.select(1, last_bank);
+        let chr_is_ram = chr_rom.is_empty();
+        let chr = if chr_is_ram {vec![0; 0x2000]} else {chr_rom};
+        UxROM {
+            prg_rom,
+            chr: BankedMemory::new(chr, 0x2000, 1),
+            chr_is_ram,
+            mirroring,
+            bus_conflicts,
+        }
+    }
+}
+impl Mapper for UxROM
+{
+    fn read(&self, address: u16) -> Option<u8>
+    {
+        match address {
+            0x8000..=0xFFFF => self.prg_rom.read((address - 0x8000) as usize),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> WriteOutcome
+    {
+        if address >= 0x8000 {
+            let bank = if self.bus_conflicts {self.read(address).map_or(data, |rom| data & rom)} else {data};
+            self.prg_rom.select(0, bank as usize);
+        }
+        WriteOutcome::Handled
+    }
+
+    fn chr_read(&self, address: u16) -> u8 { self.chr.read(address as usize).unwrap_or(0) }
+
+    fn chr_write(&mut self, address: u16, data: u8)
+    {
+        if self.chr_is_ram {
+            self.chr.write(address as usize, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring { self.mirroring }
+
+    fn save_state(&self, state: &mut StateWriter)
+    {
+        self.prg_rom.save_state(state, false);
+        self.chr.save_state(state, self.chr_is_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.prg_rom.load_state(state, false)?;
+        self.chr.load_state(state, self.chr_is_ram)
+    }
+}
+
+// CNROM: NROM with the 8KB CHR bank selected by writes to $8000-$FFFF
+pub struct CNROM
+{
+    prg_rom: BankedMemory,
+    chr_rom: BankedMemory,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+}
+impl CNROM
+{
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring, bus_conflicts: bool) -> CNROM
+    {
+        let mut prg_rom = BankedMemory::new(prg_rom, 0x4000, 2);
+        prg_rom.select(1, 1);
+        CNROM {
+            prg_rom,
+            chr_rom: BankedMemory::new(chr_rom, 0x2000, 1),
+            mirroring,
+            bus_conflicts,
+        }
+    }
+}
+impl Mapper for CNROM
+{
+    fn read(&self, address: u16) -> Option<u8>
+    {
+        match address {
+            0x8000..=0xFFFF => self.prg_rom.read((address - 0x8000) as usize),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> WriteOutcome
+    {
+        if address >= 0x8000 {
+            let bank = if self.bus_conflicts {self.read(address).map_or(data, |rom| data & rom)} else {data};
+            self.chr_rom.select(0, bank as usize);
+        }
+        WriteOutcome::Handled
+    }
+
+    fn chr_read(&self, address: u16) -> u8 { self.chr_rom.read(address as usize).unwrap_or(0) }
+
+    fn chr_write(&mut self, _address: u16, _data: u8) { }
+
+    fn mirroring(&self) -> Mirroring { self.mirroring }
+
+    fn save_state(&self, state: &mut StateWriter) { self.chr_rom.save_state(state, false) }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> { self.chr_rom.load_state(state, false) }
+}
+
+// TxROM boards. Eight bank registers R0-R7 written through $8000/$8001, and a scanline
+// counter clocked by the rising edges of PPU A12, which happen once per scanline when
+// the background and sprites use different pattern tables.
+pub struct MMC3
+{
+    prg_rom: BankedMemory,
+    chr: BankedMemory,
+    chr_is_ram: bool,
+    ram: [u8; 0x2000],
+    battery: bool,
+    ram_enabled: bool,
+    ram_write_protected: bool,
+    bank_select: u8,
+    banks: [u8; 8],
+    mirroring: Mirroring,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    // A12 only counts after staying low for a few CPU cycles, which filters out the
+    // short drops between the sprite pattern fetches
+    cycle: u64,
+    a12_low_since: Option<u64>,
+}
+impl MMC3
+{
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> MMC3
+    {
+        let chr_is_ram = chr_rom.is_empty();
+        let chr = if chr_is_ram {vec![0; 0x2000]} else {chr_rom};
+        let mut mmc3 = MMC3 {
+            prg_rom: BankedMemory::new(prg_rom, 0x2000, 4),
+            chr: BankedMemory::new(chr, 0x0400, 8),
+            chr_is_ram,
+            ram: [0; 0x2000],
+            battery: false,
+            ram_enabled: true,
+            ram_write_protected: false,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            cycle: 0,
+            a12_low_since: Some(0),
+        };
+        mmc3.update_banks();
+        mmc3
+    }
+
+    pub fn with_battery(mut self, battery: bool) -> MMC3
+    {
+        self.battery = battery;
+        self
+    }
+
+    fn update_banks(&mut self)
+    {
+        let second_last = self.prg_rom.bank_count().saturating_sub(2);
+        let r6 = self.banks[6] as usize & 0x3F;
+        let r7 = self.banks[7] as usize & 0x3F;
+        // bit 6 swaps the R6 window with the fixed second to last bank
+        let prg = if self.bank_select & 0x40 == 0 {[r6, r7, second_last]} else {[second_last, r7, r6]};
+        for (window, bank) in prg.iter().enumerate() {
+            self.prg_rom.select(window, *bank);
+        }
+        self.prg_rom.select(3, second_last + 1);
+
+        // R0 and R1 are 2KB banks, bit 7 swaps them with the 1KB banks to the other half
+        let r0 = self.banks[0] as usize & !1;
+        let r1 = self.banks[1] as usize & !1;
+        let chr = [r0, r0 + 1, r1, r1 + 1, self.banks[2] as usize, self.banks[3] as usize, self.banks[4] as usize, self.banks[5] as usize];
+        let inversion = if self.bank_select & 0x80 != 0 {4} else {0};
+        for (window, bank) in chr.iter().enumerate() {
+            self.chr.select(window ^ inversion, *bank);
+        }
+    }
+
+    fn clock_irq_counter(&mut self)
+    {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+impl Mapper for MMC3
+{
+    fn read(&self, address: u16) -> Option<u8>
+    {
+        match address {
+            0x6000..=0x7FFF if self.ram_enabled => Some(self.ram[(address - 0x6000) as usize]),
+            0x8000..=0xFFFF => self.prg_rom.read((address - 0x8000) as usize),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> WriteOutcome
+    {
+        // registers are decoded from bits 13-14 and bit 0 of the address
+        match (address, address & 0x01) {
+            (0x6000..=0x7FFF, _) if self.ram_enabled && !self.ram_write_protected => self.ram[(address - 0x6000) as usize] = data,
+            (0x8000..=0x9FFF, 0) => {
+                self.bank_select = data;
+                self.update_banks();
+            },
+            (0x8000..=0x9FFF, _) => {
+                self.banks[(self.bank_select & 0x07) as usize] = data;
+                self.update_banks();
+            },
+            // boards wired for four screens ignore it
+            (0xA000..=0xBFFF, 0) if self.mirroring != Mirroring::FourScreen => {
+                self.mirroring = if data & 0x01 == 0 {Mirroring::Vertical} else {Mirroring::Horizontal};
+            },
+            (0xA000..=0xBFFF, 1) => {
+                self.ram_enabled = data & 0x80 != 0;
+                self.ram_write_protected = data & 0x40 != 0;
+            },
+            (0xC000..=0xDFFF, 0) => self.irq_latch = data,
+            (0xC000..=0xDFFF, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            },
+            // disabling also acknowledges
+            (0xE000..=0xFFFF, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            },
+            (0xE000..=0xFFFF, _) => self.irq_enabled = true,
+            _ => {},
+        }
+        WriteOutcome::Handled
+    }
+
+    fn chr_read(&self, address: u16) -> u8 { self.chr.read(address as usize).unwrap_or(0) }
+
+    fn chr_write(&mut self, address: u16, data: u8)
+    {
+        if self.chr_is_ram {
+            self.chr.write(address as usize, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring { self.mirroring }
+
+    fn cpu_clock(&mut self) { self.cycle += 1 }
+
+    fn ppu_address(&mut self, address: u16)
+    {
+        if address & 0x1000 == 0 {
+            self.a12_low_since.get_or_insert(self.cycle);
+            return
+        }
+        if let Some(low_since) = self.a12_low_since.take() {
+            if self.cycle - low_since >= 3 {
+                self.clock_irq_counter();
             }
         }
     }
-}
\ No newline at end of file
+
+    fn irq(&self) -> bool { self.irq_pending }
+
+    fn irq_enabled(&self) -> bool { self.irq_enabled }
+
+    fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_bytes(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.ram_write_protected);
+        state.write_u8(self.bank_select);
+        state.write_bytes(&self.banks);
+        self.mirroring.save_state(state);
+        state.write_u8(self.irq_latch);
+        state.write_u8(self.irq_counter);
+        state.write_bool(self.irq_reload);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending);
+        state.write_u64(self.cycle);
+        state.write_option_u64(self.a12_low_since);
+        self.chr.save_state(state, self.chr_is_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        state.read_bytes(&mut self.ram)?;
+        self.ram_enabled = state.read_bool()?;
+        self.ram_write_protected = state.read_bool()?;
+        self.bank_select = state.read_u8()?;
+        state.read_bytes(&mut self.banks)?;
+        self.mirroring = Mirroring::load_state(state)?;
+        self.irq_latch = state.read_u8()?;
+        self.irq_counter = state.read_u8()?;
+        self.irq_reload = state.read_bool()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.cycle = state.read_u64()?;
+        self.a12_low_since = state.read_option_u64()?;
+        self.chr.load_state(state, self.chr_is_ram)?;
+        self.update_banks();
+        Ok(())
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> { if self.battery {Some(&self.ram)} else {None} }
+
+    fn load_prg_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> { load_battery_ram(&mut self.ram, self.battery, data) }
+}
@@ -0,0 +1,489 @@
+// Opt-in execution one bus access per clock(). The default mode runs a whole instruction
+// on its first cycle and waits out the others, which puts every read and write of the
+// instruction before the PPU, APU and mapper saw its later cycles. Here each opcode is
+// a sequence of micro-ops, one per cycle after the opcode fetch, each doing the access
+// the 6502 does on that cycle, dummy reads and writes included. The operations
+// themselves are shared with the default mode: they run on the data latch, between the
+// micro-ops that fill it from the bus and write it back.
+//
+// Interrupts are still polled on instruction boundaries, and the DMC still steals its
+// cycles as a stall, so both modes take the same number of cycles for everything.
+
+use super::{
+    AddressingMode,
+    Cpu,
+    Interrupts,
+    branch_target,
+};
+use super::isa::{
+    AddressingModeKind,
+    Operation,
+    opcode_info,
+};
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Index
+{
+    X,
+    Y,
+}
+
+// one cycle, and its bus access
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum MicroOp
+{
+    // reads the byte after the opcode and runs the implied or accumulator operation
+    Implied,
+    DummyReadPc,
+    FetchImmediate,
+    FetchAddressLow,
+    FetchAddressHigh,
+    // the low byte gets the index, the carry waits for FixAddress
+    FetchAddressHighIndexed(Index),
+    // reads the unindexed zero page address, the index wraps inside the page
+    IndexZeroPage(Index),
+    // reads the address before the carry, which is the operand of a read that did not cross a page
+    FixAddress,
+    FetchPointer,
+    // reads the unindexed pointer, (zp,X)
+    IndexPointer,
+    ReadPointerLow,
+    ReadPointerHigh,
+    // (zp),Y
+    ReadPointerHighIndexed,
+    Read,
+    Write,
+    ReadForModify,
+    // the unmodified value goes back first
+    DummyWrite,
+    WriteModified,
+    ReadStack,
+    // the stack operations do their access themselves
+    Execute,
+    PushPcHigh,
+    PushPcLow,
+    // BRK and the interrupts pick their vector here, an NMI detected until now takes over
+    PushStatus,
+    // the vectors and the JMP indirect pointer, which does not carry into its high byte
+    FetchVectorLow,
+    FetchVectorHigh,
+    // the byte after BRK
+    FetchPadding,
+    PullStatus,
+    PullPcLow,
+    PullPcHigh,
+    // RTS returns after the JSR operand
+    IncrementPc,
+    // fetches the high byte of the target
+    Jump,
+    // reads the byte after the opcode and moves PC past the operand
+    SkipOperand,
+    // ends the branch when it is not taken
+    FetchBranchOffset,
+    // reads the next opcode, ends the branch when the target is in the same page
+    TakeBranch,
+    FixBranchPage,
+}
+
+use self::MicroOp::*;
+
+// the address computations, before the accesses of the read, write and read-modify-write instructions
+const ZERO_PAGE: [MicroOp; 1] = [FetchAddressLow];
+const ZERO_PAGE_X: [MicroOp; 2] = [FetchAddressLow, IndexZeroPage(Index::X)];
+const ZERO_PAGE_Y: [MicroOp; 2] = [FetchAddressLow, IndexZeroPage(Index::Y)];
+const ABSOLUTE: [MicroOp; 2] = [FetchAddressLow, FetchAddressHigh];
+const ABSOLUTE_X: [MicroOp; 3] = [FetchAddressLow, FetchAddressHighIndexed(Index::X), FixAddress];
+const ABSOLUTE_Y: [MicroOp; 3] = [FetchAddressLow, FetchAddressHighIndexed(Index::Y), FixAddress];
+const INDEXED_INDIRECT: [MicroOp; 4] = [FetchPointer, IndexPointer, ReadPointerLow, ReadPointerHigh];
+const INDIRECT_INDEXED: [MicroOp; 4] = [FetchPointer, ReadPointerLow, ReadPointerHighIndexed, FixAddress];
+
+const READ: [MicroOp; 1] = [Read];
+const WRITE: [MicroOp; 1] = [Write];
+const READ_MODIFY_WRITE: [MicroOp; 3] = [ReadForModify, DummyWrite, WriteModified];
+
+const IMPLIED: [MicroOp; 1] = [Implied];
+const IMMEDIATE: [MicroOp; 1] = [FetchImmediate];
+const PUSH: [MicroOp; 2] = [DummyReadPc, Execute];
+const PULL: [MicroOp; 3] = [DummyReadPc, ReadStack, Execute];
+const JMP_ABSOLUTE: [MicroOp; 2] = [FetchAddressLow, Jump];
+const JMP_INDIRECT: [MicroOp; 4] = [FetchAddressLow, FetchAddressHigh, FetchVectorLow, FetchVectorHigh];
+const JSR: [MicroOp; 5] = [FetchAddressLow, ReadStack, PushPcHigh, PushPcLow, Jump];
+const RTS: [MicroOp; 5] = [DummyReadPc, ReadStack, PullPcLow, PullPcHigh, IncrementPc];
+const RTI: [MicroOp; 5] = [DummyReadPc, ReadStack, PullStatus, PullPcLow, PullPcHigh];
+const BRK: [MicroOp; 6] = [FetchPadding, PushPcHigh, PushPcLow, PushStatus, FetchVectorLow, FetchVectorHigh];
+const BRANCH: [MicroOp; 3] = [FetchBranchOffset, TakeBranch, FixBranchPage];
+// the unstable stores and LAS, which the opcode table runs as two cycle NOPs
+const UNSTABLE: [MicroOp; 1] = [SkipOperand];
+// the opcode fetch of the first cycle is dropped
+const INTERRUPT: [MicroOp; 6] = [DummyReadPc, PushPcHigh, PushPcLow, PushStatus, FetchVectorLow, FetchVectorHigh];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Access
+{
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+fn access(operation: Operation) -> Access
+{
+    match operation {
+        Operation::Sta | Operation::Stx | Operation::Sty | Operation::Sax => Access::Write,
+        Operation::Asl | Operation::Lsr | Operation::Rol | Operation::Ror | Operation::Inc | Operation::Dec |
+        Operation::Slo | Operation::Sre | Operation::Rla | Operation::Rra | Operation::Dcp | Operation::Isb => Access::ReadModifyWrite,
+        _ => Access::Read,
+    }
+}
+
+// the cycles after the opcode fetch, as the address computation then the accesses
+fn micro_ops(opcode: u8) -> (&'static [MicroOp], &'static [MicroOp])
+{
+    let info = opcode_info(opcode);
+    let accesses: &'static [MicroOp] = match access(info.operation) {
+        Access::Read => &READ,
+        Access::Write => &WRITE,
+        Access::ReadModifyWrite => &READ_MODIFY_WRITE,
+    };
+    match (info.operation, info.addressing_mode) {
+        (Operation::Brk, _) => (&BRK, &[]),
+        (Operation::Jsr, _) => (&JSR, &[]),
+        (Operation::Rts, _) => (&RTS, &[]),
+        (Operation::Rti, _) => (&RTI, &[]),
+        (Operation::Pha, _) | (Operation::Php, _) => (&PUSH, &[]),
+        (Operation::Pla, _) | (Operation::Plp, _) => (&PULL, &[]),
+        (Operation::Jmp, AddressingModeKind::Indirect) => (&JMP_INDIRECT, &[]),
+        (Operation::Jmp, _) => (&JMP_ABSOLUTE, &[]),
+        (Operation::Nop, mode) if info.cycles == 2 && mode.operand_bytes() > 0 && mode != AddressingModeKind::Immediate => (&UNSTABLE, &[]),
+        (_, AddressingModeKind::Implicit) | (_, AddressingModeKind::Accumulator) => (&IMPLIED, &[]),
+        (_, AddressingModeKind::Immediate) => (&IMMEDIATE, &[]),
+        (_, AddressingModeKind::Relative) => (&BRANCH, &[]),
+        (_, AddressingModeKind::ZeroPage) => (&ZERO_PAGE, accesses),
+        (_, AddressingModeKind::ZeroPageX) => (&ZERO_PAGE_X, accesses),
+        (_, AddressingModeKind::ZeroPageY) => (&ZERO_PAGE_Y, accesses),
+        (_, AddressingModeKind::Absolute) => (&ABSOLUTE, accesses),
+        (_, AddressingModeKind::AbsoluteX) => (&ABSOLUTE_X, accesses),
+        (_, AddressingModeKind::AbsoluteY) => (&ABSOLUTE_Y, accesses),
+        (_, AddressingModeKind::IndexedIndirect) => (&INDEXED_INDIRECT, accesses),
+        (_, AddressingModeKind::IndirectIndexed) => (&INDIRECT_INDEXED, accesses),
+        // only JMP uses it
+        (_, AddressingModeKind::Indirect) => (&JMP_INDIRECT, &[]),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Sequence
+{
+    Instruction(u8),
+    Nmi,
+    Irq,
+    // the copy of a page to OAMDATA, after the write to $4014
+    OamDma { page: u8, cycles: u16 },
+}
+
+impl Sequence
+{
+    fn micro_op(self, step: usize) -> Option<MicroOp>
+    {
+        match self {
+            Sequence::Instruction(opcode) => {
+                let (address, accesses) = micro_ops(opcode);
+                address.get(step).or_else(|| accesses.get(step - address.len())).copied()
+            },
+            Sequence::Nmi | Sequence::Irq => INTERRUPT.get(step).copied(),
+            Sequence::OamDma { .. } => None,
+        }
+    }
+}
+
+// what the sequence being executed has gathered so far
+pub(crate) struct MicroState
+{
+    sequence: Option<Sequence>,
+    // micro-ops done, or cycles for the OAM DMA
+    step: u16,
+    address: u16,
+    // the pointer of the indirect modes, then the indexed address before the carry
+    base: u16,
+    // the operand read, or the value to write
+    pub(crate) data: u8,
+}
+
+impl MicroState
+{
+    pub(crate) fn new() -> MicroState { MicroState {sequence: None, step: 0, address: 0, base: 0, data: 0} }
+
+    pub(crate) fn in_progress(&self) -> bool { self.sequence.is_some() }
+
+    pub(crate) fn abort(&mut self)
+    {
+        self.sequence = None;
+        self.step = 0;
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter)
+    {
+        let (kind, opcode_or_page, cycles) = match self.sequence {
+            None => (0, 0, 0),
+            Some(Sequence::Instruction(opcode)) => (1, opcode, 0),
+            Some(Sequence::Nmi) => (2, 0, 0),
+            Some(Sequence::Irq) => (3, 0, 0),
+            Some(Sequence::OamDma { page, cycles }) => (4, page, cycles),
+        };
+        state.write_u8(kind);
+        state.write_u8(opcode_or_page);
+        state.write_u16(cycles);
+        state.write_u16(self.step);
+        state.write_u16(self.address);
+        state.write_u16(self.base);
+        state.write_u8(self.data);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        let kind = state.read_u8()?;
+        let opcode_or_page = state.read_u8()?;
+        let cycles = state.read_u16()?;
+        self.sequence = match kind {
+            0 => None,
+            1 => Some(Sequence::Instruction(opcode_or_page)),
+            2 => Some(Sequence::Nmi),
+            3 => Some(Sequence::Irq),
+            4 => Some(Sequence::OamDma {page: opcode_or_page, cycles}),
+            _ => return Err(SaveStateError::Mismatch("CPU sequence")),
+        };
+        self.step = state.read_u16()?;
+        self.address = state.read_u16()?;
+        self.base = state.read_u16()?;
+        self.data = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Cpu
+{
+    // Both modes give the same results and cycle counts on instruction boundaries.
+    // Switching in the middle of an instruction finishes it in the mode it started in.
+    pub fn set_cycle_accurate(&mut self, enabled: bool) { self.cycle_accurate = enabled }
+
+    // no instruction, interrupt sequence or stall in progress
+    pub(crate) fn at_instruction_boundary(&self) -> bool { self.wait_cycles == 0 && !self.micro.in_progress() }
+
+    // one cycle of the accurate mode
+    pub(crate) fn clock_micro_op(&mut self)
+    {
+        let sequence = match self.micro.sequence {
+            Some(Sequence::OamDma { page, cycles }) => return self.clock_oam_dma(page, cycles),
+            Some(sequence) => sequence,
+            None => return self.start_sequence(),
+        };
+        let step = self.micro.step as usize;
+        let op = sequence.micro_op(step).expect("micro-op past the end of its sequence");
+        self.micro.step += 1;
+        let goes_on = self.run_micro_op(sequence, op);
+        if !goes_on || sequence.micro_op(step + 1).is_none() {
+            self.end_sequence();
+        }
+    }
+
+    // the first cycle fetches the opcode, or drops it for an interrupt
+    fn start_sequence(&mut self)
+    {
+        match self.take_interrupt() {
+            Some(kind) => {
+                self.load(self.registers.pc);
+                self.micro.sequence = Some(if let Interrupts::NMI = kind {Sequence::Nmi} else {Sequence::Irq});
+            },
+            None => {
+                self.trace();
+                if let Some(cycles) = self.accelerate_loop() {
+                    self.wait_cycles = cycles - 1;
+                    return
+                }
+                self.instruction_pc = self.registers.pc;
+                let opcode = self.fetch();
+                self.micro.sequence = Some(Sequence::Instruction(opcode));
+            },
+        }
+        self.micro.step = 0;
+    }
+
+    // the OAM DMA starts on the cycle after the write to $4014, and takes as long as in the default mode
+    fn end_sequence(&mut self)
+    {
+        self.micro.abort();
+        if let Some(page) = self.oam_dma_page.take() {
+            let start = self.cycles + 1;
+            let cycles = if start % 2 == 1 {514} else {513};
+            self.oam_dma_end = start + cycles as u64;
+            self.micro.sequence = Some(Sequence::OamDma {page, cycles});
+        }
+    }
+
+    // halted for one or two cycles, then alternating reads and writes to OAMDATA
+    fn clock_oam_dma(&mut self, page: u8, cycles: u16)
+    {
+        let step = self.micro.step;
+        self.micro.step += 1;
+        if let Some(transfer) = (step + 512).checked_sub(cycles) {
+            match transfer % 2 {
+                0 => self.micro.data = self.load(((page as u16) << 8) | (transfer / 2)),
+                _ => self.ppu.get_mut().write_register(4, self.micro.data),
+            }
+        }
+        if self.micro.step == cycles {
+            self.micro.abort();
+        }
+    }
+
+    fn index(&self, index: Index) -> u8
+    {
+        match index {
+            Index::X => self.registers.x,
+            Index::Y => self.registers.y,
+        }
+    }
+
+    fn execute_latched(&mut self, opcode: u8) { self.execute_operation(opcode_info(opcode).operation, &AddressingMode::Latch); }
+
+    // the indexed address, the carry goes to the high byte once FixAddress read the wrong page
+    fn index_address(&mut self, base_address: u16, index: u8)
+    {
+        self.micro.address = base_address.wrapping_add(index as u16);
+        self.micro.base = (base_address & 0xFF00) | (self.micro.address & 0x00FF);
+    }
+
+    // returns whether the sequence goes on
+    fn run_micro_op(&mut self, sequence: Sequence, op: MicroOp) -> bool
+    {
+        let opcode = match sequence {
+            Sequence::Instruction(opcode) => opcode,
+            _ => 0x00,
+        };
+        match op {
+            Implied => {
+                self.load(self.registers.pc);
+                let addressing_mode = match opcode_info(opcode).addressing_mode {
+                    AddressingModeKind::Accumulator => AddressingMode::Accumulator,
+                    _ => AddressingMode::Implicit,
+                };
+                self.execute_operation(opcode_info(opcode).operation, &addressing_mode);
+            },
+            DummyReadPc => {
+                self.load(self.registers.pc);
+            },
+            FetchImmediate => {
+                self.micro.data = self.fetch();
+                self.execute_latched(opcode);
+            },
+            FetchAddressLow => self.micro.address = self.fetch() as u16,
+            FetchAddressHigh => self.micro.address |= (self.fetch() as u16) << 8,
+            FetchAddressHighIndexed(index) => {
+                let base_address = self.micro.address | (self.fetch() as u16) << 8;
+                self.index_address(base_address, self.index(index));
+            },
+            IndexZeroPage(index) => {
+                self.load(self.micro.address);
+                self.micro.address = (self.micro.address as u8).wrapping_add(self.index(index)) as u16;
+            },
+            FixAddress => {
+                self.micro.data = self.load(self.micro.base);
+                if self.micro.base == self.micro.address && access(opcode_info(opcode).operation) == Access::Read {
+                    self.execute_latched(opcode);
+                    return false
+                }
+            },
+            FetchPointer => self.micro.base = self.fetch() as u16,
+            IndexPointer => {
+                self.load(self.micro.base);
+                self.micro.base = (self.micro.base as u8).wrapping_add(self.registers.x) as u16;
+            },
+            ReadPointerLow => self.micro.address = self.load(self.micro.base) as u16,
+            ReadPointerHigh => self.micro.address |= (self.load((self.micro.base as u8).wrapping_add(1) as u16) as u16) << 8,
+            ReadPointerHighIndexed => {
+                let base_address = self.micro.address | (self.load((self.micro.base as u8).wrapping_add(1) as u16) as u16) << 8;
+                self.index_address(base_address, self.registers.y);
+            },
+            Read => {
+                self.micro.data = self.load(self.micro.address);
+                self.execute_latched(opcode);
+            },
+            Write => {
+                self.execute_latched(opcode);
+                self.write(self.micro.address, self.micro.data);
+            },
+            ReadForModify => self.micro.data = self.load(self.micro.address),
+            DummyWrite => self.write(self.micro.address, self.micro.data),
+            WriteModified => {
+                self.execute_latched(opcode);
+                self.write(self.micro.address, self.micro.data);
+            },
+            ReadStack => {
+                self.load(0x0100 | self.registers.stack_pointer as u16);
+            },
+            Execute => {
+                self.execute_operation(opcode_info(opcode).operation, &AddressingMode::Implicit);
+            },
+            PushPcHigh => self.push((self.registers.pc >> 8) as u8),
+            PushPcLow => self.push(self.registers.pc as u8),
+            PushStatus => {
+                let b_flag = if let Sequence::Instruction(_) = sequence {0b0011_0000} else {0b0010_0000};
+                self.push(self.registers.p.get_byte() | b_flag);
+                self.micro.address = match sequence {
+                    Sequence::Nmi => 0xFFFA,
+                    _ if self.nmi_pending => {
+                        self.nmi_pending = false;
+                        0xFFFA
+                    },
+                    _ => 0xFFFE,
+                };
+                self.registers.p.interrupt_disable = true;
+            },
+            FetchVectorLow => self.micro.data = self.load(self.micro.address),
+            FetchVectorHigh => {
+                let address = self.micro.address;
+                let msb_address = (address & 0xFF00) | (address as u8).wrapping_add(1) as u16;
+                self.registers.pc = self.micro.data as u16 | (self.load(msb_address) as u16) << 8;
+            },
+            FetchPadding => {
+                self.fetch();
+            },
+            PullStatus => {
+                let status = self.pop();
+                self.registers.p.set_byte(status);
+            },
+            PullPcLow => self.registers.pc = self.pop() as u16,
+            PullPcHigh => self.registers.pc |= (self.pop() as u16) << 8,
+            IncrementPc => {
+                self.fetch();
+            },
+            Jump => self.registers.pc = self.micro.address | (self.fetch() as u16) << 8,
+            SkipOperand => {
+                self.load(self.registers.pc);
+                self.registers.pc += opcode_info(opcode).addressing_mode.operand_bytes() as u16;
+            },
+            FetchBranchOffset => {
+                self.micro.data = self.fetch();
+                return self.branch_taken(opcode) == Some(true)
+            },
+            TakeBranch => {
+                self.load(self.registers.pc);
+                let target = branch_target(self.micro.data, self.registers.pc);
+                let same_page = target & 0xFF00 == self.registers.pc & 0xFF00;
+                self.micro.address = target;
+                self.micro.base = (self.registers.pc & 0xFF00) | (target & 0x00FF);
+                self.registers.pc = if same_page {target} else {self.micro.base};
+                return !same_page
+            },
+            FixBranchPage => {
+                self.load(self.micro.base);
+                self.registers.pc = self.micro.address;
+            },
+        }
+        true
+    }
+}
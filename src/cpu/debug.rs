@@ -0,0 +1,131 @@
+use super::Cpu;
+use super::branch_target;
+use super::LoopState;
+
+// What to do when the program writes to memory the mapper reports as read-only
+pub enum RomWritePolicy
+{
+    Ignore, // hardware behavior
+    // a DebugEvent::Log that does not stop a run, also written in the text trace after
+    // the line of the instruction
+    Log,
+    DebugEvent,
+}
+
+// The accesses a watchpoint reports
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchKind
+{
+    Read,
+    Write,
+    Any,
+}
+
+// pc is the address of the instruction doing the access
+#[derive(Debug, PartialEq)]
+pub enum DebugEvent
+{
+    RomWrite { pc: u16, address: u16, value: u8 },
+    // raised once PC reaches the breakpoint, before the instruction there is executed
+    Breakpoint { pc: u16 },
+    Read { pc: u16, address: u16, value: u8 },
+    Write { pc: u16, address: u16, value: u8 },
+    // LoopAcceleration::Verify, the loop at pc did not end in the state the shortcut predicted
+    LoopMismatch { pc: u16, predicted: LoopState, actual: LoopState },
+    // a note for the user, the only event Cpu::run_until goes on after
+    Log(String),
+}
+
+impl DebugEvent
+{
+    pub fn pauses(&self) -> bool { !matches!(self, DebugEvent::Log(_)) }
+}
+
+impl Cpu
+{
+    pub fn set_rom_write_policy(&mut self, policy: RomWritePolicy) { self.rom_write_policy = policy }
+
+    // events queue up until they are taken, oldest first, a frontend should poll this
+    // after each clock or step
+    pub fn take_debug_event(&mut self) -> Option<DebugEvent> { self.debug_events.get_mut().pop_front() }
+
+    // whether an event that stops a run waits to be taken
+    pub(crate) fn has_debug_event(&self) -> bool { self.debug_events.borrow().iter().any(DebugEvent::pauses) }
+
+    pub(crate) fn raise_debug_event(&self, event: DebugEvent) { self.debug_events.borrow_mut().push_back(event) }
+
+    pub fn add_breakpoint(&mut self, address: u16) { self.breakpoints.insert(address); }
+
+    pub fn remove_breakpoint(&mut self, address: u16) { self.breakpoints.remove(&address); }
+
+    // addresses are watched as the CPU sees them, mirrors have to be added separately.
+    // Adding the same address again replaces its kind.
+    pub fn add_watchpoint(&mut self, address: u16, kind: WatchKind) { self.watchpoints.insert(address, kind); }
+
+    pub fn remove_watchpoint(&mut self, address: u16) { self.watchpoints.remove(&address); }
+
+    // access is Read or Write, only called when some watchpoint is set
+    pub(crate) fn watch(&self, address: u16, value: u8, access: WatchKind)
+    {
+        match self.watchpoints.get(&address) {
+            Some(&kind) if kind == access || kind == WatchKind::Any => {
+                let pc = self.instruction_pc;
+                self.raise_debug_event(match access {
+                    WatchKind::Write => DebugEvent::Write { pc, address, value },
+                    _ => DebugEvent::Read { pc, address, value },
+                });
+            },
+            _ => {},
+        }
+    }
+
+    // on instruction boundaries, only called when some breakpoint is set
+    pub(crate) fn check_breakpoint(&self)
+    {
+        let pc = self.registers.pc;
+        if self.breakpoints.contains(&pc) {
+            self.raise_debug_event(DebugEvent::Breakpoint { pc });
+        }
+    }
+
+    pub fn rom_write(&mut self, address: u16, value: u8)
+    {
+        let pc = self.instruction_pc;
+        match self.rom_write_policy {
+            RomWritePolicy::Ignore => {},
+            RomWritePolicy::Log => {
+                let note = format!("{:04X}  write to ROM ${:04X} = {:02X}", pc, address, value);
+                let result = self.trace_sink.write_note(&note);
+                self.keep_trace_error(result);
+                self.raise_debug_event(DebugEvent::Log(note));
+            },
+            RomWritePolicy::DebugEvent => self.raise_debug_event(DebugEvent::RomWrite { pc, address, value }),
+        }
+    }
+
+    // whether the branch at opcode is taken with the current flags, None if it is not a branch
+    pub fn branch_taken(&self, opcode: u8) -> Option<bool>
+    {
+        if opcode & 0x1F != 0x10 {
+            return None
+        }
+        // bits 7-6 select the flag, bit 5 the value it is compared to
+        let flag = match opcode >> 6 {
+            0b00 => self.registers.p.negative,
+            0b01 => self.registers.p.overflow,
+            0b10 => self.registers.p.carry,
+            _ => self.registers.p.zero,
+        };
+        Some(flag == (opcode & 0b0010_0000 != 0))
+    }
+
+    // where the instruction at PC will jump, if it is a branch that will be taken
+    pub fn next_branch_target(&self) -> Option<u16>
+    {
+        let pc = self.registers.pc;
+        match self.branch_taken(self.peek(pc)) {
+            Some(true) => Some(branch_target(self.peek(pc.wrapping_add(1)), pc.wrapping_add(2))),
+            _ => None,
+        }
+    }
+}
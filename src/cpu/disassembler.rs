@@ -0,0 +1,100 @@
+// Disassembles instructions in the nestest.log syntax, with the effective address and
+// the value found there for the memory modes. Memory is peeked, so disassembling never
+// changes the state of the console.
+
+use super::Cpu;
+use super::branch_target;
+use super::isa::{
+    AddressingModeKind,
+    opcode_info,
+};
+
+// An instruction and what it would access if executed with the current registers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Instruction
+{
+    pub pc: u16,
+    pub opcode: u8,
+    // unused bytes are 0
+    pub operands: [u8; 2],
+    pub x: u8,
+    pub y: u8,
+    // the branch or jump target for the relative and indirect modes
+    pub effective_address: u16,
+    pub value: u8,
+}
+
+pub fn instruction_length(opcode: u8) -> u16 { 1 + opcode_info(opcode).addressing_mode.operand_bytes() as u16 }
+
+impl Instruction
+{
+    pub fn read(cpu: &Cpu, pc: u16) -> Instruction
+    {
+        let opcode = cpu.peek(pc);
+        let length = instruction_length(opcode);
+        let operand = |i: u16| if i < length {cpu.peek(pc.wrapping_add(i))} else {0};
+        let operands = [operand(1), operand(2)];
+        let (x, y) = (cpu.registers.x, cpu.registers.y);
+        // pointers wrap inside their page
+        let pointer = |address: u16| {
+            let msb_address = (address & 0xFF00) | (address as u8).wrapping_add(1) as u16;
+            cpu.peek(address) as u16 | (cpu.peek(msb_address) as u16) << 8
+        };
+        let absolute = operands[0] as u16 | (operands[1] as u16) << 8;
+        let effective_address = match opcode_info(opcode).addressing_mode {
+            AddressingModeKind::ZeroPage => operands[0] as u16,
+            AddressingModeKind::ZeroPageX => operands[0].wrapping_add(x) as u16,
+            AddressingModeKind::ZeroPageY => operands[0].wrapping_add(y) as u16,
+            AddressingModeKind::Absolute => absolute,
+            AddressingModeKind::AbsoluteX => absolute.wrapping_add(x as u16),
+            AddressingModeKind::AbsoluteY => absolute.wrapping_add(y as u16),
+            AddressingModeKind::Indirect => pointer(absolute),
+            AddressingModeKind::IndexedIndirect => pointer(operands[0].wrapping_add(x) as u16),
+            AddressingModeKind::IndirectIndexed => pointer(operands[0] as u16).wrapping_add(y as u16),
+            AddressingModeKind::Relative => branch_target(operands[0], pc.wrapping_add(2)),
+            _ => 0,
+        };
+        Instruction {pc, opcode, operands, x, y, effective_address, value: cpu.peek(effective_address)}
+    }
+
+    // opcode and operands, as in the second column of nestest.log
+    pub fn bytes(&self) -> String
+    {
+        let bytes = [self.opcode, self.operands[0], self.operands[1]];
+        bytes[..instruction_length(self.opcode) as usize].iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+    }
+
+    // mnemonic and operand, unofficial mnemonics are starred
+    pub fn text(&self) -> String
+    {
+        let info = opcode_info(self.opcode);
+        let [low, high] = self.operands;
+        let absolute = low as u16 | (high as u16) << 8;
+        let (address, value) = (self.effective_address, self.value);
+        let operand = match info.addressing_mode {
+            AddressingModeKind::Implicit => String::new(),
+            AddressingModeKind::Accumulator => "A".to_string(),
+            AddressingModeKind::Immediate => format!("#${:02X}", low),
+            AddressingModeKind::ZeroPage => format!("${:02X} = {:02X}", low, value),
+            AddressingModeKind::ZeroPageX => format!("${:02X},X @ {:02X} = {:02X}", low, address, value),
+            AddressingModeKind::ZeroPageY => format!("${:02X},Y @ {:02X} = {:02X}", low, address, value),
+            // the jumps do not access their operand
+            AddressingModeKind::Absolute if matches!(self.opcode, 0x4C | 0x20) => format!("${:04X}", absolute),
+            AddressingModeKind::Absolute => format!("${:04X} = {:02X}", absolute, value),
+            AddressingModeKind::AbsoluteX => format!("${:04X},X @ {:04X} = {:02X}", absolute, address, value),
+            AddressingModeKind::AbsoluteY => format!("${:04X},Y @ {:04X} = {:02X}", absolute, address, value),
+            AddressingModeKind::Indirect => format!("(${:04X}) = {:04X}", absolute, address),
+            AddressingModeKind::IndexedIndirect =>
+                format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}", low, low.wrapping_add(self.x), address, value),
+            AddressingModeKind::IndirectIndexed =>
+                format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}", low, address.wrapping_sub(self.y as u16), address, value),
+            AddressingModeKind::Relative => format!("${:04X}", address),
+        };
+        match operand.is_empty() {
+            true => info.mnemonic.to_string(),
+            false => format!("{} {}", info.mnemonic, operand),
+        }
+    }
+}
+
+pub fn disassemble(cpu: &Cpu, address: u16) -> String { Instruction::read(cpu, address).text() }
@@ -0,0 +1,470 @@
+// Random official instructions from random states, checked against a reference 6502
+// written from the datasheet, independently from instructions.rs and isa.rs. Each
+// case comes from its own seed, printed on a mismatch: run_case(seed) replays it.
+//
+// The states are built so every access lands in the internal RAM or its mirrors, and
+// the only reads outside of it are the BRK vector of the dummy cartridge. The NES CPU
+// has no decimal mode: D is kept in P, ADC and SBC ignore it.
+
+use super::Cpu;
+use super::isa::opcode_info;
+
+const SEED: u64 = 0x4E45_5351_5549_434B;
+const CASES: usize = 20_000;
+
+const CARRY: u8 = 0x01;
+const ZERO: u8 = 0x02;
+const INTERRUPT_DISABLE: u8 = 0x04;
+const DECIMAL: u8 = 0x08;
+const BREAK: u8 = 0x10;
+const UNUSED: u8 = 0x20;
+const OVERFLOW: u8 = 0x40;
+const NEGATIVE: u8 = 0x80;
+
+// xorshift64*
+struct Rng(u64);
+
+impl Rng
+{
+    // xorshift never leaves 0
+    fn new(seed: u64) -> Rng { Rng(seed | 1) }
+
+    fn next_u64(&mut self) -> u64
+    {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn byte(&mut self) -> u8 { (self.next_u64() >> 56) as u8 }
+
+    fn below(&mut self, bound: u32) -> u32 { (self.next_u64() >> 32) as u32 % bound }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mode
+{
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirect,
+    IndirectIndexed,
+    Relative,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Op
+{
+    Adc, And, Asl, Bcc, Bcs, Beq, Bit, Bmi, Bne, Bpl, Brk, Bvc, Bvs, Clc,
+    Cld, Cli, Clv, Cmp, Cpx, Cpy, Dec, Dex, Dey, Eor, Inc, Inx, Iny, Jmp,
+    Jsr, Lda, Ldx, Ldy, Lsr, Nop, Ora, Pha, Php, Pla, Plp, Rol, Ror, Rti,
+    Rts, Sbc, Sec, Sed, Sei, Sta, Stx, Sty, Tax, Tay, Tsx, Txa, Txs, Tya,
+}
+
+use Mode::*;
+use Op::*;
+
+// opcode, operation, addressing mode and cycles, without the page crossing and branch penalties
+const REFERENCE: [(u8, Op, Mode, u32); 151] = [
+    (0x69, Adc, Immediate, 2), (0x65, Adc, ZeroPage, 3), (0x75, Adc, ZeroPageX, 4), (0x6D, Adc, Absolute, 4),
+    (0x7D, Adc, AbsoluteX, 4), (0x79, Adc, AbsoluteY, 4), (0x61, Adc, IndexedIndirect, 6), (0x71, Adc, IndirectIndexed, 5),
+    (0x29, And, Immediate, 2), (0x25, And, ZeroPage, 3), (0x35, And, ZeroPageX, 4), (0x2D, And, Absolute, 4),
+    (0x3D, And, AbsoluteX, 4), (0x39, And, AbsoluteY, 4), (0x21, And, IndexedIndirect, 6), (0x31, And, IndirectIndexed, 5),
+    (0x0A, Asl, Accumulator, 2), (0x06, Asl, ZeroPage, 5), (0x16, Asl, ZeroPageX, 6), (0x0E, Asl, Absolute, 6),
+    (0x1E, Asl, AbsoluteX, 7),
+    (0x90, Bcc, Relative, 2), (0xB0, Bcs, Relative, 2), (0xF0, Beq, Relative, 2), (0x30, Bmi, Relative, 2),
+    (0xD0, Bne, Relative, 2), (0x10, Bpl, Relative, 2), (0x50, Bvc, Relative, 2), (0x70, Bvs, Relative, 2),
+    (0x24, Bit, ZeroPage, 3), (0x2C, Bit, Absolute, 4),
+    (0x00, Brk, Implied, 7),
+    (0x18, Clc, Implied, 2), (0xD8, Cld, Implied, 2), (0x58, Cli, Implied, 2), (0xB8, Clv, Implied, 2),
+    (0xC9, Cmp, Immediate, 2), (0xC5, Cmp, ZeroPage, 3), (0xD5, Cmp, ZeroPageX, 4), (0xCD, Cmp, Absolute, 4),
+    (0xDD, Cmp, AbsoluteX, 4), (0xD9, Cmp, AbsoluteY, 4), (0xC1, Cmp, IndexedIndirect, 6), (0xD1, Cmp, IndirectIndexed, 5),
+    (0xE0, Cpx, Immediate, 2), (0xE4, Cpx, ZeroPage, 3), (0xEC, Cpx, Absolute, 4),
+    (0xC0, Cpy, Immediate, 2), (0xC4, Cpy, ZeroPage, 3), (0xCC, Cpy, Absolute, 4),
+    (0xC6, Dec, ZeroPage, 5), (0xD6, Dec, ZeroPageX, 6), (0xCE, Dec, Absolute, 6), (0xDE, Dec, AbsoluteX, 7),
+    (0xCA, Dex, Implied, 2), (0x88, Dey, Implied, 2),
+    (0x49, Eor, Immediate, 2), (0x45, Eor, ZeroPage, 3), (0x55, Eor, ZeroPageX, 4), (0x4D, Eor, Absolute, 4),
+    (0x5D, Eor, AbsoluteX, 4), (0x59, Eor, AbsoluteY, 4), (0x41, Eor, IndexedIndirect, 6), (0x51, Eor, IndirectIndexed, 5),
+    (0xE6, Inc, ZeroPage, 5), (0xF6, Inc, ZeroPageX, 6), (0xEE, Inc, Absolute, 6), (0xFE, Inc, AbsoluteX, 7),
+    (0xE8, Inx, Implied, 2), (0xC8, Iny, Implied, 2),
+    (0x4C, Jmp, Absolute, 3), (0x6C, Jmp, Indirect, 5),
+    (0x20, Jsr, Absolute, 6),
+    (0xA9, Lda, Immediate, 2), (0xA5, Lda, ZeroPage, 3), (0xB5, Lda, ZeroPageX, 4), (0xAD, Lda, Absolute, 4),
+    (0xBD, Lda, AbsoluteX, 4), (0xB9, Lda, AbsoluteY, 4), (0xA1, Lda, IndexedIndirect, 6), (0xB1, Lda, IndirectIndexed, 5),
+    (0xA2, Ldx, Immediate, 2), (0xA6, Ldx, ZeroPage, 3), (0xB6, Ldx, ZeroPageY, 4), (0xAE, Ldx, Absolute, 4),
+    (0xBE, Ldx, AbsoluteY, 4),
+    (0xA0, Ldy, Immediate, 2), (0xA4, Ldy, ZeroPage, 3), (0xB4, Ldy, ZeroPageX, 4), (0xAC, Ldy, Absolute, 4),
+    (0xBC, Ldy, AbsoluteX, 4),
+    (0x4A, Lsr, Accumulator, 2), (0x46, Lsr, ZeroPage, 5), (0x56, Lsr, ZeroPageX, 6), (0x4E, Lsr, Absolute, 6),
+    (0x5E, Lsr, AbsoluteX, 7),
+    (0xEA, Nop, Implied, 2),
+    (0x09, Ora, Immediate, 2), (0x05, Ora, ZeroPage, 3), (0x15, Ora, ZeroPageX, 4), (0x0D, Ora, Absolute, 4),
+    (0x1D, Ora, AbsoluteX, 4), (0x19, Ora, AbsoluteY, 4), (0x01, Ora, IndexedIndirect, 6), (0x11, Ora, IndirectIndexed, 5),
+    (0x48, Pha, Implied, 3), (0x08, Php, Implied, 3), (0x68, Pla, Implied, 4), (0x28, Plp, Implied, 4),
+    (0x2A, Rol, Accumulator, 2), (0x26, Rol, ZeroPage, 5), (0x36, Rol, ZeroPageX, 6), (0x2E, Rol, Absolute, 6),
+    (0x3E, Rol, AbsoluteX, 7),
+    (0x6A, Ror, Accumulator, 2), (0x66, Ror, ZeroPage, 5), (0x76, Ror, ZeroPageX, 6), (0x6E, Ror, Absolute, 6),
+    (0x7E, Ror, AbsoluteX, 7),
+    (0x40, Rti, Implied, 6), (0x60, Rts, Implied, 6),
+    (0xE9, Sbc, Immediate, 2), (0xE5, Sbc, ZeroPage, 3), (0xF5, Sbc, ZeroPageX, 4), (0xED, Sbc, Absolute, 4),
+    (0xFD, Sbc, AbsoluteX, 4), (0xF9, Sbc, AbsoluteY, 4), (0xE1, Sbc, IndexedIndirect, 6), (0xF1, Sbc, IndirectIndexed, 5),
+    (0x38, Sec, Implied, 2), (0xF8, Sed, Implied, 2), (0x78, Sei, Implied, 2),
+    (0x85, Sta, ZeroPage, 3), (0x95, Sta, ZeroPageX, 4), (0x8D, Sta, Absolute, 4), (0x9D, Sta, AbsoluteX, 5),
+    (0x99, Sta, AbsoluteY, 5), (0x81, Sta, IndexedIndirect, 6), (0x91, Sta, IndirectIndexed, 6),
+    (0x86, Stx, ZeroPage, 3), (0x96, Stx, ZeroPageY, 4), (0x8E, Stx, Absolute, 4),
+    (0x84, Sty, ZeroPage, 3), (0x94, Sty, ZeroPageX, 4), (0x8C, Sty, Absolute, 4),
+    (0xAA, Tax, Implied, 2), (0xA8, Tay, Implied, 2), (0xBA, Tsx, Implied, 2), (0x8A, Txa, Implied, 2),
+    (0x9A, Txs, Implied, 2), (0x98, Tya, Implied, 2),
+];
+
+#[derive(Clone)]
+struct Reference
+{
+    a: u8,
+    x: u8,
+    y: u8,
+    // B clear and the unused bit set, as the CPU reports it
+    p: u8,
+    sp: u8,
+    pc: u16,
+    ram: [u8; 0x0800],
+}
+
+impl Reference
+{
+    fn read(&self, address: u16) -> u8
+    {
+        match address {
+            0x0000..=0x1FFF => self.ram[(address & 0x07FF) as usize],
+            // the dummy cartridge, BRK goes to $8000
+            0xFFFF => 0x80,
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8)
+    {
+        assert!(address < 0x2000, "the case writes to ${:04X}, outside of the RAM", address);
+        self.ram[(address & 0x07FF) as usize] = value;
+    }
+
+    fn push(&mut self, value: u8)
+    {
+        self.write(0x0100 | self.sp as u16, value);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8
+    {
+        self.sp = self.sp.wrapping_add(1);
+        self.read(0x0100 | self.sp as u16)
+    }
+
+    fn flag(&self, flag: u8) -> bool { self.p & flag != 0 }
+
+    fn set_flag(&mut self, flag: u8, value: bool)
+    {
+        self.p = if value {self.p | flag} else {self.p & !flag};
+    }
+
+    fn set_nz(&mut self, value: u8) -> u8
+    {
+        self.set_flag(ZERO, value == 0);
+        self.set_flag(NEGATIVE, value & 0x80 != 0);
+        value
+    }
+
+    fn add(&mut self, value: u8)
+    {
+        let sum = self.a as u16 + value as u16 + self.flag(CARRY) as u16;
+        let result = sum as u8;
+        // both operands have the same sign, the result has the other
+        self.set_flag(OVERFLOW, (self.a ^ result) & (value ^ result) & 0x80 != 0);
+        self.set_flag(CARRY, sum > 0xFF);
+        self.a = self.set_nz(result);
+    }
+
+    fn compare(&mut self, register: u8, value: u8)
+    {
+        self.set_flag(CARRY, register >= value);
+        self.set_nz(register.wrapping_sub(value));
+    }
+
+    // returns the cycles taken
+    fn execute(&mut self) -> u32
+    {
+        let pc = self.pc;
+        let opcode = self.read(pc);
+        let &(_, op, mode, mut cycles) = REFERENCE.iter().find(|entry| entry.0 == opcode).expect("not an official opcode");
+        let operand = self.read(pc.wrapping_add(1));
+        let operand_word = operand as u16 | (self.read(pc.wrapping_add(2)) as u16) << 8;
+        let zero_page_word = |cpu: &Reference, pointer: u8| cpu.read(pointer as u16) as u16 | (cpu.read(pointer.wrapping_add(1) as u16) as u16) << 8;
+
+        self.pc = pc.wrapping_add(match mode {
+            Implied | Accumulator => 1,
+            Immediate | ZeroPage | ZeroPageX | ZeroPageY | IndexedIndirect | IndirectIndexed | Relative => 2,
+            Absolute | AbsoluteX | AbsoluteY | Indirect => 3,
+        });
+        let (address, base) = match mode {
+            Implied | Accumulator | Relative => (0, 0),
+            Immediate => (pc.wrapping_add(1), pc.wrapping_add(1)),
+            ZeroPage => (operand as u16, operand as u16),
+            ZeroPageX => (operand.wrapping_add(self.x) as u16, 0),
+            ZeroPageY => (operand.wrapping_add(self.y) as u16, 0),
+            Absolute => (operand_word, operand_word),
+            AbsoluteX => (operand_word.wrapping_add(self.x as u16), operand_word),
+            AbsoluteY => (operand_word.wrapping_add(self.y as u16), operand_word),
+            // the pointer does not cross pages, JMP ($10FF) reads $10FF and $1000
+            Indirect => {
+                let high = (operand_word & 0xFF00) | (operand_word as u8).wrapping_add(1) as u16;
+                (self.read(operand_word) as u16 | (self.read(high) as u16) << 8, 0)
+            },
+            IndexedIndirect => (zero_page_word(self, operand.wrapping_add(self.x)), 0),
+            IndirectIndexed => {
+                let base = zero_page_word(self, operand);
+                (base.wrapping_add(self.y as u16), base)
+            },
+        };
+        let page_crossed = matches!(mode, AbsoluteX | AbsoluteY | IndirectIndexed) && address & 0xFF00 != base & 0xFF00;
+        // stores and read-modify-writes always take the extra cycle, it is in their count
+        if page_crossed && matches!(op, Adc | And | Cmp | Eor | Lda | Ldx | Ldy | Ora | Sbc) {
+            cycles += 1;
+        }
+
+        let value = if mode == Accumulator {self.a} else {self.read(address)};
+        let mut branch = |cpu: &mut Reference, taken: bool| {
+            if taken {
+                let target = cpu.pc.wrapping_add(operand as i8 as u16);
+                cycles += if target & 0xFF00 == cpu.pc & 0xFF00 {1} else {2};
+                cpu.pc = target;
+            }
+        };
+        let shifted = |cpu: &mut Reference, result: u8| {
+            let result = cpu.set_nz(result);
+            if mode == Accumulator {
+                cpu.a = result;
+            } else {
+                cpu.write(address, result);
+            }
+        };
+
+        match op {
+            Adc => self.add(value),
+            Sbc => self.add(!value),
+            And => self.a = self.set_nz(self.a & value),
+            Ora => self.a = self.set_nz(self.a | value),
+            Eor => self.a = self.set_nz(self.a ^ value),
+            Asl => {
+                self.set_flag(CARRY, value & 0x80 != 0);
+                shifted(self, value << 1);
+            },
+            Lsr => {
+                self.set_flag(CARRY, value & 0x01 != 0);
+                shifted(self, value >> 1);
+            },
+            Rol => {
+                let carry = self.flag(CARRY) as u8;
+                self.set_flag(CARRY, value & 0x80 != 0);
+                shifted(self, value << 1 | carry);
+            },
+            Ror => {
+                let carry = (self.flag(CARRY) as u8) << 7;
+                self.set_flag(CARRY, value & 0x01 != 0);
+                shifted(self, value >> 1 | carry);
+            },
+            Bcc => branch(self, !self.flag(CARRY)),
+            Bcs => branch(self, self.flag(CARRY)),
+            Bne => branch(self, !self.flag(ZERO)),
+            Beq => branch(self, self.flag(ZERO)),
+            Bpl => branch(self, !self.flag(NEGATIVE)),
+            Bmi => branch(self, self.flag(NEGATIVE)),
+            Bvc => branch(self, !self.flag(OVERFLOW)),
+            Bvs => branch(self, self.flag(OVERFLOW)),
+            Bit => {
+                self.set_flag(ZERO, self.a & value == 0);
+                self.set_flag(NEGATIVE, value & 0x80 != 0);
+                self.set_flag(OVERFLOW, value & 0x40 != 0);
+            },
+            // the byte after BRK is skipped
+            Brk => {
+                let return_address = self.pc.wrapping_add(1);
+                self.push((return_address >> 8) as u8);
+                self.push(return_address as u8);
+                self.push(self.p | BREAK | UNUSED);
+                self.set_flag(INTERRUPT_DISABLE, true);
+                self.pc = self.read(0xFFFE) as u16 | (self.read(0xFFFF) as u16) << 8;
+            },
+            Clc => self.set_flag(CARRY, false),
+            Cld => self.set_flag(DECIMAL, false),
+            Cli => self.set_flag(INTERRUPT_DISABLE, false),
+            Clv => self.set_flag(OVERFLOW, false),
+            Sec => self.set_flag(CARRY, true),
+            Sed => self.set_flag(DECIMAL, true),
+            Sei => self.set_flag(INTERRUPT_DISABLE, true),
+            Cmp => self.compare(self.a, value),
+            Cpx => self.compare(self.x, value),
+            Cpy => self.compare(self.y, value),
+            Dec => {
+                let result = self.set_nz(value.wrapping_sub(1));
+                self.write(address, result);
+            },
+            Inc => {
+                let result = self.set_nz(value.wrapping_add(1));
+                self.write(address, result);
+            },
+            Dex => self.x = self.set_nz(self.x.wrapping_sub(1)),
+            Dey => self.y = self.set_nz(self.y.wrapping_sub(1)),
+            Inx => self.x = self.set_nz(self.x.wrapping_add(1)),
+            Iny => self.y = self.set_nz(self.y.wrapping_add(1)),
+            Jmp => self.pc = address,
+            // pushes the address of its last byte
+            Jsr => {
+                let return_address = self.pc.wrapping_sub(1);
+                self.push((return_address >> 8) as u8);
+                self.push(return_address as u8);
+                self.pc = address;
+            },
+            Lda => self.a = self.set_nz(value),
+            Ldx => self.x = self.set_nz(value),
+            Ldy => self.y = self.set_nz(value),
+            Nop => {},
+            Pha => self.push(self.a),
+            Php => self.push(self.p | BREAK | UNUSED),
+            Pla => {
+                let value = self.pull();
+                self.a = self.set_nz(value);
+            },
+            Plp => self.p = self.pull() & !BREAK | UNUSED,
+            Rti => {
+                self.p = self.pull() & !BREAK | UNUSED;
+                self.pc = self.pull() as u16;
+                self.pc |= (self.pull() as u16) << 8;
+            },
+            Rts => {
+                self.pc = self.pull() as u16;
+                self.pc |= (self.pull() as u16) << 8;
+                self.pc = self.pc.wrapping_add(1);
+            },
+            Sta => self.write(address, self.a),
+            Stx => self.write(address, self.x),
+            Sty => self.write(address, self.y),
+            Tax => self.x = self.set_nz(self.a),
+            Tay => self.y = self.set_nz(self.a),
+            Tsx => self.x = self.set_nz(self.sp),
+            Txa => self.a = self.set_nz(self.x),
+            Txs => self.sp = self.x,
+            Tya => self.a = self.set_nz(self.y),
+        }
+        cycles
+    }
+
+    // as Registers displays them, with PC
+    fn registers(&self) -> String
+    {
+        format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}", self.a, self.x, self.y, self.p, self.sp, self.pc)
+    }
+
+    // the instruction bytes and the registers
+    fn describe(&self) -> String
+    {
+        let pc = self.pc;
+        format!("{:02X} {:02X} {:02X}, {}", self.read(pc), self.read(pc.wrapping_add(1)), self.read(pc.wrapping_add(2)), self.registers())
+    }
+}
+
+// Random registers and RAM, with one instruction from $0200-$07FC, after the zero
+// page and the stack. Its absolute operands and the pointers of the indirect modes
+// stay below $1800, so indexing them never leaves the RAM mirrors.
+fn random_state(rng: &mut Rng) -> Reference
+{
+    let mut state = Reference {
+        a: rng.byte(),
+        x: rng.byte(),
+        y: rng.byte(),
+        p: rng.byte() & !BREAK | UNUSED,
+        sp: rng.byte(),
+        pc: 0x0200 + rng.below(0x05FD) as u16,
+        ram: [0; 0x0800],
+    };
+    for byte in state.ram.iter_mut() {
+        *byte = rng.byte();
+    }
+
+    let (opcode, _, mode, _) = REFERENCE[rng.below(REFERENCE.len() as u32) as usize];
+    let mut operands = [rng.byte(), rng.byte()];
+    match mode {
+        Absolute | AbsoluteX | AbsoluteY | Indirect => operands[1] = rng.below(0x18) as u8,
+        IndexedIndirect => state.ram[operands[0].wrapping_add(state.x).wrapping_add(1) as usize] = rng.below(0x18) as u8,
+        IndirectIndexed => state.ram[operands[0].wrapping_add(1) as usize] = rng.below(0x18) as u8,
+        _ => {},
+    }
+    let pc = state.pc as usize;
+    state.ram[pc] = opcode;
+    state.ram[pc + 1..pc + 3].copy_from_slice(&operands);
+    state
+}
+
+fn check(seed: u64, state: &Reference, cycle_accurate: bool)
+{
+    let mut cpu = Cpu::new_dummy();
+    cpu.set_cycle_accurate(cycle_accurate);
+    cpu.zero_page_ram.copy_from_slice(&state.ram[..0x0100]);
+    cpu.stack.copy_from_slice(&state.ram[0x0100..0x0200]);
+    cpu.internal_ram.copy_from_slice(&state.ram[0x0200..]);
+    cpu.set_a(state.a);
+    cpu.set_x(state.x);
+    cpu.set_y(state.y);
+    cpu.set_status_byte(state.p);
+    cpu.set_stack_pointer(state.sp);
+    cpu.set_pc(state.pc);
+
+    let mut expected = state.clone();
+    let expected_cycles = expected.execute();
+    let cycles = cpu.step().cycles;
+
+    // only formatted on a mismatch
+    let context = || format!(
+        "case seed {:#018X}, {} mode, {}",
+        seed, if cycle_accurate {"cycle accurate"} else {"default"}, state.describe(),
+    );
+    let registers = format!("{} PC:{:04X}", cpu.registers(), cpu.pc());
+    assert_eq!(registers, expected.registers(), "registers differ, {}", context());
+    assert_eq!(cycles, expected_cycles as u64, "cycles differ, {}", context());
+    let ram = [&cpu.zero_page_ram[..], &cpu.stack[..], &cpu.internal_ram[..]].concat();
+    if let Some(address) = (0..ram.len()).find(|&address| ram[address] != expected.ram[address]) {
+        panic!("${:04X} is {:02X} instead of {:02X}, {}", address, ram[address], expected.ram[address], context());
+    }
+}
+
+fn run_case(seed: u64)
+{
+    let state = random_state(&mut Rng::new(seed));
+    check(seed, &state, false);
+    check(seed, &state, true);
+}
+
+#[test]
+fn test_against_reference()
+{
+    let mut rng = Rng::new(SEED);
+    for _ in 0..CASES {
+        run_case(rng.next_u64());
+    }
+}
+
+#[test]
+fn test_reference_covers_official_opcodes()
+{
+    for opcode in 0..=0xFF {
+        let covered = REFERENCE.iter().any(|entry| entry.0 == opcode);
+        assert_eq!(covered, opcode_info(opcode).official, "opcode {:02X}", opcode);
+    }
+}
@@ -2,7 +2,17 @@ use super::Cpu;
 use super::InstructionResult;
 use super::AddressingMode;
 use super::Interrupts;
+use super::branch_target;
 
+// Which 6502 the core behaves as. They only differ in ADC and SBC with D set: the
+// 2A03 of the NES has the decimal mode cut out, the NMOS 6502 does BCD arithmetic.
+// The unofficial ARR keeps its binary behavior on both.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CpuVariant
+{
+    Ricoh2A03,
+    Nmos6502,
+}
 
 enum LoadStoreLocation
 {
@@ -25,19 +35,19 @@ impl Cpu
         }
     }
 
-    pub fn lda(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn lda(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        self.load_instruction(addressing_mode.read(&self), LoadStoreLocation::Accumulator);
+        self.load_instruction(addressing_mode.read(self), LoadStoreLocation::Accumulator);
         InstructionResult::Ok
     }
 
-    pub fn ldx(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn ldx(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        self.load_instruction(addressing_mode.read(&self), LoadStoreLocation::X);
+        self.load_instruction(addressing_mode.read(self), LoadStoreLocation::X);
         InstructionResult::Ok
     }
 
-    pub fn ldy(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn ldy(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.load_instruction(addressing_mode.read(self), LoadStoreLocation::Y);
         InstructionResult::Ok
@@ -52,26 +62,26 @@ impl Cpu
         }
     }
 
-    pub fn sta(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn sta(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         addressing_mode.write(self, self.store_instruction(LoadStoreLocation::Accumulator));
         InstructionResult::Ok
     }
 
-    pub fn stx(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn stx(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         addressing_mode.write(self, self.store_instruction(LoadStoreLocation::X));
         InstructionResult::Ok
     }
 
-    pub fn sty(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn sty(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         addressing_mode.write(self, self.store_instruction(LoadStoreLocation::Y));
         InstructionResult::Ok
     }
 
     // Register transfers
-    pub fn tax(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn tax(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_zero(self.registers.a == 0);
         self.registers.set_status_negative(self.registers.a & 0x80 == 0x80);
@@ -79,7 +89,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn tay(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn tay(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_zero(self.registers.a == 0);
         self.registers.set_status_negative(self.registers.a & 0x80 == 0x80);
@@ -87,7 +97,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn txa(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn txa(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_zero(self.registers.x == 0);
         self.registers.set_status_negative(self.registers.x & 0x80 == 0x80);
@@ -95,7 +105,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn tya(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn tya(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_zero(self.registers.y == 0);
         self.registers.set_status_negative(self.registers.y & 0x80 == 0x80);
@@ -104,7 +114,7 @@ impl Cpu
     }
 
     // Stack operation
-    pub fn tsx(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn tsx(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_zero(self.registers.stack_pointer == 0);
         self.registers.set_status_negative(self.registers.stack_pointer & 0x80 == 0x80);
@@ -112,25 +122,25 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn txs(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn txs(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.stack_pointer = self.registers.x;
         InstructionResult::Ok
     }
 
-    pub fn pha(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn pha(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.push(self.registers.a);
         InstructionResult::Ok
     }
 
-    pub fn php(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn php(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.push(self.registers.p.get_byte() | 0b0011_0000);
         InstructionResult::Ok
     }
 
-    pub fn pla(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn pla(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.a = self.pop();
         self.registers.set_status_zero(self.registers.a == 0);
@@ -138,7 +148,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn plp(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn plp(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         let data = self.pop() & 0b1100_1111;
         self.registers.p.set_byte(data);
@@ -147,31 +157,31 @@ impl Cpu
 
     // Logical
 
-    pub fn and(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn and(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        self.registers.a = self.registers.a & addressing_mode.read(self);
+        self.registers.a &= addressing_mode.read(self);
         self.registers.set_status_zero(self.registers.a == 0);
         self.registers.set_status_negative(self.registers.a & 0x80 == 0x80);
         InstructionResult::Ok
     }
 
-    pub fn ora(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn ora(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        self.registers.a = self.registers.a | addressing_mode.read(self);
+        self.registers.a |= addressing_mode.read(self);
         self.registers.set_status_zero(self.registers.a == 0);
         self.registers.set_status_negative(self.registers.a & 0x80 == 0x80);
         InstructionResult::Ok
     }
 
-    pub fn eor(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn eor(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        self.registers.a = self.registers.a ^ addressing_mode.read(self);
+        self.registers.a ^= addressing_mode.read(self);
         self.registers.set_status_zero(self.registers.a == 0);
         self.registers.set_status_negative(self.registers.a & 0x80 == 0x80);
         InstructionResult::Ok
     }
 
-    pub fn bit(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bit(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         let data = addressing_mode.read(self);
         self.registers.set_status_zero(self.registers.a & data == 0);
@@ -181,9 +191,17 @@ impl Cpu
     }
 
     // Arithmetic
-    pub fn adc(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub fn set_variant(&mut self, variant: CpuVariant) { self.variant = variant }
+
+    fn decimal_arithmetic(&self) -> bool { self.variant == CpuVariant::Nmos6502 && self.registers.p.decimal }
+
+    pub(crate) fn adc(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        let val = addressing_mode.read(&self);
+        let val = addressing_mode.read(self);
+        if self.decimal_arithmetic() {
+            self.adc_decimal(val);
+            return InstructionResult::Ok
+        }
         let result = self.registers.a as u16 + val as u16 + self.registers.p.carry as u16;
         self.registers.set_status_carry(result > 0xFF);
         self.registers.set_status_zero(result as u8 == 0);
@@ -193,19 +211,52 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn sbc(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    // NMOS BCD addition: Z comes from the binary sum, N and V from the sum once the low
+    // digit is adjusted, C and A once the high digit is. Invalid BCD gives what the chip gives.
+    fn adc_decimal(&mut self, val: u8)
     {
-        let val = addressing_mode.read(&self);
-        let result = (self.registers.a as u16).wrapping_sub(val as u16).wrapping_sub(!self.registers.p.carry as u16);
+        let a = self.registers.a;
+        let carry = self.registers.p.carry as u8;
+        let mut low = (a & 0x0F) + (val & 0x0F) + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let sum = (a & 0xF0) as u16 + (val & 0xF0) as u16 + low as u16;
+        let signed_sum = (a & 0xF0) as i8 as i16 + (val & 0xF0) as i8 as i16 + low as i16;
+        self.registers.set_status_zero(a.wrapping_add(val).wrapping_add(carry) == 0);
+        self.registers.set_status_negative(sum & 0x80 == 0x80);
+        self.registers.set_status_overflow(!(-128..=127).contains(&signed_sum));
+        let sum = if sum >= 0xA0 {sum + 0x60} else {sum};
+        self.registers.set_status_carry(sum > 0xFF);
+        self.registers.a = sum as u8;
+    }
+
+    pub(crate) fn sbc(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let val = addressing_mode.read(self);
+        let a = self.registers.a;
+        let borrow = !self.registers.p.carry;
+        let result = (a as u16).wrapping_sub(val as u16).wrapping_sub(borrow as u16);
         self.registers.set_status_carry(result <= 0xFF);
         self.registers.set_status_zero(result as u8 == 0);
-        self.registers.set_status_overflow((self.registers.a ^ result as u8) & (val ^ result as u8) & 0x80 == 0x80);
+        self.registers.set_status_overflow((a ^ result as u8) & (!val ^ result as u8) & 0x80 == 0x80);
         self.registers.set_status_negative(result as u8 & 0x80 == 0x80);
-        self.registers.a = result as u8;
+        // the NMOS decimal mode only changes A, the flags are the binary ones
+        self.registers.a = if self.decimal_arithmetic() {Cpu::sbc_decimal(a, val, borrow)} else {result as u8};
         InstructionResult::Ok
     }
 
-    pub fn cmp(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    fn sbc_decimal(a: u8, val: u8, borrow: bool) -> u8
+    {
+        let mut low = (a & 0x0F) as i16 - (val & 0x0F) as i16 - borrow as i16;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0F) - 0x10;
+        }
+        let difference = (a & 0xF0) as i16 - (val & 0xF0) as i16 + low;
+        (if difference < 0 {difference - 0x60} else {difference}) as u8
+    }
+
+    pub(crate) fn cmp(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         let result: i16 = self.registers.a as i16 - addressing_mode.read(self) as i16;
         self.registers.set_status_carry(result >= 0);
@@ -214,7 +265,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn cpx(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn cpx(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         let result: i16 = self.registers.x as i16 - addressing_mode.read(self) as i16;
         self.registers.set_status_carry(result >= 0);
@@ -223,7 +274,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn cpy(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn cpy(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         let result: i16 = self.registers.y as i16 - addressing_mode.read(self) as i16;
         self.registers.set_status_carry(result >= 0);
@@ -233,16 +284,16 @@ impl Cpu
     }
 
     // Increments and Decrements
-    pub fn inc(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn inc(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        let data = addressing_mode.read(self);
+        let data = addressing_mode.read_for_modify(self);
         self.registers.set_status_zero(data.wrapping_add(1) == 0);
         self.registers.set_status_negative(data.wrapping_add(1) & 0x80 == 0x80);
-        addressing_mode.write(self, data.wrapping_add(1));
+        addressing_mode.write_modified(self, data, data.wrapping_add(1));
         InstructionResult::Ok
     }
 
-    pub fn inx(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn inx(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.x = self.registers.x.wrapping_add(1);
         self.registers.set_status_zero(self.registers.x == 0);
@@ -250,7 +301,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn iny(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn iny(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.y = self.registers.y.wrapping_add(1);
         self.registers.set_status_zero(self.registers.y == 0);
@@ -258,16 +309,16 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn dec(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn dec(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        let data = addressing_mode.read(self);
+        let data = addressing_mode.read_for_modify(self);
         self.registers.set_status_zero(data.wrapping_sub(1) == 0);
         self.registers.set_status_negative(data.wrapping_sub(1) & 0x80 == 0x80);
-        addressing_mode.write(self, data.wrapping_sub(1));
+        addressing_mode.write_modified(self, data, data.wrapping_sub(1));
         InstructionResult::Ok
     }
 
-    pub fn dex(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn dex(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.x = self.registers.x.wrapping_sub(1);
         self.registers.set_status_zero(self.registers.x == 0);
@@ -275,7 +326,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn dey(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn dey(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.y = self.registers.y.wrapping_sub(1);
         self.registers.set_status_zero(self.registers.y == 0);
@@ -284,60 +335,60 @@ impl Cpu
     }
 
     // Shifts
-    pub fn asl(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn asl(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        let data = addressing_mode.read(self);
+        let data = addressing_mode.read_for_modify(self);
         self.registers.set_status_carry(data & 0x80 == 0x80);
         let result = data << 1;
         self.registers.set_status_zero(result == 0);
         self.registers.set_status_negative(result & 0x80 == 0x80);
-        addressing_mode.write(self, result);
+        addressing_mode.write_modified(self, data, result);
         InstructionResult::Ok
     }
 
-    pub fn lsr(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn lsr(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        let data = addressing_mode.read(self);
+        let data = addressing_mode.read_for_modify(self);
         self.registers.set_status_carry(data & 0x01 == 0x01);
         let result = data >> 1;
         self.registers.set_status_zero(result == 0);
         self.registers.set_status_negative(result & 0x80 == 0x80);
-        addressing_mode.write(self, result);
+        addressing_mode.write_modified(self, data, result);
         InstructionResult::Ok
     }
 
-    pub fn rol(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn rol(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        let data = addressing_mode.read(self);
-        let old_carry = (self.registers.p.carry as u8);
+        let data = addressing_mode.read_for_modify(self);
+        let old_carry = self.registers.p.carry as u8;
         self.registers.set_status_carry(data & 0x80 == 0x80);
         let result = (data << 1) | old_carry;
         self.registers.set_status_zero(result == 0);
         self.registers.set_status_negative(result & 0x80 == 0x80);
-        addressing_mode.write(self, result);
+        addressing_mode.write_modified(self, data, result);
         InstructionResult::Ok
     }
 
-    pub fn ror(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn ror(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        let data = addressing_mode.read(self);
+        let data = addressing_mode.read_for_modify(self);
         let old_carry = (self.registers.p.carry as u8) << 7;
         self.registers.set_status_carry(data & 0x01 == 0x01);
         let result = (data >> 1) | old_carry;
         self.registers.set_status_zero(result == 0);
         self.registers.set_status_negative(result & 0x80 == 0x80);
-        addressing_mode.write(self, result);
+        addressing_mode.write_modified(self, data, result);
         InstructionResult::Ok
     }
 
     // Jumps and calls
-    pub fn jmp(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn jmp(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.pc = addressing_mode.address();
         InstructionResult::Ok
     }
 
-    pub fn jsr(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn jsr(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         let address = self.registers.pc.wrapping_sub(1);
         self.push((address >> 8) as u8);
@@ -346,7 +397,7 @@ impl Cpu
         InstructionResult::Ok
     }
 
-    pub fn rts(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn rts(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         let address: u16 =  self.pop() as u16 | ((self.pop() as u16) << 8);
         let address = address.wrapping_add(1);
@@ -355,96 +406,88 @@ impl Cpu
     }
 
     // Branch
-    pub fn bcc(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bcc(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if !self.registers.p.carry {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
         }
     }
 
-    pub fn bcs(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bcs(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if self.registers.p.carry {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
         }
     }
 
-    pub fn beq(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn beq(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if self.registers.p.zero {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
         }
     }
 
-    pub fn bmi(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bmi(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if self.registers.p.negative {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
         }
     }
 
-    pub fn bne(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bne(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if !self.registers.p.zero {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
         }
     }
 
-    pub fn bpl(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bpl(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if !self.registers.p.negative {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
         }
     }
 
-    pub fn bvc(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bvc(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if !self.registers.p.overflow {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
         }
     }
 
-    pub fn bvs(&mut self, addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn bvs(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
     {
         if self.registers.p.overflow {
             let old_pc = self.registers.pc;
-            let offset: i8 = addressing_mode.read(self) as i8;
-            self.registers.pc = (self.registers.pc as i32).wrapping_add(offset as i32) as u16;
+            self.registers.pc = branch_target(addressing_mode.read(self), self.registers.pc);
             InstructionResult::Branch(if old_pc & 0xFF00 == self.registers.pc & 0xFF00 {1} else {2})
         } else {
             InstructionResult::NOP
@@ -452,60 +495,167 @@ impl Cpu
     }
 
     // Status flags change
-    pub fn clc(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    // The 2A03 has no decimal mode: D can be set, cleared, pushed and pulled,
+    // but ADC and SBC always compute in binary.
+    pub(crate) fn clc(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_carry(false);
         InstructionResult::Ok
     }
 
-    pub fn cld(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn cld(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_decimal(false);
         InstructionResult::Ok
     }
 
-    pub fn cli(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn cli(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_interupt_disable(false);
         InstructionResult::Ok
     }
 
-    pub fn clv(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn clv(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_overflow(false);
         InstructionResult::Ok
     }
 
-    pub fn sec(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn sec(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_carry(true);
         InstructionResult::Ok
     }
 
-    pub fn sed(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn sed(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_decimal(true);
         InstructionResult::Ok
     }
 
-    pub fn sei(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn sei(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         self.registers.set_status_interupt_disable(true);
         InstructionResult::Ok
     }
 
     // System functions
-    pub fn brk(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    // the byte after the opcode is padding, RTI returns after it
+    pub(crate) fn brk(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
+        self.increment_pc();
         self.interrupt(Interrupts::Break);
         InstructionResult::Ok
     }
 
-    pub fn rti(&mut self, _addressing_mode: &dyn AddressingMode) -> InstructionResult
+    pub(crate) fn rti(&mut self, _addressing_mode: &AddressingMode) -> InstructionResult
     {
         let status = self.pop();
         self.registers.p.set_byte(status);
         self.registers.pc = self.pop() as u16 | ((self.pop() as u16) << 8);
         InstructionResult::Ok
     }
+
+    // Unofficial, the stable combinations of two official instructions. The RMW ones
+    // read memory once and feed the written value to the ALU half.
+    pub(crate) fn lax(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read(self);
+        self.load_instruction(data, LoadStoreLocation::Accumulator);
+        self.load_instruction(data, LoadStoreLocation::X);
+        InstructionResult::Ok
+    }
+
+    pub(crate) fn sax(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        addressing_mode.write(self, self.registers.a & self.registers.x);
+        InstructionResult::Ok
+    }
+
+    pub(crate) fn dcp(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read_for_modify(self);
+        let result = data.wrapping_sub(1);
+        addressing_mode.write_modified(self, data, result);
+        self.cmp(&AddressingMode::Immediate(result))
+    }
+
+    pub(crate) fn isb(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read_for_modify(self);
+        let result = data.wrapping_add(1);
+        addressing_mode.write_modified(self, data, result);
+        self.sbc(&AddressingMode::Immediate(result))
+    }
+
+    pub(crate) fn slo(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read_for_modify(self);
+        self.registers.set_status_carry(data & 0x80 == 0x80);
+        addressing_mode.write_modified(self, data, data << 1);
+        self.ora(&AddressingMode::Immediate(data << 1))
+    }
+
+    pub(crate) fn rla(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read_for_modify(self);
+        let result = (data << 1) | self.registers.p.carry as u8;
+        self.registers.set_status_carry(data & 0x80 == 0x80);
+        addressing_mode.write_modified(self, data, result);
+        self.and(&AddressingMode::Immediate(result))
+    }
+
+    pub(crate) fn sre(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read_for_modify(self);
+        self.registers.set_status_carry(data & 0x01 == 0x01);
+        addressing_mode.write_modified(self, data, data >> 1);
+        self.eor(&AddressingMode::Immediate(data >> 1))
+    }
+
+    // the carry out of the rotation is the carry in of the addition
+    pub(crate) fn rra(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read_for_modify(self);
+        let result = (data >> 1) | (self.registers.p.carry as u8) << 7;
+        self.registers.set_status_carry(data & 0x01 == 0x01);
+        addressing_mode.write_modified(self, data, result);
+        self.adc(&AddressingMode::Immediate(result))
+    }
+
+    pub(crate) fn anc(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        self.and(addressing_mode);
+        self.registers.set_status_carry(self.registers.a & 0x80 == 0x80);
+        InstructionResult::Ok
+    }
+
+    pub(crate) fn alr(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = self.registers.a & addressing_mode.read(self);
+        self.registers.set_status_carry(data & 0x01 == 0x01);
+        self.load_instruction(data >> 1, LoadStoreLocation::Accumulator);
+        InstructionResult::Ok
+    }
+
+    // the rotation goes through the adder, C is bit 6 of the result and V is bit 6 XOR bit 5
+    pub(crate) fn arr(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = self.registers.a & addressing_mode.read(self);
+        let result = (data >> 1) | (self.registers.p.carry as u8) << 7;
+        self.load_instruction(result, LoadStoreLocation::Accumulator);
+        self.registers.set_status_carry(result & 0x40 == 0x40);
+        self.registers.set_status_overflow(((result >> 6) ^ (result >> 5)) & 0x01 == 0x01);
+        InstructionResult::Ok
+    }
+
+    // a compare of A AND X that keeps its result
+    pub(crate) fn axs(&mut self, addressing_mode: &AddressingMode) -> InstructionResult
+    {
+        let data = addressing_mode.read(self);
+        let operand = self.registers.a & self.registers.x;
+        self.registers.set_status_carry(operand >= data);
+        self.load_instruction(operand.wrapping_sub(data), LoadStoreLocation::X);
+        InstructionResult::Ok
+    }
 }
\ No newline at end of file
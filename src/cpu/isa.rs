@@ -0,0 +1,538 @@
+// Instruction set metadata, queried at runtime by tools (monitor help, disassembly
+// tooltips). The opcode decoding itself lives here so the CPU and the metadata
+// can't disagree on addressing modes, cycles or mnemonics.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AddressingModeKind
+{
+    Implicit,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirect, // (zp,X)
+    IndirectIndexed, // (zp),Y
+    Relative,
+}
+
+impl AddressingModeKind
+{
+    pub fn operand_bytes(self) -> u8
+    {
+        match self {
+            AddressingModeKind::Implicit | AddressingModeKind::Accumulator => 0,
+            AddressingModeKind::Absolute | AddressingModeKind::AbsoluteX | AddressingModeKind::AbsoluteY | AddressingModeKind::Indirect => 2,
+            _ => 1,
+        }
+    }
+}
+
+pub fn addressing_mode_kind(opcode: u8) -> AddressingModeKind { OPCODE_TABLE[opcode as usize].addressing_mode }
+
+// bits of the status register
+pub const CARRY: u8 = 0b0000_0001;
+pub const ZERO: u8 = 0b0000_0010;
+pub const INTERRUPT_DISABLE: u8 = 0b0000_0100;
+pub const DECIMAL: u8 = 0b0000_1000;
+pub const OVERFLOW: u8 = 0b0100_0000;
+pub const NEGATIVE: u8 = 0b1000_0000;
+const NZ: u8 = NEGATIVE | ZERO;
+const NZC: u8 = NEGATIVE | ZERO | CARRY;
+const ALL: u8 = NEGATIVE | OVERFLOW | DECIMAL | INTERRUPT_DISABLE | ZERO | CARRY;
+
+// mnemonic, flags the instruction may change, description
+const INSTRUCTIONS: [(&str, u8, &str); 70] = [
+    ("ADC", NZC | OVERFLOW, "Add memory and carry to A"),
+    ("AND", NZ, "Bitwise AND memory with A"),
+    ("ASL", NZC, "Shift left one bit, bit 7 goes to carry"),
+    ("BCC", 0, "Branch if carry clear"),
+    ("BCS", 0, "Branch if carry set"),
+    ("BEQ", 0, "Branch if zero set"),
+    ("BIT", NZ | OVERFLOW, "Test bits of memory against A, N and V are copied from memory"),
+    ("BMI", 0, "Branch if negative set"),
+    ("BNE", 0, "Branch if zero clear"),
+    ("BPL", 0, "Branch if negative clear"),
+    ("BRK", INTERRUPT_DISABLE, "Push PC and P, then jump through the IRQ vector"),
+    ("BVC", 0, "Branch if overflow clear"),
+    ("BVS", 0, "Branch if overflow set"),
+    ("CLC", CARRY, "Clear carry"),
+    ("CLD", DECIMAL, "Clear decimal mode"),
+    ("CLI", INTERRUPT_DISABLE, "Clear interrupt disable"),
+    ("CLV", OVERFLOW, "Clear overflow"),
+    ("CMP", NZC, "Compare memory with A"),
+    ("CPX", NZC, "Compare memory with X"),
+    ("CPY", NZC, "Compare memory with Y"),
+    ("DEC", NZ, "Decrement memory"),
+    ("DEX", NZ, "Decrement X"),
+    ("DEY", NZ, "Decrement Y"),
+    ("EOR", NZ, "Bitwise exclusive OR memory with A"),
+    ("INC", NZ, "Increment memory"),
+    ("INX", NZ, "Increment X"),
+    ("INY", NZ, "Increment Y"),
+    ("JMP", 0, "Jump to address"),
+    ("JSR", 0, "Push the return address minus one, then jump to subroutine"),
+    ("LDA", NZ, "Load A from memory"),
+    ("LDX", NZ, "Load X from memory"),
+    ("LDY", NZ, "Load Y from memory"),
+    ("LSR", NZC, "Shift right one bit, bit 0 goes to carry"),
+    ("NOP", 0, "No operation"),
+    ("ORA", NZ, "Bitwise OR memory with A"),
+    ("PHA", 0, "Push A"),
+    ("PHP", 0, "Push P with the B flag set"),
+    ("PLA", NZ, "Pull A"),
+    ("PLP", ALL, "Pull P"),
+    ("ROL", NZC, "Rotate left one bit through carry"),
+    ("ROR", NZC, "Rotate right one bit through carry"),
+    ("RTI", ALL, "Pull P then PC, return from interrupt"),
+    ("RTS", 0, "Pull PC plus one, return from subroutine"),
+    ("SBC", NZC | OVERFLOW, "Subtract memory and borrow from A"),
+    ("SEC", CARRY, "Set carry"),
+    ("SED", DECIMAL, "Set decimal mode, ignored by the 2A03 ALU"),
+    ("SEI", INTERRUPT_DISABLE, "Set interrupt disable"),
+    ("STA", 0, "Store A in memory"),
+    ("STX", 0, "Store X in memory"),
+    ("STY", 0, "Store Y in memory"),
+    ("TAX", NZ, "Transfer A to X"),
+    ("TAY", NZ, "Transfer A to Y"),
+    ("TSX", NZ, "Transfer the stack pointer to X"),
+    ("TXA", NZ, "Transfer X to A"),
+    ("TXS", 0, "Transfer X to the stack pointer"),
+    ("TYA", NZ, "Transfer Y to A"),
+    // unofficial
+    ("*ALR", NZC, "AND memory with A, then shift A right"),
+    ("*ANC", NZC, "AND memory with A, bit 7 of the result goes to carry"),
+    ("*ARR", NZC | OVERFLOW, "AND memory with A, then rotate A right, C and V come from bits 6 and 5"),
+    ("*AXS", NZC, "Subtract memory from A AND X without borrow, into X"),
+    ("*DCP", NZC, "Decrement memory, then compare it with A"),
+    ("*ISB", NZC | OVERFLOW, "Increment memory, then subtract it from A"),
+    ("*LAX", NZ, "Load A and X from memory"),
+    ("*NOP", 0, "No operation, reading the operand if any"),
+    ("*RLA", NZC, "Rotate memory left, then AND it with A"),
+    ("*RRA", NZC | OVERFLOW, "Rotate memory right, then add it to A"),
+    ("*SAX", 0, "Store A AND X in memory"),
+    ("*SBC", NZC | OVERFLOW, "Same as the official SBC immediate"),
+    ("*SLO", NZC, "Shift memory left, then OR it with A"),
+    ("*SRE", NZC, "Shift memory right, then EOR it with A"),
+];
+
+// what the CPU executes for an opcode, the unofficial NOPs share Nop with NOP
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operation
+{
+    Adc,
+    And,
+    Asl,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Jmp,
+    Jsr,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rol,
+    Ror,
+    Rti,
+    Rts,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Sta,
+    Stx,
+    Sty,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+    // unofficial
+    Alr,
+    Anc,
+    Arr,
+    Axs,
+    Dcp,
+    Isb,
+    Lax,
+    Rla,
+    Rra,
+    Sax,
+    Slo,
+    Sre,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OpcodeInfo
+{
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub addressing_mode: AddressingModeKind,
+    // without the page crossing and branch taken penalties
+    pub cycles: u32,
+    // one more cycle when the indexed address crosses a page, for reads only
+    pub page_cross_penalty: bool,
+    pub operation: Operation,
+    pub official: bool,
+}
+
+impl OpcodeInfo
+{
+    const fn new(opcode: u8, mnemonic: &'static str, addressing_mode: AddressingModeKind, cycles: u32, page_cross_penalty: bool, operation: Operation) -> OpcodeInfo
+    {
+        // unofficial mnemonics are starred, as in the nestest log
+        let official = mnemonic.as_bytes()[0] != b'*';
+        OpcodeInfo {opcode, mnemonic, addressing_mode, cycles, page_cross_penalty, operation, official}
+    }
+}
+
+use self::AddressingModeKind as Mode;
+use self::Operation as Op;
+
+// The single source of the opcode decoding: the CPU, the tracer and the tools all
+// read it. The branches add their taken and page crossing cycles when executed.
+pub const OPCODE_TABLE: [OpcodeInfo; 256] = [
+    // $00-$0F
+    OpcodeInfo::new(0x00, "BRK", Mode::Implicit, 7, false, Op::Brk),
+    OpcodeInfo::new(0x01, "ORA", Mode::IndexedIndirect, 6, false, Op::Ora),
+    OpcodeInfo::new(0x02, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x03, "*SLO", Mode::IndexedIndirect, 8, false, Op::Slo),
+    OpcodeInfo::new(0x04, "*NOP", Mode::ZeroPage, 3, false, Op::Nop),
+    OpcodeInfo::new(0x05, "ORA", Mode::ZeroPage, 3, false, Op::Ora),
+    OpcodeInfo::new(0x06, "ASL", Mode::ZeroPage, 5, false, Op::Asl),
+    OpcodeInfo::new(0x07, "*SLO", Mode::ZeroPage, 5, false, Op::Slo),
+    OpcodeInfo::new(0x08, "PHP", Mode::Implicit, 3, false, Op::Php),
+    OpcodeInfo::new(0x09, "ORA", Mode::Immediate, 2, false, Op::Ora),
+    OpcodeInfo::new(0x0A, "ASL", Mode::Accumulator, 2, false, Op::Asl),
+    OpcodeInfo::new(0x0B, "*ANC", Mode::Immediate, 2, false, Op::Anc),
+    OpcodeInfo::new(0x0C, "*NOP", Mode::Absolute, 4, false, Op::Nop),
+    OpcodeInfo::new(0x0D, "ORA", Mode::Absolute, 4, false, Op::Ora),
+    OpcodeInfo::new(0x0E, "ASL", Mode::Absolute, 6, false, Op::Asl),
+    OpcodeInfo::new(0x0F, "*SLO", Mode::Absolute, 6, false, Op::Slo),
+
+    // $10-$1F
+    OpcodeInfo::new(0x10, "BPL", Mode::Relative, 2, false, Op::Bpl),
+    OpcodeInfo::new(0x11, "ORA", Mode::IndirectIndexed, 5, true, Op::Ora),
+    OpcodeInfo::new(0x12, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x13, "*SLO", Mode::IndirectIndexed, 8, false, Op::Slo),
+    OpcodeInfo::new(0x14, "*NOP", Mode::ZeroPageX, 4, false, Op::Nop),
+    OpcodeInfo::new(0x15, "ORA", Mode::ZeroPageX, 4, false, Op::Ora),
+    OpcodeInfo::new(0x16, "ASL", Mode::ZeroPageX, 6, false, Op::Asl),
+    OpcodeInfo::new(0x17, "*SLO", Mode::ZeroPageX, 6, false, Op::Slo),
+    OpcodeInfo::new(0x18, "CLC", Mode::Implicit, 2, false, Op::Clc),
+    OpcodeInfo::new(0x19, "ORA", Mode::AbsoluteY, 4, true, Op::Ora),
+    OpcodeInfo::new(0x1A, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x1B, "*SLO", Mode::AbsoluteY, 7, false, Op::Slo),
+    OpcodeInfo::new(0x1C, "*NOP", Mode::AbsoluteX, 4, true, Op::Nop),
+    OpcodeInfo::new(0x1D, "ORA", Mode::AbsoluteX, 4, true, Op::Ora),
+    OpcodeInfo::new(0x1E, "ASL", Mode::AbsoluteX, 7, false, Op::Asl),
+    OpcodeInfo::new(0x1F, "*SLO", Mode::AbsoluteX, 7, false, Op::Slo),
+
+    // $20-$2F
+    OpcodeInfo::new(0x20, "JSR", Mode::Absolute, 6, false, Op::Jsr),
+    OpcodeInfo::new(0x21, "AND", Mode::IndexedIndirect, 6, false, Op::And),
+    OpcodeInfo::new(0x22, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x23, "*RLA", Mode::IndexedIndirect, 8, false, Op::Rla),
+    OpcodeInfo::new(0x24, "BIT", Mode::ZeroPage, 3, false, Op::Bit),
+    OpcodeInfo::new(0x25, "AND", Mode::ZeroPage, 3, false, Op::And),
+    OpcodeInfo::new(0x26, "ROL", Mode::ZeroPage, 5, false, Op::Rol),
+    OpcodeInfo::new(0x27, "*RLA", Mode::ZeroPage, 5, false, Op::Rla),
+    OpcodeInfo::new(0x28, "PLP", Mode::Implicit, 4, false, Op::Plp),
+    OpcodeInfo::new(0x29, "AND", Mode::Immediate, 2, false, Op::And),
+    OpcodeInfo::new(0x2A, "ROL", Mode::Accumulator, 2, false, Op::Rol),
+    OpcodeInfo::new(0x2B, "*ANC", Mode::Immediate, 2, false, Op::Anc),
+    OpcodeInfo::new(0x2C, "BIT", Mode::Absolute, 4, false, Op::Bit),
+    OpcodeInfo::new(0x2D, "AND", Mode::Absolute, 4, false, Op::And),
+    OpcodeInfo::new(0x2E, "ROL", Mode::Absolute, 6, false, Op::Rol),
+    OpcodeInfo::new(0x2F, "*RLA", Mode::Absolute, 6, false, Op::Rla),
+
+    // $30-$3F
+    OpcodeInfo::new(0x30, "BMI", Mode::Relative, 2, false, Op::Bmi),
+    OpcodeInfo::new(0x31, "AND", Mode::IndirectIndexed, 5, true, Op::And),
+    OpcodeInfo::new(0x32, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x33, "*RLA", Mode::IndirectIndexed, 8, false, Op::Rla),
+    OpcodeInfo::new(0x34, "*NOP", Mode::ZeroPageX, 4, false, Op::Nop),
+    OpcodeInfo::new(0x35, "AND", Mode::ZeroPageX, 4, false, Op::And),
+    OpcodeInfo::new(0x36, "ROL", Mode::ZeroPageX, 6, false, Op::Rol),
+    OpcodeInfo::new(0x37, "*RLA", Mode::ZeroPageX, 6, false, Op::Rla),
+    OpcodeInfo::new(0x38, "SEC", Mode::Implicit, 2, false, Op::Sec),
+    OpcodeInfo::new(0x39, "AND", Mode::AbsoluteY, 4, true, Op::And),
+    OpcodeInfo::new(0x3A, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x3B, "*RLA", Mode::AbsoluteY, 7, false, Op::Rla),
+    OpcodeInfo::new(0x3C, "*NOP", Mode::AbsoluteX, 4, true, Op::Nop),
+    OpcodeInfo::new(0x3D, "AND", Mode::AbsoluteX, 4, true, Op::And),
+    OpcodeInfo::new(0x3E, "ROL", Mode::AbsoluteX, 7, false, Op::Rol),
+    OpcodeInfo::new(0x3F, "*RLA", Mode::AbsoluteX, 7, false, Op::Rla),
+
+    // $40-$4F
+    OpcodeInfo::new(0x40, "RTI", Mode::Implicit, 6, false, Op::Rti),
+    OpcodeInfo::new(0x41, "EOR", Mode::IndexedIndirect, 6, false, Op::Eor),
+    OpcodeInfo::new(0x42, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x43, "*SRE", Mode::IndexedIndirect, 8, false, Op::Sre),
+    OpcodeInfo::new(0x44, "*NOP", Mode::ZeroPage, 3, false, Op::Nop),
+    OpcodeInfo::new(0x45, "EOR", Mode::ZeroPage, 3, false, Op::Eor),
+    OpcodeInfo::new(0x46, "LSR", Mode::ZeroPage, 5, false, Op::Lsr),
+    OpcodeInfo::new(0x47, "*SRE", Mode::ZeroPage, 5, false, Op::Sre),
+    OpcodeInfo::new(0x48, "PHA", Mode::Implicit, 3, false, Op::Pha),
+    OpcodeInfo::new(0x49, "EOR", Mode::Immediate, 2, false, Op::Eor),
+    OpcodeInfo::new(0x4A, "LSR", Mode::Accumulator, 2, false, Op::Lsr),
+    OpcodeInfo::new(0x4B, "*ALR", Mode::Immediate, 2, false, Op::Alr),
+    OpcodeInfo::new(0x4C, "JMP", Mode::Absolute, 3, false, Op::Jmp),
+    OpcodeInfo::new(0x4D, "EOR", Mode::Absolute, 4, false, Op::Eor),
+    OpcodeInfo::new(0x4E, "LSR", Mode::Absolute, 6, false, Op::Lsr),
+    OpcodeInfo::new(0x4F, "*SRE", Mode::Absolute, 6, false, Op::Sre),
+
+    // $50-$5F
+    OpcodeInfo::new(0x50, "BVC", Mode::Relative, 2, false, Op::Bvc),
+    OpcodeInfo::new(0x51, "EOR", Mode::IndirectIndexed, 5, true, Op::Eor),
+    OpcodeInfo::new(0x52, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x53, "*SRE", Mode::IndirectIndexed, 8, false, Op::Sre),
+    OpcodeInfo::new(0x54, "*NOP", Mode::ZeroPageX, 4, false, Op::Nop),
+    OpcodeInfo::new(0x55, "EOR", Mode::ZeroPageX, 4, false, Op::Eor),
+    OpcodeInfo::new(0x56, "LSR", Mode::ZeroPageX, 6, false, Op::Lsr),
+    OpcodeInfo::new(0x57, "*SRE", Mode::ZeroPageX, 6, false, Op::Sre),
+    OpcodeInfo::new(0x58, "CLI", Mode::Implicit, 2, false, Op::Cli),
+    OpcodeInfo::new(0x59, "EOR", Mode::AbsoluteY, 4, true, Op::Eor),
+    OpcodeInfo::new(0x5A, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x5B, "*SRE", Mode::AbsoluteY, 7, false, Op::Sre),
+    OpcodeInfo::new(0x5C, "*NOP", Mode::AbsoluteX, 4, true, Op::Nop),
+    OpcodeInfo::new(0x5D, "EOR", Mode::AbsoluteX, 4, true, Op::Eor),
+    OpcodeInfo::new(0x5E, "LSR", Mode::AbsoluteX, 7, false, Op::Lsr),
+    OpcodeInfo::new(0x5F, "*SRE", Mode::AbsoluteX, 7, false, Op::Sre),
+
+    // $60-$6F
+    OpcodeInfo::new(0x60, "RTS", Mode::Implicit, 6, false, Op::Rts),
+    OpcodeInfo::new(0x61, "ADC", Mode::IndexedIndirect, 6, false, Op::Adc),
+    OpcodeInfo::new(0x62, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x63, "*RRA", Mode::IndexedIndirect, 8, false, Op::Rra),
+    OpcodeInfo::new(0x64, "*NOP", Mode::ZeroPage, 3, false, Op::Nop),
+    OpcodeInfo::new(0x65, "ADC", Mode::ZeroPage, 3, false, Op::Adc),
+    OpcodeInfo::new(0x66, "ROR", Mode::ZeroPage, 5, false, Op::Ror),
+    OpcodeInfo::new(0x67, "*RRA", Mode::ZeroPage, 5, false, Op::Rra),
+    OpcodeInfo::new(0x68, "PLA", Mode::Implicit, 4, false, Op::Pla),
+    OpcodeInfo::new(0x69, "ADC", Mode::Immediate, 2, false, Op::Adc),
+    OpcodeInfo::new(0x6A, "ROR", Mode::Accumulator, 2, false, Op::Ror),
+    OpcodeInfo::new(0x6B, "*ARR", Mode::Immediate, 2, false, Op::Arr),
+    OpcodeInfo::new(0x6C, "JMP", Mode::Indirect, 5, false, Op::Jmp),
+    OpcodeInfo::new(0x6D, "ADC", Mode::Absolute, 4, false, Op::Adc),
+    OpcodeInfo::new(0x6E, "ROR", Mode::Absolute, 6, false, Op::Ror),
+    OpcodeInfo::new(0x6F, "*RRA", Mode::Absolute, 6, false, Op::Rra),
+
+    // $70-$7F
+    OpcodeInfo::new(0x70, "BVS", Mode::Relative, 2, false, Op::Bvs),
+    OpcodeInfo::new(0x71, "ADC", Mode::IndirectIndexed, 5, true, Op::Adc),
+    OpcodeInfo::new(0x72, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x73, "*RRA", Mode::IndirectIndexed, 8, false, Op::Rra),
+    OpcodeInfo::new(0x74, "*NOP", Mode::ZeroPageX, 4, false, Op::Nop),
+    OpcodeInfo::new(0x75, "ADC", Mode::ZeroPageX, 4, false, Op::Adc),
+    OpcodeInfo::new(0x76, "ROR", Mode::ZeroPageX, 6, false, Op::Ror),
+    OpcodeInfo::new(0x77, "*RRA", Mode::ZeroPageX, 6, false, Op::Rra),
+    OpcodeInfo::new(0x78, "SEI", Mode::Implicit, 2, false, Op::Sei),
+    OpcodeInfo::new(0x79, "ADC", Mode::AbsoluteY, 4, true, Op::Adc),
+    OpcodeInfo::new(0x7A, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x7B, "*RRA", Mode::AbsoluteY, 7, false, Op::Rra),
+    OpcodeInfo::new(0x7C, "*NOP", Mode::AbsoluteX, 4, true, Op::Nop),
+    OpcodeInfo::new(0x7D, "ADC", Mode::AbsoluteX, 4, true, Op::Adc),
+    OpcodeInfo::new(0x7E, "ROR", Mode::AbsoluteX, 7, false, Op::Ror),
+    OpcodeInfo::new(0x7F, "*RRA", Mode::AbsoluteX, 7, false, Op::Rra),
+
+    // $80-$8F
+    OpcodeInfo::new(0x80, "*NOP", Mode::Immediate, 2, false, Op::Nop),
+    OpcodeInfo::new(0x81, "STA", Mode::IndexedIndirect, 6, false, Op::Sta),
+    OpcodeInfo::new(0x82, "*NOP", Mode::Immediate, 2, false, Op::Nop),
+    OpcodeInfo::new(0x83, "*SAX", Mode::IndexedIndirect, 6, false, Op::Sax),
+    OpcodeInfo::new(0x84, "STY", Mode::ZeroPage, 3, false, Op::Sty),
+    OpcodeInfo::new(0x85, "STA", Mode::ZeroPage, 3, false, Op::Sta),
+    OpcodeInfo::new(0x86, "STX", Mode::ZeroPage, 3, false, Op::Stx),
+    OpcodeInfo::new(0x87, "*SAX", Mode::ZeroPage, 3, false, Op::Sax),
+    OpcodeInfo::new(0x88, "DEY", Mode::Implicit, 2, false, Op::Dey),
+    OpcodeInfo::new(0x89, "*NOP", Mode::Immediate, 2, false, Op::Nop),
+    OpcodeInfo::new(0x8A, "TXA", Mode::Implicit, 2, false, Op::Txa),
+    OpcodeInfo::new(0x8B, "*NOP", Mode::Immediate, 2, false, Op::Nop),
+    OpcodeInfo::new(0x8C, "STY", Mode::Absolute, 4, false, Op::Sty),
+    OpcodeInfo::new(0x8D, "STA", Mode::Absolute, 4, false, Op::Sta),
+    OpcodeInfo::new(0x8E, "STX", Mode::Absolute, 4, false, Op::Stx),
+    OpcodeInfo::new(0x8F, "*SAX", Mode::Absolute, 4, false, Op::Sax),
+
+    // $90-$9F
+    OpcodeInfo::new(0x90, "BCC", Mode::Relative, 2, false, Op::Bcc),
+    OpcodeInfo::new(0x91, "STA", Mode::IndirectIndexed, 6, false, Op::Sta),
+    OpcodeInfo::new(0x92, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0x93, "*NOP", Mode::IndirectIndexed, 2, false, Op::Nop),
+    OpcodeInfo::new(0x94, "STY", Mode::ZeroPageX, 4, false, Op::Sty),
+    OpcodeInfo::new(0x95, "STA", Mode::ZeroPageX, 4, false, Op::Sta),
+    OpcodeInfo::new(0x96, "STX", Mode::ZeroPageY, 4, false, Op::Stx),
+    OpcodeInfo::new(0x97, "*SAX", Mode::ZeroPageY, 4, false, Op::Sax),
+    OpcodeInfo::new(0x98, "TYA", Mode::Implicit, 2, false, Op::Tya),
+    OpcodeInfo::new(0x99, "STA", Mode::AbsoluteY, 5, false, Op::Sta),
+    OpcodeInfo::new(0x9A, "TXS", Mode::Implicit, 2, false, Op::Txs),
+    OpcodeInfo::new(0x9B, "*NOP", Mode::AbsoluteY, 2, false, Op::Nop),
+    OpcodeInfo::new(0x9C, "*NOP", Mode::AbsoluteX, 2, false, Op::Nop),
+    OpcodeInfo::new(0x9D, "STA", Mode::AbsoluteX, 5, false, Op::Sta),
+    OpcodeInfo::new(0x9E, "*NOP", Mode::AbsoluteY, 2, false, Op::Nop),
+    OpcodeInfo::new(0x9F, "*NOP", Mode::AbsoluteY, 2, false, Op::Nop),
+
+    // $A0-$AF
+    OpcodeInfo::new(0xA0, "LDY", Mode::Immediate, 2, false, Op::Ldy),
+    OpcodeInfo::new(0xA1, "LDA", Mode::IndexedIndirect, 6, false, Op::Lda),
+    OpcodeInfo::new(0xA2, "LDX", Mode::Immediate, 2, false, Op::Ldx),
+    OpcodeInfo::new(0xA3, "*LAX", Mode::IndexedIndirect, 6, false, Op::Lax),
+    OpcodeInfo::new(0xA4, "LDY", Mode::ZeroPage, 3, false, Op::Ldy),
+    OpcodeInfo::new(0xA5, "LDA", Mode::ZeroPage, 3, false, Op::Lda),
+    OpcodeInfo::new(0xA6, "LDX", Mode::ZeroPage, 3, false, Op::Ldx),
+    OpcodeInfo::new(0xA7, "*LAX", Mode::ZeroPage, 3, false, Op::Lax),
+    OpcodeInfo::new(0xA8, "TAY", Mode::Implicit, 2, false, Op::Tay),
+    OpcodeInfo::new(0xA9, "LDA", Mode::Immediate, 2, false, Op::Lda),
+    OpcodeInfo::new(0xAA, "TAX", Mode::Implicit, 2, false, Op::Tax),
+    OpcodeInfo::new(0xAB, "*NOP", Mode::Immediate, 2, false, Op::Nop),
+    OpcodeInfo::new(0xAC, "LDY", Mode::Absolute, 4, false, Op::Ldy),
+    OpcodeInfo::new(0xAD, "LDA", Mode::Absolute, 4, false, Op::Lda),
+    OpcodeInfo::new(0xAE, "LDX", Mode::Absolute, 4, false, Op::Ldx),
+    OpcodeInfo::new(0xAF, "*LAX", Mode::Absolute, 4, false, Op::Lax),
+
+    // $B0-$BF
+    OpcodeInfo::new(0xB0, "BCS", Mode::Relative, 2, false, Op::Bcs),
+    OpcodeInfo::new(0xB1, "LDA", Mode::IndirectIndexed, 5, true, Op::Lda),
+    OpcodeInfo::new(0xB2, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0xB3, "*LAX", Mode::IndirectIndexed, 5, true, Op::Lax),
+    OpcodeInfo::new(0xB4, "LDY", Mode::ZeroPageX, 4, false, Op::Ldy),
+    OpcodeInfo::new(0xB5, "LDA", Mode::ZeroPageX, 4, false, Op::Lda),
+    OpcodeInfo::new(0xB6, "LDX", Mode::ZeroPageY, 4, false, Op::Ldx),
+    OpcodeInfo::new(0xB7, "*LAX", Mode::ZeroPageY, 4, false, Op::Lax),
+    OpcodeInfo::new(0xB8, "CLV", Mode::Implicit, 2, false, Op::Clv),
+    OpcodeInfo::new(0xB9, "LDA", Mode::AbsoluteY, 4, true, Op::Lda),
+    OpcodeInfo::new(0xBA, "TSX", Mode::Implicit, 2, false, Op::Tsx),
+    OpcodeInfo::new(0xBB, "*NOP", Mode::AbsoluteY, 2, false, Op::Nop),
+    OpcodeInfo::new(0xBC, "LDY", Mode::AbsoluteX, 4, true, Op::Ldy),
+    OpcodeInfo::new(0xBD, "LDA", Mode::AbsoluteX, 4, true, Op::Lda),
+    OpcodeInfo::new(0xBE, "LDX", Mode::AbsoluteY, 4, true, Op::Ldx),
+    OpcodeInfo::new(0xBF, "*LAX", Mode::AbsoluteY, 4, true, Op::Lax),
+
+    // $C0-$CF
+    OpcodeInfo::new(0xC0, "CPY", Mode::Immediate, 2, false, Op::Cpy),
+    OpcodeInfo::new(0xC1, "CMP", Mode::IndexedIndirect, 6, false, Op::Cmp),
+    OpcodeInfo::new(0xC2, "*NOP", Mode::Immediate, 2, false, Op::Nop),
+    OpcodeInfo::new(0xC3, "*DCP", Mode::IndexedIndirect, 8, false, Op::Dcp),
+    OpcodeInfo::new(0xC4, "CPY", Mode::ZeroPage, 3, false, Op::Cpy),
+    OpcodeInfo::new(0xC5, "CMP", Mode::ZeroPage, 3, false, Op::Cmp),
+    OpcodeInfo::new(0xC6, "DEC", Mode::ZeroPage, 5, false, Op::Dec),
+    OpcodeInfo::new(0xC7, "*DCP", Mode::ZeroPage, 5, false, Op::Dcp),
+    OpcodeInfo::new(0xC8, "INY", Mode::Implicit, 2, false, Op::Iny),
+    OpcodeInfo::new(0xC9, "CMP", Mode::Immediate, 2, false, Op::Cmp),
+    OpcodeInfo::new(0xCA, "DEX", Mode::Implicit, 2, false, Op::Dex),
+    OpcodeInfo::new(0xCB, "*AXS", Mode::Immediate, 2, false, Op::Axs),
+    OpcodeInfo::new(0xCC, "CPY", Mode::Absolute, 4, false, Op::Cpy),
+    OpcodeInfo::new(0xCD, "CMP", Mode::Absolute, 4, false, Op::Cmp),
+    OpcodeInfo::new(0xCE, "DEC", Mode::Absolute, 6, false, Op::Dec),
+    OpcodeInfo::new(0xCF, "*DCP", Mode::Absolute, 6, false, Op::Dcp),
+
+    // $D0-$DF
+    OpcodeInfo::new(0xD0, "BNE", Mode::Relative, 2, false, Op::Bne),
+    OpcodeInfo::new(0xD1, "CMP", Mode::IndirectIndexed, 5, true, Op::Cmp),
+    OpcodeInfo::new(0xD2, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0xD3, "*DCP", Mode::IndirectIndexed, 8, false, Op::Dcp),
+    OpcodeInfo::new(0xD4, "*NOP", Mode::ZeroPageX, 4, false, Op::Nop),
+    OpcodeInfo::new(0xD5, "CMP", Mode::ZeroPageX, 4, false, Op::Cmp),
+    OpcodeInfo::new(0xD6, "DEC", Mode::ZeroPageX, 6, false, Op::Dec),
+    OpcodeInfo::new(0xD7, "*DCP", Mode::ZeroPageX, 6, false, Op::Dcp),
+    OpcodeInfo::new(0xD8, "CLD", Mode::Implicit, 2, false, Op::Cld),
+    OpcodeInfo::new(0xD9, "CMP", Mode::AbsoluteY, 4, true, Op::Cmp),
+    OpcodeInfo::new(0xDA, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0xDB, "*DCP", Mode::AbsoluteY, 7, false, Op::Dcp),
+    OpcodeInfo::new(0xDC, "*NOP", Mode::AbsoluteX, 4, true, Op::Nop),
+    OpcodeInfo::new(0xDD, "CMP", Mode::AbsoluteX, 4, true, Op::Cmp),
+    OpcodeInfo::new(0xDE, "DEC", Mode::AbsoluteX, 7, false, Op::Dec),
+    OpcodeInfo::new(0xDF, "*DCP", Mode::AbsoluteX, 7, false, Op::Dcp),
+
+    // $E0-$EF
+    OpcodeInfo::new(0xE0, "CPX", Mode::Immediate, 2, false, Op::Cpx),
+    OpcodeInfo::new(0xE1, "SBC", Mode::IndexedIndirect, 6, false, Op::Sbc),
+    OpcodeInfo::new(0xE2, "*NOP", Mode::Immediate, 2, false, Op::Nop),
+    OpcodeInfo::new(0xE3, "*ISB", Mode::IndexedIndirect, 8, false, Op::Isb),
+    OpcodeInfo::new(0xE4, "CPX", Mode::ZeroPage, 3, false, Op::Cpx),
+    OpcodeInfo::new(0xE5, "SBC", Mode::ZeroPage, 3, false, Op::Sbc),
+    OpcodeInfo::new(0xE6, "INC", Mode::ZeroPage, 5, false, Op::Inc),
+    OpcodeInfo::new(0xE7, "*ISB", Mode::ZeroPage, 5, false, Op::Isb),
+    OpcodeInfo::new(0xE8, "INX", Mode::Implicit, 2, false, Op::Inx),
+    OpcodeInfo::new(0xE9, "SBC", Mode::Immediate, 2, false, Op::Sbc),
+    OpcodeInfo::new(0xEA, "NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0xEB, "*SBC", Mode::Immediate, 2, false, Op::Sbc),
+    OpcodeInfo::new(0xEC, "CPX", Mode::Absolute, 4, false, Op::Cpx),
+    OpcodeInfo::new(0xED, "SBC", Mode::Absolute, 4, false, Op::Sbc),
+    OpcodeInfo::new(0xEE, "INC", Mode::Absolute, 6, false, Op::Inc),
+    OpcodeInfo::new(0xEF, "*ISB", Mode::Absolute, 6, false, Op::Isb),
+
+    // $F0-$FF
+    OpcodeInfo::new(0xF0, "BEQ", Mode::Relative, 2, false, Op::Beq),
+    OpcodeInfo::new(0xF1, "SBC", Mode::IndirectIndexed, 5, true, Op::Sbc),
+    OpcodeInfo::new(0xF2, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0xF3, "*ISB", Mode::IndirectIndexed, 8, false, Op::Isb),
+    OpcodeInfo::new(0xF4, "*NOP", Mode::ZeroPageX, 4, false, Op::Nop),
+    OpcodeInfo::new(0xF5, "SBC", Mode::ZeroPageX, 4, false, Op::Sbc),
+    OpcodeInfo::new(0xF6, "INC", Mode::ZeroPageX, 6, false, Op::Inc),
+    OpcodeInfo::new(0xF7, "*ISB", Mode::ZeroPageX, 6, false, Op::Isb),
+    OpcodeInfo::new(0xF8, "SED", Mode::Implicit, 2, false, Op::Sed),
+    OpcodeInfo::new(0xF9, "SBC", Mode::AbsoluteY, 4, true, Op::Sbc),
+    OpcodeInfo::new(0xFA, "*NOP", Mode::Implicit, 2, false, Op::Nop),
+    OpcodeInfo::new(0xFB, "*ISB", Mode::AbsoluteY, 7, false, Op::Isb),
+    OpcodeInfo::new(0xFC, "*NOP", Mode::AbsoluteX, 4, true, Op::Nop),
+    OpcodeInfo::new(0xFD, "SBC", Mode::AbsoluteX, 4, true, Op::Sbc),
+    OpcodeInfo::new(0xFE, "INC", Mode::AbsoluteX, 7, false, Op::Inc),
+    OpcodeInfo::new(0xFF, "*ISB", Mode::AbsoluteX, 7, false, Op::Isb),
+];
+
+#[derive(Debug, PartialEq)]
+pub struct InstructionInfo
+{
+    pub mnemonic: &'static str,
+    pub description: &'static str,
+    pub affected_flags: u8,
+    pub opcodes: Vec<OpcodeInfo>,
+}
+
+pub fn opcode_info(opcode: u8) -> OpcodeInfo { OPCODE_TABLE[opcode as usize] }
+
+pub fn instruction_info(mnemonic: &str) -> Option<InstructionInfo>
+{
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let (mnemonic, affected_flags, description) = INSTRUCTIONS.iter().find(|(name, _, _)| *name == mnemonic)?;
+    Some(InstructionInfo {
+        mnemonic,
+        description,
+        affected_flags: *affected_flags,
+        opcodes: (0..=0xFFu8).map(opcode_info).filter(|info| info.mnemonic == *mnemonic).collect(),
+    })
+}
@@ -0,0 +1,306 @@
+use super::Cpu;
+use super::DebugEvent;
+
+// Opt-in shortcut for the loops games spin in while they wait:
+//     loop: DEX | DEY | INX | INY        loop: LDA $2002 | BIT $2002
+//           BNE loop                           BPL loop
+// The number of iterations of a counter loop only depends on the counter register,
+// and a polling loop reads the same status until the PPU enters vblank, so whole
+// iterations can be replaced by their final register state and cycle count.
+//
+// The peripherals still run cycle by cycle under a skipped window, only the CPU
+// jumps ahead. An interrupt becoming pending inside the window would be taken late,
+// so the window stops before the next NMI or IRQ could assert and the rest of the
+// loop runs normally. Nothing is skipped while the DMC plays, its fetches stall the CPU.
+pub enum LoopAcceleration
+{
+    Off,
+    On,
+    // the loops still run instruction by instruction, and their outcome is checked
+    // against the shortcut once the CPU reaches the end of the skipped window
+    Verify,
+}
+
+// what Verify compares, once the naive run reaches the predicted cycle
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LoopState
+{
+    pub cycle: u64,
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+}
+
+pub struct LoopPrediction
+{
+    loop_pc: u16,
+    pub(super) state: LoopState,
+}
+
+enum CounterRegister
+{
+    X,
+    Y,
+}
+
+struct CounterLoop
+{
+    register: CounterRegister,
+    end_pc: u16,
+    iterations: u32,
+    // counter step and taken branch
+    iteration_cycles: u32,
+    // the last branch is not taken
+    cycles: u32,
+}
+
+impl Cpu
+{
+    pub fn set_loop_acceleration(&mut self, mode: LoopAcceleration) { self.loop_acceleration = mode }
+
+    fn find_counter_loop(&self) -> Option<CounterLoop>
+    {
+        let pc = self.registers.pc;
+        let opcode = self.peek(pc);
+        // BNE with an offset of -3 jumps back on the counter instruction
+        if self.peek(pc.wrapping_add(1)) != 0xD0 || self.peek(pc.wrapping_add(2)) != 0xFD {
+            return None
+        }
+        let (register, iterations) = match opcode {
+            0xCA => (CounterRegister::X, if self.registers.x == 0 {256} else {self.registers.x as u32}),
+            0x88 => (CounterRegister::Y, if self.registers.y == 0 {256} else {self.registers.y as u32}),
+            0xE8 => (CounterRegister::X, 256 - self.registers.x as u32),
+            0xC8 => (CounterRegister::Y, 256 - self.registers.y as u32),
+            _ => return None,
+        };
+
+        let end_pc = pc.wrapping_add(3);
+        let branch_taken_cycles = if end_pc & 0xFF00 == pc & 0xFF00 {1} else {2};
+        let step_cycles = Cpu::get_wait_cycles(opcode, false) + Cpu::get_wait_cycles(0xD0, false);
+        Some(CounterLoop {
+            register,
+            end_pc,
+            iterations,
+            iteration_cycles: step_cycles + branch_taken_cycles,
+            cycles: iterations * step_cycles + (iterations - 1) * branch_taken_cycles,
+        })
+    }
+
+    // LDA $2002 or BIT $2002, then BPL back to it. Returns the cycles of an iteration.
+    fn find_polling_loop(&self) -> Option<u32>
+    {
+        let pc = self.registers.pc;
+        let code = [0, 1, 2, 3, 4].map(|offset| self.peek(pc.wrapping_add(offset)));
+        if !matches!(code, [0xAD, 0x02, 0x20, 0x10, 0xFB] | [0x2C, 0x02, 0x20, 0x10, 0xFB]) {
+            return None
+        }
+        let branch_taken_cycles = if pc.wrapping_add(5) & 0xFF00 == pc & 0xFF00 {1} else {2};
+        Some(Cpu::get_wait_cycles(code[0], false) + Cpu::get_wait_cycles(0x10, false) + branch_taken_cycles)
+    }
+
+    // CPU cycles from the current one before the one where an interrupt could become
+    // pending. Every instruction boundary up to it is sure to see none. None when only a
+    // register write could raise one, which the loops never do.
+    fn interrupt_horizon(&mut self) -> Option<u32>
+    {
+        // DMC fetches stall the CPU, the boundaries are not where the loop puts them
+        if self.nmi_pending || (self.irq_line() && !self.registers.p.interrupt_disable) || self.apu.get_mut().dmc_active() {
+            return Some(0)
+        }
+        let ppu = self.ppu.get_mut();
+        // three dots a cycle, and one less when the odd frame skips a dot
+        let nmi = if ppu.nmi_enabled() {Some(ppu.dots_until_vblank().saturating_sub(1) / 3)} else {None};
+        let irq = if self.registers.p.interrupt_disable {
+            None
+        } else if self.cartridge.borrow().irq_enabled() {
+            // the mapper counters follow the PPU address bus
+            Some(0)
+        } else {
+            self.apu.get_mut().irq_horizon()
+        };
+        match (nmi, irq) {
+            (Some(nmi), Some(irq)) => Some(nmi.min(irq)),
+            (nmi, irq) => nmi.or(irq),
+        }
+    }
+
+    // Returns the number of cycles the skipped iterations would have taken, if the CPU
+    // sits on an acceleratable loop.
+    pub fn accelerate_loop(&mut self) -> Option<u32>
+    {
+        match self.loop_acceleration {
+            LoopAcceleration::Off => None,
+            // breakpoints and watchpoints have to see every iteration
+            LoopAcceleration::On if !self.breakpoints.is_empty() || !self.watchpoints.is_empty() => None,
+            LoopAcceleration::On => {
+                if let Some(counter_loop) = self.find_counter_loop() {
+                    return self.skip_counter_loop(&counter_loop)
+                }
+                let iteration_cycles = self.find_polling_loop()?;
+                self.skip_polling_loop(iteration_cycles)
+            },
+            LoopAcceleration::Verify => {
+                self.verify_loop_prediction();
+                if self.loop_prediction.is_none() {
+                    self.loop_prediction = self.predict_loop();
+                }
+                None
+            },
+        }
+    }
+
+    fn predict_loop(&mut self) -> Option<LoopPrediction>
+    {
+        if let Some(counter_loop) = self.find_counter_loop() {
+            return Some(self.predict_counter_loop(&counter_loop))
+        }
+        let iteration_cycles = self.find_polling_loop()?;
+        self.predict_polling_loop(iteration_cycles)
+    }
+
+    // the whole loop, or as many iterations as end before the interrupt horizon
+    fn skip_counter_loop(&mut self, counter_loop: &CounterLoop) -> Option<u32>
+    {
+        let iterations = match self.interrupt_horizon() {
+            Some(horizon) if horizon < counter_loop.cycles => horizon / counter_loop.iteration_cycles,
+            _ => counter_loop.iterations,
+        };
+        if iterations == 0 {
+            return None
+        }
+        if iterations == counter_loop.iterations {
+            self.apply_counter_loop(counter_loop);
+            return Some(counter_loop.cycles)
+        }
+        // the counter is not zero yet, PC stays on the loop
+        let increment = matches!(self.peek(self.registers.pc), 0xE8 | 0xC8);
+        let counter = match counter_loop.register {
+            CounterRegister::X => &mut self.registers.x,
+            CounterRegister::Y => &mut self.registers.y,
+        };
+        *counter = if increment {counter.wrapping_add(iterations as u8)} else {counter.wrapping_sub(iterations as u8)};
+        let counter = *counter;
+        self.registers.set_status_zero(false);
+        self.registers.set_status_negative(counter & 0x80 == 0x80);
+        Some(iterations * counter_loop.iteration_cycles)
+    }
+
+    fn apply_counter_loop(&mut self, counter_loop: &CounterLoop)
+    {
+        match counter_loop.register {
+            CounterRegister::X => self.registers.x = 0,
+            CounterRegister::Y => self.registers.y = 0,
+        }
+        self.registers.set_status_zero(true);
+        self.registers.set_status_negative(false);
+        self.registers.pc = counter_loop.end_pc;
+    }
+
+    // The iterations whose read lands before vblank are skipped, the last ones run
+    // normally to leave the loop on the right cycle.
+    fn polling_loop_iterations(&mut self, iteration_cycles: u32) -> Option<u32>
+    {
+        let ppu = self.ppu.get_mut();
+        if ppu.vblank() {
+            return None
+        }
+        // the read of the instruction starting on the cycle of the flag does not see it
+        let vblank = ppu.dots_until_vblank().saturating_sub(1) / 3;
+        let mut iterations = vblank / iteration_cycles + 1;
+        if let Some(horizon) = self.interrupt_horizon() {
+            iterations = iterations.min(horizon / iteration_cycles);
+        }
+        if iterations < 2 {
+            return None
+        }
+        Some(iterations)
+    }
+
+    // A, Z and V once the loop read a status with the vblank flag clear, N is clear
+    fn polling_read_result(&self, status: u8) -> (u8, bool, bool)
+    {
+        let registers = &self.registers;
+        if self.peek(registers.pc) == 0xAD {
+            (status, status == 0, registers.p.overflow)
+        } else {
+            (registers.a, registers.a & status == 0, status & 0x40 == 0x40)
+        }
+    }
+
+    // The status read is done once for real, the skipped ones would only repeat its
+    // effects.
+    fn skip_polling_loop(&mut self, iteration_cycles: u32) -> Option<u32>
+    {
+        let iterations = self.polling_loop_iterations(iteration_cycles)?;
+        self.instruction_pc = self.registers.pc;
+        let status = self.load(0x2002);
+        let (a, zero, overflow) = self.polling_read_result(status);
+        self.registers.a = a;
+        self.registers.set_status_zero(zero);
+        self.registers.set_status_overflow(overflow);
+        self.registers.set_status_negative(false);
+        Some(iterations * iteration_cycles)
+    }
+
+    // back on the loop once the skipped iterations are over
+    fn predict_polling_loop(&mut self, iteration_cycles: u32) -> Option<LoopPrediction>
+    {
+        let iterations = self.polling_loop_iterations(iteration_cycles)?;
+        let status = self.ppu.get_mut().peek_status();
+        let (a, zero, overflow) = self.polling_read_result(status);
+        Some(LoopPrediction {
+            loop_pc: self.registers.pc,
+            state: LoopState {
+                cycle: self.cycles + (iterations * iteration_cycles) as u64,
+                pc: self.registers.pc,
+                a,
+                x: self.registers.x,
+                y: self.registers.y,
+                status: (self.registers.p.get_byte() & 0b0011_1101) | (zero as u8) << 1 | (overflow as u8) << 6,
+            },
+        })
+    }
+
+    fn predict_counter_loop(&self, counter_loop: &CounterLoop) -> LoopPrediction
+    {
+        let (x, y) = match counter_loop.register {
+            CounterRegister::X => (0, self.registers.y),
+            CounterRegister::Y => (self.registers.x, 0),
+        };
+        LoopPrediction {
+            loop_pc: self.registers.pc,
+            state: LoopState {
+                cycle: self.cycles + counter_loop.cycles as u64,
+                pc: counter_loop.end_pc,
+                a: self.registers.a,
+                x,
+                y,
+                status: (self.registers.p.get_byte() & 0b0111_1101) | 0b0000_0010,
+            },
+        }
+    }
+
+    // Called on instruction boundaries, checks the naive run ended where the shortcut said
+    // it would. A mismatch is raised as a debug event, the run goes on.
+    fn verify_loop_prediction(&mut self)
+    {
+        let prediction = match &self.loop_prediction {
+            Some(prediction) if self.cycles >= prediction.state.cycle => prediction,
+            _ => return,
+        };
+        let actual = LoopState {
+            cycle: self.cycles,
+            pc: self.registers.pc,
+            a: self.registers.a,
+            x: self.registers.x,
+            y: self.registers.y,
+            status: self.registers.p.get_byte(),
+        };
+        if actual != prediction.state {
+            self.raise_debug_event(DebugEvent::LoopMismatch { pc: prediction.loop_pc, predicted: prediction.state, actual });
+        }
+        self.loop_prediction = None;
+    }
+}
@@ -3,36 +3,90 @@ mod instructions;
 mod address_space;
 mod registers;
 mod addressing_mode;
+mod loop_acceleration;
+mod debug;
+mod trace;
+mod disassembler;
+mod run;
+mod save_state;
+mod cycle_accurate;
+pub mod isa;
+#[cfg(test)]
+mod fuzz;
+#[cfg(test)]
+mod blargg;
+
+use std::cell::{
+    Cell,
+    Ref,
+    RefCell,
+};
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+use std::io;
+use std::rc::Rc;
 
 use super::utils::Clocked;
-use registers::Registers;
-use address_space::{
-    AddressSpace,
-    ZeroPageAddressSpace,
-    StackAddressSpace,
-    RamAddressSpace,
-    PpuRegistersAddressSpace,
-    ApuRegistersAddressSpace,
-    IORegistersAddressSpace,
-    NullAddressSpace,
+use crate::ppu::Ppu;
+use crate::apu::Apu;
+use crate::input::{
+    Buttons,
+    Controller,
 };
+use address_space::AddressSpace;
 use addressing_mode::{
     AddressingMode,
-    Implicit,
-    Accumulator,
-    Immediate,
-    Relative,
     MemoryAccess,
+    branch_target,
 };
-use cartridge::{
-    Mapper,
-    DummyMapper,
+use cartridge::DummyMapper;
+use loop_acceleration::LoopPrediction;
+use isa::{
+    AddressingModeKind,
+    Operation,
+    addressing_mode_kind,
+    opcode_info,
 };
+use trace::FlightRecorder;
+use cycle_accurate::MicroState;
 
-pub use cartridge::load_cartridge;
-use crate::cpu::address_space::CartridgeAddressSpace;
+pub use cartridge::{
+    Mapper,
+    Mirroring,
+    CartridgeError,
+    load_cartridge,
+    load_cartridge_from_bytes,
+    load_cartridge_from_reader,
+};
+pub use instructions::CpuVariant;
+pub use loop_acceleration::{
+    LoopAcceleration,
+    LoopState,
+};
+pub use trace::{
+    TraceSink,
+    decode_binary_trace,
+};
+pub use disassembler::disassemble;
+pub use debug::{
+    RomWritePolicy,
+    DebugEvent,
+    WatchKind,
+};
+pub use registers::{
+    Registers,
+    Status,
+};
+pub use run::{
+    StopCondition,
+    StopReason,
+    StepInfo,
+};
 
-enum Interrupts
+pub(crate) enum Interrupts
 {
     Break,
     Reset,
@@ -40,7 +94,18 @@ enum Interrupts
     NMI,
 }
 
-pub enum InstructionResult
+// devices that can pull the IRQ line low, the line is asserted while any of them does
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IrqSource
+{
+    Mapper,
+    FrameCounter,
+    Dmc,
+    // the expansion port, left to frontends and tests since nothing emulated drives it
+    Expansion,
+}
+
+pub(crate) enum InstructionResult
 {
     Ok,
     NOP,
@@ -51,78 +116,132 @@ pub enum InstructionResult
 pub struct Cpu
 {
     registers: Registers,
-    pub cycles: u64,
+    // address of the instruction being executed
+    instruction_pc: u16,
+    // the sequence being executed is an interrupt, not an instruction
+    servicing_interrupt: bool,
+    cycles: u64,
     wait_cycles: u32,
+    // NMI is edge triggered: the line driven by other components than the PPU, the
+    // level seen last, and whether a rising edge waits to be serviced
+    nmi_line: bool,
+    nmi_level: bool,
+    nmi_pending: bool,
+    // last cycle an NMI can still take over the vector fetch of a BRK or IRQ sequence
+    interrupt_hijack_end: Option<u64>,
+    // IRQ is level triggered, one bit per IrqSource asserting it
+    irq_sources: u8,
+    // page written to $4014, and the cycle the copy ends
+    oam_dma_page: Option<u8>,
+    oam_dma_end: u64,
+    // last value on the data bus, read back from addresses nothing drives
+    last_bus_value: Cell<u8>,
     // internal ram : size 0x0800
     zero_page_ram: [u8; 0x0100],
     stack: [u8; 0x0100],
     internal_ram: [u8; 0x0600],
-    // cartridge space
-    cartridge: Box<dyn Mapper>,
+    // cartridge space, shared with the PPU for CHR
+    cartridge: Rc<RefCell<Box<dyn Mapper>>>,
+    // reading some PPU registers changes its state, even through Cpu::load
+    ppu: RefCell<Ppu>,
+    // reading $4015 clears the frame IRQ flag
+    apu: RefCell<Apu>,
+    // read through $4016 and $4017, reading shifts them
+    controllers: RefCell<[Controller; 2]>,
+    // one bus access per clock instead of whole instructions, and its progress
+    cycle_accurate: bool,
+    micro: MicroState,
+    // whether D turns ADC and SBC decimal
+    variant: CpuVariant,
+    // speed hacks
+    loop_acceleration: LoopAcceleration,
+    loop_prediction: Option<LoopPrediction>,
+    // debugging
+    rom_write_policy: RomWritePolicy,
+    debug_events: RefCell<VecDeque<DebugEvent>>,
+    breakpoints: HashSet<u16>,
+    watchpoints: HashMap<u16, WatchKind>,
+    trace_sink: TraceSink,
+    trace_error: Option<io::Error>,
+    flight_recorder: Option<FlightRecorder>,
 }
 
 impl Cpu
 {
-    pub fn new_dummy() -> Cpu
+    fn with_cartridge(cartridge: Box<dyn Mapper>) -> Cpu
     {
+        let cartridge = Rc::new(RefCell::new(cartridge));
+        let mut ppu = Ppu::new(Rc::clone(&cartridge));
+        // the PPU kept running during the 7 cycles of the reset sequence
+        for _ in 0..7 * 3 {
+            ppu.clock();
+        }
         Cpu {
             registers: Registers::new(),
+            instruction_pc: 0,
+            servicing_interrupt: false,
             cycles: 7,
             wait_cycles: 0,
+            nmi_line: false,
+            nmi_level: false,
+            nmi_pending: false,
+            interrupt_hijack_end: None,
+            irq_sources: 0,
+            oam_dma_page: None,
+            oam_dma_end: 0,
+            last_bus_value: Cell::new(0),
             zero_page_ram: [0; 0x0100],
             stack: [0; 0x0100],
             internal_ram: [0; 0x0600],
-            cartridge: Box::new(DummyMapper::new()),
+            cartridge,
+            ppu: RefCell::new(ppu),
+            apu: RefCell::new(Apu::new()),
+            controllers: RefCell::new([Controller::new(), Controller::new()]),
+            cycle_accurate: false,
+            micro: MicroState::new(),
+            variant: CpuVariant::Ricoh2A03,
+            loop_acceleration: LoopAcceleration::Off,
+            loop_prediction: None,
+            rom_write_policy: RomWritePolicy::Ignore,
+            debug_events: RefCell::new(VecDeque::new()),
+            breakpoints: HashSet::new(),
+            watchpoints: HashMap::new(),
+            trace_sink: TraceSink::Off,
+            trace_error: None,
+            flight_recorder: None,
         }
     }
 
+    pub fn new_dummy() -> Cpu { Cpu::with_cartridge(Box::new(DummyMapper::new())) }
+
     pub fn new(cartridge: Box<dyn Mapper>) -> Cpu
     {
-        let mut cpu = Cpu {
-            registers: Registers::new(),
-            cycles: 7,
-            wait_cycles: 0,
-            zero_page_ram: [0; 0x0100],
-            stack: [0; 0x0100],
-            internal_ram: [0; 0x0600],
-            cartridge,
-        };
-        cpu.registers.pc = cpu.load(0xFFFE) as u16 | (cpu.load(0xFFFF) as u16) << 8;
+        let mut cpu = Cpu::with_cartridge(cartridge);
+        cpu.registers.pc = cpu.load(0xFFFC) as u16 | (cpu.load(0xFFFD) as u16) << 8;
         cpu
     }
 
-    fn corresponding_address_space(&self, address: u16) -> Box<dyn AddressSpace>
-    {
-        let first_nibble = (address >> 8) as u8;
-        let second_nibble = address as u8;
-
-        match (first_nibble, second_nibble) {
-            (x, index) if x <= 0x1F => match (x % 0x08, index) {
-                (0x00, index) => Box::new(ZeroPageAddressSpace::new(index)),
-                (0x01, index) => Box::new(StackAddressSpace::new(index)),
-                (0x02..=0x07, _) => Box::new(RamAddressSpace::new(address % 0x0800 - 0x0200)),
-                (_, _) => Box::new(NullAddressSpace::new()), // should never happen
-            },
-            (x, y) if x <= 0x40 => Box::new(PpuRegistersAddressSpace::new((y % 0x08) as u16)),
-            (0x40, x) if x <= 0x13 => Box::new(ApuRegistersAddressSpace::new(x as u16)),
-            (0x40, x) if x <= 0x17 => Box::new(IORegistersAddressSpace::new(x as u16)),
-            (0x40, x) if x <= 0x1F => Box::new(NullAddressSpace::new()), // unused APU and IO functionnalities
-            _ => Box::new(CartridgeAddressSpace::new(address))
-        }
-    }
+    // the PPU is read through its registers, this is for frontends and tests
+    pub fn ppu(&self) -> Ref<'_, Ppu> { self.ppu.borrow() }
 
-    pub fn push(&mut self, data: u8)
+    // for frontends to plug their audio output
+    pub fn apu_mut(&mut self) -> &mut Apu { self.apu.get_mut() }
+
+    // port 0 is read at $4016, port 1 at $4017
+    pub fn set_controller_state(&mut self, port: usize, buttons: Buttons) { self.controllers.get_mut()[port].set_buttons(buttons) }
+
+    pub(crate) fn push(&mut self, data: u8)
     {
         self.write(0x0100 | self.registers.stack_pointer as u16, data);
         self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(1);
     }
 
-    pub fn top(&self) -> u8
+    pub(crate) fn top(&self) -> u8
     {
         self.load(0x0100 | self.registers.stack_pointer.wrapping_add(1) as u16)
     }
 
-    pub fn pop(&mut self) -> u8
+    pub(crate) fn pop(&mut self) -> u8
     {
         let data = self.top();
         self.registers.stack_pointer = self.registers.stack_pointer.wrapping_add(1);
@@ -131,343 +250,316 @@ impl Cpu
 
     pub fn load(&self, address: u16) -> u8
     {
-        let address_space = self.corresponding_address_space(address);
-
-        address_space.read(&self)
+        let data = AddressSpace::decode(address).read(self);
+        if !self.watchpoints.is_empty() {
+            self.watch(address, data, WatchKind::Read);
+        }
+        data
     }
 
+    // reads without touching the PPU, APU or controllers
+    pub fn peek(&self, address: u16) -> u8 { AddressSpace::decode(address).peek(self) }
+
     pub fn write(&mut self, address: u16, data: u8)
     {
-        let address_space = self.corresponding_address_space(address);
-
-        address_space.write(self, data);
+        AddressSpace::decode(address).write(self, data);
+        if !self.watchpoints.is_empty() {
+            self.watch(address, data, WatchKind::Write);
+        }
     }
 
     fn load_byte_at_pc(&self) -> u8 { self.load(self.registers.pc) }
 
     fn increment_pc(&mut self) { self.registers.pc += 1 }
 
-    pub fn fetch(&mut self) -> u8
+    pub(crate) fn fetch(&mut self) -> u8
     {
         let data = self.load_byte_at_pc();
         self.increment_pc();
         data
     }
 
-    pub fn interrupt(&mut self, kind: Interrupts)
+    pub(crate) fn interrupt(&mut self, kind: Interrupts)
     {
-        let (vector, b_flag, side_effect_flags) = match kind {
-            Interrupts::Break => (0xFFFEu16, 0b0011_0000u8, 0b0000_0100u8),
-            Interrupts::Reset => (0xFFFCu16, 0b0000_0000u8, 0b0000_0000u8),
-            Interrupts::IRQ => (0xFFFEu16, 0b0010_0000u8, 0b0000_0100u8),
-            Interrupts::NMI => (0xFFFAu16, 0b0010_0000u8, 0b0000_0100u8),
+        let (vector, b_flag) = match kind {
+            Interrupts::Break => (0xFFFEu16, 0b0011_0000u8),
+            Interrupts::Reset => (0xFFFCu16, 0b0000_0000u8),
+            Interrupts::IRQ => (0xFFFEu16, 0b0010_0000u8),
+            Interrupts::NMI => (0xFFFAu16, 0b0010_0000u8),
         };
 
-        let address = self.registers.pc;
-        self.push((address >> 8) as u8);
-        self.push(address as u8);
-        self.push(self.registers.p.get_byte() | b_flag);
+        match kind {
+            // the writes are turned into reads, only the stack pointer moves
+            Interrupts::Reset => self.registers.stack_pointer = self.registers.stack_pointer.wrapping_sub(3),
+            _ => {
+                let address = self.registers.pc;
+                self.push((address >> 8) as u8);
+                self.push(address as u8);
+                self.push(self.registers.p.get_byte() | b_flag);
+            },
+        }
 
         self.registers.pc = self.load(vector) as u16 | (self.load(vector + 1) as u16) << 8;
-        self.registers.p.set_byte(side_effect_flags);
+        // the other flags are left as they were
+        self.registers.p.interrupt_disable = true;
+        // the vector is fetched on the last two of the 7 cycles, an NMI detected during
+        // the first four redirects the sequence to its own vector
+        self.interrupt_hijack_end = match kind {
+            Interrupts::Break | Interrupts::IRQ => Some(self.cycles + 4),
+            _ => None,
+        };
+    }
+
+    // the pushed status keeps the B flag of a hijacked BRK
+    fn hijack_interrupt(&mut self)
+    {
+        match self.interrupt_hijack_end {
+            Some(end) if self.cycles > end => self.interrupt_hijack_end = None,
+            Some(_) if self.nmi_pending => {
+                self.nmi_pending = false;
+                self.interrupt_hijack_end = None;
+                self.registers.pc = self.load(0xFFFA) as u16 | (self.load(0xFFFB) as u16) << 8;
+            },
+            _ => {},
+        }
     }
 
     pub fn set_pc(&mut self, address: u16) { self.registers.pc = address }
 
-    fn get_addressing_mode(&mut self, opcode: u8) -> Box<dyn AddressingMode>
+    // registers, for frontends, debuggers and tests outside the crate
+    pub fn a(&self) -> u8 { self.registers.a }
+    pub fn x(&self) -> u8 { self.registers.x }
+    pub fn y(&self) -> u8 { self.registers.y }
+    pub fn pc(&self) -> u16 { self.registers.pc }
+    pub fn stack_pointer(&self) -> u8 { self.registers.stack_pointer }
+    pub fn registers(&self) -> &Registers { &self.registers }
+    pub fn set_a(&mut self, value: u8) { self.registers.a = value }
+    pub fn set_x(&mut self, value: u8) { self.registers.x = value }
+    pub fn set_y(&mut self, value: u8) { self.registers.y = value }
+    pub fn set_stack_pointer(&mut self, value: u8) { self.registers.stack_pointer = value }
+
+    // CPU cycles since power on
+    pub fn cycles(&self) -> u64 { self.cycles }
+
+    // NV1-DIZC, as PHP pushes it without B, and as PLP pulls it
+    pub fn status_byte(&self) -> u8 { self.registers.p.get_byte() }
+    pub fn set_status_byte(&mut self, status: u8) { self.registers.p.set_byte(status) }
+
+    pub fn carry(&self) -> bool { self.registers.p.carry }
+    pub fn zero(&self) -> bool { self.registers.p.zero }
+    pub fn interrupt_disable(&self) -> bool { self.registers.p.interrupt_disable }
+    pub fn decimal(&self) -> bool { self.registers.p.decimal }
+    pub fn overflow(&self) -> bool { self.registers.p.overflow }
+    pub fn negative(&self) -> bool { self.registers.p.negative }
+    pub fn set_carry(&mut self, value: bool) { self.registers.p.carry = value }
+    pub fn set_zero(&mut self, value: bool) { self.registers.p.zero = value }
+    pub fn set_interrupt_disable(&mut self, value: bool) { self.registers.p.interrupt_disable = value }
+    pub fn set_decimal(&mut self, value: bool) { self.registers.p.decimal = value }
+    pub fn set_overflow(&mut self, value: bool) { self.registers.p.overflow = value }
+    pub fn set_negative(&mut self, value: bool) { self.registers.p.negative = value }
+
+    // a copy of the battery backed RAM of the cartridge, for the .sav file
+    pub fn prg_ram(&self) -> Option<Vec<u8>> { self.cartridge.borrow().prg_ram().map(|ram| ram.to_vec()) }
+
+    pub fn load_prg_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> { self.cartridge.borrow_mut().load_prg_ram(data) }
+
+    // the interrupt is taken on the next instruction boundary, whatever the I flag
+    pub fn set_nmi_line(&mut self, level: bool)
+    {
+        self.nmi_line = level;
+        self.poll_nmi();
+    }
+
+    // checked on every instruction boundary, masked by the I flag
+    pub fn set_irq_line(&mut self, source: IrqSource, level: bool)
+    {
+        let bit = 1 << source as u8;
+        if level {
+            self.irq_sources |= bit;
+        } else {
+            self.irq_sources &= !bit;
+        }
+    }
+
+    pub fn irq_line(&self) -> bool { self.irq_sources != 0 }
+
+    // The DMC reads its sample byte while the CPU is halted, 4 cycles in general. The
+    // real count depends on the cycle it lands on: 3 on a write cycle, and about 2
+    // during an OAM DMA, which only loses the cycles to realign its reads. The writes of
+    // an instruction are not tracked, so everything outside OAM DMA costs 4.
+    fn dmc_fetch(&mut self)
+    {
+        let address = match self.apu.get_mut().dmc_fetch_address() {
+            Some(address) => address,
+            None => return,
+        };
+        let data = self.load(address);
+        self.apu.get_mut().dmc_fill(data);
+        self.wait_cycles += if self.cycles < self.oam_dma_end {2} else {4};
+    }
+
+    // the 256 bytes of the page go through OAMDATA, from the current OAMADDR
+    fn oam_dma(&mut self, page: u8)
+    {
+        for offset in 0..=0xFF {
+            let data = self.load((page as u16) << 8 | offset);
+            self.ppu.get_mut().write_register(4, data);
+        }
+    }
+
+    // the interrupt to service on this instruction boundary, NMI first
+    fn take_interrupt(&mut self) -> Option<Interrupts>
     {
-        match opcode {
-            //+00
-            0x20 => Box::new(MemoryAccess::new_absolute(self)),
-            0x80 | 0xA0 | 0xC0 | 0xE0 => Box::new(Immediate::new(self)),
-            //+01
-            x if x & 0x1F == 0x01 => Box::new(MemoryAccess::new_indexed_indirect(self, self.registers.x)),
-            //+02
-            0x82 | 0xA2 | 0xC2 | 0xE2 => Box::new(Immediate::new(self)),
-            //+03
-            x if x & 0x1F == 0x03 => Box::new(MemoryAccess::new_indexed_zero_page(self, self.registers.x)),
-            //+04
-            x if x & 0x1F == 0x04 => Box::new(MemoryAccess::new_zero_page(self)),
-            //+05
-            x if x & 0x1F == 0x05 => Box::new(MemoryAccess::new_zero_page(self)),
-            //+06
-            x if x & 0x1F == 0x06 => Box::new(MemoryAccess::new_zero_page(self)),
-            //+07
-            x if x & 0x1F == 0x07 => Box::new(MemoryAccess::new_zero_page(self)),
-            //+08
-            //+09
-            x if x & 0x1F == 0x09 => Box::new(Immediate::new(self)),
-            //+0A
-            0x0A | 0x2A | 0x4A | 0x6A => Box::new(Accumulator{}),
-            //+0B
-            //+0C
-            0x6C => Box::new(MemoryAccess::new_indirect(self)),
-            x if x & 0x1F == 0x0C => Box::new(MemoryAccess::new_absolute(self)),
-            //+0D
-            x if x & 0x1F == 0x0D => Box::new(MemoryAccess::new_absolute(self)),
-            //+0E
-            x if x & 0x1F == 0x0E => Box::new(MemoryAccess::new_absolute(self)),
-            //+0F
-            x if x & 0x1F == 0x0F => Box::new(MemoryAccess::new_absolute(self)),
-            //+10
-            x if x & 0x1F == 0x10 => Box::new(Relative::new(self)),
-            //+11
-            x if x & 0x1F == 0x11 => Box::new(MemoryAccess::new_indirect_indexed(self, self.registers.y)),
-            //+12
-            //+13
-            x if x & 0x1F == 0x13 => Box::new(MemoryAccess::new_indirect_indexed(self, self.registers.y)),
-            //+14
-            x if x & 0x1F == 0x14 => Box::new(MemoryAccess::new_indexed_zero_page(self, self.registers.x)),
-            //+15
-            x if x & 0x1F == 0x15 => Box::new(MemoryAccess::new_indexed_zero_page(self, self.registers.x)),
-            //+16
-            0x96 | 0xB6 => Box::new(MemoryAccess::new_indexed_zero_page(self, self.registers.y)),
-            x if x & 0x1F == 0x16 => Box::new(MemoryAccess::new_indexed_zero_page(self, self.registers.x)),
-            //+17
-            0x97 | 0xB7 => Box::new(MemoryAccess::new_indexed_zero_page(self, self.registers.y)),
-            x if x & 0x1F == 0x17 => Box::new(MemoryAccess::new_indexed_zero_page(self, self.registers.x)),
-            //+18
-            //+19
-            x if x & 0x1F == 0x19 => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.y)),
-            //+1A
-            //+1B
-            x if x & 0x1F == 0x1B => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.y)),
-            //+1C
-            x if x & 0x1F == 0x1C => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.x)),
-            //+1D
-            x if x & 0x1F == 0x1D => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.x)),
-            //+1E
-            0x9E | 0xBE => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.y)),
-            x if x & 0x1F == 0x1E => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.x)),
-            //+1F
-            0x9F | 0xBF => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.y)),
-            x if x & 0x1F == 0x1F => Box::new(MemoryAccess::new_indexed_absolute(self, self.registers.x)),
-            _ => Box::new(Implicit{})
+        let kind = if self.nmi_pending {
+            self.nmi_pending = false;
+            Some(Interrupts::NMI)
+        } else if self.irq_line() && !self.registers.p.interrupt_disable {
+            Some(Interrupts::IRQ)
+        } else {
+            None
+        };
+        self.servicing_interrupt = kind.is_some();
+        if kind.is_some() {
+            // a loop being verified does not end where predicted anymore
+            self.loop_prediction = None;
         }
+        kind
     }
 
-    fn get_wait_cycles(&mut self, opcode: u8, page_boundary_crossed: bool) -> u32
+    fn poll_nmi(&mut self)
     {
-        match opcode {
-            // stack operation
-            0x00 => 7,
-            0x40 | 0x60 | 0x20 => 6,
-            0x08 | 0x48 => 3,
-            0x28 | 0x68 => 4,
-            // absolute
-            //// Read
-            0x4C => 3,
-            0xAD | 0xAE | 0xAC | 0x4D | 0x2D | 0x0D | 0x6D | 0xED | 0xCD | 0x2C | 0x0C | 0xCC | 0xEC => 4,
-            //// RMW
-            0x0E | 0x4E | 0x2E | 0x6E | 0xEE | 0xCE => 6,
-            //// write
-            0x8D | 0x8E | 0x8C => 4,
-            // zero page
-            //// Read
-            0xA5 | 0xA6 | 0xA4 | 0x45 | 0x25 | 0x05 | 0x65 | 0xE5 | 0xC5 | 0x24 | 0x04 | 0x44 | 0x64 | 0xC4 | 0xE4 => 3,
-            //// RMW
-            0x06 | 0x46 | 0x26 | 0x66 | 0xE6 | 0xC6 => 5,
-            //// write
-            0x85 | 0x86 | 0x84 => 3,
-            // indexed zero page
-            //// Read
-            0xB5 | 0xB6 | 0xB4 | 0x55 | 0x35 | 0x15 | 0x75 | 0xF5 | 0xD5 | 0x34 | 0x14 | 0x54 | 0x74 | 0xD4 | 0xF4 => 4,
-            //// RMW
-            0x16 | 0x56 | 0x36 | 0x76 | 0xF6 | 0xD6 => 6,
-            //// write
-            0x95 | 0x96 | 0x94 => 4,
-            // indexed absolute
-            //// Read
-            0xBC | 0x19 | 0x1D | 0x39 | 0x3D | 0x59 | 0x5D | 0x79 | 0x7D | 0xB9 | 0xBD | 0xD9 | 0xDD | 0xF9 | 0xFD | 0xBE | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => if page_boundary_crossed {5} else {4},
-            //// RMW
-            0x1E | 0x3E | 0x5E | 0x7E | 0xDE | 0xFE => 7,
-            //// write
-            0x99 | 0x9D => 5,
-            // Relative
-            0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xB0 | 0xD0 | 0xF0 => 2, // todo, add cycle if branch is taken and if page boundary is crossed in branched instruction
-            // indexed indirect
-            //// Read
-            0x01 | 0x21 | 0x41 | 0x61 | 0xA1 | 0xC1 | 0xE1 => 6,
-            //// write
-            0x81 => 6,
-            // indirect indexed
-            //// Read
-            0x11 | 0x31 | 0x51 | 0x71 | 0xB1 | 0xD1 | 0xF1 => if page_boundary_crossed {6} else {5},
-            //// write
-            0x91 => 6,
-            // indirect
-            0x6C => 5,
-            // implicit, accumulator, immediate
-            _ => 2
+        let level = self.nmi_line || self.ppu.get_mut().nmi_output();
+        if level && !self.nmi_level {
+            self.nmi_pending = true;
         }
+        self.nmi_level = level;
     }
 
-    fn get_instruction_name(&self, opcode: u8) -> &str
+    fn get_addressing_mode(&mut self, opcode: u8) -> AddressingMode
     {
-        match opcode {
-            // Control operations
-            //// Stack operation
-            0x00 => "BRK",
-            0x40 => "RTI",
-            0x60 => "RTS",
-            0x08 => "PHP",
-            0x28 => "PLP",
-            0x48 => "PHA",
-            0x68 => "PLA",
-            0x20 => "JSR",
-            //// Jump operation
-            0x4C | 0x6C => "JMP",
-            //// Branch operation
-            0x10 => "BPL",
-            0x30 => "BMI",
-            0x50 => "BVC",
-            0x70 => "BVS",
-            0x90 => "BCC",
-            0xB0 => "BCS",
-            0xD0 => "BNE",
-            0xF0 => "BEQ",
-            ////
-            0x88 => "DEY",
-            0xA8 => "TAY",
-            0xC8 => "INY",
-            0xE8 => "INX",
-            0x18 => "CLC",
-            0x38 => "SEC",
-            0x58 => "CLI",
-            0x78 => "SEI",
-            0x98 => "TYA",
-            0xB8 => "CLV",
-            0xD8 => "CLD",
-            0xF8 => "SED",
-            0x80 | 0x04 | 0x44 | 0x64 | 0x0C | 0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => "NOP",
-            0x9C => "NOP", // undocumented instructions
-            x if x & 0xE0 == 0x20 && x & 0x03 == 0x00 => "BIT",
-            x if x & 0xE0 == 0x80 && x & 0x03 == 0x00 => "STY",
-            x if x & 0xE0 == 0xA0 && x & 0x03 == 0x00 => "LDY",
-            x if x & 0xE0 == 0xC0 && x & 0x03 == 0x00 => "CPY",
-            x if x & 0xE0 == 0xE0 && x & 0x03 == 0x00 => "CPx",
-            // ALU operations
-            0x89 => "NOP",
-            x if x & 0xE0 == 0x00 && x & 0x03 == 0x01 => "ORA",
-            x if x & 0xE0 == 0x20 && x & 0x03 == 0x01 => "AND",
-            x if x & 0xE0 == 0x40 && x & 0x03 == 0x01 => "EOR",
-            x if x & 0xE0 == 0x60 && x & 0x03 == 0x01 => "ADC",
-            x if x & 0xE0 == 0x80 && x & 0x03 == 0x01 => "STA",
-            x if x & 0xE0 == 0xA0 && x & 0x03 == 0x01 => "LDA",
-            x if x & 0xE0 == 0xC0 && x & 0x03 == 0x01 => "CMP",
-            x if x & 0xE0 == 0xE0 && x & 0x03 == 0x01 => "SBC",
-            // RMW operations
-            0x82 | 0xC2 | 0xE2 | 0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => "NOP",
-            0x02 | 0x22 | 0x42 | 0x62 | 0x12 | 0x31 | 0x52 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 | 0x9E  => "NOP", // undocumented instructions
-            x if x & 0xE0 == 0x00 && x & 0x03 == 0x02 => "ASL",
-            x if x & 0xE0 == 0x20 && x & 0x03 == 0x02 => "ROL",
-            x if x & 0xE0 == 0x40 && x & 0x03 == 0x02 => "LSR",
-            x if x & 0xE0 == 0x60 && x & 0x03 == 0x02 => "ROR",
-            x if x & 0xE0 == 0x80 && x & 0x03 == 0x02 => "STX",
-            x if x & 0xE0 == 0xA0 && x & 0x03 == 0x02 => "LDX",
-            x if x & 0xE0 == 0xC0 && x & 0x03 == 0x02 => "DEC",
-            x if x & 0xE0 == 0xE0 && x & 0x03 == 0x02 => "INC",
-            _ => "NOP", // undocumented instructions
+        match addressing_mode_kind(opcode) {
+            AddressingModeKind::Implicit => AddressingMode::Implicit,
+            AddressingModeKind::Accumulator => AddressingMode::Accumulator,
+            AddressingModeKind::Immediate => AddressingMode::immediate(self),
+            AddressingModeKind::ZeroPage => AddressingMode::Memory(MemoryAccess::new_zero_page(self)),
+            AddressingModeKind::ZeroPageX => AddressingMode::Memory(MemoryAccess::new_indexed_zero_page(self, self.registers.x)),
+            AddressingModeKind::ZeroPageY => AddressingMode::Memory(MemoryAccess::new_indexed_zero_page(self, self.registers.y)),
+            AddressingModeKind::Absolute => AddressingMode::Memory(MemoryAccess::new_absolute(self)),
+            AddressingModeKind::AbsoluteX => AddressingMode::Memory(MemoryAccess::new_indexed_absolute(self, self.registers.x)),
+            AddressingModeKind::AbsoluteY => AddressingMode::Memory(MemoryAccess::new_indexed_absolute(self, self.registers.y)),
+            AddressingModeKind::Indirect => AddressingMode::Memory(MemoryAccess::new_indirect(self)),
+            AddressingModeKind::IndexedIndirect => AddressingMode::Memory(MemoryAccess::new_indexed_indirect(self, self.registers.x)),
+            AddressingModeKind::IndirectIndexed => AddressingMode::Memory(MemoryAccess::new_indirect_indexed(self, self.registers.y)),
+            AddressingModeKind::Relative => AddressingMode::relative(self),
         }
     }
 
+    fn get_wait_cycles(opcode: u8, page_boundary_crossed: bool) -> u32
+    {
+        let info = opcode_info(opcode);
+        info.cycles + (info.page_cross_penalty && page_boundary_crossed) as u32
+    }
+
+    pub fn get_instruction_name(opcode: u8) -> &'static str { opcode_info(opcode).mnemonic }
+
 
     // returns the number of cycle to wait
     fn execute_instruction(&mut self, opcode: u8) -> u32
     {
         let addressing_mode = self.get_addressing_mode(opcode);
-        let wait_cycles = self.get_wait_cycles(opcode, addressing_mode.page_boundary_crossed());
-        let instruction_result = match opcode {
-            // Control operations
-            0x00 => self.brk(&*addressing_mode),
-            0x40 => self.rti(&*addressing_mode),
-            0x60 => self.rts(&*addressing_mode),
-            //// Stack operation
-            0x08 => self.php(&*addressing_mode),
-            0x28 => self.plp(&*addressing_mode),
-            0x48 => self.pha(&*addressing_mode),
-            0x68 => self.pla(&*addressing_mode),
-            //// Jump operation
-            0x4C | 0x6C => self.jmp(&*addressing_mode),
-            0x20 => self.jsr(&*addressing_mode),
-            //// Branch operation
-            0x10 => self.bpl(&*addressing_mode),
-            0x30 => self.bmi(&*addressing_mode),
-            0x50 => self.bvc(&*addressing_mode),
-            0x70 => self.bvs(&*addressing_mode),
-            0x90 => self.bcc(&*addressing_mode),
-            0xB0 => self.bcs(&*addressing_mode),
-            0xD0 => self.bne(&*addressing_mode),
-            0xF0 => self.beq(&*addressing_mode),
-            ////
-            0x18 => self.clc(&*addressing_mode),
-            0x38 => self.sec(&*addressing_mode),
-            0x58 => self.cli(&*addressing_mode),
-            0x78 => self.sei(&*addressing_mode),
-            0x88 => self.dey(&*addressing_mode),
-            0x98 => self.tya(&*addressing_mode),
-            0xA8 => self.tay(&*addressing_mode),
-            0xB8 => self.clv(&*addressing_mode),
-            0xC8 => self.iny(&*addressing_mode),
-            0xCA => self.dex(&*addressing_mode),
-            0xD8 => self.cld(&*addressing_mode),
-            0xE8 => self.inx(&*addressing_mode),
-            0xF8 => self.sed(&*addressing_mode),
-            0x80 | 0x04 | 0x44 | 0x64 | 0x0C | 0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => InstructionResult::NOP,
-            0x9C => InstructionResult::NOP, // undocumented instructions
-            x if x & 0xE0 == 0x20 && x & 0x03 == 0x00 => self.bit(&*addressing_mode),
-            x if x & 0xE0 == 0x80 && x & 0x03 == 0x00 => self.sty(&*addressing_mode),
-            x if x & 0xE0 == 0xA0 && x & 0x03 == 0x00 => self.ldy(&*addressing_mode),
-            x if x & 0xE0 == 0xC0 && x & 0x03 == 0x00 => self.cpy(&*addressing_mode),
-            x if x & 0xE0 == 0xE0 && x & 0x03 == 0x00 => self.cpx(&*addressing_mode),
-            // ALU operations
-            0x89 => InstructionResult::NOP,
-            x if x & 0xE0 == 0x00 && x & 0x03 == 0x01 => self.ora(&*addressing_mode),
-            x if x & 0xE0 == 0x20 && x & 0x03 == 0x01 => self.and(&*addressing_mode),
-            x if x & 0xE0 == 0x40 && x & 0x03 == 0x01 => self.eor(&*addressing_mode),
-            x if x & 0xE0 == 0x60 && x & 0x03 == 0x01 => self.adc(&*addressing_mode),
-            x if x & 0xE0 == 0x80 && x & 0x03 == 0x01 => self.sta(&*addressing_mode),
-            x if x & 0xE0 == 0xA0 && x & 0x03 == 0x01 => self.lda(&*addressing_mode),
-            x if x & 0xE0 == 0xC0 && x & 0x03 == 0x01 => self.cmp(&*addressing_mode),
-            x if x & 0xE0 == 0xE0 && x & 0x03 == 0x01 => self.sbc(&*addressing_mode),
-            // RMW operations
-            0x8A => self.txa(&*addressing_mode),
-            0xAA => self.tax(&*addressing_mode),
-            0x9A => self.txs(&*addressing_mode),
-            0xBA => self.tsx(&*addressing_mode),
-            0x82 | 0xC2 | 0xE2 | 0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => InstructionResult::NOP,
-            0x02 | 0x22 | 0x42 | 0x62 | 0x12 | 0x31 | 0x52 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 | 0x9E  => InstructionResult::NOP, // undocumented instructions
-            x if x & 0xE0 == 0x00 && x & 0x03 == 0x02 => self.asl(&*addressing_mode),
-            x if x & 0xE0 == 0x20 && x & 0x03 == 0x02 => self.rol(&*addressing_mode),
-            x if x & 0xE0 == 0x40 && x & 0x03 == 0x02 => self.lsr(&*addressing_mode),
-            x if x & 0xE0 == 0x60 && x & 0x03 == 0x02 => self.ror(&*addressing_mode),
-            x if x & 0xE0 == 0x80 && x & 0x03 == 0x02 => self.stx(&*addressing_mode),
-            x if x & 0xE0 == 0xA0 && x & 0x03 == 0x02 => self.ldx(&*addressing_mode),
-            x if x & 0xE0 == 0xC0 && x & 0x03 == 0x02 => self.dec(&*addressing_mode),
-            x if x & 0xE0 == 0xE0 && x & 0x03 == 0x02 => self.inc(&*addressing_mode),
-            _ => InstructionResult::NOP, // undocumented instructions
+        let wait_cycles = Cpu::get_wait_cycles(opcode, addressing_mode.page_boundary_crossed());
+        let instruction_result = self.execute_operation(opcode_info(opcode).operation, &addressing_mode);
+        let instruction_result = match self.oam_dma_page.take() {
+            Some(page) => {
+                self.oam_dma(page);
+                InstructionResult::OAMDMA
+            },
+            None => instruction_result,
         };
         wait_cycles + match instruction_result {
             InstructionResult::Ok | InstructionResult::NOP => 0,
             InstructionResult::Branch(cycles) => cycles,
-            InstructionResult::OAMDMA => if self.cycles % 2 == 1 {514} else {513},
+            InstructionResult::OAMDMA => {
+                let dma_cycles = if self.cycles % 2 == 1 {514} else {513};
+                self.oam_dma_end = self.cycles + (wait_cycles + dma_cycles) as u64;
+                dma_cycles
+            },
         }
     }
 
-    fn trace(&self)
+    fn execute_operation(&mut self, operation: Operation, addressing_mode: &AddressingMode) -> InstructionResult
     {
-        println!(
-            "{:04X}  {:02X} {:02X} {:02X}  {:3}                             A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}             CYC:{}",
-            self.registers.pc,
-            self.load(self.registers.pc), self.load(self.registers.pc + 1), self.load(self.registers.pc + 2),
-            self.get_instruction_name(self.load(self.registers.pc)),
-            self.registers.a,
-            self.registers.x,
-            self.registers.y,
-            self.registers.p.get_byte() | 0b0010_0000,
-            self.registers.stack_pointer,
-            self.cycles,
-        );
+        match operation {
+            Operation::Adc => self.adc(addressing_mode),
+            Operation::And => self.and(addressing_mode),
+            Operation::Asl => self.asl(addressing_mode),
+            Operation::Bcc => self.bcc(addressing_mode),
+            Operation::Bcs => self.bcs(addressing_mode),
+            Operation::Beq => self.beq(addressing_mode),
+            Operation::Bit => self.bit(addressing_mode),
+            Operation::Bmi => self.bmi(addressing_mode),
+            Operation::Bne => self.bne(addressing_mode),
+            Operation::Bpl => self.bpl(addressing_mode),
+            Operation::Brk => self.brk(addressing_mode),
+            Operation::Bvc => self.bvc(addressing_mode),
+            Operation::Bvs => self.bvs(addressing_mode),
+            Operation::Clc => self.clc(addressing_mode),
+            Operation::Cld => self.cld(addressing_mode),
+            Operation::Cli => self.cli(addressing_mode),
+            Operation::Clv => self.clv(addressing_mode),
+            Operation::Cmp => self.cmp(addressing_mode),
+            Operation::Cpx => self.cpx(addressing_mode),
+            Operation::Cpy => self.cpy(addressing_mode),
+            Operation::Dec => self.dec(addressing_mode),
+            Operation::Dex => self.dex(addressing_mode),
+            Operation::Dey => self.dey(addressing_mode),
+            Operation::Eor => self.eor(addressing_mode),
+            Operation::Inc => self.inc(addressing_mode),
+            Operation::Inx => self.inx(addressing_mode),
+            Operation::Iny => self.iny(addressing_mode),
+            Operation::Jmp => self.jmp(addressing_mode),
+            Operation::Jsr => self.jsr(addressing_mode),
+            Operation::Lda => self.lda(addressing_mode),
+            Operation::Ldx => self.ldx(addressing_mode),
+            Operation::Ldy => self.ldy(addressing_mode),
+            Operation::Lsr => self.lsr(addressing_mode),
+            Operation::Nop => InstructionResult::NOP,
+            Operation::Ora => self.ora(addressing_mode),
+            Operation::Pha => self.pha(addressing_mode),
+            Operation::Php => self.php(addressing_mode),
+            Operation::Pla => self.pla(addressing_mode),
+            Operation::Plp => self.plp(addressing_mode),
+            Operation::Rol => self.rol(addressing_mode),
+            Operation::Ror => self.ror(addressing_mode),
+            Operation::Rti => self.rti(addressing_mode),
+            Operation::Rts => self.rts(addressing_mode),
+            Operation::Sbc => self.sbc(addressing_mode),
+            Operation::Sec => self.sec(addressing_mode),
+            Operation::Sed => self.sed(addressing_mode),
+            Operation::Sei => self.sei(addressing_mode),
+            Operation::Sta => self.sta(addressing_mode),
+            Operation::Stx => self.stx(addressing_mode),
+            Operation::Sty => self.sty(addressing_mode),
+            Operation::Tax => self.tax(addressing_mode),
+            Operation::Tay => self.tay(addressing_mode),
+            Operation::Tsx => self.tsx(addressing_mode),
+            Operation::Txa => self.txa(addressing_mode),
+            Operation::Txs => self.txs(addressing_mode),
+            Operation::Tya => self.tya(addressing_mode),
+            // unofficial
+            Operation::Alr => self.alr(addressing_mode),
+            Operation::Anc => self.anc(addressing_mode),
+            Operation::Arr => self.arr(addressing_mode),
+            Operation::Axs => self.axs(addressing_mode),
+            Operation::Dcp => self.dcp(addressing_mode),
+            Operation::Isb => self.isb(addressing_mode),
+            Operation::Lax => self.lax(addressing_mode),
+            Operation::Rla => self.rla(addressing_mode),
+            Operation::Rra => self.rra(addressing_mode),
+            Operation::Sax => self.sax(addressing_mode),
+            Operation::Slo => self.slo(addressing_mode),
+            Operation::Sre => self.sre(addressing_mode),
+        }
     }
 }
 
@@ -476,14 +568,50 @@ impl Clocked for Cpu
     fn clock(&mut self)
     {
         match self.wait_cycles {
+            0 if self.cycle_accurate || self.micro.in_progress() => self.clock_micro_op(),
             0 => {
-                self.trace();
-                let opcode = self.fetch();
-                self.wait_cycles = self.execute_instruction(opcode);
+                let cycles = match self.take_interrupt() {
+                    Some(kind) => {
+                        self.interrupt(kind);
+                        7
+                    },
+                    None => {
+                        self.trace();
+                        match self.accelerate_loop() {
+                            Some(cycles) => cycles,
+                            None => {
+                                self.instruction_pc = self.registers.pc;
+                                let opcode = self.fetch();
+                                self.execute_instruction(opcode)
+                            },
+                        }
+                    },
+                };
+                // the current clock is the first cycle of the instruction
+                self.wait_cycles = cycles - 1;
             },
             _ => self.wait_cycles -= 1
         }
         self.cycles += 1;
+        self.cartridge.borrow_mut().cpu_clock();
+        let apu = self.apu.get_mut();
+        apu.clock();
+        let (frame_irq, dmc_irq) = (apu.frame_irq(), apu.dmc_irq());
+        self.set_irq_line(IrqSource::FrameCounter, frame_irq);
+        self.set_irq_line(IrqSource::Dmc, dmc_irq);
+        self.dmc_fetch();
+        // the PPU runs three dots per CPU cycle
+        let ppu = self.ppu.get_mut();
+        for _ in 0..3 {
+            ppu.clock();
+        }
+        self.poll_nmi();
+        self.hijack_interrupt();
+        let irq = self.cartridge.borrow().irq();
+        self.set_irq_line(IrqSource::Mapper, irq);
+        if !self.breakpoints.is_empty() && self.at_instruction_boundary() {
+            self.check_breakpoint();
+        }
     }
 }
 
@@ -641,6 +769,110 @@ mod tests {
                 assert_eq!(cpu.internal_ram[0x0004], 0x1A);
             }
         }
+
+        mod open_bus
+        {
+            use super::*;
+            use crate::cpu::cartridge::NROM;
+
+            #[test]
+            fn test_unmapped_read()
+            {
+                let mut cpu = Cpu::new(Box::new(NROM::new(vec![0; 0x4000], vec![], Mirroring::Horizontal)));
+                cpu.write(0x0300, 0x5A);
+
+                assert_eq!(cpu.load(0x4018), 0x5A);
+                // below the PRG-RAM of NROM
+                assert_eq!(cpu.load(0x5000), 0x5A);
+                // write-only APU registers
+                assert_eq!(cpu.load(0x4000), 0x5A);
+            }
+
+            #[test]
+            fn test_unmapped_write()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.write(0x401F, 0x77);
+
+                assert_eq!(cpu.load(0x4018), 0x77);
+            }
+
+            #[test]
+            fn test_reads_update_the_bus()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.internal_ram[0x0100] = 0x33;
+                cpu.write(0x0301, 0x11);
+                cpu.load(0x0300);
+
+                assert_eq!(cpu.load(0x4018), 0x33);
+            }
+
+            #[test]
+            fn test_apu_status_bit_5()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.write(0x0300, 0xFF);
+
+                // the other bits are the status, all clear at power on
+                assert_eq!(cpu.load(0x4015), 0x20);
+                // the read does not reach the bus
+                assert_eq!(cpu.load(0x4018), 0xFF);
+            }
+
+            // the last bus cycle of LDA $4018 reads the high byte of the operand
+            #[test]
+            fn test_operand_left_on_bus()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.internal_ram[..3].copy_from_slice(&[0xAD, 0x18, 0x40]);
+                cpu.set_pc(0x0200);
+                cpu.step();
+
+                assert_eq!(cpu.registers.a, 0x40);
+            }
+        }
+
+        // cargo test --release bench_memory_path -- --ignored --nocapture
+        #[test]
+        #[ignore]
+        fn bench_memory_path()
+        {
+            use std::time::Instant;
+
+            const ACCESSES: u32 = 4_000_000;
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_trace_sink(TraceSink::Off);
+            let start = Instant::now();
+            let mut sum = 0u32;
+            for i in 0..ACCESSES {
+                let address = (i as u16) & 0x07FF;
+                cpu.write(address, i as u8);
+                sum = sum.wrapping_add(cpu.load(address) as u32);
+            }
+            let elapsed = start.elapsed();
+            println!("{} loads and writes: {:?}, {:.1} ns per access (sum {})", ACCESSES, elapsed,
+                elapsed.as_nanos() as f64 / (2 * ACCESSES) as f64, sum);
+
+            // LDA $0300 / STA $0301 / LDA $0302,X / STA $0303,X / JMP $0200, from RAM
+            let program = [0xAD, 0x00, 0x03, 0x8D, 0x01, 0x03, 0xBD, 0x02, 0x03, 0x9D, 0x03, 0x03, 0x4C, 0x00, 0x02];
+            for (i, byte) in program.iter().enumerate() {
+                cpu.write(0x0200 + i as u16, *byte);
+            }
+            cpu.set_pc(0x0200);
+            let mut instructions = 0u32;
+            let start = Instant::now();
+            while instructions < ACCESSES {
+                cpu.clock();
+                while cpu.wait_cycles != 0 {
+                    cpu.clock();
+                }
+                instructions += 1;
+            }
+            let elapsed = start.elapsed();
+            println!("{} LDA/STA/JMP instructions: {:?}, {:.1} ns per instruction", instructions, elapsed,
+                elapsed.as_nanos() as f64 / instructions as f64);
+        }
     }
 
     mod addressing_mode
@@ -656,7 +888,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.a = 4;
-                let addressing_mode = Accumulator{};
+                let addressing_mode = AddressingMode::Accumulator;
 
                 assert_eq!(addressing_mode.read(&cpu), 4);
             }
@@ -666,7 +898,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.a = 0;
-                let addressing_mode = Accumulator{};
+                let addressing_mode = AddressingMode::Accumulator;
 
                 addressing_mode.write(&mut cpu, 4);
                 assert_eq!(cpu.registers.a, 4);
@@ -683,7 +915,7 @@ mod tests {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0200;
                 cpu.internal_ram[0x00] = 4;
-                let addressing_mode = Immediate::new(&mut cpu);
+                let addressing_mode = AddressingMode::immediate(&mut cpu);
 
                 assert_eq!(addressing_mode.read(&cpu), 4);
             }
@@ -699,7 +931,7 @@ mod tests {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0200;
                 cpu.internal_ram[0x00] = 4;
-                let addressing_mode = Relative::new(&mut cpu);
+                let addressing_mode = AddressingMode::relative(&mut cpu);
 
                 assert_eq!(addressing_mode.read(&cpu), 4);
             }
@@ -1022,6 +1254,156 @@ mod tests {
                 }
             }
         }
+
+        // the order in which an instruction touches the bus, seen from the cartridge space
+        mod bus_accesses
+        {
+            use super::*;
+            use std::rc::Rc;
+            use std::cell::RefCell;
+            use crate::cpu::cartridge::WriteOutcome;
+
+            #[derive(Debug, PartialEq)]
+            enum Access
+            {
+                Read(u16),
+                Write(u16, u8),
+            }
+
+            // 8KB of RAM at $6000-$7FFF, recording every CPU access
+            struct RecordingMapper
+            {
+                ram: [u8; 0x2000],
+                accesses: Rc<RefCell<Vec<Access>>>,
+            }
+            impl Mapper for RecordingMapper
+            {
+                fn read(&self, address: u16) -> Option<u8>
+                {
+                    self.accesses.borrow_mut().push(Access::Read(address));
+                    Some(self.ram[address as usize & 0x1FFF])
+                }
+                fn write(&mut self, address: u16, data: u8) -> WriteOutcome
+                {
+                    self.accesses.borrow_mut().push(Access::Write(address, data));
+                    self.ram[address as usize & 0x1FFF] = data;
+                    WriteOutcome::Handled
+                }
+                fn chr_read(&self, _address: u16) -> u8 { 0 }
+                fn chr_write(&mut self, _address: u16, _data: u8) { }
+                fn mirroring(&self) -> Mirroring { Mirroring::Horizontal }
+            }
+
+            // the instruction operand is at $0200, X is 1
+            fn run(opcode: u8, operand: u16) -> (Cpu, Vec<Access>)
+            {
+                let accesses = Rc::new(RefCell::new(Vec::new()));
+                let mut ram = [0; 0x2000];
+                ram[0x0100] = 0x41;
+                let mapper = RecordingMapper {ram, accesses: Rc::clone(&accesses)};
+                let mut cpu = Cpu::with_cartridge(Box::new(mapper));
+                cpu.registers.pc = 0x0200;
+                cpu.registers.x = 0x01;
+                cpu.registers.a = 0x55;
+                cpu.internal_ram[0] = operand as u8;
+                cpu.internal_ram[1] = (operand >> 8) as u8;
+
+                cpu.execute_instruction(opcode);
+
+                let accesses = accesses.replace(Vec::new());
+                (cpu, accesses)
+            }
+
+            #[test]
+            fn test_inc_indexed_absolute()
+            {
+                let (_, accesses) = run(0xFE, 0x60FF);
+
+                assert_eq!(accesses, vec![
+                    Access::Read(0x6000),
+                    Access::Read(0x6100),
+                    Access::Write(0x6100, 0x41),
+                    Access::Write(0x6100, 0x42),
+                ]);
+
+                let (_, accesses) = run(0xFE, 0x60FE);
+
+                assert_eq!(accesses, vec![
+                    Access::Read(0x60FF),
+                    Access::Read(0x60FF),
+                    Access::Write(0x60FF, 0x00),
+                    Access::Write(0x60FF, 0x01),
+                ]);
+            }
+
+            #[test]
+            fn test_inc_absolute()
+            {
+                let (_, accesses) = run(0xEE, 0x6100);
+
+                assert_eq!(accesses, vec![
+                    Access::Read(0x6100),
+                    Access::Write(0x6100, 0x41),
+                    Access::Write(0x6100, 0x42),
+                ]);
+            }
+
+            #[test]
+            fn test_sta_indexed_absolute()
+            {
+                let (_, accesses) = run(0x9D, 0x6010);
+
+                assert_eq!(accesses, vec![
+                    Access::Read(0x6011),
+                    Access::Write(0x6011, 0x55),
+                ]);
+
+                let (_, accesses) = run(0x9D, 0x60FF);
+
+                assert_eq!(accesses, vec![
+                    Access::Read(0x6000),
+                    Access::Write(0x6100, 0x55),
+                ]);
+            }
+
+            #[test]
+            fn test_lda_indexed_absolute()
+            {
+                let (cpu, accesses) = run(0xBD, 0x60FF);
+
+                assert_eq!(accesses, vec![
+                    Access::Read(0x6000),
+                    Access::Read(0x6100),
+                ]);
+                assert_eq!(cpu.registers.a, 0x41);
+
+                let (_, accesses) = run(0xBD, 0x6010);
+
+                assert_eq!(accesses, vec![Access::Read(0x6011)]);
+            }
+
+            // the dummy read is visible on PPUDATA, which moves the VRAM address
+            #[test]
+            fn test_sta_indexed_absolute_on_ppudata()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x06;
+                cpu.internal_ram[1] = 0x20;
+                cpu.registers.x = 0x01;
+                cpu.registers.a = 0x55;
+                cpu.write(0x2006, 0x21);
+                cpu.write(0x2006, 0x00);
+
+                cpu.execute_instruction(0x9D);
+
+                // the read of $2007 incremented v before the write
+                cpu.write(0x2006, 0x21);
+                cpu.write(0x2006, 0x01);
+                cpu.load(0x2007);
+                assert_eq!(cpu.load(0x2007), 0x55);
+            }
+        }
     }
 
     mod instructions
@@ -3414,7 +3796,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x00;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x29);
+                cpu.execute_instruction(0x29);
 
                 assert_eq!(cpu.registers.p.zero, true);
 
@@ -3422,7 +3804,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x0F;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x29);
+                cpu.execute_instruction(0x29);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -3435,7 +3817,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x80;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x29);
+                cpu.execute_instruction(0x29);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -3443,7 +3825,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x0F;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x29);
+                cpu.execute_instruction(0x29);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -4126,7 +4508,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x00;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x09);
+                cpu.execute_instruction(0x09);
 
                 assert_eq!(cpu.registers.p.zero, true);
 
@@ -4134,7 +4516,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x0F;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x09);
+                cpu.execute_instruction(0x09);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -4147,7 +4529,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x80;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x09);
+                cpu.execute_instruction(0x09);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -4155,7 +4537,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x0F;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x09);
+                cpu.execute_instruction(0x09);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -4838,7 +5220,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x00;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x49);
+                cpu.execute_instruction(0x49);
 
                 assert_eq!(cpu.registers.p.zero, true);
 
@@ -4847,7 +5229,7 @@ mod tests {
                 cpu.internal_ram[0] = 0xFF;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x49);
+                cpu.execute_instruction(0x49);
 
                 assert_eq!(cpu.registers.p.zero, true);
 
@@ -4855,7 +5237,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x0F;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x49);
+                cpu.execute_instruction(0x49);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
@@ -4863,7 +5245,7 @@ mod tests {
                 cpu.internal_ram[0] = 0xF0;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x49);
+                cpu.execute_instruction(0x49);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -4876,7 +5258,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x80;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x09);
+                cpu.execute_instruction(0x09);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -4884,7 +5266,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x0F;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x09);
+                cpu.execute_instruction(0x09);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -4997,7 +5379,7 @@ mod tests {
                 cpu.zero_page_ram[0x04] = 0x01;
                 cpu.registers.a = 0x0F;
 
-                let wait_cycles = cpu.execute_instruction(0x24);
+                cpu.execute_instruction(0x24);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
@@ -5006,7 +5388,7 @@ mod tests {
                 cpu.zero_page_ram[0x04] = 0x81;
                 cpu.registers.a = 0x0F;
 
-                let wait_cycles = cpu.execute_instruction(0x24);
+                cpu.execute_instruction(0x24);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -5020,7 +5402,7 @@ mod tests {
                 cpu.zero_page_ram[0x04] = 0x01;
                 cpu.registers.a = 0x0F;
 
-                let wait_cycles = cpu.execute_instruction(0x24);
+                cpu.execute_instruction(0x24);
 
                 assert_eq!(cpu.registers.p.overflow, false);
 
@@ -5029,7 +5411,7 @@ mod tests {
                 cpu.zero_page_ram[0x04] = 0x41;
                 cpu.registers.a = 0x0F;
 
-                let wait_cycles = cpu.execute_instruction(0x24);
+                cpu.execute_instruction(0x24);
 
                 assert_eq!(cpu.registers.p.overflow, true);
             }
@@ -5989,6 +6371,26 @@ mod tests {
                 cpu.registers.p.carry = true;
                 cpu.registers.p.overflow = false;
 
+                cpu.execute_instruction(0xE9);
+                assert_eq!(cpu.registers.p.overflow, false);
+
+                // -128 - 1 does not fit
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x01;
+                cpu.registers.a = 0x80;
+                cpu.registers.p.carry = true;
+                cpu.registers.p.overflow = false;
+
+                cpu.execute_instruction(0xE9);
+                assert_eq!(cpu.registers.p.overflow, true);
+
+                // 127 - -1 does not fit either
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0xFF;
+                cpu.registers.a = 0x7F;
+                cpu.registers.p.carry = true;
+                cpu.registers.p.overflow = false;
+
                 cpu.execute_instruction(0xE9);
                 assert_eq!(cpu.registers.p.overflow, true);
             }
@@ -6017,59 +6419,408 @@ mod tests {
             }
         }
 
-        mod cmp
+        // starred mnemonics of the nestest log
+        mod unofficial
         {
             use super::*;
 
             #[test]
-            fn test_immediate()
+            fn test_lax()
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0200;
-                cpu.internal_ram[0] = 0x05;
-                cpu.registers.a = 0x06;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x86;
 
-                let wait_cycles = cpu.execute_instruction(0xC9);
+                let wait_cycles = cpu.execute_instruction(0xA7);
 
-                assert_eq!(cpu.registers.p.carry, true);
-                assert_eq!(cpu.registers.p.zero, false);
-                assert_eq!(wait_cycles, 2);
+                assert_eq!(cpu.registers.a, 0x86);
+                assert_eq!(cpu.registers.x, 0x86);
+                assert!(cpu.registers.p.negative);
+                assert!(!cpu.registers.p.zero);
+                assert_eq!(wait_cycles, 3);
+            }
 
+            #[test]
+            fn test_lax_indexed_absolute_page_crossing()
+            {
+                let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0200;
-                cpu.internal_ram[0] = 0x05;
-                cpu.registers.a = 0x05;
+                cpu.internal_ram[0] = 0xFF;
+                cpu.internal_ram[1] = 0x02;
+                cpu.internal_ram[0x0100] = 0x00;
+                cpu.registers.y = 0x01;
 
-                let wait_cycles = cpu.execute_instruction(0xC9);
+                let wait_cycles = cpu.execute_instruction(0xBF);
 
-                assert_eq!(cpu.registers.p.carry, true);
-                assert_eq!(cpu.registers.p.zero, true);
-                assert_eq!(wait_cycles, 2);
+                assert_eq!(cpu.registers.a, 0x00);
+                assert_eq!(cpu.registers.x, 0x00);
+                assert!(cpu.registers.p.zero);
+                assert_eq!(wait_cycles, 5);
+            }
 
+            #[test]
+            fn test_sax()
+            {
+                let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0200;
-                cpu.internal_ram[0] = 0x05;
-                cpu.registers.a = 0x04;
+                cpu.internal_ram[0] = 0x04;
+                cpu.registers.a = 0x3C;
+                cpu.registers.x = 0x0F;
+                cpu.registers.p.set_byte(0x00);
 
-                let wait_cycles = cpu.execute_instruction(0xC9);
+                let wait_cycles = cpu.execute_instruction(0x87);
 
-                assert_eq!(cpu.registers.p.carry, false);
-                assert_eq!(cpu.registers.p.zero, false);
-                assert_eq!(wait_cycles, 2);
+                assert_eq!(cpu.zero_page_ram[0x04], 0x0C);
+                // no flag is affected
+                assert_eq!(cpu.registers.p.get_byte() & 0b1100_0011, 0x00);
+                assert_eq!(wait_cycles, 3);
             }
 
             #[test]
-            fn test_zero_page()
+            fn test_sax_indexed_indirect()
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0200;
                 cpu.internal_ram[0] = 0x04;
-                cpu.zero_page_ram[0x04] = 0x05;
-                cpu.registers.a = 0x06;
+                cpu.zero_page_ram[0x05] = 0x10;
+                cpu.zero_page_ram[0x06] = 0x03;
+                cpu.registers.a = 0xFF;
+                cpu.registers.x = 0x01;
 
-                let wait_cycles = cpu.execute_instruction(0xC5);
+                let wait_cycles = cpu.execute_instruction(0x83);
 
-                assert_eq!(cpu.registers.p.carry, true);
-                assert_eq!(cpu.registers.p.zero, false);
-                assert_eq!(wait_cycles, 3);
+                assert_eq!(cpu.internal_ram[0x0110], 0x01);
+                assert_eq!(wait_cycles, 6);
+            }
+
+            #[test]
+            fn test_dcp()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x41;
+                cpu.registers.a = 0x40;
+
+                let wait_cycles = cpu.execute_instruction(0xC7);
+
+                assert_eq!(cpu.zero_page_ram[0x04], 0x40);
+                assert!(cpu.registers.p.zero);
+                assert!(cpu.registers.p.carry);
+                assert_eq!(cpu.registers.a, 0x40);
+                assert_eq!(wait_cycles, 5);
+            }
+
+            #[test]
+            fn test_isb()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x01;
+                cpu.registers.a = 0x05;
+                cpu.registers.p.carry = true;
+
+                let wait_cycles = cpu.execute_instruction(0xE7);
+
+                assert_eq!(cpu.zero_page_ram[0x04], 0x02);
+                assert_eq!(cpu.registers.a, 0x03);
+                assert!(cpu.registers.p.carry);
+                assert_eq!(wait_cycles, 5);
+            }
+
+            #[test]
+            fn test_slo()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x81;
+                cpu.registers.a = 0x10;
+
+                let wait_cycles = cpu.execute_instruction(0x07);
+
+                assert_eq!(cpu.zero_page_ram[0x04], 0x02);
+                assert_eq!(cpu.registers.a, 0x12);
+                assert!(cpu.registers.p.carry);
+                assert_eq!(wait_cycles, 5);
+            }
+
+            #[test]
+            fn test_rla()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x81;
+                cpu.registers.a = 0x03;
+                cpu.registers.p.carry = true;
+
+                let wait_cycles = cpu.execute_instruction(0x27);
+
+                assert_eq!(cpu.zero_page_ram[0x04], 0x03);
+                assert_eq!(cpu.registers.a, 0x03);
+                assert!(cpu.registers.p.carry);
+                assert_eq!(wait_cycles, 5);
+            }
+
+            #[test]
+            fn test_sre()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x03;
+                cpu.registers.a = 0x01;
+
+                let wait_cycles = cpu.execute_instruction(0x47);
+
+                assert_eq!(cpu.zero_page_ram[0x04], 0x01);
+                assert_eq!(cpu.registers.a, 0x00);
+                assert!(cpu.registers.p.zero);
+                assert!(cpu.registers.p.carry);
+                assert_eq!(wait_cycles, 5);
+            }
+
+            #[test]
+            fn test_rra()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x03;
+                cpu.registers.a = 0x10;
+                cpu.registers.p.carry = false;
+
+                let wait_cycles = cpu.execute_instruction(0x67);
+
+                // 0x03 rotates to 0x01 and carries out 1, added to A
+                assert_eq!(cpu.zero_page_ram[0x04], 0x01);
+                assert_eq!(cpu.registers.a, 0x12);
+                assert!(!cpu.registers.p.carry);
+                assert_eq!(wait_cycles, 5);
+            }
+
+            #[test]
+            fn test_anc()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0xF0;
+                cpu.registers.a = 0x81;
+
+                let wait_cycles = cpu.execute_instruction(0x2B);
+
+                assert_eq!(cpu.registers.a, 0x80);
+                assert!(cpu.registers.p.negative);
+                assert!(cpu.registers.p.carry);
+                assert_eq!(cpu.registers.pc, 0x0201);
+                assert_eq!(wait_cycles, 2);
+            }
+
+            #[test]
+            fn test_alr()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x03;
+                cpu.registers.a = 0xFF;
+
+                let wait_cycles = cpu.execute_instruction(0x4B);
+
+                assert_eq!(cpu.registers.a, 0x01);
+                assert!(cpu.registers.p.carry);
+                assert!(!cpu.registers.p.negative);
+                assert_eq!(cpu.registers.pc, 0x0201);
+                assert_eq!(wait_cycles, 2);
+            }
+
+            #[test]
+            fn test_arr()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0xC0;
+                cpu.registers.a = 0xFF;
+                cpu.registers.p.carry = true;
+
+                let wait_cycles = cpu.execute_instruction(0x6B);
+
+                // 0xC0 rotated with the carry in is 0xE0: bits 6 and 5 both set
+                assert_eq!(cpu.registers.a, 0xE0);
+                assert!(cpu.registers.p.negative);
+                assert!(cpu.registers.p.carry);
+                assert!(!cpu.registers.p.overflow);
+                assert_eq!(wait_cycles, 2);
+
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x40;
+                cpu.registers.a = 0xFF;
+                cpu.registers.p.carry = false;
+
+                cpu.execute_instruction(0x6B);
+
+                assert_eq!(cpu.registers.a, 0x20);
+                assert!(!cpu.registers.p.carry);
+                assert!(cpu.registers.p.overflow);
+            }
+
+            #[test]
+            fn test_axs()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x02;
+                cpu.registers.a = 0x0F;
+                cpu.registers.x = 0x3C;
+                cpu.registers.p.carry = false;
+
+                let wait_cycles = cpu.execute_instruction(0xCB);
+
+                assert_eq!(cpu.registers.x, 0x0A);
+                assert!(cpu.registers.p.carry);
+                assert_eq!(cpu.registers.a, 0x0F);
+                assert_eq!(wait_cycles, 2);
+
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x10;
+                cpu.registers.x = 0x0F;
+
+                cpu.execute_instruction(0xCB);
+
+                // no borrow in, unlike SBC
+                assert_eq!(cpu.registers.x, 0xFF);
+                assert!(!cpu.registers.p.carry);
+                assert!(cpu.registers.p.negative);
+            }
+
+            #[test]
+            fn test_sbc_alias()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x01;
+                cpu.registers.a = 0x04;
+                cpu.registers.p.carry = true;
+
+                let wait_cycles = cpu.execute_instruction(0xEB);
+
+                assert_eq!(cpu.registers.a, 0x03);
+                assert_eq!(cpu.registers.pc, 0x0201);
+                assert_eq!(wait_cycles, 2);
+            }
+
+            // the operand is skipped whatever the addressing mode
+            #[test]
+            fn test_nop_operands()
+            {
+                let nops = [
+                    (0x1A, 0, 2), (0x3A, 0, 2), (0x5A, 0, 2), (0x7A, 0, 2), (0xDA, 0, 2), (0xFA, 0, 2),
+                    (0x80, 1, 2), (0x82, 1, 2), (0x89, 1, 2), (0xC2, 1, 2), (0xE2, 1, 2),
+                    (0x04, 1, 3), (0x44, 1, 3), (0x64, 1, 3),
+                    (0x14, 1, 4), (0x34, 1, 4), (0x54, 1, 4), (0x74, 1, 4), (0xD4, 1, 4), (0xF4, 1, 4),
+                    (0x0C, 2, 4),
+                    (0x1C, 2, 4), (0x3C, 2, 4), (0x5C, 2, 4), (0x7C, 2, 4), (0xDC, 2, 4), (0xFC, 2, 4),
+                ];
+                for &(opcode, operand_bytes, cycles) in nops.iter() {
+                    let mut cpu = Cpu::new_dummy();
+                    cpu.registers.pc = 0x0200;
+                    cpu.internal_ram[0] = 0x04;
+                    cpu.internal_ram[1] = 0x03;
+                    let status = cpu.registers.p.get_byte();
+
+                    let wait_cycles = cpu.execute_instruction(opcode);
+
+                    assert_eq!(cpu.registers.pc, 0x0200 + operand_bytes, "opcode {:02X}", opcode);
+                    assert_eq!(wait_cycles, cycles, "opcode {:02X}", opcode);
+                    assert_eq!(cpu.registers.p.get_byte(), status, "opcode {:02X}", opcode);
+                }
+            }
+
+            #[test]
+            fn test_nop_indexed_absolute_page_crossing()
+            {
+                for &opcode in [0x1C, 0x3C, 0x5C, 0x7C, 0xDC, 0xFC].iter() {
+                    let mut cpu = Cpu::new_dummy();
+                    cpu.registers.pc = 0x0200;
+                    cpu.internal_ram[0] = 0xFF;
+                    cpu.internal_ram[1] = 0x02;
+                    cpu.registers.x = 0x01;
+
+                    let wait_cycles = cpu.execute_instruction(opcode);
+
+                    assert_eq!(cpu.registers.pc, 0x0202);
+                    assert_eq!(wait_cycles, 5, "opcode {:02X}", opcode);
+                }
+            }
+
+            // indexed read-modify-write never takes the page crossing shortcut
+            #[test]
+            fn test_read_modify_write_cycles()
+            {
+                for &(opcode, cycles) in [(0x03, 8), (0x13, 8), (0x0F, 6), (0x17, 6), (0x1B, 7), (0x1F, 7)].iter() {
+                    for &row in [0x00, 0x20, 0x40, 0x60, 0xC0, 0xE0].iter() {
+                        assert_eq!(Cpu::get_wait_cycles(row | opcode, false), cycles, "opcode {:02X}", row | opcode);
+                        assert_eq!(Cpu::get_wait_cycles(row | opcode, true), cycles, "opcode {:02X}", row | opcode);
+                    }
+                }
+            }
+        }
+
+        mod cmp
+        {
+            use super::*;
+
+            #[test]
+            fn test_immediate()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x05;
+                cpu.registers.a = 0x06;
+
+                let wait_cycles = cpu.execute_instruction(0xC9);
+
+                assert_eq!(cpu.registers.p.carry, true);
+                assert_eq!(cpu.registers.p.zero, false);
+                assert_eq!(wait_cycles, 2);
+
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x05;
+                cpu.registers.a = 0x05;
+
+                let wait_cycles = cpu.execute_instruction(0xC9);
+
+                assert_eq!(cpu.registers.p.carry, true);
+                assert_eq!(cpu.registers.p.zero, true);
+                assert_eq!(wait_cycles, 2);
+
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x05;
+                cpu.registers.a = 0x04;
+
+                let wait_cycles = cpu.execute_instruction(0xC9);
+
+                assert_eq!(cpu.registers.p.carry, false);
+                assert_eq!(cpu.registers.p.zero, false);
+                assert_eq!(wait_cycles, 2);
+            }
+
+            #[test]
+            fn test_zero_page()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0] = 0x04;
+                cpu.zero_page_ram[0x04] = 0x05;
+                cpu.registers.a = 0x06;
+
+                let wait_cycles = cpu.execute_instruction(0xC5);
+
+                assert_eq!(cpu.registers.p.carry, true);
+                assert_eq!(cpu.registers.p.zero, false);
+                assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0200;
                 cpu.internal_ram[0] = 0x04;
@@ -6581,7 +7332,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x70;
                 cpu.registers.a = 0xF0;
 
-                let wait_cycles = cpu.execute_instruction(0xC9);
+                cpu.execute_instruction(0xC9);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -6589,7 +7340,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x05;
                 cpu.registers.a = 0x05;
 
-                let wait_cycles = cpu.execute_instruction(0xC9);
+                cpu.execute_instruction(0xC9);
 
                 assert_eq!(cpu.registers.p.negative, false);
             }
@@ -6721,7 +7472,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x70;
                 cpu.registers.x = 0xF0;
 
-                let wait_cycles = cpu.execute_instruction(0xE0);
+                cpu.execute_instruction(0xE0);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -6729,7 +7480,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x05;
                 cpu.registers.x = 0x05;
 
-                let wait_cycles = cpu.execute_instruction(0xE0);
+                cpu.execute_instruction(0xE0);
 
                 assert_eq!(cpu.registers.p.negative, false);
             }
@@ -6861,7 +7612,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x70;
                 cpu.registers.y = 0xF0;
 
-                let wait_cycles = cpu.execute_instruction(0xC0);
+                cpu.execute_instruction(0xC0);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -6869,7 +7620,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x05;
                 cpu.registers.y = 0x05;
 
-                let wait_cycles = cpu.execute_instruction(0xC0);
+                cpu.execute_instruction(0xC0);
 
                 assert_eq!(cpu.registers.p.negative, false);
             }
@@ -7039,7 +7790,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0xE6);
+                cpu.execute_instruction(0xE6);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
@@ -7047,7 +7798,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0xE6);
+                cpu.execute_instruction(0xE6);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7061,7 +7812,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0x7E;
 
-                let wait_cycles = cpu.execute_instruction(0xE6);
+                cpu.execute_instruction(0xE6);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
@@ -7069,7 +7820,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0x7F;
 
-                let wait_cycles = cpu.execute_instruction(0xE6);
+                cpu.execute_instruction(0xE6);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -7107,14 +7858,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0xE8);
+                cpu.execute_instruction(0xE8);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0xE8);
+                cpu.execute_instruction(0xE8);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7127,14 +7878,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0xE8);
+                cpu.execute_instruction(0xE8);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0x7F;
 
-                let wait_cycles = cpu.execute_instruction(0xE8);
+                cpu.execute_instruction(0xE8);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -7172,14 +7923,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0xC8);
+                cpu.execute_instruction(0xC8);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0xC8);
+                cpu.execute_instruction(0xC8);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7192,14 +7943,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0xC8);
+                cpu.execute_instruction(0xC8);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0x7F;
 
-                let wait_cycles = cpu.execute_instruction(0xC8);
+                cpu.execute_instruction(0xC8);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -7369,7 +8120,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0xC6);
+                cpu.execute_instruction(0xC6);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
@@ -7377,7 +8128,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0x01;
 
-                let wait_cycles = cpu.execute_instruction(0xC6);
+                cpu.execute_instruction(0xC6);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7391,7 +8142,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0x80;
 
-                let wait_cycles = cpu.execute_instruction(0xC6);
+                cpu.execute_instruction(0xC6);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
@@ -7399,7 +8150,7 @@ mod tests {
                 cpu.internal_ram[0] = 0x04;
                 cpu.zero_page_ram[0x04] = 0x81;
 
-                let wait_cycles = cpu.execute_instruction(0xC6);
+                cpu.execute_instruction(0xC6);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -7437,14 +8188,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0xCA);
+                cpu.execute_instruction(0xCA);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0x01;
 
-                let wait_cycles = cpu.execute_instruction(0xCA);
+                cpu.execute_instruction(0xCA);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7457,14 +8208,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0x80;
 
-                let wait_cycles = cpu.execute_instruction(0xCA);
+                cpu.execute_instruction(0xCA);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.x = 0x81;
 
-                let wait_cycles = cpu.execute_instruction(0xCA);
+                cpu.execute_instruction(0xCA);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -7502,14 +8253,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0x88);
+                cpu.execute_instruction(0x88);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0x01;
 
-                let wait_cycles = cpu.execute_instruction(0x88);
+                cpu.execute_instruction(0x88);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7522,14 +8273,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0x80;
 
-                let wait_cycles = cpu.execute_instruction(0x88);
+                cpu.execute_instruction(0x88);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.y = 0x81;
 
-                let wait_cycles = cpu.execute_instruction(0x88);
+                cpu.execute_instruction(0x88);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -7650,14 +8401,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0x0A);
+                cpu.execute_instruction(0x0A);
 
                 assert_eq!(cpu.registers.p.carry, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x0A);
+                cpu.execute_instruction(0x0A);
 
                 assert_eq!(cpu.registers.p.carry, true);
             }
@@ -7669,14 +8420,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0x0A);
+                cpu.execute_instruction(0x0A);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x80;
 
-                let wait_cycles = cpu.execute_instruction(0x0A);
+                cpu.execute_instruction(0x0A);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7688,14 +8439,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0x0A);
+                cpu.execute_instruction(0x0A);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x40;
 
-                let wait_cycles = cpu.execute_instruction(0x0A);
+                cpu.execute_instruction(0x0A);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -7816,14 +8567,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0x4A);
+                cpu.execute_instruction(0x4A);
 
                 assert_eq!(cpu.registers.p.carry, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x4A);
+                cpu.execute_instruction(0x4A);
 
                 assert_eq!(cpu.registers.p.carry, true);
             }
@@ -7835,14 +8586,14 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0x4A);
+                cpu.execute_instruction(0x4A);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x01;
 
-                let wait_cycles = cpu.execute_instruction(0x4A);
+                cpu.execute_instruction(0x4A);
 
                 assert_eq!(cpu.registers.p.zero, true);
             }
@@ -7854,21 +8605,21 @@ mod tests {
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x04;
 
-                let wait_cycles = cpu.execute_instruction(0x4A);
+                cpu.execute_instruction(0x4A);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0xFF;
 
-                let wait_cycles = cpu.execute_instruction(0x4A);
+                cpu.execute_instruction(0x4A);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
                 cpu.registers.pc = 0x0200;
                 cpu.registers.a = 0x00;
 
-                let wait_cycles = cpu.execute_instruction(0x4A);
+                cpu.execute_instruction(0x4A);
 
                 assert_eq!(cpu.registers.p.negative, false);
             }
@@ -8073,7 +8824,7 @@ mod tests {
                 cpu.registers.a = 0x04;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x2A);
+                cpu.execute_instruction(0x2A);
 
                 assert_eq!(cpu.registers.p.carry, false);
 
@@ -8081,7 +8832,7 @@ mod tests {
                 cpu.registers.a = 0xFF;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x2A);
+                cpu.execute_instruction(0x2A);
 
                 assert_eq!(cpu.registers.p.carry, true);
             }
@@ -8094,7 +8845,7 @@ mod tests {
                 cpu.registers.a = 0x04;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x2A);
+                cpu.execute_instruction(0x2A);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
@@ -8102,7 +8853,7 @@ mod tests {
                 cpu.registers.a = 0x80;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x2A);
+                cpu.execute_instruction(0x2A);
 
                 assert_eq!(cpu.registers.p.zero, true);
 
@@ -8110,7 +8861,7 @@ mod tests {
                 cpu.registers.a = 0x80;
                 cpu.registers.p.carry = true;
 
-                let wait_cycles = cpu.execute_instruction(0x2A);
+                cpu.execute_instruction(0x2A);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -8123,7 +8874,7 @@ mod tests {
                 cpu.registers.a = 0x04;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x2A);
+                cpu.execute_instruction(0x2A);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
@@ -8131,7 +8882,7 @@ mod tests {
                 cpu.registers.a = 0x40;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x2A);
+                cpu.execute_instruction(0x2A);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -8336,7 +9087,7 @@ mod tests {
                 cpu.registers.a = 0x04;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.carry, false);
 
@@ -8344,7 +9095,7 @@ mod tests {
                 cpu.registers.a = 0xFF;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.carry, true);
             }
@@ -8357,7 +9108,7 @@ mod tests {
                 cpu.registers.a = 0x04;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.zero, false);
 
@@ -8365,7 +9116,7 @@ mod tests {
                 cpu.registers.a = 0x01;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.zero, true);
 
@@ -8373,7 +9124,7 @@ mod tests {
                 cpu.registers.a = 0x01;
                 cpu.registers.p.carry = true;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.zero, false);
             }
@@ -8386,7 +9137,7 @@ mod tests {
                 cpu.registers.a = 0x04;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
@@ -8394,7 +9145,7 @@ mod tests {
                 cpu.registers.a = 0xFF;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
@@ -8402,7 +9153,7 @@ mod tests {
                 cpu.registers.a = 0x00;
                 cpu.registers.p.carry = false;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.negative, false);
 
@@ -8410,7 +9161,7 @@ mod tests {
                 cpu.registers.a = 0x04;
                 cpu.registers.p.carry = true;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -8418,7 +9169,7 @@ mod tests {
                 cpu.registers.a = 0xFF;
                 cpu.registers.p.carry = true;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.negative, true);
 
@@ -8426,7 +9177,7 @@ mod tests {
                 cpu.registers.a = 0x00;
                 cpu.registers.p.carry = true;
 
-                let wait_cycles = cpu.execute_instruction(0x6A);
+                cpu.execute_instruction(0x6A);
 
                 assert_eq!(cpu.registers.p.negative, true);
             }
@@ -8465,6 +9216,23 @@ mod tests {
                 assert_eq!(cpu.registers.pc, 0x0440);
                 assert_eq!(wait_cycles, 5);
             }
+
+            #[test]
+            fn test_indirect_page_wrap()
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.internal_ram[0x00] = 0xFF;
+                cpu.internal_ram[0x01] = 0x03;
+                cpu.internal_ram[0x01FF] = 0x40;
+                // the high byte comes from $0300, not $0400
+                cpu.internal_ram[0x0100] = 0x05;
+                cpu.internal_ram[0x0200] = 0x04;
+
+                cpu.execute_instruction(0x6C);
+
+                assert_eq!(cpu.registers.pc, 0x0540);
+            }
         }
 
         mod jsr
@@ -8556,7 +9324,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.carry = false;
 
                 let wait_cycles = cpu.execute_instruction(0x90);
@@ -8565,7 +9333,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.carry = false;
 
                 let wait_cycles = cpu.execute_instruction(0x90);
@@ -8616,7 +9384,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.carry = true;
 
                 let wait_cycles = cpu.execute_instruction(0xB0);
@@ -8625,7 +9393,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.carry = true;
 
                 let wait_cycles = cpu.execute_instruction(0xB0);
@@ -8676,7 +9444,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.zero = true;
 
                 let wait_cycles = cpu.execute_instruction(0xF0);
@@ -8685,7 +9453,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.zero = true;
 
                 let wait_cycles = cpu.execute_instruction(0xF0);
@@ -8736,7 +9504,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.zero = false;
 
                 let wait_cycles = cpu.execute_instruction(0xD0);
@@ -8745,7 +9513,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.zero = false;
 
                 let wait_cycles = cpu.execute_instruction(0xD0);
@@ -8796,7 +9564,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.negative = true;
 
                 let wait_cycles = cpu.execute_instruction(0x30);
@@ -8805,7 +9573,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.negative = true;
 
                 let wait_cycles = cpu.execute_instruction(0x30);
@@ -8856,7 +9624,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.negative = false;
 
                 let wait_cycles = cpu.execute_instruction(0x10);
@@ -8865,7 +9633,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.negative = false;
 
                 let wait_cycles = cpu.execute_instruction(0x10);
@@ -8916,7 +9684,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.overflow = true;
 
                 let wait_cycles = cpu.execute_instruction(0x70);
@@ -8925,7 +9693,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.overflow = true;
 
                 let wait_cycles = cpu.execute_instruction(0x70);
@@ -8976,7 +9744,7 @@ mod tests {
             {
                 let mut cpu = Cpu::new_dummy();
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-4 as i8) as u8;
+                cpu.internal_ram[0x03] = -4i8 as u8;
                 cpu.registers.p.overflow = false;
 
                 let wait_cycles = cpu.execute_instruction(0x50);
@@ -8985,7 +9753,7 @@ mod tests {
                 assert_eq!(wait_cycles, 3);
 
                 cpu.registers.pc = 0x0203;
-                cpu.internal_ram[0x03] = (-5 as i8) as u8;
+                cpu.internal_ram[0x03] = -5i8 as u8;
                 cpu.registers.p.overflow = false;
 
                 let wait_cycles = cpu.execute_instruction(0x50);
@@ -9195,8 +9963,9 @@ mod tests {
                 // dummy mapper returns address 0x8000 when loading irq/brk vector
                 assert_eq!(cpu.registers.pc, 0x8000);
                 assert_eq!(cpu.registers.stack_pointer, 0xFA);
+                // the padding byte at $0201 is skipped
                 assert_eq!(cpu.stack[0xFD], 0x02);
-                assert_eq!(cpu.stack[0xFC], 0x01);
+                assert_eq!(cpu.stack[0xFC], 0x02);
                 assert_eq!(cpu.stack[0xFB], 0b0011_0001);
                 assert_eq!(cpu.registers.p.interrupt_disable, true);
                 assert_eq!(wait_cycles, 7);
@@ -9233,5 +10002,3666 @@ mod tests {
                 assert_eq!(wait_cycles, 6);
             }
         }
+
+        mod branch_cycles
+        {
+            use super::*;
+
+            // N, V, C, Z picked by bits 7-6, taken when the flag equals bit 5
+            fn branch_status(opcode: u8, taken: bool) -> u8
+            {
+                let flag = [0b1000_0000, 0b0100_0000, 0b0000_0001, 0b0000_0010][(opcode >> 6) as usize];
+                if (opcode & 0x20 != 0) == taken {flag} else {0}
+            }
+
+            // branch with its operand at pc, returns the cycles and the new pc
+            fn branch(opcode: u8, pc: u16, offset: u8, taken: bool) -> (u32, u16)
+            {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = pc;
+                cpu.internal_ram[(pc - 0x0200) as usize] = offset;
+                cpu.registers.p.set_byte(branch_status(opcode, taken));
+                let cycles = cpu.execute_instruction(opcode);
+                (cycles, cpu.registers.pc)
+            }
+
+            #[test]
+            fn test_all_branches()
+            {
+                for opcode in [0x10, 0x30, 0x50, 0x70, 0x90, 0xB0, 0xD0, 0xF0] {
+                    assert_eq!(branch(opcode, 0x0280, 0x10, false), (2, 0x0281), "{:02X}", opcode);
+                    assert_eq!(branch(opcode, 0x0280, 0x10, true), (3, 0x0291), "{:02X}", opcode);
+                    assert_eq!(branch(opcode, 0x0210, 0x80, true), (4, 0x0191), "{:02X}", opcode);
+                    assert_eq!(branch(opcode, 0x02F0, 0x7F, true), (4, 0x0370), "{:02X}", opcode);
+                }
+            }
+
+            // the page crossing is relative to the next instruction, not to the branch opcode
+            #[test]
+            fn test_page_crossing_from_next_instruction()
+            {
+                for opcode in [0x10, 0x30, 0x50, 0x70, 0x90, 0xB0, 0xD0, 0xF0] {
+                    // opcode at $02FE, next instruction at $0300
+                    assert_eq!(branch(opcode, 0x02FF, 0x02, true), (3, 0x0302), "{:02X}", opcode);
+                    assert_eq!(branch(opcode, 0x02FF, 0xFE, true), (4, 0x02FE), "{:02X}", opcode);
+                    // opcode at $02FD, next instruction at $02FF
+                    assert_eq!(branch(opcode, 0x02FE, 0x01, true), (4, 0x0300), "{:02X}", opcode);
+                }
+            }
+        }
+    }
+
+    mod loop_acceleration
+    {
+        use super::*;
+
+        // returns the number of instruction boundaries on the way
+        fn run_until(cpu: &mut Cpu, address: u16) -> u32
+        {
+            let mut boundaries = 0;
+            loop {
+                cpu.clock();
+                if cpu.wait_cycles == 0 {
+                    boundaries += 1;
+                    if cpu.registers.pc == address {
+                        return boundaries
+                    }
+                }
+            }
+        }
+
+        fn delay_loop_cpu(mode: LoopAcceleration) -> Cpu
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_loop_acceleration(mode);
+            cpu.registers.pc = 0x0200;
+            cpu.registers.x = 0x00;
+            // 40 * 256 iterations of the inner loop
+            cpu.internal_ram[0x00..0x09].copy_from_slice(&[
+                0xA0, 0x28, // LDY #$28
+                0xCA,       // DEX
+                0xD0, 0xFD, // BNE $0202
+                0x88,       // DEY
+                0xD0, 0xFA, // BNE $0202
+                0xEA,       // NOP
+            ]);
+            cpu
+        }
+
+        #[test]
+        fn test_delay_loop()
+        {
+            let mut naive = delay_loop_cpu(LoopAcceleration::Off);
+            let mut accelerated = delay_loop_cpu(LoopAcceleration::On);
+
+            run_until(&mut naive, 0x0208);
+            run_until(&mut accelerated, 0x0208);
+
+            assert_eq!(accelerated.cycles, naive.cycles);
+            assert_eq!(accelerated.registers.x, naive.registers.x);
+            assert_eq!(accelerated.registers.y, naive.registers.y);
+            assert_eq!(accelerated.registers.p.get_byte(), naive.registers.p.get_byte());
+        }
+
+        #[test]
+        fn test_verify_delay_loop()
+        {
+            let mut naive = delay_loop_cpu(LoopAcceleration::Off);
+            let mut verified = delay_loop_cpu(LoopAcceleration::Verify);
+
+            run_until(&mut naive, 0x0208);
+            run_until(&mut verified, 0x0208);
+
+            assert_eq!(verified.cycles, naive.cycles);
+            assert_eq!(verified.loop_prediction.is_none(), true);
+        }
+
+        #[test]
+        fn test_increment_loop_page_boundary_crossed()
+        {
+            let mut cpus = [Cpu::new_dummy(), Cpu::new_dummy()];
+            cpus[1].set_loop_acceleration(LoopAcceleration::On);
+            for cpu in cpus.iter_mut() {
+                cpu.registers.pc = 0x02FE;
+                cpu.registers.x = 0x10;
+                cpu.registers.p.negative = true;
+                cpu.internal_ram[0xFE] = 0xE8;  // INX
+                cpu.internal_ram[0xFF] = 0xD0;  // BNE $02FE
+                cpu.internal_ram[0x100] = 0xFD;
+                cpu.internal_ram[0x101] = 0xEA; // NOP
+                run_until(cpu, 0x0301);
+            }
+
+            assert_eq!(cpus[1].cycles, cpus[0].cycles);
+            assert_eq!(cpus[1].registers.x, 0);
+            assert_eq!(cpus[1].registers.p.get_byte(), cpus[0].registers.p.get_byte());
+        }
+
+        #[test]
+        fn test_not_a_loop()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_loop_acceleration(LoopAcceleration::On);
+            cpu.registers.pc = 0x0200;
+            cpu.registers.x = 0x10;
+            cpu.internal_ram[0x00] = 0xCA; // DEX
+            cpu.internal_ram[0x01] = 0xD0; // BNE $0201
+            cpu.internal_ram[0x02] = 0xFE;
+
+            assert_eq!(cpu.accelerate_loop().is_none(), true);
+            assert_eq!(cpu.registers.x, 0x10);
+        }
+
+        #[test]
+        fn test_nmi_during_delay_loop()
+        {
+            let mut cpus = [delay_loop_cpu(LoopAcceleration::Off), delay_loop_cpu(LoopAcceleration::On)];
+            let mut boundaries = [0; 2];
+            for (cpu, boundaries) in cpus.iter_mut().zip(boundaries.iter_mut()) {
+                // the DummyMapper vector sends the NMI to $0000: INC $10, RTI
+                cpu.zero_page_ram[0x00..0x03].copy_from_slice(&[0xE6, 0x10, 0x40]);
+                cpu.write(0x2000, 0x80);
+                *boundaries = run_until(cpu, 0x0208);
+            }
+            let [naive, accelerated] = &cpus;
+
+            // the loop takes about 51,000 cycles, vblank starts after 27,400
+            assert_eq!(naive.zero_page_ram[0x10], 1);
+            assert_eq!(accelerated.zero_page_ram[0x10], 1);
+            assert_eq!(accelerated.cycles, naive.cycles);
+            assert_eq!(accelerated.registers.to_string(), naive.registers.to_string());
+            // the last NMI pushed the same PC and P, from inside the loop
+            assert_eq!(accelerated.stack[..], naive.stack[..]);
+            let sp = naive.registers.stack_pointer as usize;
+            let pushed_pc = naive.stack[sp - 1] as u16 | (naive.stack[sp] as u16) << 8;
+            assert_eq!((0x0202..=0x0206).contains(&pushed_pc), true);
+            assert_eq!(boundaries[1] < boundaries[0] / 100, true, "{:?}", boundaries);
+        }
+
+        #[test]
+        fn test_loop_not_accelerated_with_nmi_pending()
+        {
+            let mut cpu = delay_loop_cpu(LoopAcceleration::On);
+            cpu.registers.pc = 0x0202;
+            cpu.registers.x = 0x10;
+            cpu.nmi_pending = true;
+
+            assert_eq!(cpu.accelerate_loop(), None);
+            assert_eq!(cpu.registers.x, 0x10);
+        }
+
+        fn polling_loop_cpu(mode: LoopAcceleration, opcode: u8, nmi: bool) -> Cpu
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_loop_acceleration(mode);
+            cpu.registers.pc = 0x0200;
+            cpu.internal_ram[0x00..0x06].copy_from_slice(&[
+                opcode, 0x02, 0x20, // LDA $2002 or BIT $2002
+                0x10, 0xFB,         // BPL $0200
+                0xEA,               // NOP
+            ]);
+            if nmi {
+                // INC $10, RTI
+                cpu.zero_page_ram[0x00..0x03].copy_from_slice(&[0xE6, 0x10, 0x40]);
+                cpu.write(0x2000, 0x80);
+            }
+            cpu
+        }
+
+        #[test]
+        fn test_polling_loop()
+        {
+            for &(opcode, nmi) in &[(0xAD, false), (0x2C, false), (0xAD, true)] {
+                let mut naive = polling_loop_cpu(LoopAcceleration::Off, opcode, nmi);
+                let mut accelerated = polling_loop_cpu(LoopAcceleration::On, opcode, nmi);
+
+                // the loop is entered at a different dot of the frame every time
+                for frame in 0..3 {
+                    let naive_boundaries = run_until(&mut naive, 0x0205);
+                    let accelerated_boundaries = run_until(&mut accelerated, 0x0205);
+
+                    let context = format!("opcode {:02X} nmi {} frame {}", opcode, nmi, frame);
+                    assert_eq!(accelerated.cycles, naive.cycles, "{}", context);
+                    assert_eq!(accelerated.registers.to_string(), naive.registers.to_string(), "{}", context);
+                    assert_eq!(accelerated.zero_page_ram[0x10], naive.zero_page_ram[0x10], "{}", context);
+                    assert_eq!(accelerated_boundaries < naive_boundaries / 100, true, "{}", context);
+                    naive.registers.pc = 0x0200;
+                    accelerated.registers.pc = 0x0200;
+                }
+            }
+        }
+
+        // LDA $2002 polling with NMI off and I set, while a long DMC sample plays
+        #[test]
+        fn test_polling_loop_with_dmc_playing()
+        {
+            let mut cpus = [polling_loop_cpu(LoopAcceleration::Off, 0xAD, false), polling_loop_cpu(LoopAcceleration::On, 0xAD, false)];
+            for cpu in cpus.iter_mut() {
+                cpu.registers.p.interrupt_disable = true;
+                // fastest rate, 4081 bytes from $C000
+                cpu.write(0x4010, 0x0F);
+                cpu.write(0x4012, 0x00);
+                cpu.write(0x4013, 0xFF);
+                cpu.write(0x4015, 0x10);
+            }
+            let [naive, accelerated] = &mut cpus;
+
+            for frame in 0..3 {
+                run_until(naive, 0x0205);
+                run_until(accelerated, 0x0205);
+
+                assert_eq!(accelerated.cycles, naive.cycles, "frame {}", frame);
+                assert_eq!(accelerated.registers.to_string(), naive.registers.to_string(), "frame {}", frame);
+                naive.registers.pc = 0x0200;
+                accelerated.registers.pc = 0x0200;
+            }
+            assert_eq!(accelerated.apu_mut().dmc_active(), true);
+        }
+
+        #[test]
+        fn test_verify_polling_loop()
+        {
+            for &opcode in &[0xAD, 0x2C] {
+                let mut naive = polling_loop_cpu(LoopAcceleration::Off, opcode, false);
+                let mut verified = polling_loop_cpu(LoopAcceleration::Verify, opcode, false);
+
+                run_until(&mut naive, 0x0205);
+                run_until(&mut verified, 0x0205);
+
+                assert_eq!(verified.cycles, naive.cycles);
+                assert_eq!(verified.take_debug_event(), None);
+            }
+
+            let mut cpu = polling_loop_cpu(LoopAcceleration::Verify, 0xAD, false);
+            cpu.clock();
+            let predicted = cpu.loop_prediction.as_ref().map(|prediction| prediction.state);
+            assert_eq!(predicted.map(|state| (state.pc, state.a & 0x80)), Some((0x0200, 0x00)));
+            // X is not touched by the loop
+            cpu.registers.x = 0x01;
+            run_until(&mut cpu, 0x0205);
+
+            match cpu.take_debug_event() {
+                Some(DebugEvent::LoopMismatch { pc: 0x0200, predicted, actual }) => {
+                    assert_eq!((predicted.pc, predicted.x), (actual.pc, 0x00));
+                    assert_eq!(actual.x, 0x01);
+                },
+                event => panic!("{:?}", event),
+            }
+        }
+
+        #[test]
+        fn test_verify_mismatch_is_a_debug_event()
+        {
+            let mut cpu = delay_loop_cpu(LoopAcceleration::Verify);
+            run_until(&mut cpu, 0x0202);
+            cpu.clock();
+            // Y is not part of the inner loop, the prediction kept its value
+            cpu.registers.y = 0x01;
+
+            run_until(&mut cpu, 0x0205);
+            // checked on the boundary
+            cpu.clock();
+
+            match cpu.take_debug_event() {
+                Some(DebugEvent::LoopMismatch { pc: 0x0202, predicted, actual }) => {
+                    assert_eq!((predicted.cycle, predicted.pc, predicted.y), (actual.cycle, 0x0205, 0x28));
+                    assert_eq!(actual.y, 0x01);
+                },
+                event => panic!("{:?}", event),
+            }
+            assert_eq!(cpu.take_debug_event(), None);
+        }
+    }
+
+    mod debug
+    {
+        use super::*;
+        use crate::cpu::cartridge::{
+            NROM,
+            UxROM,
+        };
+        use std::io::{
+            self,
+            Write,
+        };
+        use std::rc::Rc;
+
+        fn nrom_cpu(policy: RomWritePolicy) -> Cpu
+        {
+            let mut cpu = Cpu::new(Box::new(NROM::new(vec![0; 0x4000], vec![], Mirroring::Horizontal)));
+            cpu.set_rom_write_policy(policy);
+            cpu.registers.pc = 0x0200;
+            cpu.registers.a = 0x42;
+            // STA $C000
+            cpu.internal_ram[0x00] = 0x8D;
+            cpu.internal_ram[0x01] = 0x00;
+            cpu.internal_ram[0x02] = 0xC0;
+            cpu
+        }
+
+        #[test]
+        fn test_rom_write_debug_event()
+        {
+            let mut cpu = nrom_cpu(RomWritePolicy::DebugEvent);
+
+            cpu.clock();
+
+            assert_eq!(cpu.take_debug_event(), Some(DebugEvent::RomWrite { pc: 0x0200, address: 0xC000, value: 0x42 }));
+            assert_eq!(cpu.take_debug_event(), None);
+            assert_eq!(cpu.load(0xC000), 0x00);
+        }
+
+        // a text trace kept in memory
+        struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for SharedBuffer
+        {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> { self.0.borrow_mut().write(data) }
+
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        #[test]
+        fn test_rom_write_log()
+        {
+            let mut cpu = nrom_cpu(RomWritePolicy::Log);
+            let buffer = Rc::new(RefCell::new(vec![]));
+            cpu.set_trace_sink(TraceSink::text(Box::new(SharedBuffer(buffer.clone()))));
+
+            cpu.clock();
+            cpu.flush_trace().unwrap();
+
+            let trace = String::from_utf8(buffer.borrow().clone()).unwrap();
+            let lines: Vec<&str> = trace.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert_eq!(lines[0].starts_with("0200  8D 00 C0  STA $C000"), true, "{}", lines[0]);
+            assert_eq!(lines[1], "0200  write to ROM $C000 = 42");
+            assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Log("0200  write to ROM $C000 = 42".to_string())));
+            assert_eq!(cpu.take_debug_event(), None);
+        }
+
+        // the log is kept without a trace, and does not stop a run
+        #[test]
+        fn test_rom_write_log_without_trace()
+        {
+            let mut cpu = nrom_cpu(RomWritePolicy::Log);
+            // JMP $0200
+            cpu.internal_ram[0x03..0x06].copy_from_slice(&[0x4C, 0x00, 0x02]);
+            let end = cpu.cycles + 70;
+
+            assert_eq!(cpu.run_until(&StopCondition::CycleCount(end)), StopReason::CycleCount(end));
+            let mut logs = 0;
+            while let Some(event) = cpu.take_debug_event() {
+                assert_eq!(event, DebugEvent::Log("0200  write to ROM $C000 = 42".to_string()));
+                logs += 1;
+            }
+            // 7 cycles a round
+            assert_eq!(logs, 10);
+        }
+
+        struct FailingWriter;
+
+        impl Write for FailingWriter
+        {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(io::Error::other("disk full")) }
+
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        // the writes fail once the buffer fills, the error waits for flush_trace
+        #[test]
+        fn test_trace_error_kept()
+        {
+            let mut cpu = nrom_cpu(RomWritePolicy::Log);
+            cpu.internal_ram[0x03..0x06].copy_from_slice(&[0x4C, 0x00, 0x02]);
+            cpu.set_trace_sink(TraceSink::text(Box::new(FailingWriter)));
+
+            for _ in 0..1000 {
+                cpu.step();
+            }
+            assert_eq!(cpu.flush_trace().map_err(|error| error.to_string()), Err("disk full".to_string()));
+        }
+
+        #[test]
+        fn test_uxrom_bank_switch_not_a_rom_write()
+        {
+            let prg_rom = (0..4).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+            let mut cpu = Cpu::new(Box::new(UxROM::new(prg_rom, vec![], Mirroring::Vertical, false)));
+            cpu.set_rom_write_policy(RomWritePolicy::DebugEvent);
+            cpu.registers.pc = 0x0200;
+            cpu.registers.a = 0x02;
+            // STA $C000
+            cpu.internal_ram[0x00..0x03].copy_from_slice(&[0x8D, 0x00, 0xC0]);
+
+            cpu.clock();
+
+            assert_eq!(cpu.take_debug_event(), None);
+            assert_eq!((cpu.load(0x8000), cpu.load(0xC000)), (2, 3));
+        }
+
+        #[test]
+        fn test_rom_write_ignored()
+        {
+            let mut cpu = nrom_cpu(RomWritePolicy::Ignore);
+
+            cpu.clock();
+
+            assert_eq!(cpu.take_debug_event(), None);
+            assert_eq!(cpu.load(0xC000), 0x00);
+        }
+
+        #[test]
+        fn test_prg_ram_write()
+        {
+            let mut cpu = nrom_cpu(RomWritePolicy::DebugEvent);
+            cpu.internal_ram[0x02] = 0x60;
+
+            cpu.clock();
+
+            assert_eq!(cpu.take_debug_event(), None);
+            assert_eq!(cpu.load(0x6000), 0x42);
+        }
+
+        // the program runs from $0300, out of the way of the watched $0200
+        fn watch_cpu(program: &[u8], cycle_accurate: bool) -> Cpu
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_cycle_accurate(cycle_accurate);
+            cpu.registers.pc = 0x0300;
+            cpu.internal_ram[0x100..0x100 + program.len()].copy_from_slice(program);
+            cpu
+        }
+
+        #[test]
+        fn test_write_watchpoint()
+        {
+            for &cycle_accurate in [false, true].iter() {
+                // LDA #$42, STA $0200, LDA $0200
+                let mut cpu = watch_cpu(&[0xA9, 0x42, 0x8D, 0x00, 0x02, 0xAD, 0x00, 0x02], cycle_accurate);
+                cpu.add_watchpoint(0x0200, WatchKind::Write);
+
+                cpu.step();
+                assert_eq!(cpu.take_debug_event(), None);
+                cpu.step();
+                assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Write { pc: 0x0302, address: 0x0200, value: 0x42 }));
+                cpu.step();
+                assert_eq!(cpu.take_debug_event(), None);
+            }
+        }
+
+        #[test]
+        fn test_read_watchpoint()
+        {
+            // STA $0200, LDA $0200
+            let mut cpu = watch_cpu(&[0x8D, 0x00, 0x02, 0xAD, 0x00, 0x02], false);
+            cpu.registers.a = 0x42;
+            cpu.add_watchpoint(0x0200, WatchKind::Read);
+
+            cpu.step();
+            assert_eq!(cpu.take_debug_event(), None);
+            cpu.step();
+            assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Read { pc: 0x0303, address: 0x0200, value: 0x42 }));
+            // peeking is not an access
+            cpu.peek(0x0200);
+            assert_eq!(cpu.take_debug_event(), None);
+        }
+
+        #[test]
+        fn test_watchpoint_read_modify_write()
+        {
+            for &cycle_accurate in [false, true].iter() {
+                // INC $0200, with its dummy write of the old value
+                let mut cpu = watch_cpu(&[0xEE, 0x00, 0x02], cycle_accurate);
+                cpu.internal_ram[0x00] = 0x41;
+                cpu.add_watchpoint(0x0200, WatchKind::Any);
+
+                cpu.step();
+
+                assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Read { pc: 0x0300, address: 0x0200, value: 0x41 }));
+                assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Write { pc: 0x0300, address: 0x0200, value: 0x41 }));
+                assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Write { pc: 0x0300, address: 0x0200, value: 0x42 }));
+                assert_eq!(cpu.take_debug_event(), None);
+            }
+        }
+
+        #[test]
+        fn test_remove_watchpoint()
+        {
+            // STA $0200, STA $0200
+            let mut cpu = watch_cpu(&[0x8D, 0x00, 0x02, 0x8D, 0x00, 0x02], false);
+            cpu.add_watchpoint(0x0200, WatchKind::Any);
+            cpu.step();
+            assert!(cpu.take_debug_event().is_some());
+
+            cpu.remove_watchpoint(0x0200);
+            cpu.step();
+            assert_eq!(cpu.take_debug_event(), None);
+        }
+
+        #[test]
+        fn test_breakpoint()
+        {
+            for &cycle_accurate in [false, true].iter() {
+                // LDX #$03, loop: DEX, BNE loop, SEC
+                let mut cpu = watch_cpu(&[0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x38], cycle_accurate);
+                cpu.add_breakpoint(0x0302);
+                let end = StopCondition::PcEquals(0x0306);
+
+                // the DEX has not run yet, and the flags are the ones LDX left
+                assert_eq!(cpu.run_until(&end), StopReason::DebugEvent);
+                assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Breakpoint { pc: 0x0302 }));
+                assert_eq!((cpu.pc(), cpu.x()), (0x0302, 0x03));
+                assert_eq!((cpu.zero(), cpu.negative()), (false, false));
+
+                // taking the event resumes, the next iteration stops again
+                assert_eq!(cpu.run_until(&end), StopReason::DebugEvent);
+                assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Breakpoint { pc: 0x0302 }));
+                assert_eq!((cpu.pc(), cpu.x()), (0x0302, 0x02));
+
+                cpu.remove_breakpoint(0x0302);
+                assert_eq!(cpu.run_until(&end), StopReason::PcEquals(0x0306));
+                assert_eq!((cpu.x(), cpu.zero(), cpu.carry()), (0x00, true, true));
+                assert_eq!(cpu.take_debug_event(), None);
+            }
+        }
+
+        #[test]
+        fn test_breakpoint_step()
+        {
+            // LDX #$03, DEX
+            let mut cpu = watch_cpu(&[0xA2, 0x03, 0xCA], false);
+            cpu.add_breakpoint(0x0302);
+
+            assert_eq!(cpu.step().pc, 0x0300);
+            assert_eq!(cpu.take_debug_event(), Some(DebugEvent::Breakpoint { pc: 0x0302 }));
+            assert_eq!(cpu.step().pc, 0x0302);
+            assert_eq!(cpu.take_debug_event(), None);
+        }
+
+        #[test]
+        fn test_next_branch_target()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.registers.pc = 0x0200;
+            // LDA #$00
+            cpu.internal_ram[0x00] = 0xA9;
+            assert_eq!(cpu.next_branch_target(), None);
+
+            // BNE -$20
+            cpu.internal_ram[0x00] = 0xD0;
+            cpu.internal_ram[0x01] = 0xE0;
+            cpu.registers.p.zero = true;
+            assert_eq!(cpu.next_branch_target(), None);
+            cpu.registers.p.zero = false;
+            assert_eq!(cpu.next_branch_target(), Some(0x01E2));
+
+            // every branch, taken when its flag matches bit 5 of the opcode
+            for (opcode, flag) in [(0x10u8, 0b1000_0000u8), (0x50, 0b0100_0000), (0x90, 0b0000_0001), (0xD0, 0b0000_0010)] {
+                cpu.internal_ram[0x00] = opcode;
+                cpu.internal_ram[0x01] = 0x10;
+                cpu.registers.p.set_byte(0);
+                assert_eq!(cpu.next_branch_target(), Some(0x0212), "{:02X}", opcode);
+                cpu.registers.p.set_byte(flag);
+                assert_eq!(cpu.next_branch_target(), None, "{:02X}", opcode);
+                cpu.internal_ram[0x00] = opcode | 0x20;
+                assert_eq!(cpu.next_branch_target(), Some(0x0212), "{:02X}", opcode | 0x20);
+            }
+        }
+
+        #[test]
+        fn test_next_branch_target_agrees_with_execution()
+        {
+            for opcode in [0x10u8, 0x30, 0x50, 0x70, 0x90, 0xB0, 0xD0, 0xF0] {
+                for status in [0x00u8, 0xFF] {
+                    let mut cpu = Cpu::new_dummy();
+                    cpu.registers.pc = 0x0200;
+                    cpu.internal_ram[0x00] = opcode;
+                    cpu.internal_ram[0x01] = 0x80;
+                    cpu.registers.p.set_byte(status);
+                    let target = cpu.next_branch_target();
+
+                    let opcode = cpu.fetch();
+                    cpu.execute_instruction(opcode);
+                    assert_eq!(target.unwrap_or(0x0202), cpu.registers.pc);
+                }
+            }
+        }
+    }
+
+    mod disassembler
+    {
+        use super::*;
+        use crate::cpu::disassembler::instruction_length;
+
+        // the program is at $0200
+        fn dummy_cpu(program: &[u8]) -> Cpu
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.internal_ram[..program.len()].copy_from_slice(program);
+            cpu
+        }
+
+        #[test]
+        fn test_instruction_length()
+        {
+            assert_eq!(instruction_length(0xEA), 1);
+            assert_eq!(instruction_length(0x0A), 1);
+            assert_eq!(instruction_length(0xA9), 2);
+            assert_eq!(instruction_length(0xD0), 2);
+            assert_eq!(instruction_length(0xB1), 2);
+            assert_eq!(instruction_length(0x6C), 3);
+            assert_eq!(instruction_length(0xBF), 3);
+        }
+
+        #[test]
+        fn test_memory_operands()
+        {
+            let mut cpu = dummy_cpu(&[0xB5, 0x10, 0xAD, 0x00, 0x03, 0xBD, 0xFF, 0x02, 0x20, 0x00, 0x90]);
+            cpu.registers.x = 0x02;
+            cpu.zero_page_ram[0x12] = 0x34;
+            cpu.internal_ram[0x0100] = 0x56;
+            cpu.internal_ram[0x0101] = 0x78;
+
+            assert_eq!(disassemble(&cpu, 0x0200), "LDA $10,X @ 12 = 34");
+            assert_eq!(disassemble(&cpu, 0x0202), "LDA $0300 = 56");
+            assert_eq!(disassemble(&cpu, 0x0205), "LDA $02FF,X @ 0301 = 78");
+            // jumps show no value
+            assert_eq!(disassemble(&cpu, 0x0208), "JSR $9000");
+        }
+
+        #[test]
+        fn test_indirect_operands()
+        {
+            let mut cpu = dummy_cpu(&[0xA1, 0xFE, 0xB1, 0xFF, 0x6C, 0xFF, 0x03]);
+            cpu.registers.x = 0x01;
+            cpu.registers.y = 0x04;
+            // ($FE,X) and ($FF),Y both read their pointer at $FF and $00
+            cpu.zero_page_ram[0x00] = 0x03;
+            cpu.zero_page_ram[0xFF] = 0x00;
+            cpu.internal_ram[0x0100] = 0x11;
+            cpu.internal_ram[0x0104] = 0x22;
+            // JMP ($03FF) takes the high byte from $0300
+            cpu.internal_ram[0x01FF] = 0x34;
+
+            assert_eq!(disassemble(&cpu, 0x0200), "LDA ($FE,X) @ FF = 0300 = 11");
+            assert_eq!(disassemble(&cpu, 0x0202), "LDA ($FF),Y = 0300 @ 0304 = 22");
+            assert_eq!(disassemble(&cpu, 0x0204), "JMP ($03FF) = 1134");
+        }
+
+        #[test]
+        fn test_other_modes()
+        {
+            let cpu = dummy_cpu(&[0xEA, 0x0A, 0xA2, 0x7F, 0xF0, 0xFA, 0xA7, 0x10, 0x80, 0x01]);
+
+            assert_eq!(disassemble(&cpu, 0x0200), "NOP");
+            assert_eq!(disassemble(&cpu, 0x0201), "ASL A");
+            assert_eq!(disassemble(&cpu, 0x0202), "LDX #$7F");
+            assert_eq!(disassemble(&cpu, 0x0204), "BEQ $0200");
+            assert_eq!(disassemble(&cpu, 0x0206), "*LAX $10 = 00");
+            assert_eq!(disassemble(&cpu, 0x0208), "*NOP #$01");
+        }
+
+        #[test]
+        fn test_registers_are_not_read()
+        {
+            // LDA $2007 would move the VRAM address
+            let mut cpu = dummy_cpu(&[0xAD, 0x07, 0x20, 0xAD, 0x15, 0x40]);
+            for (address, data) in [(0x2006, 0x20), (0x2006, 0x00), (0x2007, 0x42), (0x2006, 0x20), (0x2006, 0x00)] {
+                cpu.write(address, data);
+            }
+
+            assert_eq!(disassemble(&cpu, 0x0200), "LDA $2007 = FF");
+            assert_eq!(disassemble(&cpu, 0x0203), "LDA $4015 = FF");
+            // the read buffer is only filled by the first real read of $2000
+            assert_eq!([cpu.load(0x2007), cpu.load(0x2007)], [0x00, 0x42]);
+        }
+
+        #[test]
+        fn test_no_read_past_instruction()
+        {
+            let mut cpu = dummy_cpu(&[0xE8, 0xFF, 0xFF]);
+            cpu.registers.pc = 0x0200;
+
+            let record = cpu.trace_record();
+            assert_eq!(record.operands, [0x00, 0x00]);
+            assert!(record.to_string().starts_with("0200  E8        INX "));
+        }
     }
-}
\ No newline at end of file
+
+    mod trace
+    {
+        use super::*;
+        use std::env;
+        use std::fs::{self, File};
+        use crate::cpu::cartridge::load_cartridge;
+        use crate::cpu::trace::{
+            TraceRecord,
+            FlightRecorder,
+            BinaryTraceReader,
+            TRACE_RECORD_SIZE,
+        };
+
+        #[test]
+        fn test_record_bytes()
+        {
+            let record = TraceRecord {
+                pc: 0xC5F5,
+                opcode: 0xA2,
+                operands: [0x00, 0x86],
+                a: 0x01,
+                x: 0x02,
+                y: 0x03,
+                p: 0x24,
+                sp: 0xFD,
+                effective_address: 0x0000,
+                value: 0x00,
+                scanline: 241,
+                dot: 7,
+                cycles: 0x0123_4567_89AB,
+            };
+
+            assert_eq!(TraceRecord::from_bytes(&record.to_bytes()), record);
+            // only the bytes of the instruction are shown
+            assert_eq!(
+                record.to_string(),
+                "C5F5  A2 00     LDX #$00                        A:01 X:02 Y:03 P:24 SP:FD PPU:241,  7 CYC:1250999896491"
+            );
+        }
+
+        #[test]
+        fn test_branch_target_formatting()
+        {
+            let branch = |pc: u16, opcode: u8, offset: u8| TraceRecord {
+                pc,
+                opcode,
+                operands: [offset, 0x00],
+                a: 0x00,
+                x: 0x00,
+                y: 0x00,
+                p: 0x24,
+                sp: 0xFD,
+                effective_address: crate::cpu::branch_target(offset, pc.wrapping_add(2)),
+                value: 0x00,
+                scanline: 0,
+                dot: 21,
+                cycles: 7,
+            }.to_string();
+
+            // forward and backward, from nestest.log
+            assert_eq!(&branch(0xC72F, 0xB0, 0x04)[16..25], "BCS $C735");
+            assert_eq!(&branch(0xC72A, 0xD0, 0xE0)[16..25], "BNE $C70C");
+            // wrapping around the address space
+            assert_eq!(&branch(0xFFF0, 0xF0, 0x7F)[16..25], "BEQ $0071");
+            assert_eq!(&branch(0x0010, 0x10, 0x80)[16..25], "BPL $FF92");
+            // the register columns do not move
+            assert_eq!(&branch(0xC72A, 0xD0, 0xE0)[48..52], "A:00");
+        }
+
+        #[test]
+        fn test_truncated_binary_trace()
+        {
+            let bytes = [0u8; TRACE_RECORD_SIZE + 3];
+            let records: Vec<_> = BinaryTraceReader::new(&bytes[..]).collect();
+
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].is_ok(), true);
+            assert_eq!(records[1].is_err(), true);
+        }
+
+        fn run_nestest(sink: TraceSink, instructions: u32)
+        {
+            let mut cpu = Cpu::new(load_cartridge("rom_tests/nestest/nestest.nes").unwrap());
+            cpu.set_pc(0xC000);
+            cpu.set_trace_sink(sink);
+            let mut executed = 0;
+            while executed < instructions {
+                if cpu.wait_cycles == 0 {
+                    executed += 1;
+                }
+                cpu.clock();
+            }
+            cpu.flush_trace().unwrap();
+        }
+
+        #[test]
+        fn test_binary_trace_decodes_to_text_trace()
+        {
+            let dir = env::temp_dir();
+            let text_path = dir.join(format!("nesquick-trace-{}.log", std::process::id()));
+            let binary_path = dir.join(format!("nesquick-trace-{}.bin", std::process::id()));
+
+            run_nestest(TraceSink::text(Box::new(File::create(&text_path).unwrap())), 10_000);
+            run_nestest(TraceSink::binary(Box::new(File::create(&binary_path).unwrap())), 10_000);
+            let mut decoded = Vec::new();
+            decode_binary_trace(File::open(&binary_path).unwrap(), &mut decoded).unwrap();
+            let text = fs::read(&text_path).unwrap();
+            fs::remove_file(&text_path).unwrap();
+            fs::remove_file(&binary_path).unwrap();
+
+            assert_eq!(text.iter().filter(|&&byte| byte == b'\n').count(), 10_000);
+            assert_eq!(decoded, text);
+        }
+
+        #[test]
+        fn test_flight_recorder_keeps_last_records()
+        {
+            let text_path = env::temp_dir().join(format!("nesquick-flight-recorder-{}.log", std::process::id()));
+            let mut cpu = Cpu::new(load_cartridge("rom_tests/nestest/nestest.nes").unwrap());
+            cpu.set_pc(0xC000);
+            cpu.set_trace_sink(TraceSink::text(Box::new(File::create(&text_path).unwrap())));
+            cpu.set_flight_recorder(1000);
+            let mut executed = 0;
+            while executed < 1500 {
+                if cpu.wait_cycles == 0 {
+                    executed += 1;
+                }
+                cpu.clock();
+            }
+            cpu.flush_trace().unwrap();
+            let text = fs::read_to_string(&text_path).unwrap();
+            fs::remove_file(&text_path).unwrap();
+
+            let recorder = cpu.flight_recorder().unwrap();
+            let mut exported = Vec::new();
+            recorder.export_text(&mut exported).unwrap();
+            let last_lines: Vec<_> = text.lines().skip(500).collect();
+
+            assert_eq!(recorder.len(), 1000);
+            assert_eq!(String::from_utf8(exported).unwrap().lines().collect::<Vec<_>>(), last_lines);
+            let cycles: Vec<_> = recorder.records().map(|record| record.cycles).collect();
+            assert_eq!(cycles.windows(2).all(|pair| pair[0] < pair[1]), true);
+        }
+
+        #[test]
+        fn test_flight_recorder_binary_export()
+        {
+            let mut recorder = FlightRecorder::new(3);
+            let mut record = TraceRecord {
+                pc: 0,
+                opcode: 0xEA,
+                operands: [0, 0],
+                a: 0,
+                x: 0,
+                y: 0,
+                p: 0x24,
+                sp: 0xFD,
+                effective_address: 0,
+                value: 0,
+                scanline: 0,
+                dot: 0,
+                cycles: 0,
+            };
+            for pc in 0..5 {
+                record.pc = pc;
+                recorder.record(&record);
+            }
+            let mut exported = Vec::new();
+            recorder.export_binary(&mut exported).unwrap();
+            let pcs: Vec<_> = BinaryTraceReader::new(&exported[..]).map(|record| record.unwrap().pc).collect();
+
+            assert_eq!(pcs, vec![2, 3, 4]);
+            assert_eq!(FlightRecorder::new(0).records().count(), 0);
+        }
+    }
+
+    mod registers
+    {
+        use super::*;
+
+        #[test]
+        fn test_status_byte_round_trip()
+        {
+            let mut cpu = Cpu::new_dummy();
+            for status in 0..=0xFF {
+                cpu.set_status_byte(status);
+                // B is dropped and the unused bit is set, as with PLP then PHP
+                assert_eq!(cpu.status_byte(), status & 0b1100_1111 | 0b0010_0000, "status {:02X}", status);
+            }
+        }
+
+        #[test]
+        fn test_flags_in_status_byte()
+        {
+            type Getter = fn(&Cpu) -> bool;
+            type Setter = fn(&mut Cpu, bool);
+            let flags: [(u8, Getter, Setter); 6] = [
+                (0b0000_0001, Cpu::carry, Cpu::set_carry),
+                (0b0000_0010, Cpu::zero, Cpu::set_zero),
+                (0b0000_0100, Cpu::interrupt_disable, Cpu::set_interrupt_disable),
+                (0b0000_1000, Cpu::decimal, Cpu::set_decimal),
+                (0b0100_0000, Cpu::overflow, Cpu::set_overflow),
+                (0b1000_0000, Cpu::negative, Cpu::set_negative),
+            ];
+            for &(bit, get, set) in flags.iter() {
+                let mut cpu = Cpu::new_dummy();
+                cpu.set_status_byte(0x00);
+                set(&mut cpu, true);
+                assert!(get(&cpu));
+                assert_eq!(cpu.status_byte(), 0b0010_0000 | bit);
+
+                cpu.set_status_byte(0xFF);
+                set(&mut cpu, false);
+                assert!(!get(&cpu));
+                assert_eq!(cpu.status_byte(), 0b1110_1111 & !bit);
+
+                cpu.set_status_byte(bit);
+                assert!(get(&cpu));
+                cpu.set_status_byte(!bit);
+                assert!(!get(&cpu));
+            }
+        }
+
+        #[test]
+        fn test_php_plp_match_status_byte()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.registers.pc = 0x0200;
+            // PHP, PLP
+            cpu.internal_ram[0..2].copy_from_slice(&[0x08, 0x28]);
+            cpu.set_status_byte(0b1100_0011);
+
+            cpu.execute_instruction(0x08);
+            assert_eq!(cpu.peek(0x0100 | (cpu.stack_pointer() as u16 + 1)), cpu.status_byte() | 0b0001_0000);
+
+            cpu.execute_instruction(0x28);
+            assert_eq!(cpu.status_byte(), 0b1110_0011);
+        }
+
+        #[test]
+        fn test_setters()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_a(0x12);
+            cpu.set_x(0x34);
+            cpu.set_y(0x56);
+            cpu.set_stack_pointer(0x78);
+            cpu.set_pc(0x9ABC);
+            assert_eq!((cpu.a(), cpu.x(), cpu.y(), cpu.stack_pointer(), cpu.pc()), (0x12, 0x34, 0x56, 0x78, 0x9ABC));
+        }
+
+        #[test]
+        fn test_display()
+        {
+            let mut cpu = Cpu::new_dummy();
+            assert_eq!(cpu.registers().to_string(), "A:00 X:00 Y:00 P:24 SP:FD");
+
+            cpu.set_a(0xC0);
+            cpu.set_x(0x0A);
+            cpu.set_y(0xFF);
+            cpu.set_stack_pointer(0x01);
+            cpu.set_status_byte(0xFF);
+            assert_eq!(cpu.registers().to_string(), "A:C0 X:0A Y:FF P:EF SP:01");
+        }
+    }
+
+    mod flag_instructions
+    {
+        use super::*;
+
+        // opcode, affected flag, flag value afterwards
+        const FLAG_INSTRUCTIONS: [(u8, u8, bool); 7] = [
+            (0x18, 0b0000_0001, false), // CLC
+            (0x38, 0b0000_0001, true),  // SEC
+            (0x58, 0b0000_0100, false), // CLI
+            (0x78, 0b0000_0100, true),  // SEI
+            (0xB8, 0b0100_0000, false), // CLV
+            (0xD8, 0b0000_1000, false), // CLD
+            (0xF8, 0b0000_1000, true),  // SED
+        ];
+
+        const OFFICIAL_OPCODES: [u8; 151] = [
+            0x00, 0x01, 0x05, 0x06, 0x08, 0x09, 0x0A, 0x0D, 0x0E, 0x10, 0x11, 0x15, 0x16, 0x18, 0x19, 0x1D, 0x1E,
+            0x20, 0x21, 0x24, 0x25, 0x26, 0x28, 0x29, 0x2A, 0x2C, 0x2D, 0x2E, 0x30, 0x31, 0x35, 0x36, 0x38, 0x39, 0x3D, 0x3E,
+            0x40, 0x41, 0x45, 0x46, 0x48, 0x49, 0x4A, 0x4C, 0x4D, 0x4E, 0x50, 0x51, 0x55, 0x56, 0x58, 0x59, 0x5D, 0x5E,
+            0x60, 0x61, 0x65, 0x66, 0x68, 0x69, 0x6A, 0x6C, 0x6D, 0x6E, 0x70, 0x71, 0x75, 0x76, 0x78, 0x79, 0x7D, 0x7E,
+            0x81, 0x84, 0x85, 0x86, 0x88, 0x8A, 0x8C, 0x8D, 0x8E, 0x90, 0x91, 0x94, 0x95, 0x96, 0x98, 0x99, 0x9A, 0x9D,
+            0xA0, 0xA1, 0xA2, 0xA4, 0xA5, 0xA6, 0xA8, 0xA9, 0xAA, 0xAC, 0xAD, 0xAE, 0xB0, 0xB1, 0xB4, 0xB5, 0xB6, 0xB8, 0xB9, 0xBA, 0xBC, 0xBD, 0xBE,
+            0xC0, 0xC1, 0xC4, 0xC5, 0xC6, 0xC8, 0xC9, 0xCA, 0xCC, 0xCD, 0xCE, 0xD0, 0xD1, 0xD5, 0xD6, 0xD8, 0xD9, 0xDD, 0xDE,
+            0xE0, 0xE1, 0xE4, 0xE5, 0xE6, 0xE8, 0xE9, 0xEA, 0xEC, 0xED, 0xEE, 0xF0, 0xF1, 0xF5, 0xF6, 0xF8, 0xF9, 0xFD, 0xFE,
+        ];
+
+        // ADC, SBC, BIT, PLP, RTI and CLV
+        const OVERFLOW_OPCODES: [u8; 21] = [
+            0x61, 0x65, 0x69, 0x6D, 0x71, 0x75, 0x79, 0x7D,
+            0xE1, 0xE5, 0xE9, 0xED, 0xF1, 0xF5, 0xF9, 0xFD,
+            0x24, 0x2C, 0x28, 0x40, 0xB8,
+        ];
+
+        fn flag_cpu(status: u8) -> Cpu
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.registers.pc = 0x0200;
+            cpu.registers.a = 0x11;
+            cpu.registers.x = 0x22;
+            cpu.registers.y = 0x33;
+            cpu.registers.stack_pointer = 0xF0;
+            cpu.registers.p.set_byte(status);
+            for (i, byte) in cpu.internal_ram.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+            for (i, byte) in cpu.zero_page_ram.iter_mut().enumerate() {
+                *byte = !(i as u8);
+            }
+            cpu
+        }
+
+        #[test]
+        fn test_flag_isolation()
+        {
+            for &(opcode, flag, value) in FLAG_INSTRUCTIONS.iter() {
+                for &status in [0b0010_0000, 0b1110_1111].iter() {
+                    let mut cpu = flag_cpu(status);
+                    let internal_ram = cpu.internal_ram;
+                    let zero_page_ram = cpu.zero_page_ram;
+                    let stack = cpu.stack;
+
+                    let wait_cycles = cpu.execute_instruction(opcode);
+
+                    let expected = if value {status | flag} else {status & !flag};
+                    assert_eq!(cpu.registers.p.get_byte(), expected, "opcode {:02X}", opcode);
+                    assert_eq!(cpu.registers.a, 0x11);
+                    assert_eq!(cpu.registers.x, 0x22);
+                    assert_eq!(cpu.registers.y, 0x33);
+                    assert_eq!(cpu.registers.stack_pointer, 0xF0);
+                    assert_eq!(cpu.registers.pc, 0x0200);
+                    assert_eq!(cpu.internal_ram[..], internal_ram[..]);
+                    assert_eq!(cpu.zero_page_ram[..], zero_page_ram[..]);
+                    assert_eq!(cpu.stack[..], stack[..]);
+                    assert_eq!(wait_cycles, 2);
+                }
+            }
+        }
+
+        #[test]
+        fn test_flag_instructions_clocked()
+        {
+            let mut cpu = flag_cpu(0);
+            for (i, &(opcode, _, _)) in FLAG_INSTRUCTIONS.iter().enumerate() {
+                cpu.internal_ram[i] = opcode;
+            }
+            let start = cpu.cycles;
+
+            while cpu.registers.pc != 0x0207 || cpu.wait_cycles != 0 {
+                cpu.clock();
+            }
+
+            assert_eq!(cpu.cycles - start, 14);
+            assert_eq!(cpu.registers.p.get_byte(), 0b0010_1101);
+        }
+
+        #[test]
+        fn test_decimal_flag_ignored_by_adc()
+        {
+            let mut cpu = flag_cpu(0);
+            cpu.registers.a = 0x09;
+            cpu.internal_ram[0x00] = 0xF8; // SED
+            cpu.internal_ram[0x01] = 0x69; // ADC #$01
+            cpu.internal_ram[0x02] = 0x01;
+
+            while cpu.registers.pc != 0x0203 || cpu.wait_cycles != 0 {
+                cpu.clock();
+            }
+
+            assert_eq!(cpu.registers.p.decimal, true);
+            assert_eq!(cpu.registers.a, 0x0A);
+        }
+
+        #[test]
+        fn test_overflow_only_changed_by_documented_instructions()
+        {
+            for &opcode in OFFICIAL_OPCODES.iter().filter(|opcode| !OVERFLOW_OPCODES.contains(opcode)) {
+                for &overflow in [false, true].iter() {
+                    let mut cpu = flag_cpu(0);
+                    cpu.registers.p.overflow = overflow;
+
+                    cpu.execute_instruction(opcode);
+
+                    assert_eq!(cpu.registers.p.overflow, overflow, "opcode {:02X}", opcode);
+                }
+            }
+        }
+    }
+
+    mod decimal_mode
+    {
+        use super::*;
+
+        // a, operand, carry in, then a, carry, negative, overflow and zero out
+        fn arithmetic(variant: CpuVariant, opcode: u8, a: u8, operand: u8, carry: bool) -> (u8, bool, bool, bool, bool)
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_variant(variant);
+            cpu.registers.pc = 0x0200;
+            cpu.internal_ram[0x00] = operand;
+            cpu.registers.a = a;
+            cpu.set_decimal(true);
+            cpu.set_carry(carry);
+
+            cpu.execute_instruction(opcode);
+
+            assert_eq!(cpu.decimal(), true);
+            (cpu.a(), cpu.carry(), cpu.negative(), cpu.overflow(), cpu.zero())
+        }
+
+        #[test]
+        fn test_adc()
+        {
+            let vectors = [
+                (0x12, 0x34, false, (0x46, false, false, false, false)),
+                (0x15, 0x26, false, (0x41, false, false, false, false)),
+                (0x81, 0x92, false, (0x73, true, false, true, false)),
+                (0x58, 0x46, true, (0x05, true, true, true, false)),
+                // N is set from $A0, before the high digit is adjusted, Z from the binary $9A
+                (0x99, 0x01, false, (0x00, true, true, false, false)),
+                (0x99, 0x00, true, (0x00, true, true, false, false)),
+                (0x79, 0x00, true, (0x80, false, true, true, false)),
+                // Z from the binary sum
+                (0x50, 0xB0, false, (0x60, true, false, false, true)),
+            ];
+            for &(a, operand, carry, expected) in vectors.iter() {
+                assert_eq!(arithmetic(CpuVariant::Nmos6502, 0x69, a, operand, carry), expected, "{:02X} + {:02X} + {}", a, operand, carry as u8);
+            }
+        }
+
+        #[test]
+        fn test_sbc()
+        {
+            // the flags are the binary ones
+            let vectors = [
+                (0x46, 0x12, true, (0x34, true, false, false, false)),
+                (0x40, 0x13, true, (0x27, true, false, false, false)),
+                (0x32, 0x02, false, (0x29, true, false, false, false)),
+                (0x12, 0x21, true, (0x91, false, true, false, false)),
+                (0x21, 0x34, true, (0x87, false, true, false, false)),
+                (0x00, 0x01, true, (0x99, false, true, false, false)),
+                (0x50, 0x50, true, (0x00, true, false, false, true)),
+            ];
+            for &(a, operand, carry, expected) in vectors.iter() {
+                assert_eq!(arithmetic(CpuVariant::Nmos6502, 0xE9, a, operand, carry), expected, "{:02X} - {:02X} - {}", a, operand, !carry as u8);
+            }
+        }
+
+        #[test]
+        fn test_nes_ignores_decimal()
+        {
+            assert_eq!(arithmetic(CpuVariant::Ricoh2A03, 0x69, 0x99, 0x01, false), (0x9A, false, true, false, false));
+            assert_eq!(arithmetic(CpuVariant::Ricoh2A03, 0x69, 0x09, 0x01, false), (0x0A, false, false, false, false));
+            assert_eq!(arithmetic(CpuVariant::Ricoh2A03, 0xE9, 0x10, 0x01, true), (0x0F, true, false, false, false));
+        }
+
+        #[test]
+        fn test_binary_without_decimal_flag()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_variant(CpuVariant::Nmos6502);
+            cpu.registers.pc = 0x0200;
+            cpu.internal_ram[0x00] = 0x01;
+            cpu.registers.a = 0x09;
+            cpu.set_decimal(false);
+
+            cpu.execute_instruction(0x69);
+
+            assert_eq!(cpu.a(), 0x0A);
+        }
+
+        #[test]
+        fn test_decimal_flag_pushed_and_pulled()
+        {
+            for &variant in [CpuVariant::Ricoh2A03, CpuVariant::Nmos6502].iter() {
+                let mut cpu = Cpu::new_dummy();
+                cpu.set_variant(variant);
+                cpu.registers.pc = 0x0200;
+                // SED, PHP, CLD, PLP
+                cpu.internal_ram[0x00..0x04].copy_from_slice(&[0xF8, 0x08, 0xD8, 0x28]);
+
+                cpu.step();
+                cpu.step();
+                assert_eq!(cpu.top() & 0b0000_1000, 0b0000_1000);
+                cpu.step();
+                assert_eq!(cpu.decimal(), false);
+                cpu.step();
+                assert_eq!(cpu.decimal(), true);
+            }
+        }
+    }
+
+    mod nestest
+    {
+        use super::*;
+        use std::io;
+        use crate::cpu::cartridge::load_cartridge_from_reader;
+
+        #[test]
+        fn test_reference_log()
+        {
+            let rom = fixture_or_skip!("nestest/nestest.nes");
+            let log = String::from_utf8(fixture_or_skip!("nestest/nestest.log.txt")).unwrap();
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+            cpu.set_pc(0xC000);
+            cpu.set_trace_sink(TraceSink::text(Box::new(io::sink())));
+
+            // byte for byte, disassembly and PPU position included
+            for (i, line) in log.lines().enumerate() {
+                assert_eq!(cpu.trace_record().to_string(), line, "nestest.log line {}", i + 1);
+
+                cpu.clock();
+                while cpu.wait_cycles != 0 {
+                    cpu.clock();
+                }
+            }
+        }
+
+        #[test]
+        fn test_step_matches_clock()
+        {
+            let rom = fixture_or_skip!("nestest/nestest.nes");
+            let log = String::from_utf8(fixture_or_skip!("nestest/nestest.log.txt")).unwrap();
+            let instructions = log.lines().count();
+            let nestest_cpu = || {
+                let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+                cpu.set_pc(0xC000);
+                cpu
+            };
+
+            let mut clocked = nestest_cpu();
+            for _ in 0..instructions {
+                clocked.clock();
+                while clocked.wait_cycles != 0 {
+                    clocked.clock();
+                }
+            }
+            let mut stepped = nestest_cpu();
+            let start = stepped.cycles;
+            let step_cycles: u64 = (0..instructions).map(|_| stepped.step().cycles).sum();
+
+            assert_eq!(stepped.cycles, clocked.cycles);
+            assert_eq!(step_cycles, stepped.cycles - start);
+            assert_eq!(stepped.registers.pc, clocked.registers.pc);
+        }
+
+        // one bus access per cycle, and still the same trace as the reference
+        #[test]
+        fn test_cycle_accurate_trace()
+        {
+            let rom = fixture_or_skip!("nestest/nestest.nes");
+            let log = String::from_utf8(fixture_or_skip!("nestest/nestest.log.txt")).unwrap();
+            let trace = |cycle_accurate: bool| {
+                let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+                cpu.set_pc(0xC000);
+                cpu.set_cycle_accurate(cycle_accurate);
+                let lines: Vec<String> = log.lines().map(|_| {
+                    let line = cpu.trace_record().to_string();
+                    cpu.step();
+                    line
+                }).collect();
+                (lines, cpu.cycles, cpu.load(0x0002), cpu.load(0x0003))
+            };
+
+            let accurate = trace(true);
+            assert_eq!(accurate, trace(false));
+            for (i, (line, expected)) in accurate.0.iter().zip(log.lines()).enumerate() {
+                assert_eq!(line, expected, "nestest.log line {}", i + 1);
+            }
+        }
+
+        // the trace lines of the instructions started in the next cycles
+        fn trace_cycles(cpu: &mut Cpu, cycles: u64) -> Vec<String>
+        {
+            let end = cpu.cycles + cycles;
+            let mut lines = vec![];
+            while cpu.cycles < end {
+                if cpu.at_instruction_boundary() {
+                    lines.push(cpu.trace_record().to_string());
+                }
+                cpu.clock();
+            }
+            lines
+        }
+
+        #[test]
+        fn test_save_state_replay()
+        {
+            let rom = fixture_or_skip!("nestest/nestest.nes");
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+            cpu.set_pc(0xC000);
+            // in the middle of an instruction
+            for _ in 0..10_001 {
+                cpu.clock();
+            }
+
+            let state = cpu.save_state();
+            let first = trace_cycles(&mut cpu, 5_000);
+            cpu.load_state(&state).unwrap();
+            let second = trace_cycles(&mut cpu, 5_000);
+
+            assert!(first.len() > 1000);
+            assert_eq!(first, second);
+        }
+
+        // nestest leaves the number of the first failed official test at $02, unofficial at $03
+        #[test]
+        fn test_result_codes()
+        {
+            let rom = fixture_or_skip!("nestest/nestest.nes");
+            let profile = crate::rom_profiles::find_profile(&rom).unwrap();
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+            cpu.set_pc(profile.entry.unwrap());
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.run_until(&(profile.stop)());
+
+            // the last RTS of the log, back to the caller of the test menu
+            assert_eq!(cpu.registers.pc, 0xC66E);
+            assert_eq!(cpu.load(0x0002), 0x00);
+            assert_eq!(cpu.load(0x0003), 0x00);
+        }
+    }
+
+    mod cartridge
+    {
+        use std::io::{
+            self,
+            Read,
+        };
+        use crate::cpu::cartridge::{
+            BankedMemory,
+            CartridgeError,
+            CartridgeHeader,
+            MMC1,
+            MMC3,
+            NROM,
+            Mapper,
+            Mirroring,
+            load_cartridge_from_reader,
+        };
+        use crate::save_state::StateWriter;
+        use crate::cpu::{
+            Cpu,
+            StopCondition,
+            TraceSink,
+        };
+
+        // counts how many bytes each position of the input was handed out
+        struct CountingReader<'a>
+        {
+            data: &'a [u8],
+            position: usize,
+            reads: Vec<u32>,
+        }
+
+        impl Read for CountingReader<'_>
+        {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+            {
+                let n = buf.len().min(self.data.len() - self.position);
+                buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+                for count in &mut self.reads[self.position..self.position + n] {
+                    *count += 1;
+                }
+                self.position += n;
+                Ok(n)
+            }
+        }
+
+        fn ines(prg_banks: u8, chr_banks: u8, trainer: bool) -> Vec<u8>
+        {
+            let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, chr_banks, if trainer {0b0000_0100} else {0}, 0];
+            rom.resize(16, 0);
+            if trainer {
+                rom.resize(rom.len() + 512, 0xEE);
+            }
+            let prg_start = rom.len();
+            rom.resize(prg_start + prg_banks as usize * 0x4000, 0);
+            rom.resize(rom.len() + chr_banks as usize * 0x2000, 0);
+            // reset vector at $FFFC, mirrored for a single bank
+            rom[prg_start + prg_banks as usize * 0x4000 - 4] = 0x34;
+            rom[prg_start + prg_banks as usize * 0x4000 - 3] = 0x12;
+            rom
+        }
+
+        #[test]
+        fn test_each_byte_read_once()
+        {
+            for (prg_banks, chr_banks, trainer) in [(1, 1, false), (2, 1, true), (2, 0, false)] {
+                let rom = ines(prg_banks, chr_banks, trainer);
+                let mut reader = CountingReader {data: &rom, position: 0, reads: vec![0; rom.len()]};
+                let cartridge = load_cartridge_from_reader(&mut reader).unwrap();
+
+                assert_eq!(reader.reads.iter().all(|&count| count == 1), true);
+                assert_eq!(cartridge.read(0xFFFC), Some(0x34));
+                assert_eq!(cartridge.read(0xFFFD), Some(0x12));
+            }
+        }
+
+        #[test]
+        fn test_truncated_sections()
+        {
+            let rom = ines(2, 1, false);
+
+            match load_cartridge_from_reader(&rom[..0x10 + 0x5000]) {
+                Err(CartridgeError::TruncatedPrgRom { expected, got }) => assert_eq!((expected, got), (0x8000, 0x5000)),
+                _ => panic!("expected a truncated PRG ROM"),
+            }
+            match load_cartridge_from_reader(&rom[..0x10 + 0x8000 + 0x100]) {
+                Err(CartridgeError::TruncatedChrRom { expected, got }) => assert_eq!((expected, got), (0x2000, 0x100)),
+                _ => panic!("expected a truncated CHR ROM"),
+            }
+        }
+
+        #[test]
+        fn test_bad_magic()
+        {
+            let mut rom = ines(1, 1, false);
+            rom[3] = 0x00;
+
+            match load_cartridge_from_reader(&rom[..]) {
+                Err(CartridgeError::BadMagic) => {},
+                _ => panic!("expected a bad magic error"),
+            }
+        }
+
+        #[test]
+        fn test_missing_file_is_io_error()
+        {
+            match crate::cpu::cartridge::load_cartridge("rom_tests/missing.nes") {
+                Err(CartridgeError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+                _ => panic!("expected an io error"),
+            }
+        }
+
+        #[test]
+        fn test_load_from_bytes()
+        {
+            let cartridge = crate::cpu::load_cartridge_from_bytes(&ines(1, 0, false)).unwrap();
+
+            assert_eq!(cartridge.read(0xFFFC), Some(0x34));
+            assert_eq!(cartridge.read(0xFFFD), Some(0x12));
+        }
+
+        #[test]
+        fn test_truncated_header()
+        {
+            match load_cartridge_from_reader(&ines(1, 1, false)[..10]) {
+                Err(CartridgeError::TruncatedHeader) => {},
+                _ => panic!("expected a truncated header"),
+            }
+        }
+
+        #[test]
+        fn test_trainer_skipped()
+        {
+            let mut rom = ines(1, 0, true);
+            rom[0x10 + 0x200] = 0x42;
+            let cartridge = load_cartridge_from_reader(&rom[..]).unwrap();
+
+            assert_eq!(cartridge.read(0x8000), Some(0x42));
+            match load_cartridge_from_reader(&rom[..0x10 + 0x100]) {
+                Err(CartridgeError::TruncatedTrainer) => {},
+                _ => panic!("expected a truncated trainer"),
+            }
+        }
+
+        #[test]
+        fn test_unsupported_mapper()
+        {
+            let mut rom = ines(1, 1, false);
+            rom[6] = 0x50;
+
+            match load_cartridge_from_reader(&rom[..]) {
+                Err(CartridgeError::UnsupportedMapper(5)) => {},
+                _ => panic!("expected an unsupported mapper"),
+            }
+        }
+
+        #[test]
+        fn test_ines_header()
+        {
+            let mut header = ines(2, 1, true)[..16].to_vec();
+            header[6] |= 0x20 | 0b0000_0011;
+            header[7] = 0x40;
+
+            assert_eq!(CartridgeHeader::parse(&header).unwrap(), CartridgeHeader {
+                prg_rom_size: 0x8000,
+                chr_rom_size: 0x2000,
+                mapper: 0x42,
+                submapper: 0,
+                mirroring: crate::cpu::Mirroring::Vertical,
+                battery: true,
+                trainer: true,
+                nes2: false,
+            });
+
+            // a dumper signature over the end of the header, the upper mapper nibble is garbage
+            header[7..16].copy_from_slice(b"DiskDude!");
+            assert_eq!(CartridgeHeader::parse(&header).unwrap().mapper, 0x02);
+            // four screen wins over the mirroring bit
+            header[6] |= 0b0000_1000;
+            assert_eq!(CartridgeHeader::parse(&header).unwrap().mirroring, crate::cpu::Mirroring::FourScreen);
+        }
+
+        #[test]
+        fn test_nes2_header()
+        {
+            let mut header = ines(2, 1, false)[..16].to_vec();
+            header[6] = 0x40;
+            header[7] = 0x18;
+            header[8] = 0x21;
+            // 0x102 PRG units, CHR in exponent form: 2^5 * 3 bytes
+            header[9] = 0xF1;
+            header[5] = 0b0001_0101;
+            let header = CartridgeHeader::parse(&header).unwrap();
+
+            assert_eq!(header.nes2, true);
+            assert_eq!((header.mapper, header.submapper), (0x114, 2));
+            assert_eq!(header.prg_rom_size, 0x102 * 0x4000);
+            assert_eq!(header.chr_rom_size, 96);
+        }
+
+        #[test]
+        fn test_banked_memory_wraps_selection()
+        {
+            // 4 banks of 0x10 bytes, each filled with its bank number
+            let data: Vec<u8> = (0..0x40).map(|i| i / 0x10).collect();
+            let mut memory = BankedMemory::new(data, 0x10, 2);
+
+            assert_eq!(memory.bank_count(), 4);
+            assert_eq!(memory.select(1, 200), Some(0));
+            assert_eq!(memory.select(1, 3), Some(3));
+            assert_eq!(memory.read(0x15), Some(3));
+            assert_eq!(memory.select(0, 6), Some(2));
+            assert_eq!(memory.read(0x05), Some(2));
+
+            // 3 banks
+            let data: Vec<u8> = (0..0x30).map(|i| i / 0x10).collect();
+            let mut memory = BankedMemory::new(data, 0x10, 1);
+            assert_eq!(memory.select(0, 4), Some(1));
+            assert_eq!(memory.select(0, 255), Some(0));
+        }
+
+        #[test]
+        fn test_banked_memory_translate()
+        {
+            let mut memory = BankedMemory::new(vec![0; 0x8000], 0x2000, 4);
+            for window in 0..4 {
+                memory.select(window, 3 - window);
+            }
+
+            for window in 0..4 {
+                for offset in [0, 1, 0x1FFF] {
+                    let index = memory.translate(window * 0x2000 + offset).unwrap();
+                    assert_eq!(index, (3 - window) * 0x2000 + offset);
+                }
+            }
+            memory.write(0x0001, 0x42);
+            assert_eq!(memory.read(0x0001), Some(0x42));
+        }
+
+        #[test]
+        fn test_banked_memory_empty_is_open_bus()
+        {
+            let mut memory = BankedMemory::new(vec![], 0x2000, 1);
+
+            assert_eq!(memory.bank_count(), 0);
+            assert_eq!(memory.select(0, 1), None);
+            assert_eq!(memory.read(0x0000), None);
+            memory.write(0x0000, 0x42);
+        }
+
+        #[test]
+        fn test_nrom_128_mirrors_prg()
+        {
+            let mut rom = ines(1, 0, false);
+            rom[0x10 + 0x0123] = 0x42;
+            let cartridge = load_cartridge_from_reader(&rom[..]).unwrap();
+
+            assert_eq!(cartridge.read(0x8123), Some(0x42));
+            assert_eq!(cartridge.read(0xC123), Some(0x42));
+        }
+
+        #[test]
+        fn test_nrom_256_maps_both_banks()
+        {
+            let mut rom = ines(2, 0, false);
+            rom[0x10 + 0x0123] = 0x42;
+            rom[0x10 + 0x4123] = 0x43;
+            let cartridge = load_cartridge_from_reader(&rom[..]).unwrap();
+
+            assert_eq!(cartridge.read(0x8123), Some(0x42));
+            assert_eq!(cartridge.read(0xC123), Some(0x43));
+        }
+
+        #[test]
+        fn test_nrom_prg_ram()
+        {
+            let mut cartridge = load_cartridge_from_reader(&ines(1, 0, false)[..]).unwrap();
+            cartridge.write(0x6000, 0x12);
+            cartridge.write(0x7FFF, 0x34);
+
+            assert_eq!(cartridge.read(0x6000), Some(0x12));
+            assert_eq!(cartridge.read(0x7FFF), Some(0x34));
+            // PRG ROM is not writable
+            cartridge.write(0x8000, 0x56);
+            assert_eq!(cartridge.read(0x8000), Some(0x00));
+        }
+
+        #[test]
+        fn test_nrom_chr()
+        {
+            let mut rom = ines(1, 1, false);
+            rom[0x10 + 0x4000 + 0x1FFF] = 0x42;
+            let mut cartridge = load_cartridge_from_reader(&rom[..]).unwrap();
+            cartridge.chr_write(0x1FFF, 0x00);
+            assert_eq!(cartridge.chr_read(0x1FFF), 0x42);
+
+            // without CHR ROM the board has CHR RAM
+            let mut cartridge = load_cartridge_from_reader(&ines(1, 0, false)[..]).unwrap();
+            cartridge.chr_write(0x1FFF, 0x42);
+            assert_eq!(cartridge.chr_read(0x1FFF), 0x42);
+        }
+
+        #[test]
+        fn test_cpu_starts_at_reset_vector()
+        {
+            let mut rom = ines(1, 0, false);
+            // IRQ vector, which is not where a reset goes
+            rom[0x10 + 0x3FFE] = 0x78;
+            rom[0x10 + 0x3FFF] = 0x56;
+            let cpu = crate::cpu::Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+
+            assert_eq!(cpu.registers.pc, 0x1234);
+            assert_eq!(cpu.load(0xFFFC), 0x34);
+        }
+
+        // Every 16KB PRG bank starts with its number and holds the same program at
+        // offset $100, so it keeps running whatever gets switched in. The program does
+        // the serial writes, five STA with LSR in between, then loops on itself.
+        fn mmc1_cpu(prg_banks: u8, chr_banks: u8, writes: &[(u16, u8)]) -> (Cpu, u16)
+        {
+            let mut program = vec![];
+            for (address, value) in writes {
+                program.extend([0xA9, *value]);
+                for bit in 0..5 {
+                    if bit > 0 {
+                        program.push(0x4A);
+                    }
+                    program.extend([0x8D, *address as u8, (*address >> 8) as u8]);
+                }
+            }
+            mmc1_program_cpu(prg_banks, chr_banks, program)
+        }
+
+        // the program runs from $C100, the byte after the bank number is 1 so that
+        // read-modify-write instructions on it write 1 then something with bit 0 clear
+        fn mmc1_program_cpu(prg_banks: u8, chr_banks: u8, mut program: Vec<u8>) -> (Cpu, u16)
+        {
+            let end = 0xC100 + program.len() as u16;
+            program.extend([0x4C, end as u8, (end >> 8) as u8]);
+
+            let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, chr_banks, 0x10, 0];
+            rom.resize(16, 0);
+            for bank in 0..prg_banks {
+                let mut prg = vec![0xEA; 0x4000];
+                prg[0] = bank;
+                prg[1] = 0x01;
+                prg[0x100..0x100 + program.len()].copy_from_slice(&program);
+                prg[0x3FFC] = 0x00;
+                prg[0x3FFD] = 0xC1;
+                rom.extend(prg);
+            }
+            for bank in 0..chr_banks as usize * 2 {
+                rom.extend(vec![bank as u8; 0x1000]);
+            }
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+            cpu.set_trace_sink(TraceSink::Off);
+            (cpu, end)
+        }
+
+        fn run_mmc1(prg_banks: u8, writes: &[(u16, u8)]) -> Cpu
+        {
+            let (mut cpu, end) = mmc1_cpu(prg_banks, 0, writes);
+            cpu.run_until(&StopCondition::PcEquals(end));
+            cpu
+        }
+
+        #[test]
+        fn test_mmc1_power_on()
+        {
+            let cpu = run_mmc1(8, &[]);
+
+            assert_eq!(cpu.load(0x8000), 0);
+            assert_eq!(cpu.load(0xC000), 7);
+        }
+
+        #[test]
+        fn test_mmc1_prg_16k_modes()
+        {
+            // last bank fixed at $C000
+            let cpu = run_mmc1(8, &[(0xE000, 3)]);
+            assert_eq!(cpu.load(0x8000), 3);
+            assert_eq!(cpu.load(0xC000), 7);
+
+            // first bank fixed at $8000
+            let cpu = run_mmc1(8, &[(0x8000, 0b0_1000), (0xE000, 5)]);
+            assert_eq!(cpu.load(0x8000), 0);
+            assert_eq!(cpu.load(0xC000), 5);
+        }
+
+        #[test]
+        fn test_mmc1_prg_32k_mode()
+        {
+            // the low bit of the bank number is ignored
+            let cpu = run_mmc1(8, &[(0x8000, 0b0_0000), (0xE000, 5)]);
+
+            assert_eq!(cpu.load(0x8000), 4);
+            assert_eq!(cpu.load(0xC000), 5);
+        }
+
+        fn mmc1_serial_write(mmc1: &mut MMC1, address: u16, value: u8)
+        {
+            for bit in 0..5 {
+                mmc1.write(address, value >> bit);
+                mmc1.cpu_clock();
+                mmc1.cpu_clock();
+            }
+        }
+
+        #[test]
+        fn test_mmc1_reset_bit()
+        {
+            let mut mmc1 = MMC1::new((0..8).flat_map(|bank| vec![bank as u8; 0x4000]).collect(), vec![]);
+            mmc1_serial_write(&mut mmc1, 0x8000, 0b0_0000);
+            // two bits of a PRG bank write, then the reset
+            for _ in 0..2 {
+                mmc1.write(0xE000, 1);
+                mmc1.cpu_clock();
+                mmc1.cpu_clock();
+            }
+            mmc1.write(0xE000, 0x80);
+            mmc1.cpu_clock();
+            mmc1.cpu_clock();
+            mmc1_serial_write(&mut mmc1, 0xE000, 6);
+
+            // back with the last bank fixed at $C000, and the shift register started over
+            assert_eq!(mmc1.read(0x8000), Some(6));
+            assert_eq!(mmc1.read(0xC000), Some(7));
+        }
+
+        #[test]
+        fn test_mmc1_consecutive_writes_ignored()
+        {
+            let mut mmc1 = MMC1::new((0..8).flat_map(|bank| vec![bank as u8; 0x4000]).collect(), vec![]);
+            for bit in [1, 1, 0, 0, 0] {
+                mmc1.write(0xE000, bit);
+                // the dummy write of a read-modify-write instruction
+                mmc1.cpu_clock();
+                mmc1.write(0xE000, 0);
+                mmc1.cpu_clock();
+                mmc1.cpu_clock();
+            }
+
+            assert_eq!(mmc1.read(0x8000), Some(3));
+        }
+
+        fn run_mmc1_program(program: Vec<u8>, cycle_accurate: bool) -> Cpu
+        {
+            let (mut cpu, end) = mmc1_program_cpu(8, 0, program);
+            cpu.set_cycle_accurate(cycle_accurate);
+            cpu.run_until(&StopCondition::PcEquals(end));
+            cpu
+        }
+
+        #[test]
+        fn test_mmc1_read_modify_write()
+        {
+            // INC $8001 writes 1, then 2 on the next cycle. Only the 1s reach the shift
+            // register: the second write of each pair would load 0, both would load
+            // %10101.
+            let program = [0xEE, 0x01, 0x80].repeat(5);
+            for cycle_accurate in [false, true] {
+                let cpu = run_mmc1_program(program.clone(), cycle_accurate);
+
+                assert_eq!(cpu.cartridge.borrow().mirroring(), crate::cpu::Mirroring::Horizontal, "{}", cycle_accurate);
+                // PRG mode 3, last bank fixed at $C000
+                assert_eq!((cpu.load(0x8000), cpu.load(0xC000)), (0, 7), "{}", cycle_accurate);
+            }
+        }
+
+        #[test]
+        fn test_mmc1_store_writes_apart()
+        {
+            let program = vec![
+                0xA9, 0x00,       // LDA #$00
+                0x8D, 0x00, 0x80, // STA $8000
+                0xA9, 0x01,       // LDA #$01
+                0x8D, 0x00, 0x80, // STA $8000
+                0xA9, 0x00,       // LDA #$00
+                // back to back, four cycles apart
+                0x8D, 0x00, 0x80, // STA $8000
+                0x8D, 0x00, 0x80, // STA $8000
+                0x8D, 0x00, 0x80, // STA $8000
+            ];
+            for cycle_accurate in [false, true] {
+                let cpu = run_mmc1_program(program.clone(), cycle_accurate);
+
+                // control %00010: vertical mirroring, 32KB PRG mode
+                assert_eq!(cpu.cartridge.borrow().mirroring(), crate::cpu::Mirroring::Vertical, "{}", cycle_accurate);
+                assert_eq!((cpu.load(0x8000), cpu.load(0xC000)), (0, 1), "{}", cycle_accurate);
+            }
+        }
+
+        #[test]
+        fn test_mmc1_mixed_writes()
+        {
+            let program = vec![
+                0xA9, 0x00,       // LDA #$00
+                0x8D, 0x00, 0x80, // STA $8000, 0
+                0xEE, 0x01, 0x80, // INC $8001, 1 then 2
+                0xA9, 0x01,       // LDA #$01
+                0x8D, 0x00, 0x80, // STA $8000, 1
+                0xCE, 0x01, 0x80, // DEC $8001, 1 then 0
+                0x0E, 0x00, 0x80, // ASL $8000, 0 then 0
+                // PRG bank 5, five STA with LSR in between
+                0xA9, 0x05,
+                0x8D, 0x00, 0xE0, 0x4A,
+                0x8D, 0x00, 0xE0, 0x4A,
+                0x8D, 0x00, 0xE0, 0x4A,
+                0x8D, 0x00, 0xE0, 0x4A,
+                0x8D, 0x00, 0xE0,
+            ];
+            for cycle_accurate in [false, true] {
+                let cpu = run_mmc1_program(program.clone(), cycle_accurate);
+
+                // control %01110: vertical mirroring, last bank fixed at $C000
+                assert_eq!(cpu.cartridge.borrow().mirroring(), crate::cpu::Mirroring::Vertical, "{}", cycle_accurate);
+                assert_eq!((cpu.load(0x8000), cpu.load(0xC000)), (5, 7), "{}", cycle_accurate);
+            }
+        }
+
+        #[test]
+        fn test_mmc1_save_state()
+        {
+            let prg_rom = || (0..8).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+            let mut mmc1 = MMC1::new(prg_rom(), vec![]);
+            mmc1.write(0x6000, 0x42);
+            mmc1_serial_write(&mut mmc1, 0xE000, 5);
+            // the first two bits of the next bank
+            for bit in [1, 1] {
+                mmc1.write(0xE000, bit);
+                mmc1.cpu_clock();
+                mmc1.cpu_clock();
+            }
+            let mut state = StateWriter::new();
+            mmc1.save_state(&mut state);
+            let state = state.finish();
+
+            let mut loaded = MMC1::new(prg_rom(), vec![]);
+            loaded.load_state(&mut state.reader()).unwrap();
+            assert_eq!((loaded.read(0x6000), loaded.read(0x8000)), (Some(0x42), Some(5)));
+            for mapper in [&mut mmc1, &mut loaded] {
+                for _ in 0..3 {
+                    mapper.write(0xE000, 0);
+                    mapper.cpu_clock();
+                    mapper.cpu_clock();
+                }
+            }
+            assert_eq!(loaded.read(0x8000), Some(3));
+            assert_eq!(loaded.read(0x8000), mmc1.read(0x8000));
+        }
+
+        // MMC1 with 32KB of PRG ROM and CHR RAM
+        fn battery_cpu(battery: bool) -> Cpu
+        {
+            let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 2, 0, 0x10 | (battery as u8) << 1, 0];
+            rom.resize(16 + 0x8000, 0xEA);
+            Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap())
+        }
+
+        #[test]
+        fn test_battery_ram()
+        {
+            let mut cpu = battery_cpu(true);
+            for i in 0..0x2000 {
+                cpu.write(0x6000 + i, (i * 7) as u8);
+            }
+            let ram = cpu.prg_ram().unwrap();
+
+            let mut cpu = battery_cpu(true);
+            assert_eq!(cpu.load(0x7FFF), 0x00);
+            cpu.load_prg_ram(&ram).unwrap();
+            for i in 0..0x2000 {
+                assert_eq!(cpu.load(0x6000 + i), (i * 7) as u8);
+            }
+        }
+
+        #[test]
+        fn test_battery_ram_errors()
+        {
+            let mut cpu = battery_cpu(false);
+            assert_eq!(cpu.prg_ram(), None);
+            assert!(matches!(cpu.load_prg_ram(&[0; 0x2000]), Err(CartridgeError::NoBatteryRam)));
+
+            let mut cpu = battery_cpu(true);
+            cpu.write(0x6000, 0x42);
+            assert!(matches!(cpu.load_prg_ram(&[0; 0x1000]), Err(CartridgeError::PrgRamSize { expected: 0x2000, got: 0x1000 })));
+            // nothing is loaded
+            assert_eq!(cpu.load(0x6000), 0x42);
+        }
+
+        #[test]
+        fn test_chr_ram_save_state()
+        {
+            let mut nrom = NROM::new(vec![0; 0x4000], vec![], Mirroring::Vertical);
+            nrom.chr_write(0x1234, 0x56);
+            let mut state = StateWriter::new();
+            nrom.save_state(&mut state);
+            let state = state.finish();
+
+            let mut loaded = NROM::new(vec![0; 0x4000], vec![], Mirroring::Vertical);
+            loaded.load_state(&mut state.reader()).unwrap();
+            assert_eq!(loaded.chr_read(0x1234), 0x56);
+        }
+
+        #[test]
+        fn test_mmc1_chr_banks()
+        {
+            let mut mmc1 = MMC1::new(vec![0; 0x4000 * 2], (0..8).flat_map(|bank| vec![bank as u8; 0x1000]).collect());
+            // 8KB mode ignores the low bit and the second bank register
+            mmc1_serial_write(&mut mmc1, 0x8000, 0b0_1100);
+            mmc1_serial_write(&mut mmc1, 0xA000, 5);
+            mmc1_serial_write(&mut mmc1, 0xC000, 1);
+            assert_eq!((mmc1.chr_read(0x0000), mmc1.chr_read(0x1000)), (4, 5));
+
+            // 4KB mode
+            mmc1_serial_write(&mut mmc1, 0x8000, 0b1_1100);
+            assert_eq!((mmc1.chr_read(0x0000), mmc1.chr_read(0x1000)), (5, 1));
+            // CHR ROM is not writable
+            mmc1.chr_write(0x0000, 0xFF);
+            assert_eq!(mmc1.chr_read(0x0000), 5);
+        }
+
+        #[test]
+        fn test_mmc1_mirroring_and_ram()
+        {
+            let (mut cpu, end) = mmc1_cpu(2, 1, &[(0x8000, 0b0_1110)]);
+            cpu.run_until(&StopCondition::PcEquals(end));
+            assert_eq!(cpu.cartridge.borrow().mirroring(), crate::cpu::Mirroring::Vertical);
+
+            cpu.write(0x6000, 0x42);
+            assert_eq!(cpu.load(0x6000), 0x42);
+
+            // bit 4 of the PRG bank register disables the RAM
+            let (mut cpu, end) = mmc1_cpu(2, 1, &[(0xE000, 0x10)]);
+            cpu.run_until(&StopCondition::PcEquals(end));
+            cpu.write(0x6000, 0x42);
+            // open bus, the last value written
+            cpu.write(0x0000, 0x13);
+            assert_eq!(cpu.load(0x6000), 0x13);
+        }
+
+        // 128KB of PRG, every bank starting with its number
+        fn uxrom_image(submapper: u8) -> Vec<u8>
+        {
+            let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 8, 0, 0x20, 0b0000_1000, submapper << 4];
+            rom.resize(16, 0);
+            for bank in 0..8 {
+                rom.extend(vec![bank; 0x4000]);
+            }
+            rom
+        }
+
+        #[test]
+        fn test_uxrom_bank_select()
+        {
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&uxrom_image(0)[..]).unwrap());
+            assert_eq!((cpu.load(0x8000), cpu.load(0xC000)), (0, 7));
+
+            for bank in [3, 5, 9] {
+                cpu.write(0xC123, bank);
+                assert_eq!((cpu.load(0x8000), cpu.load(0xC000)), (bank % 8, 7));
+            }
+        }
+
+        #[test]
+        fn test_uxrom_bus_conflicts()
+        {
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&uxrom_image(2)[..]).unwrap());
+
+            // the fixed bank reads 7 everywhere, 6 & 7 selects bank 6
+            cpu.write(0xC000, 6);
+            assert_eq!(cpu.load(0x8000), 6);
+            // bank 6 reads 6 at $8000, 5 & 6 selects bank 4
+            cpu.write(0x8000, 5);
+            assert_eq!(cpu.load(0x8000), 4);
+        }
+
+        #[test]
+        fn test_uxrom_chr_ram()
+        {
+            let cpu = Cpu::new(load_cartridge_from_reader(&uxrom_image(0)[..]).unwrap());
+            let mut cartridge = cpu.cartridge.borrow_mut();
+            cartridge.chr_write(0x1FFF, 0x42);
+
+            assert_eq!(cartridge.chr_read(0x1FFF), 0x42);
+        }
+
+        #[test]
+        fn test_cnrom_chr_select()
+        {
+            let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 4, 0x30, 0];
+            rom.resize(16 + 0x4000, 0);
+            for bank in 0..4 {
+                rom.extend(vec![bank; 0x2000]);
+            }
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+
+            assert_eq!(cpu.cartridge.borrow().chr_read(0x1FFF), 0);
+            cpu.write(0x8000, 2);
+            assert_eq!(cpu.cartridge.borrow().chr_read(0x0000), 2);
+            assert_eq!(cpu.cartridge.borrow().chr_read(0x1FFF), 2);
+        }
+
+        // 8 PRG banks of 8KB and 16 CHR banks of 1KB, each filled with its number
+        fn mmc3() -> MMC3
+        {
+            let prg_rom = (0..8).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+            let chr_rom = (0..16).flat_map(|bank| vec![bank as u8; 0x0400]).collect();
+            MMC3::new(prg_rom, chr_rom, crate::cpu::Mirroring::Vertical)
+        }
+
+        fn prg_windows(mmc3: &MMC3) -> [u8; 4]
+        {
+            [0x8000, 0xA000, 0xC000, 0xE000].map(|address| mmc3.read(address).unwrap())
+        }
+
+        fn chr_windows(mmc3: &MMC3) -> Vec<u8>
+        {
+            (0..8).map(|window| mmc3.chr_read(window * 0x0400)).collect()
+        }
+
+        #[test]
+        fn test_mmc3_prg_modes()
+        {
+            let mut mmc3 = mmc3();
+            for (register, bank) in [(6, 2), (7, 3)] {
+                mmc3.write(0x8000, register);
+                mmc3.write(0x8001, bank);
+            }
+            assert_eq!(prg_windows(&mmc3), [2, 3, 6, 7]);
+
+            // the fixed second to last bank moves to $8000
+            mmc3.write(0x8000, 0x40);
+            assert_eq!(prg_windows(&mmc3), [6, 3, 2, 7]);
+            // the registers are mirrored across $8000-$9FFF
+            mmc3.write(0x9FFE, 0x47);
+            mmc3.write(0x9FFF, 12);
+            assert_eq!(prg_windows(&mmc3), [6, 4, 2, 7]);
+        }
+
+        #[test]
+        fn test_mmc3_chr_modes()
+        {
+            let mut mmc3 = mmc3();
+            // the low bit of the 2KB banks is ignored
+            for (register, bank) in [(0, 5), (1, 6), (2, 8), (3, 9), (4, 10), (5, 11)] {
+                mmc3.write(0x8000, register);
+                mmc3.write(0x8001, bank);
+            }
+            assert_eq!(chr_windows(&mmc3), [4, 5, 6, 7, 8, 9, 10, 11]);
+
+            mmc3.write(0x8000, 0x80);
+            assert_eq!(chr_windows(&mmc3), [8, 9, 10, 11, 4, 5, 6, 7]);
+        }
+
+        #[test]
+        fn test_mmc3_mirroring_and_ram()
+        {
+            let mut mmc3 = mmc3();
+            mmc3.write(0xA000, 1);
+            assert_eq!(mmc3.mirroring(), crate::cpu::Mirroring::Horizontal);
+
+            mmc3.write(0xA001, 0x80);
+            mmc3.write(0x6000, 0x42);
+            assert_eq!(mmc3.read(0x6000), Some(0x42));
+            // write protected
+            mmc3.write(0xA001, 0xC0);
+            mmc3.write(0x6000, 0x43);
+            assert_eq!(mmc3.read(0x6000), Some(0x42));
+            // disabled, the bus is left open
+            mmc3.write(0xA001, 0x00);
+            assert_eq!(mmc3.read(0x6000), None);
+        }
+
+        // background fetches from $0000 for most of the scanline, then sprites from $1000
+        fn mmc3_scanline(mmc3: &mut MMC3)
+        {
+            mmc3.ppu_address(0x0FF0);
+            for _ in 0..100 {
+                mmc3.cpu_clock();
+            }
+            mmc3.ppu_address(0x1FF0);
+            // the nametable fetches between sprites are too short to count
+            mmc3.ppu_address(0x2000);
+            mmc3.ppu_address(0x1FF8);
+            mmc3.cpu_clock();
+        }
+
+        #[test]
+        fn test_mmc3_scanline_irq()
+        {
+            let mut mmc3 = mmc3();
+            mmc3.write(0xC000, 3);
+            mmc3.write(0xC001, 0);
+            mmc3.write(0xE001, 0);
+
+            // reload to 3, then 2, 1, 0
+            for _ in 0..3 {
+                mmc3_scanline(&mut mmc3);
+                assert_eq!(mmc3.irq(), false);
+            }
+            mmc3_scanline(&mut mmc3);
+            assert_eq!(mmc3.irq(), true);
+
+            // acknowledged by disabling, the counter then reloads and keeps going
+            mmc3.write(0xE000, 0);
+            assert_eq!(mmc3.irq(), false);
+            for _ in 0..4 {
+                mmc3_scanline(&mut mmc3);
+                assert_eq!(mmc3.irq(), false);
+            }
+            mmc3.write(0xE001, 0);
+            for _ in 0..3 {
+                mmc3_scanline(&mut mmc3);
+            }
+            assert_eq!(mmc3.irq(), false);
+            mmc3_scanline(&mut mmc3);
+            assert_eq!(mmc3.irq(), true);
+        }
+
+        #[test]
+        fn test_mmc3_irq_on_scanline()
+        {
+            let program = [
+                0xA9, 0x08,         // LDA #$08
+                0x8D, 0x00, 0x20,   // STA $2000
+                0x2C, 0x02, 0x20,   // BIT $2002
+                0x10, 0xFB,         // BPL $E005
+                0xA9, 0x09,         // LDA #$09
+                0x8D, 0x00, 0xC0,   // STA $C000
+                0x8D, 0x01, 0xC0,   // STA $C001
+                0x8D, 0x01, 0xE0,   // STA $E001
+                0xA9, 0x18,         // LDA #$18
+                0x8D, 0x01, 0x20,   // STA $2001
+                0xA9, 0x40,         // LDA #$40
+                0x8D, 0x17, 0x40,   // STA $4017, no frame IRQ
+                0x58,               // CLI
+                0x4C, 0x1F, 0xE0,   // JMP $E01F
+            ];
+            let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 4, 1, 0x40, 0];
+            rom.resize(16 + 0x10000 - 0x2000, 0);
+            let mut last_bank = vec![0xEA; 0x2000];
+            last_bank[..program.len()].copy_from_slice(&program);
+            // reset and IRQ vectors
+            last_bank[0x1FFC..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xF0]);
+            rom.extend(last_bank);
+            rom.resize(rom.len() + 0x2000, 0);
+            let mut cpu = Cpu::new(load_cartridge_from_reader(&rom[..]).unwrap());
+            cpu.set_trace_sink(TraceSink::Off);
+
+            cpu.run_until(&StopCondition::PcEquals(0xF000));
+            // the pre-render scanline reloads 9, scanlines 0 to 8 count it down
+            assert_eq!(cpu.ppu().scanline(), 8);
+            assert_eq!(cpu.ppu().dot() > 256, true);
+        }
+    }
+
+    mod run
+    {
+        use super::*;
+        use crate::cpu::cartridge::NROM;
+
+        // NROM cartridge running program from $8000, through the reset vector
+        fn nrom_cpu(program: &[u8]) -> Cpu
+        {
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..program.len()].copy_from_slice(program);
+            prg_rom[0x3FFC] = 0x00;
+            prg_rom[0x3FFD] = 0x80;
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x8000);
+            cpu
+        }
+
+        #[test]
+        fn test_cycle_count()
+        {
+            let mut cpu = nrom_cpu(&[]);
+
+            // NOPs take 2 cycles, the run stops on the first boundary at or after the count
+            assert_eq!(cpu.run_until(&StopCondition::CycleCount(20)), StopReason::CycleCount(21));
+            assert_eq!(cpu.registers.pc, 0x8007);
+        }
+
+        #[test]
+        fn test_pc_equals()
+        {
+            let mut cpu = nrom_cpu(&[]);
+
+            assert_eq!(cpu.run_until(&StopCondition::PcEquals(0x8010)), StopReason::PcEquals(0x8010));
+            assert_eq!(cpu.cycles, 7 + 16 * 2);
+        }
+
+        #[test]
+        fn test_memory_equals()
+        {
+            // INC $10 ; JMP $8000
+            let mut cpu = nrom_cpu(&[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+            let condition = StopCondition::MemoryEquals { address: 0x0010, value: 3 };
+
+            assert_eq!(cpu.run_until(&condition), StopReason::MemoryEquals { address: 0x0010, value: 3 });
+            assert_eq!(cpu.zero_page_ram[0x10], 3);
+            assert_eq!(cpu.registers.pc, 0x8002);
+        }
+
+        #[test]
+        fn test_any()
+        {
+            let mut cpu = nrom_cpu(&[]);
+            let condition = StopCondition::Any(vec![
+                StopCondition::PcEquals(0x9000),
+                StopCondition::CycleCount(11),
+            ]);
+
+            assert_eq!(cpu.run_until(&condition), StopReason::CycleCount(11));
+        }
+
+        #[test]
+        fn test_status_byte_protocol()
+        {
+            let program = [
+                // signature
+                0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE ; STA $6001
+                0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0 ; STA $6002
+                0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61 ; STA $6003
+                // second run, after the reset
+                0xAD, 0x00, 0x60,             // LDA $6000
+                0xC9, 0x81,                   // CMP #$81
+                0xF0, 0x0D,                   // BEQ done
+                // first run, asks for a reset and waits
+                0xA9, 0x80, 0x8D, 0x00, 0x60, // LDA #$80 ; STA $6000
+                0xA9, 0x81, 0x8D, 0x00, 0x60, // LDA #$81 ; STA $6000
+                0x4C, 0x20, 0x80,             // JMP *
+                // done: "ok" then result 0
+                0xA9, 0x6F, 0x8D, 0x04, 0x60, // LDA #'o' ; STA $6004
+                0xA9, 0x6B, 0x8D, 0x05, 0x60, // LDA #'k' ; STA $6005
+                0xA9, 0x00, 0x8D, 0x06, 0x60, // LDA #0 ; STA $6006
+                0x8D, 0x00, 0x60,             // STA $6000
+                0x4C, 0x35, 0x80,             // JMP *
+            ];
+            let mut cpu = nrom_cpu(&program);
+            let condition = StopCondition::Any(vec![
+                StopCondition::StatusByteProtocol,
+                StopCondition::CycleCount(10_000),
+            ]);
+
+            assert_eq!(
+                cpu.run_until(&condition),
+                StopReason::TestCompleted { result: 0, message: "ok".to_string() }
+            );
+            assert_eq!(cpu.registers.pc, 0x8035);
+        }
+
+        #[test]
+        fn test_step()
+        {
+            // LDA $0300,X ; *NOP $10
+            let mut cpu = nrom_cpu(&[0xBD, 0xFF, 0x03, 0x04, 0x10]);
+            cpu.registers.x = 0x01;
+
+            assert_eq!(cpu.step(), StepInfo {
+                pc: 0x8000,
+                opcode: 0xBD,
+                mnemonic: "LDA",
+                operands: [0xFF, 0x03],
+                length: 3,
+                cycles: 5,
+                interrupt: false,
+            });
+            assert_eq!(cpu.step(), StepInfo {
+                pc: 0x8003,
+                opcode: 0x04,
+                mnemonic: "*NOP",
+                operands: [0x10, 0x00],
+                length: 2,
+                cycles: 3,
+                interrupt: false,
+            });
+            assert_eq!(cpu.registers.pc, 0x8005);
+        }
+
+        #[test]
+        fn test_step_interrupt()
+        {
+            let mut cpu = nrom_cpu(&[]);
+            cpu.set_nmi_line(true);
+
+            let step = cpu.step();
+            assert_eq!((step.pc, step.opcode, step.length, step.cycles, step.interrupt), (0x8000, 0x00, 0, 7, true));
+            // the handler, at the $EAEA the NOPs put in the NMI vector
+            let step = cpu.step();
+            assert_eq!((step.pc, step.mnemonic, step.interrupt), (0xEAEA, "NOP", false));
+        }
+
+        #[test]
+        fn test_step_after_clock()
+        {
+            // LDA #$01 ; LDX #$02
+            let mut cpu = nrom_cpu(&[0xA9, 0x01, 0xA2, 0x02]);
+            cpu.clock();
+
+            // the LDA is finished, then the LDX reported
+            assert_eq!(cpu.step().pc, 0x8002);
+            assert_eq!((cpu.registers.a, cpu.registers.x), (0x01, 0x02));
+        }
+    }
+
+    mod save_state
+    {
+        use super::*;
+        use crate::cpu::cartridge::NROM;
+        use crate::save_state::{
+            SaveState,
+            SaveStateError,
+        };
+
+        // enables a pulse channel, then keeps writing to PRG-RAM and the PPU
+        const PROGRAM: [u8; 24] = [
+            0xA9, 0x1F, 0x8D, 0x15, 0x40, // LDA #$1F ; STA $4015
+            0xA9, 0x08, 0x8D, 0x03, 0x40, // LDA #$08 ; STA $4003
+            0xE8,                         // INX
+            0x8E, 0x00, 0x60,             // STX $6000
+            0x8E, 0x06, 0x20,             // STX $2006
+            0xAD, 0x02, 0x20,             // LDA $2002
+            0x4C, 0x0A, 0x80,             // JMP $800A
+            0x00,
+        ];
+
+        fn nrom_cpu() -> Cpu
+        {
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+            cpu.set_pc(0x8000);
+            cpu
+        }
+
+        fn run_cycles(cpu: &mut Cpu, cycles: u64) -> Vec<String>
+        {
+            let end = cpu.cycles + cycles;
+            let mut lines = vec![];
+            while cpu.cycles < end {
+                if cpu.at_instruction_boundary() {
+                    lines.push(cpu.trace_record().to_string());
+                }
+                cpu.clock();
+            }
+            lines
+        }
+
+        #[test]
+        fn test_round_trip()
+        {
+            let mut cpu = nrom_cpu();
+            run_cycles(&mut cpu, 1001);
+
+            let state = cpu.save_state();
+            let first = run_cycles(&mut cpu, 3000);
+            let first_end = cpu.save_state();
+            cpu.load_state(&state).unwrap();
+            assert_eq!(cpu.save_state(), state);
+            let second = run_cycles(&mut cpu, 3000);
+
+            assert_eq!(first, second);
+            assert_eq!(cpu.save_state(), first_end);
+        }
+
+        // saved in the middle of a sequence of micro-ops
+        #[test]
+        fn test_round_trip_cycle_accurate()
+        {
+            let mut cpu = nrom_cpu();
+            cpu.set_cycle_accurate(true);
+            run_cycles(&mut cpu, 1001);
+            assert_eq!(cpu.at_instruction_boundary(), false);
+
+            let state = cpu.save_state();
+            let first = run_cycles(&mut cpu, 3000);
+            let first_end = cpu.save_state();
+            cpu.load_state(&state).unwrap();
+            let second = run_cycles(&mut cpu, 3000);
+
+            assert_eq!(first, second);
+            assert_eq!(cpu.save_state(), first_end);
+        }
+
+        #[test]
+        fn test_load_into_another_cpu()
+        {
+            let mut cpu = nrom_cpu();
+            run_cycles(&mut cpu, 1001);
+            let state = SaveState::from_bytes(cpu.save_state().as_bytes().to_vec()).unwrap();
+
+            let mut other = nrom_cpu();
+            other.load_state(&state).unwrap();
+            assert_eq!(run_cycles(&mut other, 500), run_cycles(&mut cpu, 500));
+            assert_eq!(other.load(0x6000), cpu.load(0x6000));
+        }
+
+        // the $2005/$2006 write toggle is part of the state
+        #[test]
+        fn test_w_latch_round_trip()
+        {
+            let mut cpu = nrom_cpu();
+            cpu.write(0x2006, 0x21);
+            let state = cpu.save_state();
+            cpu.write(0x2006, 0x50);
+            assert_eq!(cpu.ppu().vram_address(), 0x2150);
+
+            let mut other = nrom_cpu();
+            other.load_state(&state).unwrap();
+            other.write(0x2006, 0x50);
+            assert_eq!(other.ppu().vram_address(), 0x2150);
+
+            cpu.load_state(&state).unwrap();
+            cpu.write(0x2006, 0x60);
+            assert_eq!(cpu.ppu().vram_address(), 0x2160);
+        }
+
+        #[test]
+        fn test_failed_load_changes_nothing()
+        {
+            let mut cpu = nrom_cpu();
+            let state = cpu.save_state();
+            run_cycles(&mut cpu, 1001);
+            let before = cpu.save_state();
+
+            let mut bytes = state.as_bytes().to_vec();
+            bytes.pop();
+            assert_eq!(cpu.load_state(&SaveState::from_bytes(bytes).unwrap()), Err(SaveStateError::Truncated));
+            let mut bytes = state.as_bytes().to_vec();
+            bytes.push(0);
+            assert_eq!(cpu.load_state(&SaveState::from_bytes(bytes).unwrap()), Err(SaveStateError::TrailingBytes(1)));
+            assert_eq!(cpu.save_state(), before);
+        }
+    }
+
+    mod isa
+    {
+        use super::*;
+        use crate::cpu::isa::{
+            OPCODE_TABLE,
+            opcode_info,
+            instruction_info,
+        };
+
+        #[test]
+        fn test_instruction_table()
+        {
+            let official: Vec<_> = (0..=0xFFu8).map(opcode_info).filter(|info| info.official).collect();
+
+            assert_eq!(official.len(), 151);
+            for info in official.iter() {
+                let instruction = instruction_info(info.mnemonic).unwrap();
+                assert_eq!(instruction.opcodes.contains(info), true, "{:02X}", info.opcode);
+            }
+            let lda = instruction_info("lda").unwrap();
+            assert_eq!(lda.mnemonic, "LDA");
+            assert_eq!(lda.affected_flags, 0b1000_0010);
+            assert_eq!(lda.opcodes.len(), 8);
+            assert_eq!(instruction_info("XYZ"), None);
+            let info = opcode_info(0xB1);
+            assert_eq!((info.mnemonic, info.addressing_mode, info.cycles, info.official), ("LDA", AddressingModeKind::IndirectIndexed, 5, true));
+        }
+
+        // each entry sits at its opcode, and executes what its mnemonic says
+        #[test]
+        fn test_opcode_table_is_consistent()
+        {
+            for (index, info) in OPCODE_TABLE.iter().enumerate() {
+                assert_eq!(info.opcode as usize, index);
+                let operation = format!("{:?}", info.operation).to_ascii_uppercase();
+                assert_eq!(info.mnemonic.trim_start_matches('*'), operation, "{:02X}", info.opcode);
+                if info.page_cross_penalty {
+                    assert_eq!(Cpu::get_wait_cycles(info.opcode, true), info.cycles + 1);
+                }
+            }
+            assert_eq!(Cpu::get_instruction_name(0x31), "AND");
+            assert_eq!(Cpu::get_instruction_name(0xCA), "DEX");
+        }
+
+        #[test]
+        fn test_operand_bytes_match_decoding()
+        {
+            for opcode in 0..=0xFFu8 {
+                let mut cpu = Cpu::new_dummy();
+                cpu.registers.pc = 0x0200;
+                cpu.get_addressing_mode(opcode);
+
+                assert_eq!(cpu.registers.pc - 0x0200, opcode_info(opcode).addressing_mode.operand_bytes() as u16, "{:02X}", opcode);
+            }
+        }
+
+        // only the flags the table declares may change, whatever the starting state
+        #[test]
+        fn test_affected_flags_match_behavior()
+        {
+            for info in (0..=0xFFu8).map(opcode_info).filter(|info| info.official) {
+                let affected_flags = instruction_info(info.mnemonic).unwrap().affected_flags;
+                for status in [0x00u8, 0xFF, 0b0100_0001, 0b1000_0010, 0b1100_1111] {
+                    for value in [0x00u8, 0x01, 0x7F, 0x80, 0xFF] {
+                        let mut cpu = Cpu::new_dummy();
+                        cpu.registers.pc = 0x0200;
+                        cpu.registers.a = value;
+                        cpu.registers.x = value.wrapping_add(1);
+                        cpu.registers.y = value ^ 0x80;
+                        cpu.registers.p.set_byte(status);
+                        cpu.zero_page_ram = [value; 0x0100];
+                        cpu.stack = [value; 0x0100];
+                        cpu.internal_ram = [value; 0x0600];
+                        cpu.internal_ram[0x00] = info.opcode;
+                        cpu.internal_ram[0x01] = 0x10;
+                        cpu.internal_ram[0x02] = 0x02;
+
+                        let before = cpu.registers.p.get_byte();
+                        let opcode = cpu.fetch();
+                        cpu.execute_instruction(opcode);
+                        let changed = before ^ cpu.registers.p.get_byte();
+                        assert_eq!(changed & !affected_flags, 0, "{:02X} {} from P={:02X} value {:02X}", info.opcode, info.mnemonic, status, value);
+                    }
+                }
+            }
+        }
+    }
+
+    mod ppu
+    {
+        use super::*;
+        use crate::cpu::cartridge::NROM;
+        use crate::ppu::SCREEN_WIDTH;
+
+        #[test]
+        fn test_background_rendering()
+        {
+            let program = [
+                0x2C, 0x02, 0x20,   // BIT $2002
+                0x10, 0xFB,         // BPL $8000
+                0xA9, 0x3F,         // LDA #$3F
+                0x8D, 0x06, 0x20,   // STA $2006
+                0xA9, 0x00,         // LDA #$00
+                0x8D, 0x06, 0x20,   // STA $2006
+                0xA9, 0x0F,         // LDA #$0F
+                0x8D, 0x07, 0x20,   // STA $2007
+                0xA9, 0x30,         // LDA #$30
+                0x8D, 0x07, 0x20,   // STA $2007
+                0xA9, 0x20,         // LDA #$20
+                0x8D, 0x06, 0x20,   // STA $2006
+                0xA9, 0x00,         // LDA #$00
+                0x8D, 0x06, 0x20,   // STA $2006
+                0xA9, 0x01,         // LDA #$01
+                0x8D, 0x07, 0x20,   // STA $2007
+                0xA9, 0x00,         // LDA #$00
+                0x8D, 0x05, 0x20,   // STA $2005
+                0x8D, 0x05, 0x20,   // STA $2005
+                0xA9, 0x0A,         // LDA #$0A
+                0x8D, 0x01, 0x20,   // STA $2001
+                0x4C, 0x35, 0x80,   // JMP $8035
+            ];
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..program.len()].copy_from_slice(&program);
+            // tile 1 is a solid square of color 1
+            let mut chr_rom = vec![0; 0x2000];
+            chr_rom[0x10..0x18].copy_from_slice(&[0xFF; 8]);
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, chr_rom, Mirroring::Horizontal)));
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x8000);
+
+            let mut vblank_edges = 0;
+            let mut vblank = cpu.ppu().vblank();
+            while cpu.ppu().frame() < 3 {
+                cpu.clock();
+                if cpu.ppu().vblank() != vblank {
+                    vblank = !vblank;
+                    vblank_edges += 1;
+                }
+            }
+
+            assert_eq!(vblank_edges >= 4, true);
+            let ppu = cpu.ppu();
+            let frame_buffer = ppu.frame_buffer();
+            for y in 0..16 {
+                for x in 0..16 {
+                    let expected = if x < 8 && y < 8 {0x30} else {0x0F};
+                    assert_eq!(frame_buffer[y * SCREEN_WIDTH + x], expected, "pixel {} {}", x, y);
+                }
+            }
+            assert_eq!(frame_buffer.iter().filter(|color| **color == 0x30).count(), 64);
+        }
+
+        fn set_vram_address(cpu: &mut Cpu, address: u16)
+        {
+            cpu.write(0x2006, (address >> 8) as u8);
+            cpu.write(0x2006, address as u8);
+        }
+
+        // INC and ASL on PPUDATA access it three times, each moving v by the increment.
+        // From v0 with M in the read buffer: the read returns M, the dummy write stores M
+        // at v0 + inc, the result lands at v0 + 2 * inc and v ends at v0 + 3 * inc.
+        #[test]
+        fn test_read_modify_write_on_ppudata()
+        {
+            for &(opcode, result) in &[(0xEE, 0x42), (0x0E, 0x82)] {
+                for &(control, increment) in &[(0x00, 1), (0x04, 32)] {
+                    for cycle_accurate in [false, true] {
+                        let context = format!("opcode {:02X} increment {} cycle accurate {}", opcode, increment, cycle_accurate);
+                        let mut cpu = Cpu::new_dummy();
+                        cpu.set_cycle_accurate(cycle_accurate);
+                        cpu.write(0x2000, control);
+                        set_vram_address(&mut cpu, 0x2100);
+                        for value in 0x41..=0x44 {
+                            cpu.write(0x2007, value);
+                        }
+                        // M = $41 in the buffer, v0 = $2100 + inc
+                        set_vram_address(&mut cpu, 0x2100);
+                        cpu.load(0x2007);
+                        cpu.registers.pc = 0x0200;
+                        cpu.internal_ram[0x00..0x03].copy_from_slice(&[opcode, 0x07, 0x20]);
+
+                        let step = cpu.step();
+
+                        assert_eq!(step.cycles, 6, "{}", context);
+                        assert_eq!(cpu.ppu().vram_address(), 0x2100 + 4 * increment, "{}", context);
+                        for (i, &expected) in [0x41, 0x42, 0x41, result].iter().enumerate() {
+                            set_vram_address(&mut cpu, 0x2100 + i as u16 * increment);
+                            cpu.load(0x2007);
+                            assert_eq!(cpu.load(0x2007), expected, "{} cell {}", context, i);
+                        }
+                        assert_eq!(cpu.registers.a, 0x00, "{}", context);
+                    }
+                }
+            }
+        }
+    }
+
+    mod interrupt
+    {
+        use super::*;
+
+        // pc $0234, SP $FD, C, Z and V set
+        fn interrupted_cpu() -> Cpu
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.registers.pc = 0x0234;
+            cpu.registers.stack_pointer = 0xFD;
+            cpu.registers.p.set_byte(0b0100_0011);
+            cpu.stack = [0xEE; 0x0100];
+            cpu
+        }
+
+        fn assert_flags_kept(cpu: &Cpu)
+        {
+            assert_eq!(cpu.registers.p.carry, true);
+            assert_eq!(cpu.registers.p.zero, true);
+            assert_eq!(cpu.registers.p.overflow, true);
+            assert_eq!(cpu.registers.p.negative, false);
+            assert_eq!(cpu.registers.p.interrupt_disable, true);
+        }
+
+        #[test]
+        fn test_break()
+        {
+            let mut cpu = interrupted_cpu();
+            cpu.interrupt(Interrupts::Break);
+
+            assert_eq!(cpu.registers.stack_pointer, 0xFA);
+            assert_eq!(&cpu.stack[0xFB..=0xFD], &[0b0111_0011, 0x34, 0x02]);
+            assert_eq!(cpu.registers.pc, 0x8000);
+            assert_flags_kept(&cpu);
+        }
+
+        #[test]
+        fn test_irq()
+        {
+            let mut cpu = interrupted_cpu();
+            cpu.interrupt(Interrupts::IRQ);
+
+            assert_eq!(cpu.registers.stack_pointer, 0xFA);
+            // B clear, the unused bit set
+            assert_eq!(&cpu.stack[0xFB..=0xFD], &[0b0110_0011, 0x34, 0x02]);
+            assert_eq!(cpu.registers.pc, 0x8000);
+            assert_flags_kept(&cpu);
+        }
+
+        #[test]
+        fn test_nmi()
+        {
+            let mut cpu = interrupted_cpu();
+            cpu.interrupt(Interrupts::NMI);
+
+            assert_eq!(cpu.registers.stack_pointer, 0xFA);
+            assert_eq!(&cpu.stack[0xFB..=0xFD], &[0b0110_0011, 0x34, 0x02]);
+            // the dummy mapper only answers at the IRQ vector
+            assert_eq!(cpu.registers.pc, 0x0000);
+            assert_flags_kept(&cpu);
+        }
+
+        #[test]
+        fn test_reset_writes_nothing()
+        {
+            let mut cpu = interrupted_cpu();
+            cpu.reset();
+
+            assert_eq!(cpu.registers.stack_pointer, 0xFA);
+            assert_eq!(&cpu.stack[0xFB..=0xFD], &[0xEE, 0xEE, 0xEE]);
+            assert_eq!(cpu.registers.pc, 0x0000);
+            assert_flags_kept(&cpu);
+        }
+
+        #[test]
+        fn test_reset_takes_seven_cycles()
+        {
+            for cycle_accurate in [false, true] {
+                let mut cpu = interrupted_cpu();
+                cpu.set_cycle_accurate(cycle_accurate);
+                let start = cpu.cycles;
+                cpu.reset();
+                assert_eq!(cpu.cycles, start);
+
+                let mut cycles = 0;
+                while !cpu.at_instruction_boundary() {
+                    cpu.clock();
+                    cycles += 1;
+                }
+                assert_eq!(cycles, 7, "cycle accurate {}", cycle_accurate);
+                assert_eq!(cpu.cycles, start + 7);
+                assert_eq!(cpu.registers.pc, 0x0000);
+            }
+        }
+
+        #[test]
+        fn test_reset_resets_ppu_and_apu()
+        {
+            let mut cpu = interrupted_cpu();
+            cpu.write(0x2000, 0x80);
+            cpu.write(0x4015, 0x01);
+            cpu.write(0x4003, 0x08);
+            assert_eq!(cpu.ppu().nmi_enabled(), true);
+            assert_eq!(cpu.apu_mut().read_status() & 0x01, 0x01);
+
+            cpu.reset();
+            assert_eq!(cpu.ppu().nmi_enabled(), false);
+            assert_eq!(cpu.apu_mut().read_status() & 0x1F, 0x00);
+        }
+    }
+
+    mod brk_sequence
+    {
+        use super::*;
+        use crate::cpu::cartridge::NROM;
+
+        // program at $8000, BRK handler at $9000, NMI handler at $9100
+        fn nrom_cpu(program: &[u8], brk_handler: &[u8], nmi_handler: &[u8]) -> Cpu
+        {
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..program.len()].copy_from_slice(program);
+            prg_rom[0x1000..0x1000 + brk_handler.len()].copy_from_slice(brk_handler);
+            prg_rom[0x1100..0x1100 + nmi_handler.len()].copy_from_slice(nmi_handler);
+            prg_rom[0x3FFA] = 0x00;
+            prg_rom[0x3FFB] = 0x91;
+            prg_rom[0x3FFE] = 0x00;
+            prg_rom[0x3FFF] = 0x90;
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x8000);
+            cpu.registers.stack_pointer = 0xFD;
+            cpu
+        }
+
+        #[test]
+        fn test_rti_skips_padding_byte()
+        {
+            // BRK, padding, LDA #$42 ; handler: RTI
+            let mut cpu = nrom_cpu(&[0x00, 0xFF, 0xA9, 0x42], &[0x40], &[]);
+            cpu.registers.p.set_byte(0b0000_0011);
+
+            cpu.step();
+            assert_eq!(cpu.registers.pc, 0x9000);
+            assert_eq!(cpu.registers.p.interrupt_disable, true);
+            assert_eq!(cpu.registers.p.carry, true);
+            assert_eq!(cpu.stack[0xFD], 0x80);
+            assert_eq!(cpu.stack[0xFC], 0x02);
+            // B and bit 5 set, I clear as it was before the BRK
+            assert_eq!(cpu.stack[0xFB], 0b0011_0011);
+
+            cpu.step();
+            assert_eq!(cpu.registers.pc, 0x8002);
+            cpu.step();
+            assert_eq!(cpu.registers.a, 0x42);
+        }
+
+        #[test]
+        fn test_nmi_hijacks_brk()
+        {
+            let mut cpu = nrom_cpu(&[0x00, 0xFF], &[], &[]);
+            cpu.clock();
+            cpu.clock();
+            cpu.set_nmi_line(true);
+            while cpu.wait_cycles != 0 {
+                cpu.clock();
+            }
+
+            assert_eq!(cpu.registers.pc, 0x9100);
+            // still a BRK on the stack
+            assert_eq!(cpu.stack[0xFB] & 0b0011_0000, 0b0011_0000);
+            assert_eq!(cpu.nmi_pending, false);
+        }
+
+        #[test]
+        fn test_late_nmi_follows_brk()
+        {
+            let mut cpu = nrom_cpu(&[0x00, 0xFF], &[], &[]);
+            for _ in 0..6 {
+                cpu.clock();
+            }
+            cpu.set_nmi_line(true);
+            cpu.clock();
+
+            assert_eq!(cpu.registers.pc, 0x9000);
+            // the NMI comes right after, before the handler's first instruction
+            cpu.step();
+            assert_eq!(cpu.registers.pc, 0x9100);
+            assert_eq!(cpu.stack[0xFA], 0x90);
+            assert_eq!(cpu.stack[0xF9], 0x00);
+        }
+    }
+
+    mod nmi
+    {
+        use super::*;
+        use crate::cpu::cartridge::NROM;
+
+        // program at $8000, NMI handler at $9000
+        fn nrom_cpu(program: &[u8], handler: &[u8]) -> Cpu
+        {
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..program.len()].copy_from_slice(program);
+            prg_rom[0x1000..0x1000 + handler.len()].copy_from_slice(handler);
+            prg_rom[0x3FFA] = 0x00;
+            prg_rom[0x3FFB] = 0x90;
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x8000);
+            cpu
+        }
+
+        #[test]
+        fn test_nmi_sequence()
+        {
+            // SEI ; NOP
+            let mut cpu = nrom_cpu(&[0x78, 0xEA], &[0xA9, 0x42]);
+            cpu.registers.p.set_byte(0b1100_0011);
+            cpu.clock();
+            cpu.clock();
+            // assert the line in the middle of the NOP
+            cpu.clock();
+            cpu.set_nmi_line(true);
+            cpu.clock();
+            assert_eq!(cpu.registers.pc, 0x8002);
+            let cycles = cpu.cycles;
+
+            for _ in 0..7 {
+                cpu.clock();
+            }
+            assert_eq!(cpu.wait_cycles, 0);
+            assert_eq!(cpu.cycles - cycles, 7);
+            assert_eq!(cpu.registers.pc, 0x9000);
+            assert_eq!(cpu.registers.p.interrupt_disable, true);
+            // PC high, PC low, then P with B clear and bit 5 set
+            assert_eq!(cpu.registers.stack_pointer, 0xFA);
+            assert_eq!(cpu.stack[0xFD], 0x80);
+            assert_eq!(cpu.stack[0xFC], 0x02);
+            assert_eq!(cpu.stack[0xFB], 0b1110_0111);
+
+            cpu.clock();
+            assert_eq!(cpu.registers.a, 0x42);
+        }
+
+        #[test]
+        fn test_nmi_is_edge_triggered()
+        {
+            let mut cpu = nrom_cpu(&[], &[]);
+            cpu.set_nmi_line(true);
+            for _ in 0..50 {
+                cpu.clock();
+            }
+            // held high, the line triggered a single interrupt
+            assert_eq!(cpu.registers.stack_pointer, 0xFA);
+            assert_eq!(cpu.registers.pc > 0x9000, true);
+
+            cpu.set_nmi_line(false);
+            cpu.set_nmi_line(true);
+            for _ in 0..9 {
+                cpu.clock();
+            }
+            assert_eq!(cpu.registers.stack_pointer, 0xF7);
+            assert_eq!(cpu.registers.pc, 0x9001);
+        }
+
+        #[test]
+        fn test_nmi_on_vblank()
+        {
+            // LDA #$80 ; STA $2000 ; JMP $8005
+            let program = [0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80];
+            // INC $10 ; RTI
+            let mut cpu = nrom_cpu(&program, &[0xE6, 0x10, 0x40]);
+
+            while cpu.registers.pc < 0x9000 {
+                cpu.clock();
+            }
+            assert_eq!(cpu.ppu().scanline(), 241);
+            assert_eq!(cpu.ppu().vblank(), true);
+
+            while cpu.ppu().frame() < 3 {
+                cpu.clock();
+            }
+            assert_eq!(cpu.zero_page_ram[0x10], 3);
+        }
+    }
+
+    mod irq
+    {
+        use super::*;
+        use crate::cpu::cartridge::NROM;
+
+        // program at $8000, IRQ handler at $9000
+        fn nrom_cpu(program: &[u8], handler: &[u8]) -> Cpu
+        {
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..program.len()].copy_from_slice(program);
+            prg_rom[0x1000..0x1000 + handler.len()].copy_from_slice(handler);
+            prg_rom[0x3FFE] = 0x00;
+            prg_rom[0x3FFF] = 0x90;
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x8000);
+            cpu
+        }
+
+        fn run_cycles(cpu: &mut Cpu, cycles: u32)
+        {
+            for _ in 0..cycles {
+                cpu.clock();
+            }
+        }
+
+        #[test]
+        fn test_irq_masked_by_sei()
+        {
+            // SEI
+            let mut cpu = nrom_cpu(&[0x78], &[]);
+            cpu.registers.p.interrupt_disable = false;
+            run_cycles(&mut cpu, 2);
+            cpu.set_irq_line(IrqSource::Expansion, true);
+            run_cycles(&mut cpu, 40);
+
+            assert_eq!(cpu.registers.pc, 0x8015);
+            assert_eq!(cpu.registers.stack_pointer, 0xFD);
+        }
+
+        #[test]
+        fn test_irq_unmasked_by_cli()
+        {
+            // CLI
+            let mut cpu = nrom_cpu(&[0x58], &[]);
+            cpu.set_irq_line(IrqSource::Expansion, true);
+            run_cycles(&mut cpu, 2);
+            assert_eq!(cpu.registers.pc, 0x8001);
+            let cycles = cpu.cycles;
+
+            run_cycles(&mut cpu, 7);
+            assert_eq!(cpu.wait_cycles, 0);
+            assert_eq!(cpu.cycles - cycles, 7);
+            assert_eq!(cpu.registers.pc, 0x9000);
+            assert_eq!(cpu.registers.p.interrupt_disable, true);
+            // PC high, PC low, then P with B clear and I still clear
+            assert_eq!(cpu.registers.stack_pointer, 0xFA);
+            assert_eq!(cpu.stack[0xFD], 0x80);
+            assert_eq!(cpu.stack[0xFC], 0x01);
+            assert_eq!(cpu.stack[0xFB], 0b0010_0000);
+        }
+
+        #[test]
+        fn test_irq_refires_after_rti()
+        {
+            // CLI
+            // handler: INC $10 ; RTI
+            let mut cpu = nrom_cpu(&[0x58], &[0xE6, 0x10, 0x40]);
+            cpu.set_irq_line(IrqSource::Expansion, true);
+            // CLI, IRQ, INC, RTI
+            run_cycles(&mut cpu, 2 + 7 + 5 + 6);
+            assert_eq!(cpu.zero_page_ram[0x10], 1);
+            assert_eq!(cpu.registers.pc, 0x8001);
+
+            // still asserted, the restored I flag lets it through again
+            run_cycles(&mut cpu, 7 + 5 + 6);
+            assert_eq!(cpu.zero_page_ram[0x10], 2);
+            assert_eq!(cpu.registers.stack_pointer, 0xFD);
+
+            cpu.set_irq_line(IrqSource::Expansion, false);
+            run_cycles(&mut cpu, 3 * 2);
+            assert_eq!(cpu.registers.pc, 0x8004);
+        }
+
+        #[test]
+        fn test_irq_sources_are_ored()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_irq_line(IrqSource::Dmc, true);
+            cpu.set_irq_line(IrqSource::FrameCounter, true);
+            cpu.set_irq_line(IrqSource::Dmc, false);
+            assert_eq!(cpu.irq_line(), true);
+
+            cpu.set_irq_line(IrqSource::FrameCounter, false);
+            assert_eq!(cpu.irq_line(), false);
+        }
+    }
+
+    mod oam_dma
+    {
+        use super::*;
+        use crate::cpu::cartridge::{
+            MMC1,
+            NROM,
+        };
+
+        // program at $8000, page $0200 filled with a pattern
+        fn nrom_cpu(program: &[u8]) -> Cpu
+        {
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..program.len()].copy_from_slice(program);
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x8000);
+            for offset in 0..0x100 {
+                cpu.internal_ram[offset] = (offset as u8).wrapping_mul(3) ^ 0x5A;
+            }
+            cpu
+        }
+
+        // returns the number of cycles the instruction took
+        #[test]
+        fn test_copy_to_oam()
+        {
+            // LDA #$02 ; STA $4014
+            let mut cpu = nrom_cpu(&[0xA9, 0x02, 0x8D, 0x14, 0x40]);
+            cpu.step();
+            cpu.step();
+
+            assert_eq!(cpu.ppu().oam(), &cpu.internal_ram[..0x100]);
+            assert_eq!(cpu.registers.pc, 0x8005);
+        }
+
+        // the copy reads through the whole bus, the cartridge included
+        #[test]
+        fn test_copy_from_prg_rom()
+        {
+            // LDA #$C1 ; STA $4014
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..5].copy_from_slice(&[0xA9, 0xC1, 0x8D, 0x14, 0x40]);
+            for offset in 0..0x100 {
+                prg_rom[0x100 + offset] = (offset as u8).wrapping_mul(5) ^ 0xA5;
+            }
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom.clone(), vec![], Mirroring::Horizontal)));
+            cpu.set_pc(0x8000);
+            cpu.step();
+            cpu.step();
+
+            assert_eq!(cpu.ppu().oam(), &prg_rom[0x100..0x200]);
+        }
+
+        #[test]
+        fn test_copy_from_prg_ram()
+        {
+            // LDA #$60 ; STA $4014, from the fixed last bank
+            let mut prg_rom = vec![0xEA; 0x8000];
+            prg_rom[0x4000..0x4005].copy_from_slice(&[0xA9, 0x60, 0x8D, 0x14, 0x40]);
+            let mut cpu = Cpu::new(Box::new(MMC1::new(prg_rom, vec![])));
+            cpu.set_pc(0xC000);
+            for offset in 0..0x100 {
+                cpu.write(0x6000 + offset, (offset as u8).wrapping_mul(7) ^ 0x3C);
+            }
+            cpu.step();
+            cpu.step();
+
+            let expected: Vec<u8> = (0..0x100).map(|offset| cpu.peek(0x6000 + offset)).collect();
+            assert_eq!(cpu.ppu().oam(), &expected[..]);
+            assert_eq!(expected[1], 0x7 ^ 0x3C);
+        }
+
+        // the copy goes through OAMDATA and wraps around OAM
+        #[test]
+        fn test_copy_from_oam_address()
+        {
+            // LDA #$02 ; STA $4014
+            let mut cpu = nrom_cpu(&[0xA9, 0x02, 0x8D, 0x14, 0x40]);
+            cpu.write(0x2003, 0x10);
+            cpu.step();
+            cpu.step();
+
+            let oam = cpu.ppu().oam().to_vec();
+            for offset in 0..0x100 {
+                assert_eq!(oam[(offset + 0x10) & 0xFF], cpu.internal_ram[offset], "offset {:02X}", offset);
+            }
+        }
+
+        #[test]
+        fn test_stall_on_odd_cycle()
+        {
+            // LDA #$02 ; STA $4014
+            let mut cpu = nrom_cpu(&[0xA9, 0x02, 0x8D, 0x14, 0x40]);
+            cpu.step();
+            assert_eq!(cpu.cycles % 2, 1);
+
+            assert_eq!(cpu.step().cycles, 4 + 514);
+        }
+
+        #[test]
+        fn test_stall_on_even_cycle()
+        {
+            // LDA $10 ; STA $4014
+            let mut cpu = nrom_cpu(&[0xA5, 0x10, 0x8D, 0x14, 0x40]);
+            cpu.zero_page_ram[0x10] = 0x02;
+            cpu.step();
+            assert_eq!(cpu.cycles % 2, 0);
+
+            assert_eq!(cpu.step().cycles, 4 + 513);
+            assert_eq!(cpu.ppu().oam(), &cpu.internal_ram[..0x100]);
+        }
+    }
+
+    mod cycle_accurate
+    {
+        use super::*;
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        use crate::cpu::cartridge::{
+            NROM,
+            WriteOutcome,
+        };
+
+        // 8KB of RAM at $6000-$7FFF, recording the cycle of every write. It counts the
+        // cycles itself, which matches cpu.cycles once the CPU starts from 0.
+        struct TimedMapper
+        {
+            ram: [u8; 0x2000],
+            cycles: u64,
+            writes: Rc<RefCell<Vec<(u64, u16, u8)>>>,
+        }
+        impl Mapper for TimedMapper
+        {
+            fn read(&self, address: u16) -> Option<u8> { Some(self.ram[address as usize & 0x1FFF]) }
+            fn write(&mut self, address: u16, data: u8) -> WriteOutcome
+            {
+                self.writes.borrow_mut().push((self.cycles, address, data));
+                self.ram[address as usize & 0x1FFF] = data;
+                WriteOutcome::Handled
+            }
+            fn chr_read(&self, _address: u16) -> u8 { 0 }
+            fn chr_write(&mut self, _address: u16, _data: u8) { }
+            fn mirroring(&self) -> Mirroring { Mirroring::Horizontal }
+            fn cpu_clock(&mut self) { self.cycles += 1 }
+        }
+
+        // runs the instruction at $0200 and returns the cycles of its writes, from its first cycle
+        fn write_cycles(program: &[u8], cycle_accurate: bool) -> Vec<(u64, u16, u8)>
+        {
+            let writes = Rc::new(RefCell::new(Vec::new()));
+            let mapper = TimedMapper {ram: [0x41; 0x2000], cycles: 0, writes: Rc::clone(&writes)};
+            let mut cpu = Cpu::with_cartridge(Box::new(mapper));
+            cpu.set_cycle_accurate(cycle_accurate);
+            cpu.cycles = 0;
+            cpu.registers.pc = 0x0200;
+            cpu.registers.a = 0x55;
+            cpu.registers.x = 0x01;
+            cpu.registers.y = 0x02;
+            cpu.zero_page_ram[0x10] = 0x00;
+            cpu.zero_page_ram[0x11] = 0x60;
+            cpu.internal_ram[..program.len()].copy_from_slice(program);
+
+            cpu.step();
+            writes.replace(Vec::new())
+        }
+
+        #[test]
+        fn test_store_on_last_cycle()
+        {
+            // STA $6000
+            assert_eq!(write_cycles(&[0x8D, 0x00, 0x60], true), vec![(3, 0x6000, 0x55)]);
+            // STA $6000,X
+            assert_eq!(write_cycles(&[0x9D, 0x00, 0x60], true), vec![(4, 0x6001, 0x55)]);
+            // STA ($10),Y
+            assert_eq!(write_cycles(&[0x91, 0x10], true), vec![(5, 0x6002, 0x55)]);
+            // the default mode writes on the first cycle
+            assert_eq!(write_cycles(&[0x8D, 0x00, 0x60], false), vec![(0, 0x6000, 0x55)]);
+        }
+
+        #[test]
+        fn test_read_modify_write_cycles()
+        {
+            // INC $6000
+            assert_eq!(write_cycles(&[0xEE, 0x00, 0x60], true), vec![(4, 0x6000, 0x41), (5, 0x6000, 0x42)]);
+            // INC $6000,X
+            assert_eq!(write_cycles(&[0xFE, 0x00, 0x60], true), vec![(5, 0x6001, 0x41), (6, 0x6001, 0x42)]);
+        }
+
+        #[test]
+        fn test_jsr_pushes()
+        {
+            // JSR $6000
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_cycle_accurate(true);
+            cpu.registers.pc = 0x0200;
+            cpu.registers.stack_pointer = 0xFD;
+            cpu.internal_ram[..3].copy_from_slice(&[0x20, 0x00, 0x60]);
+            for _ in 0..5 {
+                cpu.clock();
+            }
+            // the return address is pushed, the target is not fetched yet
+            assert_eq!((cpu.stack[0xFD], cpu.stack[0xFC]), (0x02, 0x02));
+            assert_eq!(cpu.registers.pc, 0x0202);
+
+            cpu.clock();
+            assert_eq!(cpu.registers.pc, 0x6000);
+            assert_eq!(cpu.at_instruction_boundary(), true);
+        }
+
+        // The program bytes at pc, the zero page pointing at $03xx and the registers set
+        // so that the indexed modes cross pages when X and Y are large
+        fn opcode_cpu(opcode: u8, pc: u16, index: u8, status: u8, cycle_accurate: bool) -> Cpu
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_cycle_accurate(cycle_accurate);
+            for (i, byte) in cpu.zero_page_ram.iter_mut().enumerate() {
+                *byte = if i % 2 == 0 {i as u8} else {0x03};
+            }
+            for (i, byte) in cpu.stack.iter_mut().enumerate() {
+                *byte = (i as u8).wrapping_mul(5);
+            }
+            for (i, byte) in cpu.internal_ram.iter_mut().enumerate() {
+                *byte = (i as u8).wrapping_mul(7).wrapping_add(1);
+            }
+            cpu.write(pc, opcode);
+            cpu.write(pc + 1, 0x10);
+            cpu.write(pc + 2, 0x03);
+            cpu.registers.pc = pc;
+            cpu.registers.a = 0x9C;
+            cpu.registers.x = index;
+            cpu.registers.y = index.wrapping_add(2);
+            cpu.registers.p.set_byte(status);
+            cpu.registers.stack_pointer = 0xFD;
+            cpu
+        }
+
+        fn cpu_state(cpu: &Cpu) -> (u8, u8, u8, u8, u8, u16, u64, Vec<u8>)
+        {
+            let mut ram = cpu.zero_page_ram.to_vec();
+            ram.extend_from_slice(&cpu.stack);
+            ram.extend_from_slice(&cpu.internal_ram);
+            (cpu.a(), cpu.x(), cpu.y(), cpu.status_byte(), cpu.stack_pointer(), cpu.pc(), cpu.cycles, ram)
+        }
+
+        #[test]
+        fn test_every_opcode_matches_default_mode()
+        {
+            // page crossings and branches taken, then none of them
+            for (pc, index, status) in [(0x02F0, 0xF5, 0xFF), (0x0200, 0x00, 0x00)] {
+                for opcode in 0..=0xFF {
+                    let mut default = opcode_cpu(opcode, pc, index, status, false);
+                    let mut accurate = opcode_cpu(opcode, pc, index, status, true);
+                    let default_step = default.step();
+                    let accurate_step = accurate.step();
+
+                    assert_eq!(accurate_step, default_step, "opcode {:02X} at {:04X}", opcode, pc);
+                    assert_eq!(cpu_state(&accurate), cpu_state(&default), "opcode {:02X} at {:04X}", opcode, pc);
+                }
+            }
+        }
+
+        #[test]
+        fn test_interrupts_match_default_mode()
+        {
+            for cycle_accurate in [false, true] {
+                // CLI
+                let mut cpu = opcode_cpu(0x58, 0x0200, 0, 0b0000_0100, cycle_accurate);
+                cpu.set_irq_line(IrqSource::Expansion, true);
+                cpu.step();
+
+                let irq = cpu.step();
+                assert_eq!((irq.interrupt, irq.cycles, cpu.registers.pc), (true, 7, 0x8000));
+                assert_eq!(&cpu.stack[0xFB..0xFE], &[0b0010_0000, 0x01, 0x02]);
+
+                cpu.set_nmi_line(true);
+                let nmi = cpu.step();
+                assert_eq!((nmi.interrupt, nmi.cycles, cpu.registers.p.interrupt_disable), (true, 7, true));
+                assert_eq!(&cpu.stack[0xF8..0xFB], &[0b0010_0100, 0x00, 0x80]);
+            }
+        }
+
+        #[test]
+        fn test_nmi_hijacks_brk()
+        {
+            // BRK with the NMI vector at $0000
+            let mut cpu = opcode_cpu(0x00, 0x0200, 0, 0, true);
+            cpu.clock();
+            cpu.clock();
+            cpu.set_nmi_line(true);
+            while !cpu.at_instruction_boundary() {
+                cpu.clock();
+            }
+
+            assert_eq!(cpu.registers.pc, 0x0000);
+            assert_eq!(cpu.stack[0xFB] & 0b0011_0000, 0b0011_0000);
+            assert_eq!(cpu.nmi_pending, false);
+        }
+
+        // the copy goes through OAMDATA one byte every two cycles
+        #[test]
+        fn test_oam_dma()
+        {
+            for (program, dma_cycles) in [([0xA9, 0x02, 0x8D, 0x14, 0x40], 514), ([0xA5, 0x10, 0x8D, 0x14, 0x40], 513)] {
+                let mut prg_rom = vec![0xEA; 0x4000];
+                prg_rom[..program.len()].copy_from_slice(&program);
+                let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+                cpu.set_cycle_accurate(true);
+                cpu.set_pc(0x8000);
+                cpu.zero_page_ram[0x10] = 0x02;
+                for offset in 0..0x100 {
+                    cpu.internal_ram[offset] = (offset as u8).wrapping_mul(3) ^ 0x5A;
+                }
+                cpu.step();
+                for _ in 0..4 + dma_cycles - 8 {
+                    cpu.clock();
+                }
+                // the last four bytes are still to copy
+                assert_eq!(cpu.ppu().oam()[..0xFC], cpu.internal_ram[..0xFC]);
+                assert_ne!(cpu.ppu().oam()[0xFC..], cpu.internal_ram[0xFC..0x100]);
+
+                while !cpu.at_instruction_boundary() {
+                    cpu.clock();
+                }
+                assert_eq!(cpu.ppu().oam(), &cpu.internal_ram[..0x100]);
+                assert_eq!(cpu.registers.pc, 0x8005);
+            }
+        }
+
+        #[test]
+        fn test_switch_mid_instruction()
+        {
+            // INC $6000, finished in the mode it started in
+            let mut cpu = opcode_cpu(0xEE, 0x0200, 0, 0, true);
+            cpu.write(0x0201, 0x00);
+            cpu.write(0x0202, 0x03);
+            cpu.clock();
+            cpu.set_cycle_accurate(false);
+            cpu.clock();
+            assert_eq!(cpu.wait_cycles, 0);
+            while !cpu.at_instruction_boundary() {
+                cpu.clock();
+            }
+
+            assert_eq!(cpu.cycles, 7 + 6);
+            assert_eq!(cpu.internal_ram[0x0100], 0x02);
+        }
+    }
+
+    mod controllers
+    {
+        use super::*;
+
+        // as LDA $4016 does, with the high byte of its operand left on the bus
+        fn read(cpu: &Cpu, address: u16) -> u8
+        {
+            cpu.last_bus_value.set(0x40);
+            cpu.load(address)
+        }
+
+        fn read_eight(cpu: &Cpu, address: u16) -> Vec<u8> { (0..8).map(|_| read(cpu, address)).collect() }
+
+        #[test]
+        fn test_read_buttons()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_controller_state(0, Buttons {a: true, select: true, down: true, right: true, ..Buttons::default()});
+            cpu.set_controller_state(1, Buttons {b: true, up: true, ..Buttons::default()});
+            cpu.write(0x4016, 0x01);
+            cpu.write(0x4016, 0x00);
+
+            assert_eq!(read_eight(&cpu, 0x4016), vec![0x41, 0x40, 0x41, 0x40, 0x40, 0x41, 0x40, 0x41]);
+            assert_eq!(read_eight(&cpu, 0x4017), vec![0x40, 0x41, 0x40, 0x40, 0x41, 0x40, 0x40, 0x40]);
+            // exhausted
+            assert_eq!(read(&cpu, 0x4016), 0x41);
+            assert_eq!(read(&cpu, 0x4017), 0x41);
+        }
+
+        // $4017 writes belong to the APU frame counter
+        #[test]
+        fn test_strobe_only_on_4016()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_controller_state(1, Buttons {a: true, ..Buttons::default()});
+            cpu.write(0x4017, 0x01);
+            cpu.write(0x4017, 0x00);
+
+            assert_eq!(read(&cpu, 0x4017), 0x40);
+        }
+
+        // one strobe line: both ports latch on the same write, and keep what they latched
+        #[test]
+        fn test_strobe_latches_both_ports()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_controller_state(0, Buttons {a: true, ..Buttons::default()});
+            cpu.set_controller_state(1, Buttons {b: true, ..Buttons::default()});
+            cpu.write(0x4016, 0x01);
+            cpu.write(0x4016, 0x00);
+            cpu.set_controller_state(0, Buttons {start: true, ..Buttons::default()});
+            cpu.set_controller_state(1, Buttons {select: true, ..Buttons::default()});
+
+            assert_eq!(read_eight(&cpu, 0x4016), vec![0x41, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40]);
+            assert_eq!(read_eight(&cpu, 0x4017), vec![0x40, 0x41, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40]);
+        }
+
+        // the shift registers go on where they were
+        #[test]
+        fn test_4017_write_leaves_shift_registers()
+        {
+            let mut cpu = Cpu::new_dummy();
+            let buttons = Buttons {a: true, start: true, right: true, ..Buttons::default()};
+            cpu.set_controller_state(0, buttons);
+            cpu.set_controller_state(1, buttons);
+            cpu.write(0x4016, 0x01);
+            cpu.write(0x4016, 0x00);
+            let first = [read(&cpu, 0x4016), read(&cpu, 0x4017)];
+
+            cpu.write(0x4017, 0x01);
+            cpu.write(0x4017, 0x00);
+
+            assert_eq!(first, [0x41, 0x41]);
+            assert_eq!(read_eight(&cpu, 0x4016)[..7], [0x40, 0x40, 0x41, 0x40, 0x40, 0x40, 0x41]);
+            assert_eq!(read_eight(&cpu, 0x4017)[..7], [0x40, 0x40, 0x41, 0x40, 0x40, 0x40, 0x41]);
+        }
+    }
+
+    mod apu
+    {
+        use super::*;
+        use crate::cpu::cartridge::NROM;
+
+        // program at $8000, sample byte at $C040, IRQ handler at $9000
+        fn nrom_cpu(program: &[u8]) -> Cpu
+        {
+            let mut prg_rom = vec![0xEA; 0x4000];
+            prg_rom[..program.len()].copy_from_slice(program);
+            prg_rom[0x40] = 0xAA;
+            prg_rom[0x3FFE] = 0x00;
+            prg_rom[0x3FFF] = 0x90;
+            let mut cpu = Cpu::new(Box::new(NROM::new(prg_rom, vec![], Mirroring::Horizontal)));
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x8000);
+            cpu
+        }
+
+        // returns the number of cycles the instruction took
+        const DMC_PROGRAM: [u8; 20] = [
+            0xA9, 0x8F,         // LDA #$8F, IRQ and fastest rate
+            0x8D, 0x10, 0x40,   // STA $4010
+            0xA9, 0x01,         // LDA #$01
+            0x8D, 0x12, 0x40,   // STA $4012, $C040
+            0xA9, 0x00,         // LDA #$00
+            0x8D, 0x13, 0x40,   // STA $4013, 1 byte
+            0xA9, 0x10,         // LDA #$10
+            0x8D, 0x15, 0x40,   // STA $4015
+        ];
+
+        #[test]
+        fn test_dmc_fetch_stalls()
+        {
+            let mut cpu = nrom_cpu(&DMC_PROGRAM);
+            for _ in 0..7 {
+                cpu.step();
+            }
+            assert_eq!(cpu.load(0x4015) & 0x10, 0x00);
+
+            // the fetch happens right after the write enabling the channel
+            assert_eq!(cpu.step().cycles, 4 + 4);
+            assert_eq!(cpu.registers.pc, 0x8014);
+            // the last byte is read, the channel is done
+            assert_eq!(cpu.load(0x4015) & 0x10, 0x00);
+            assert_eq!(cpu.step().cycles, 2);
+        }
+
+        #[test]
+        fn test_dmc_irq()
+        {
+            // CLI after the program
+            let mut program = DMC_PROGRAM.to_vec();
+            program.push(0x58);
+            let mut cpu = nrom_cpu(&program);
+            for _ in 0..8 {
+                cpu.step();
+            }
+            assert_eq!(cpu.irq_line(), true);
+            assert_eq!(cpu.load(0x4015) & 0x80, 0x80);
+
+            // CLI, then the IRQ
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.registers.pc, 0x9000);
+        }
+
+        #[test]
+        fn test_frame_irq()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x0200);
+            // JMP $0200
+            cpu.internal_ram[..3].copy_from_slice(&[0x4C, 0x00, 0x02]);
+            cpu.write(0x4017, 0x00);
+
+            // the sequence restarts 3 cycles after the write, then sets the flag on its last 3 cycles
+            for _ in 0..3 + 29830 {
+                cpu.clock();
+            }
+            assert_eq!(cpu.irq_line(), true);
+            // I is set, the IRQ waits
+            assert_eq!(cpu.registers.pc & 0xFF00, 0x0200);
+
+            assert_eq!(cpu.load(0x4015) & 0x40, 0x40);
+            assert_eq!(cpu.load(0x4015) & 0x40, 0x00);
+            cpu.clock();
+            assert_eq!(cpu.irq_line(), false);
+        }
+
+        // Reading $4015 acknowledges the frame IRQ only, the DMC IRQ is acknowledged by
+        // $4010 or $4015 writes. The line stays up while either is pending.
+        #[test]
+        fn test_irq_acknowledge()
+        {
+            // JMP $8014 after the program
+            let mut program = DMC_PROGRAM.to_vec();
+            program.extend([0x4C, 0x14, 0x80]);
+            let mut cpu = nrom_cpu(&program);
+            cpu.registers.p.interrupt_disable = true;
+            // the frame counter runs its 4-step sequence from power on, the flag is set on
+            // its last three cycles
+            while !cpu.apu_mut().frame_irq() {
+                cpu.clock();
+            }
+            for _ in 0..3 {
+                cpu.clock();
+            }
+            assert_eq!(cpu.irq_line(), true);
+
+            assert_eq!(cpu.load(0x4015) & 0xC0, 0xC0);
+            assert_eq!(cpu.load(0x4015) & 0xC0, 0x80);
+            cpu.clock();
+            assert_eq!(cpu.irq_line(), true);
+
+            // IRQ disabled
+            cpu.write(0x4010, 0x0F);
+            assert_eq!(cpu.load(0x4015) & 0xC0, 0x00);
+            cpu.clock();
+            assert_eq!(cpu.irq_line(), false);
+
+            // nothing to take anymore
+            cpu.registers.p.interrupt_disable = false;
+            for _ in 0..10 {
+                cpu.step();
+                assert_eq!(cpu.registers.pc, 0x8014);
+            }
+        }
+
+        // a $4015 write acknowledges the DMC IRQ too
+        #[test]
+        fn test_dmc_irq_acknowledged_by_status_write()
+        {
+            let mut cpu = nrom_cpu(&DMC_PROGRAM);
+            for _ in 0..8 {
+                cpu.step();
+            }
+            assert_eq!(cpu.irq_line(), true);
+
+            cpu.write(0x4015, 0x00);
+            assert_eq!(cpu.load(0x4015) & 0x80, 0x00);
+            cpu.clock();
+            assert_eq!(cpu.irq_line(), false);
+        }
+
+        #[test]
+        fn test_frame_irq_taken()
+        {
+            let mut cpu = Cpu::new_dummy();
+            cpu.set_trace_sink(TraceSink::Off);
+            cpu.set_pc(0x0200);
+            // CLI ; JMP $0201
+            cpu.internal_ram[..4].copy_from_slice(&[0x58, 0x4C, 0x01, 0x02]);
+            cpu.write(0x4017, 0x00);
+
+            for _ in 0..29840 {
+                cpu.clock();
+            }
+            // DummyMapper has its IRQ vector at $8000
+            assert_eq!(cpu.registers.pc & 0xFF00, 0x8000);
+        }
+    }
+}
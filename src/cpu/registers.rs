@@ -1,3 +1,5 @@
+use std::fmt;
+
 
 pub struct Status
 {
@@ -11,9 +13,11 @@ pub struct Status
 
 impl Status
 {
+    // NV1-DIZC: the unused bit always reads as set, B only exists on the stack
     pub fn get_byte(&self) -> u8
     {
-        (self.carry as u8)
+        0b0010_0000
+            | (self.carry as u8)
             | (self.zero as u8) << 1
             | (self.interrupt_disable as u8) << 2
             | (self.decimal as u8) << 3
@@ -21,6 +25,7 @@ impl Status
             | (self.negative as u8) << 7
     }
 
+    // bits 4 and 5 are ignored, like PLP and RTI do
     pub fn set_byte(&mut self, status: u8)
     {
         self.carry = (status & 0b0000_0001) == 1;
@@ -69,11 +74,18 @@ impl Registers
     pub fn set_status_decimal(&mut self, status: bool) -> &mut Self { self.p.decimal = status; self }
     pub fn set_status_overflow(&mut self, status: bool) -> &mut Self { self.p.overflow = status; self }
     pub fn set_status_negative(&mut self, status: bool) -> &mut Self { self.p.negative = status; self }
+}
+
+impl Default for Registers
+{
+    fn default() -> Registers { Registers::new() }
+}
 
-    pub fn flip_status_carry(&mut self) -> &mut Self { self.p.carry = !self.p.carry; self }
-    pub fn flip_status_zero(&mut self) -> &mut Self { self.p.zero = !self.p.zero; self }
-    pub fn flip_status_interupt_disable(&mut self) -> &mut Self { self.p.interrupt_disable = !self.p.interrupt_disable; self }
-    pub fn flip_status_decimal(&mut self) -> &mut Self { self.p.decimal = !self.p.decimal; self }
-    pub fn flip_status_overflow(&mut self) -> &mut Self { self.p.overflow = !self.p.overflow; self }
-    pub fn flip_status_negative(&mut self) -> &mut Self { self.p.negative = !self.p.negative; self }
+// the registers as nestest logs them, A:00 X:00 Y:00 P:24 SP:FD
+impl fmt::Display for Registers
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}", self.a, self.x, self.y, self.p.get_byte(), self.stack_pointer)
+    }
 }
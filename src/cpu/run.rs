@@ -0,0 +1,183 @@
+use super::{
+    Cpu,
+    Interrupts,
+};
+use super::disassembler::{
+    Instruction,
+    instruction_length,
+};
+use super::isa::opcode_info;
+use crate::utils::Clocked;
+
+// blargg's test ROMs report through PRG-RAM: $6000 is the status, $6001-$6003 the
+// signature telling the status is valid, $6004 a zero terminated message
+const STATUS_ADDRESS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const MESSAGE_ADDRESS: u16 = 0x6004;
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+
+// When a run stops, checked on instruction boundaries. Memory is peeked, checking
+// never trips a watchpoint.
+pub enum StopCondition
+{
+    CycleCount(u64),
+    PcEquals(u16),
+    MemoryEquals { address: u16, value: u8 },
+    // stops once the status byte reports a result, presses reset when asked to
+    StatusByteProtocol,
+    Any(Vec<StopCondition>),
+}
+
+// What one step executed. An interrupt sequence is reported as the BRK the CPU
+// forces into its instruction register, at the address of the delayed instruction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StepInfo
+{
+    pub pc: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    // bytes past the instruction are 0
+    pub operands: [u8; 2],
+    // 0 for interrupts, they consume no byte
+    pub length: u16,
+    // DMA stalls included
+    pub cycles: u64,
+    pub interrupt: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StopReason
+{
+    CycleCount(u64),
+    PcEquals(u16),
+    MemoryEquals { address: u16, value: u8 },
+    TestCompleted { result: u8, message: String },
+    // a breakpoint, a watchpoint or a ROM write raised an event, the run stops on the
+    // next boundary until every event is taken
+    DebugEvent,
+}
+
+// what the status byte protocol saw last, a reset is pressed on the change to $81.
+// A result only counts once the test reported running, PRG-RAM may hold anything before.
+#[derive(Default)]
+struct StatusByteState
+{
+    last_status: Option<u8>,
+    started: bool,
+}
+
+impl StopCondition
+{
+    fn check(&self, cpu: &mut Cpu, state: &mut StatusByteState) -> Option<StopReason>
+    {
+        match self {
+            StopCondition::CycleCount(cycles) if cpu.cycles >= *cycles => Some(StopReason::CycleCount(cpu.cycles)),
+            StopCondition::PcEquals(pc) if cpu.registers.pc == *pc => Some(StopReason::PcEquals(*pc)),
+            StopCondition::MemoryEquals { address, value } if cpu.peek(*address) == *value =>
+                Some(StopReason::MemoryEquals { address: *address, value: *value }),
+            StopCondition::StatusByteProtocol => StopCondition::check_status_byte(cpu, state),
+            StopCondition::Any(conditions) => conditions.iter().find_map(|condition| condition.check(cpu, state)),
+            _ => None,
+        }
+    }
+
+    fn check_status_byte(cpu: &mut Cpu, state: &mut StatusByteState) -> Option<StopReason>
+    {
+        let signature = [cpu.peek(STATUS_ADDRESS + 1), cpu.peek(STATUS_ADDRESS + 2), cpu.peek(STATUS_ADDRESS + 3)];
+        if signature != SIGNATURE {
+            return None
+        }
+        let status = cpu.peek(STATUS_ADDRESS);
+        let changed = state.last_status != Some(status);
+        state.last_status = Some(status);
+        match status {
+            STATUS_RUNNING => {
+                state.started = true;
+                None
+            },
+            STATUS_NEEDS_RESET => {
+                if changed && state.started {
+                    cpu.reset();
+                }
+                None
+            },
+            result if result < STATUS_RUNNING && state.started => Some(StopReason::TestCompleted { result, message: cpu.read_string(MESSAGE_ADDRESS) }),
+            _ => None,
+        }
+    }
+}
+
+impl Cpu
+{
+    // The sequence takes 7 cycles like the other interrupts, they all come after this
+    // call. The reset line also goes to the PPU and the APU.
+    pub fn reset(&mut self)
+    {
+        self.interrupt(Interrupts::Reset);
+        self.wait_cycles = 7;
+        self.micro.abort();
+        self.ppu.get_mut().reset();
+        self.apu.get_mut().reset();
+    }
+
+    // zero terminated string, as the test ROMs write their messages, peeked
+    pub fn read_string(&self, address: u16) -> String
+    {
+        let mut bytes = Vec::new();
+        let mut address = address;
+        loop {
+            match self.peek(address) {
+                0 => break,
+                byte => bytes.push(byte),
+            }
+            address = address.wrapping_add(1);
+            if address == 0 {
+                break
+            }
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    // Runs one whole instruction or interrupt sequence through clock(). An instruction
+    // clock() already started is finished first, without being reported. With loop
+    // acceleration on, a skipped loop counts as one step.
+    pub fn step(&mut self) -> StepInfo
+    {
+        while !self.at_instruction_boundary() {
+            self.clock();
+        }
+        let instruction = Instruction::read(self, self.registers.pc);
+        let start = self.cycles;
+        self.clock();
+        while !self.at_instruction_boundary() {
+            self.clock();
+        }
+        let opcode = if self.servicing_interrupt {0x00} else {instruction.opcode};
+        StepInfo {
+            pc: instruction.pc,
+            opcode,
+            mnemonic: opcode_info(opcode).mnemonic,
+            operands: if self.servicing_interrupt {[0, 0]} else {instruction.operands},
+            length: if self.servicing_interrupt {0} else {instruction_length(opcode)},
+            cycles: self.cycles - start,
+            interrupt: self.servicing_interrupt,
+        }
+    }
+
+    pub fn run_until(&mut self, condition: &StopCondition) -> StopReason
+    {
+        let mut state = StatusByteState::default();
+        loop {
+            if self.at_instruction_boundary() {
+                if self.has_debug_event() {
+                    return StopReason::DebugEvent
+                }
+                if let Some(reason) = condition.check(self, &mut state) {
+                    return reason
+                }
+            }
+            self.clock();
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use super::Cpu;
+use crate::save_state::{
+    SaveState,
+    SaveStateError,
+    StateWriter,
+};
+
+impl Cpu
+{
+    // The whole console: the CPU, the RAM, the cartridge board, the PPU, the APU and
+    // the controllers. Settings like the trace sink or loop acceleration are not part of it.
+    pub fn save_state(&self) -> SaveState
+    {
+        let mut state = StateWriter::new();
+        let registers = &self.registers;
+        for register in [registers.a, registers.x, registers.y, registers.p.get_byte(), registers.stack_pointer] {
+            state.write_u8(register);
+        }
+        state.write_u16(registers.pc);
+        state.write_u16(self.instruction_pc);
+        state.write_bool(self.servicing_interrupt);
+        state.write_u64(self.cycles);
+        state.write_u32(self.wait_cycles);
+        self.micro.save_state(&mut state);
+        for line in [self.nmi_line, self.nmi_level, self.nmi_pending] {
+            state.write_bool(line);
+        }
+        state.write_option_u64(self.interrupt_hijack_end);
+        state.write_u8(self.irq_sources);
+        state.write_option_u8(self.oam_dma_page);
+        state.write_u64(self.oam_dma_end);
+        state.write_u8(self.last_bus_value.get());
+        state.write_bytes(&self.zero_page_ram);
+        state.write_bytes(&self.stack);
+        state.write_bytes(&self.internal_ram);
+        self.cartridge.borrow().save_state(&mut state);
+        self.ppu.borrow().save_state(&mut state);
+        self.apu.borrow().save_state(&mut state);
+        for controller in self.controllers.borrow().iter() {
+            controller.save_state(&mut state);
+        }
+        state.finish()
+    }
+
+    // a state that fails to load leaves the console as it was
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), SaveStateError>
+    {
+        let backup = self.save_state();
+        let result = self.read_state(state);
+        if result.is_err() {
+            self.read_state(&backup).expect("could not restore the state saved before loading");
+        }
+        result
+    }
+
+    fn read_state(&mut self, state: &SaveState) -> Result<(), SaveStateError>
+    {
+        let mut state = state.reader();
+        let registers = &mut self.registers;
+        registers.a = state.read_u8()?;
+        registers.x = state.read_u8()?;
+        registers.y = state.read_u8()?;
+        registers.p.set_byte(state.read_u8()?);
+        registers.stack_pointer = state.read_u8()?;
+        registers.pc = state.read_u16()?;
+        self.instruction_pc = state.read_u16()?;
+        self.servicing_interrupt = state.read_bool()?;
+        self.cycles = state.read_u64()?;
+        self.wait_cycles = state.read_u32()?;
+        self.micro.load_state(&mut state)?;
+        for line in [&mut self.nmi_line, &mut self.nmi_level, &mut self.nmi_pending] {
+            *line = state.read_bool()?;
+        }
+        self.interrupt_hijack_end = state.read_option_u64()?;
+        self.irq_sources = state.read_u8()?;
+        self.oam_dma_page = state.read_option_u8()?;
+        self.oam_dma_end = state.read_u64()?;
+        self.last_bus_value.set(state.read_u8()?);
+        state.read_bytes(&mut self.zero_page_ram)?;
+        state.read_bytes(&mut self.stack)?;
+        state.read_bytes(&mut self.internal_ram)?;
+        self.cartridge.borrow_mut().load_state(&mut state)?;
+        self.ppu.get_mut().load_state(&mut state)?;
+        self.apu.get_mut().load_state(&mut state)?;
+        for controller in self.controllers.get_mut().iter_mut() {
+            controller.load_state(&mut state)?;
+        }
+        // a loop being verified may not exist in the loaded state
+        self.loop_prediction = None;
+        state.finish()
+    }
+}
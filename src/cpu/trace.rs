@@ -0,0 +1,338 @@
+use std::fmt;
+use std::io::{
+    self,
+    BufWriter,
+    Read,
+    Write,
+};
+
+use super::Cpu;
+use super::disassembler::Instruction;
+
+// pc (2) + opcode (1) + operands (2) + a, x, y, p, sp (5) + effective address (2) + value (1)
+// + scanline (2) + dot (2) + cycles (8), little endian
+pub const TRACE_RECORD_SIZE: usize = 25;
+
+// CPU state right before an instruction executes
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TraceRecord
+{
+    pub pc: u16,
+    pub opcode: u8,
+    // bytes past the instruction are 0
+    pub operands: [u8; 2],
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    // what the instruction accesses, for the disassembly
+    pub effective_address: u16,
+    pub value: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycles: u64,
+}
+
+impl TraceRecord
+{
+    pub fn to_bytes(self) -> [u8; TRACE_RECORD_SIZE]
+    {
+        let mut bytes = [0; TRACE_RECORD_SIZE];
+        bytes[0..2].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[2] = self.opcode;
+        bytes[3..5].copy_from_slice(&self.operands);
+        bytes[5..10].copy_from_slice(&[self.a, self.x, self.y, self.p, self.sp]);
+        bytes[10..12].copy_from_slice(&self.effective_address.to_le_bytes());
+        bytes[12] = self.value;
+        bytes[13..15].copy_from_slice(&self.scanline.to_le_bytes());
+        bytes[15..17].copy_from_slice(&self.dot.to_le_bytes());
+        bytes[17..25].copy_from_slice(&self.cycles.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; TRACE_RECORD_SIZE]) -> TraceRecord
+    {
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&bytes[17..25]);
+        TraceRecord {
+            pc: u16::from_le_bytes([bytes[0], bytes[1]]),
+            opcode: bytes[2],
+            operands: [bytes[3], bytes[4]],
+            a: bytes[5],
+            x: bytes[6],
+            y: bytes[7],
+            p: bytes[8],
+            sp: bytes[9],
+            effective_address: u16::from_le_bytes([bytes[10], bytes[11]]),
+            value: bytes[12],
+            scanline: u16::from_le_bytes([bytes[13], bytes[14]]),
+            dot: u16::from_le_bytes([bytes[15], bytes[16]]),
+            cycles: u64::from_le_bytes(cycles),
+        }
+    }
+
+    fn instruction(&self) -> Instruction
+    {
+        Instruction {
+            pc: self.pc,
+            opcode: self.opcode,
+            operands: self.operands,
+            x: self.x,
+            y: self.y,
+            effective_address: self.effective_address,
+            value: self.value,
+        }
+    }
+}
+
+// canonical text trace line, as in nestest.log
+impl fmt::Display for TraceRecord
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let instruction = self.instruction();
+        // the star of unofficial mnemonics takes the space before them
+        let text = instruction.text();
+        let text = if text.starts_with('*') {text} else {format!(" {}", text)};
+        write!(
+            f,
+            "{:04X}  {:8} {:33}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            self.pc,
+            instruction.bytes(),
+            text,
+            self.a,
+            self.x,
+            self.y,
+            self.p,
+            self.sp,
+            self.scanline,
+            self.dot,
+            self.cycles,
+        )
+    }
+}
+
+pub enum TraceSink
+{
+    Off,
+    Stdout,
+    Text(BufWriter<Box<dyn Write>>),
+    Binary(BufWriter<Box<dyn Write>>),
+}
+
+impl TraceSink
+{
+    pub fn text(out: Box<dyn Write>) -> TraceSink { TraceSink::Text(BufWriter::new(out)) }
+    pub fn binary(out: Box<dyn Write>) -> TraceSink { TraceSink::Binary(BufWriter::new(out)) }
+
+    pub fn write_record(&mut self, record: &TraceRecord) -> io::Result<()>
+    {
+        match self {
+            TraceSink::Off => Ok(()),
+            TraceSink::Stdout => {
+                println!("{}", record);
+                Ok(())
+            },
+            TraceSink::Text(out) => writeln!(out, "{}", record),
+            TraceSink::Binary(out) => out.write_all(&record.to_bytes()),
+        }
+    }
+
+    // lines between the instructions, like the writes to ROM of RomWritePolicy::Log. The
+    // binary format only holds instruction records, it leaves them out.
+    pub fn write_note(&mut self, note: &str) -> io::Result<()>
+    {
+        match self {
+            TraceSink::Off | TraceSink::Binary(_) => Ok(()),
+            TraceSink::Stdout => {
+                println!("{}", note);
+                Ok(())
+            },
+            TraceSink::Text(out) => writeln!(out, "{}", note),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()>
+    {
+        match self {
+            TraceSink::Off => Ok(()),
+            TraceSink::Stdout => io::stdout().flush(),
+            TraceSink::Text(out) | TraceSink::Binary(out) => out.flush(),
+        }
+    }
+}
+
+// Keeps the last records in the binary trace format, for post-mortem debugging.
+// The buffer is allocated once, recording a record is a copy into the next slot.
+pub struct FlightRecorder
+{
+    records: Box<[[u8; TRACE_RECORD_SIZE]]>,
+    next: usize,
+    len: usize,
+}
+
+impl FlightRecorder
+{
+    pub fn new(capacity: usize) -> FlightRecorder
+    {
+        FlightRecorder {
+            records: vec![[0; TRACE_RECORD_SIZE]; capacity].into_boxed_slice(),
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize { self.records.len() }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn record(&mut self, record: &TraceRecord)
+    {
+        if self.records.is_empty() {
+            return
+        }
+        self.records[self.next] = record.to_bytes();
+        self.next = (self.next + 1) % self.records.len();
+        self.len = (self.len + 1).min(self.records.len());
+    }
+
+    // oldest first
+    pub fn records(&self) -> impl Iterator<Item = TraceRecord> + '_
+    {
+        let start = (self.next + self.records.len() - self.len) % self.records.len().max(1);
+        (0..self.len).map(move |i| TraceRecord::from_bytes(&self.records[(start + i) % self.records.len()]))
+    }
+
+    // writes the recorded instructions as a binary trace
+    pub fn export_binary<W: Write>(&self, output: W) -> io::Result<()>
+    {
+        let mut output = BufWriter::new(output);
+        for record in self.records() {
+            output.write_all(&record.to_bytes())?;
+        }
+        output.flush()
+    }
+
+    // writes the recorded instructions as a text trace
+    pub fn export_text<W: Write>(&self, output: W) -> io::Result<()>
+    {
+        let mut output = BufWriter::new(output);
+        for record in self.records() {
+            writeln!(output, "{}", record)?;
+        }
+        output.flush()
+    }
+}
+
+// Lazily decodes a binary trace, one record at a time
+pub struct BinaryTraceReader<R: Read>
+{
+    input: R,
+}
+
+impl<R: Read> BinaryTraceReader<R>
+{
+    pub fn new(input: R) -> BinaryTraceReader<R> { BinaryTraceReader{input} }
+}
+
+impl<R: Read> Iterator for BinaryTraceReader<R>
+{
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<io::Result<TraceRecord>>
+    {
+        let mut bytes = [0; TRACE_RECORD_SIZE];
+        let mut read = 0;
+        while read < TRACE_RECORD_SIZE {
+            match self.input.read(&mut bytes[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated trace record"))),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(TraceRecord::from_bytes(&bytes)))
+    }
+}
+
+// Converts a binary trace to the canonical text trace
+pub fn decode_binary_trace<R: Read, W: Write>(input: R, output: W) -> io::Result<()>
+{
+    let mut output = BufWriter::new(output);
+    for record in BinaryTraceReader::new(input) {
+        writeln!(output, "{}", record?)?;
+    }
+    output.flush()
+}
+
+impl Cpu
+{
+    pub fn set_trace_sink(&mut self, sink: TraceSink) { self.trace_sink = sink }
+
+    // also reports the first write that failed since the last flush
+    pub fn flush_trace(&mut self) -> io::Result<()>
+    {
+        match self.trace_error.take() {
+            Some(error) => Err(error),
+            None => self.trace_sink.flush(),
+        }
+    }
+
+    // the writes happen in the middle of instructions and bus accesses, which cannot
+    // fail, so the first error waits for flush_trace
+    pub(crate) fn keep_trace_error(&mut self, result: io::Result<()>)
+    {
+        if let Err(error) = result {
+            self.trace_error.get_or_insert(error);
+        }
+    }
+
+    // keeps the last capacity instructions, 0 turns the recorder off
+    pub fn set_flight_recorder(&mut self, capacity: usize)
+    {
+        self.flight_recorder = match capacity {
+            0 => None,
+            _ => Some(FlightRecorder::new(capacity)),
+        }
+    }
+
+    pub fn flight_recorder(&self) -> Option<&FlightRecorder> { self.flight_recorder.as_ref() }
+
+    pub fn trace_record(&self) -> TraceRecord
+    {
+        let instruction = Instruction::read(self, self.registers.pc);
+        let ppu = self.ppu.borrow();
+        TraceRecord {
+            pc: instruction.pc,
+            opcode: instruction.opcode,
+            operands: instruction.operands,
+            a: self.registers.a,
+            x: self.registers.x,
+            y: self.registers.y,
+            p: self.registers.p.get_byte(),
+            sp: self.registers.stack_pointer,
+            effective_address: instruction.effective_address,
+            value: instruction.value,
+            scanline: ppu.scanline(),
+            dot: ppu.dot(),
+            cycles: self.cycles,
+        }
+    }
+
+    pub fn trace(&mut self)
+    {
+        if let (TraceSink::Off, None) = (&self.trace_sink, &self.flight_recorder) {
+            return
+        }
+        let record = self.trace_record();
+        if let Some(recorder) = &mut self.flight_recorder {
+            recorder.record(&record);
+        }
+        let result = self.trace_sink.write_record(&record);
+        self.keep_trace_error(result);
+    }
+}
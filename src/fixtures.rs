@@ -0,0 +1,365 @@
+// Reference files (test ROMs, golden logs) that tests can use without committing them.
+// Point NESQUICK_FIXTURES_DIR at a directory laid out like rom_tests/ to enable them.
+// Without it, tests needing a fixture are skipped, unless built with the
+// require-fixtures feature, which turns skips into failures.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+pub const FIXTURES_DIR_VAR: &str = "NESQUICK_FIXTURES_DIR";
+
+pub struct Fixture
+{
+    pub path: &'static str,
+    // None until the hash has been taken from a copy of the file, the fixture then
+    // loads with a warning giving the hash to pin
+    pub sha256: Option<&'static str>,
+    pub source: &'static str,
+}
+
+pub const FIXTURES: [Fixture; 21] = [
+    Fixture {
+        path: "nestest/nestest.nes",
+        sha256: Some("f67d55fd6b3cf0bad1cc85f1df0d739c65b53e79cecb7fea8f77ec0eadab0004"),
+        source: "https://www.qmtpro.com/~nes/misc/nestest.nes",
+    },
+    Fixture {
+        path: "nestest/nestest.log.txt",
+        sha256: Some("442c4dd5539c7e88b3fd73c7b732a7eadbd22b47c2cd9e58397ef147f64f6f8f"),
+        source: "https://www.qmtpro.com/~nes/misc/nestest.log",
+    },
+    // blargg's CPU suites, laid out like the archives they come from
+    Fixture {
+        path: "instr_test-v5/rom_singles/01-basics.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/01-basics.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/02-implied.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/02-implied.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/03-immediate.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/03-immediate.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/04-zero_page.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/04-zero_page.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/05-zp_xy.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/05-zp_xy.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/06-absolute.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/06-absolute.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/07-abs_xy.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/07-abs_xy.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/08-ind_x.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/08-ind_x.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/09-ind_y.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/09-ind_y.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/10-branches.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/10-branches.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/11-stack.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/11-stack.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/12-jmp_jsr.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/12-jmp_jsr.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/13-rts.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/13-rts.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/14-rti.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/14-rti.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/15-brk.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/15-brk.nes",
+    },
+    Fixture {
+        path: "instr_test-v5/rom_singles/16-special.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_test-v5/rom_singles/16-special.nes",
+    },
+    Fixture {
+        path: "cpu_dummy_reads/cpu_dummy_reads.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/cpu_dummy_reads/cpu_dummy_reads.nes",
+    },
+    Fixture {
+        path: "instr_timing/rom_singles/1-instr_timing.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_timing/rom_singles/1-instr_timing.nes",
+    },
+    Fixture {
+        path: "instr_timing/rom_singles/2-branch_timing.nes",
+        sha256: None,
+        source: "https://github.com/christopherpow/nes-test-roms/raw/master/instr_timing/rom_singles/2-branch_timing.nes",
+    },
+];
+
+#[derive(Debug, PartialEq)]
+pub enum FixtureError
+{
+    Unknown(String),
+    Missing { path: PathBuf, expected: Option<&'static str>, source: &'static str },
+    Corrupted { path: PathBuf, expected: &'static str, actual: String, source: &'static str },
+    Unpinned { path: PathBuf, actual: String, source: &'static str },
+}
+
+impl fmt::Display for FixtureError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            FixtureError::Unknown(path) => write!(f, "{} is not a registered fixture", path),
+            FixtureError::Missing { path, expected: Some(expected), source } =>
+                write!(f, "fixture {} is missing (expected sha256 {}, get it from {})", path.display(), expected, source),
+            FixtureError::Missing { path, expected: None, source } =>
+                write!(f, "fixture {} is missing (get it from {})", path.display(), source),
+            FixtureError::Corrupted { path, expected, actual, source } =>
+                write!(f, "fixture {} has sha256 {} instead of {} (get it again from {})", path.display(), actual, expected, source),
+            FixtureError::Unpinned { path, actual, source } =>
+                write!(f, "fixture {} has no registered sha256, pin {} once checked against {}", path.display(), actual, source),
+        }
+    }
+}
+
+pub fn fixture(path: &str) -> Option<&'static Fixture>
+{
+    FIXTURES.iter().find(|fixture| fixture.path == path)
+}
+
+// Reads a registered fixture from dir and checks its hash. An unpinned fixture comes
+// back with the Unpinned error next to its content.
+pub fn load_from(dir: &Path, path: &str) -> Result<(Vec<u8>, Option<FixtureError>), FixtureError>
+{
+    let fixture = fixture(path).ok_or_else(|| FixtureError::Unknown(path.to_string()))?;
+    let full_path = dir.join(fixture.path);
+    let content = fs::read(&full_path).map_err(|_| FixtureError::Missing {
+        path: full_path.clone(),
+        expected: fixture.sha256,
+        source: fixture.source,
+    })?;
+    let actual = to_hex(&sha256(&content));
+    match fixture.sha256 {
+        Some(expected) if actual != expected =>
+            Err(FixtureError::Corrupted {path: full_path, expected, actual, source: fixture.source}),
+        Some(_) => Ok((content, None)),
+        None => Ok((content, Some(FixtureError::Unpinned {path: full_path, actual, source: fixture.source}))),
+    }
+}
+
+// Returns None when the test should be skipped, panics on a missing or corrupted fixture
+pub fn load(path: &str) -> Option<Vec<u8>>
+{
+    let dir = match env::var_os(FIXTURES_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None if cfg!(feature = "require-fixtures") => panic!("{} must be set to run fixture tests", FIXTURES_DIR_VAR),
+        None => {
+            eprintln!("skipped: {} is not set, fixture {} unavailable", FIXTURES_DIR_VAR, path);
+            return None
+        },
+    };
+    match load_from(&dir, path) {
+        Ok((_, Some(unpinned))) if cfg!(feature = "require-fixtures") => panic!("{}", unpinned),
+        Ok((content, unpinned)) => {
+            if let Some(unpinned) = unpinned {
+                eprintln!("warning: {}", unpinned);
+            }
+            Some(content)
+        },
+        Err(e) => panic!("{}", e),
+    }
+}
+
+// Loads a fixture or returns from the calling test
+macro_rules! fixture_or_skip {
+    ($path:expr) => {
+        match crate::fixtures::load($path) {
+            Some(content) => content,
+            None => return,
+        }
+    };
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+pub fn sha256(data: &[u8]) -> [u8; 32]
+{
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256()
+    {
+        assert_eq!(to_hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(to_hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(
+            to_hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            to_hex(&sha256(&[b'a'; 1000])),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+    }
+
+    #[test]
+    fn test_registry()
+    {
+        let dir = Path::new("rom_tests");
+
+        // only nestest is committed, and it is pinned
+        for path in ["nestest/nestest.nes", "nestest/nestest.log.txt"] {
+            assert_eq!(load_from(dir, path).map(|(_, unpinned)| unpinned), Ok(None), "{}", path);
+        }
+        assert_eq!(load_from(dir, "nestest/unknown.nes"), Err(FixtureError::Unknown("nestest/unknown.nes".to_string())));
+        for (i, fixture) in FIXTURES.iter().enumerate() {
+            assert_eq!(FIXTURES[..i].iter().any(|other| other.path == fixture.path), false, "{}", fixture.path);
+            assert_eq!(fixture.sha256.is_none_or(|hash| hash.len() == 64), true, "{}", fixture.path);
+        }
+    }
+
+    #[test]
+    fn test_missing_and_corrupted()
+    {
+        let dir = env::temp_dir().join(format!("nesquick-fixtures-{}", std::process::id()));
+        let missing = load_from(&dir, "nestest/nestest.nes");
+        fs::create_dir_all(dir.join("nestest")).unwrap();
+        fs::write(dir.join("nestest/nestest.nes"), b"NES\x1A").unwrap();
+        let corrupted = load_from(&dir, "nestest/nestest.nes");
+        fs::remove_dir_all(&dir).unwrap();
+
+        match missing {
+            Err(FixtureError::Missing { path, expected, .. }) => {
+                assert_eq!(path, dir.join("nestest/nestest.nes"));
+                assert_eq!(expected, FIXTURES[0].sha256);
+            },
+            _ => panic!("expected a missing fixture"),
+        }
+        match corrupted {
+            Err(FixtureError::Corrupted { actual, .. }) => assert_eq!(actual, to_hex(&sha256(b"NES\x1A"))),
+            _ => panic!("expected a corrupted fixture"),
+        }
+    }
+
+    #[test]
+    fn test_unpinned()
+    {
+        let path = "instr_test-v5/rom_singles/01-basics.nes";
+        let dir = env::temp_dir().join(format!("nesquick-unpinned-{}", std::process::id()));
+        fs::create_dir_all(dir.join("instr_test-v5/rom_singles")).unwrap();
+        fs::write(dir.join(path), b"NES\x1A").unwrap();
+        let loaded = load_from(&dir, path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(fixture(path).unwrap().sha256, None);
+        match loaded {
+            Ok((content, Some(FixtureError::Unpinned { actual, .. }))) => {
+                assert_eq!(content, b"NES\x1A");
+                assert_eq!(actual, to_hex(&sha256(b"NES\x1A")));
+            },
+            _ => panic!("expected an unpinned fixture"),
+        }
+    }
+}
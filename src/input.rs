@@ -0,0 +1,156 @@
+// Standard controller: a 4021 shift register loaded from the buttons while the
+// strobe (bit 0 of $4016) is high, then shifted out one bit per read, A first.
+
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Buttons
+{
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+impl Buttons
+{
+    // in the order they are shifted out
+    fn bits(self) -> u8
+    {
+        self.a as u8
+            | (self.b as u8) << 1
+            | (self.select as u8) << 2
+            | (self.start as u8) << 3
+            | (self.up as u8) << 4
+            | (self.down as u8) << 5
+            | (self.left as u8) << 6
+            | (self.right as u8) << 7
+    }
+
+    fn from_bits(bits: u8) -> Buttons
+    {
+        let bit = |index: u8| bits >> index & 0x01 != 0;
+        Buttons {
+            a: bit(0),
+            b: bit(1),
+            select: bit(2),
+            start: bit(3),
+            up: bit(4),
+            down: bit(5),
+            left: bit(6),
+            right: bit(7),
+        }
+    }
+}
+
+pub struct Controller
+{
+    buttons: Buttons,
+    shift_register: u8,
+    strobe: bool,
+}
+
+impl Controller
+{
+    pub fn new() -> Controller { Controller {buttons: Buttons::default(), shift_register: 0, strobe: false} }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) { self.buttons = buttons }
+
+    pub fn write_strobe(&mut self, data: u8)
+    {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.shift_register = self.buttons.bits();
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        state.write_u8(self.buttons.bits());
+        state.write_u8(self.shift_register);
+        state.write_bool(self.strobe);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        self.buttons = Buttons::from_bits(state.read_u8()?);
+        self.shift_register = state.read_u8()?;
+        self.strobe = state.read_bool()?;
+        Ok(())
+    }
+
+    // bit 0 only, the register fills with 1s once the eight buttons are out
+    pub fn read(&mut self) -> u8
+    {
+        if self.strobe {
+            self.shift_register = self.buttons.bits();
+        }
+        let bit = self.shift_register & 0x01;
+        self.shift_register = self.shift_register >> 1 | 0x80;
+        bit
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn read_eight(controller: &mut Controller) -> Vec<u8> { (0..8).map(|_| controller.read()).collect() }
+
+    #[test]
+    fn test_shift_order()
+    {
+        let mut controller = Controller::new();
+        controller.set_buttons(Buttons {a: true, start: true, left: true, ..Buttons::default()});
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+
+        assert_eq!(read_eight(&mut controller), vec![1, 0, 0, 1, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_reads_one_when_exhausted()
+    {
+        let mut controller = Controller::new();
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+
+        assert_eq!(read_eight(&mut controller), vec![0; 8]);
+        assert_eq!(read_eight(&mut controller), vec![1; 8]);
+    }
+
+    // while the strobe is high, every read reloads and returns A
+    #[test]
+    fn test_strobe_high()
+    {
+        let mut controller = Controller::new();
+        controller.set_buttons(Buttons {a: true, b: true, ..Buttons::default()});
+        controller.write_strobe(1);
+
+        assert_eq!(read_eight(&mut controller), vec![1; 8]);
+        controller.set_buttons(Buttons::default());
+        assert_eq!(controller.read(), 0);
+    }
+
+    // the buttons are only latched by the strobe
+    #[test]
+    fn test_latch()
+    {
+        let mut controller = Controller::new();
+        controller.set_buttons(Buttons {right: true, ..Buttons::default()});
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        controller.set_buttons(Buttons::default());
+
+        assert_eq!(read_eight(&mut controller), vec![0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+}
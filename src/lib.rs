@@ -0,0 +1,55 @@
+// hardware names (NROM, IRQ, NMI...) and opcode lists read better as written
+#![allow(clippy::upper_case_acronyms, clippy::manual_range_patterns, clippy::bool_assert_comparison)]
+
+mod utils;
+#[cfg(test)]
+#[macro_use]
+mod fixtures;
+mod cpu;
+mod ppu;
+mod apu;
+mod input;
+mod save_state;
+mod nes;
+pub mod rom_profiles;
+
+// The emulator core, for frontends, fuzzers and tools. The Cpu owns the whole
+// console: the cartridge, the PPU, the APU and the controllers. Nes wraps it with
+// the frame and cycle run loops frontends need.
+pub use utils::Clocked;
+pub use cpu::{
+    Cpu,
+    CpuVariant,
+    IrqSource,
+    Mapper,
+    Mirroring,
+    CartridgeError,
+    load_cartridge,
+    load_cartridge_from_bytes,
+    load_cartridge_from_reader,
+    StopCondition,
+    StopReason,
+    StepInfo,
+    TraceSink,
+    decode_binary_trace,
+    disassemble,
+    LoopAcceleration,
+    LoopState,
+    RomWritePolicy,
+    DebugEvent,
+    WatchKind,
+    Registers,
+    Status,
+    isa,
+};
+pub use nes::Nes;
+pub use ppu::Ppu;
+pub use apu::Apu;
+pub use input::Buttons;
+pub use save_state::{
+    SaveState,
+    SaveStateError,
+    StateReader,
+    StateWriter,
+    SAVE_STATE_VERSION,
+};
@@ -1,20 +1,187 @@
-mod utils;
-mod cpu;
+use std::env;
+use std::fs::{
+    self,
+    File,
+};
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process;
 
-use cpu::{
+use nesquick::{
     Cpu,
-    load_cartridge,
+    Nes,
+    load_cartridge_from_bytes,
+    StopCondition,
+    StopReason,
+    TraceSink,
+    decode_binary_trace,
 };
-use crate::utils::Clocked;
+use nesquick::rom_profiles::find_profile;
+
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str>
+{
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1)).map(|value| value.as_str())
+}
+
+// nesquick trace-decode <bin> [--out text.log]
+fn trace_decode(args: &[String]) -> io::Result<()>
+{
+    let input = match args.first() {
+        Some(path) => File::open(path)?,
+        None => {
+            eprintln!("usage: nesquick trace-decode <bin> [--out text.log]");
+            process::exit(2);
+        },
+    };
+    match option_value(args, "--out") {
+        Some(path) => decode_binary_trace(input, File::create(path)?),
+        None => decode_binary_trace(input, io::stdout()),
+    }
+}
+
+const USAGE: &str = "usage: nesquick <rom> [--pc ADDRESS] [--max-cycles N] [--trace] [--trace-file PATH] [--binary-trace PATH]
+       nesquick trace-decode <bin> [--out text.log]";
+
+fn usage_error(message: &str) -> !
+{
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+// $C000, 0xC000 or 49152
+fn parse_number(value: &str) -> Option<u64>
+{
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix('$')) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn number_option(args: &[String], name: &str) -> Option<u64>
+{
+    option_value(args, name).map(|value| {
+        parse_number(value).unwrap_or_else(|| usage_error(&format!("{} expects a number, got {}", name, value)))
+    })
+}
+
+fn create_file(path: &str) -> File
+{
+    File::create(path).unwrap_or_else(|e| {
+        eprintln!("could not create {}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+// the first argument that is neither an option nor an option value
+fn rom_path(args: &[String]) -> Option<&str>
+{
+    const VALUE_OPTIONS: [&str; 4] = ["--pc", "--max-cycles", "--trace-file", "--binary-trace"];
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            option if VALUE_OPTIONS.contains(&option) => i += 2,
+            "--trace" => i += 1,
+            option if option.starts_with("--") => usage_error(&format!("unknown option {}", option)),
+            path => return Some(path),
+        }
+    }
+    None
+}
+
+// battery backed RAM is kept next to the ROM, game.nes saves to game.sav
+fn load_save_file(cpu: &mut Cpu, rom_path: &str) -> PathBuf
+{
+    let save_path = Path::new(rom_path).with_extension("sav");
+    if cpu.prg_ram().is_none() {
+        return save_path
+    }
+    let data = match fs::read(&save_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return save_path,
+        Err(e) => {
+            eprintln!("could not read {}: {}", save_path.display(), e);
+            process::exit(1);
+        },
+    };
+    if let Err(e) = cpu.load_prg_ram(&data) {
+        eprintln!("could not load {}: {}", save_path.display(), e);
+        process::exit(1);
+    }
+    save_path
+}
+
+fn write_save_file(cpu: &Cpu, save_path: &Path)
+{
+    if let Some(ram) = cpu.prg_ram() {
+        if let Err(e) = fs::write(save_path, ram) {
+            eprintln!("could not write {}: {}", save_path.display(), e);
+            process::exit(1);
+        }
+    }
+}
 
 fn main()
 {
-    let cartridge = load_cartridge("rom_tests/nestest/nestest.nes");
-    let mut cpu = Cpu::new(cartridge);
-    cpu.set_pc(0xC000);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|arg| arg.as_str()) == Some("trace-decode") {
+        if let Err(e) = trace_decode(&args[1..]) {
+            eprintln!("could not decode trace: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let path = rom_path(&args).unwrap_or_else(|| usage_error("missing ROM path"));
+    let pc = number_option(&args, "--pc");
+    if let Some(pc) = pc.filter(|pc| *pc > 0xFFFF) {
+        usage_error(&format!("--pc expects a 16 bits address, got {}", pc));
+    }
+    let max_cycles = number_option(&args, "--max-cycles");
 
+    let rom = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("could not read {}: {}", path, e);
+        process::exit(1);
+    });
+    let cartridge = load_cartridge_from_bytes(&rom).unwrap_or_else(|e| {
+        eprintln!("could not load {}: {}", path, e);
+        process::exit(1);
+    });
+    let mut nes = Nes::new(cartridge);
+    let save_path = load_save_file(nes.cpu_mut(), path);
+    // the test ROMs with a profile run in their automated mode, others until they
+    // report through the status byte, if ever
+    let mut stop = match find_profile(&rom) {
+        Some(profile) => {
+            if let Some(entry) = profile.entry {
+                nes.cpu_mut().set_pc(entry);
+            }
+            (profile.stop)()
+        },
+        None => StopCondition::StatusByteProtocol,
+    };
+    if let Some(pc) = pc {
+        nes.cpu_mut().set_pc(pc as u16);
+    }
+    if let Some(cycles) = max_cycles {
+        stop = StopCondition::Any(vec![stop, StopCondition::CycleCount(cycles)]);
+    }
+
+    if let Some(path) = option_value(&args, "--binary-trace") {
+        nes.cpu_mut().set_trace_sink(TraceSink::binary(Box::new(create_file(path))));
+    } else if let Some(path) = option_value(&args, "--trace-file") {
+        nes.cpu_mut().set_trace_sink(TraceSink::text(Box::new(create_file(path))));
+    } else if args.iter().any(|arg| arg == "--trace") {
+        nes.cpu_mut().set_trace_sink(TraceSink::Stdout);
+    }
 
-    while cpu.cycles < 26554 {
-        cpu.clock();
+    let reason = nes.run_until_stopped(&stop);
+    nes.cpu_mut().flush_trace().expect("could not write trace");
+    write_save_file(nes.cpu(), &save_path);
+    if let StopReason::TestCompleted { result, message } = reason {
+        eprintln!("{}", message);
+        process::exit(result as i32);
     }
 }
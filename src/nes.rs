@@ -0,0 +1,205 @@
+// The console as frontends drive it: whole frames, cycle counts or any condition on
+// the console state. The Cpu already owns everything else: the PPU and the APU are
+// clocked from Cpu::clock, and the cartridge is shared with the PPU through an
+// Rc<RefCell>, since the PPU reads CHR while the CPU owns the PRG side of the board.
+// Nes only wraps it, so a frontend holds one value and borrows the parts it needs.
+
+use std::cell::Ref;
+
+use crate::apu::Apu;
+use crate::cpu::{
+    Cpu,
+    Mapper,
+    StopCondition,
+    StopReason,
+};
+use crate::input::Buttons;
+use crate::ppu::{
+    Ppu,
+    DOTS_PER_SCANLINE,
+    VBLANK_SCANLINE,
+};
+use crate::utils::Clocked;
+
+// the PPU raises the vblank flag on dot 1 of its scanline
+const VBLANK_START: u32 = VBLANK_SCANLINE as u32 * DOTS_PER_SCANLINE as u32 + 1;
+
+pub struct Nes
+{
+    cpu: Cpu,
+}
+
+impl Nes
+{
+    pub fn new(cartridge: Box<dyn Mapper>) -> Nes { Nes {cpu: Cpu::new(cartridge)} }
+
+    // settings, registers, save states and debugging all live on the Cpu
+    pub fn cpu(&self) -> &Cpu { &self.cpu }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu { &mut self.cpu }
+
+    pub fn ppu(&self) -> Ref<'_, Ppu> { self.cpu.ppu() }
+
+    pub fn apu_mut(&mut self) -> &mut Apu { self.cpu.apu_mut() }
+
+    pub fn set_controller_state(&mut self, port: usize, buttons: Buttons) { self.cpu.set_controller_state(port, buttons) }
+
+    // CPU cycles since power on
+    pub fn cycles(&self) -> u64 { self.cpu.cycles() }
+
+    fn ppu_position(&self) -> u32
+    {
+        let ppu = self.cpu.ppu();
+        ppu.scanline() as u32 * DOTS_PER_SCANLINE as u32 + ppu.dot() as u32
+    }
+
+    // Runs until the PPU enters vblank, once the last visible scanline is in the frame
+    // buffer. Stops on the CPU cycle that raised the flag, always running at least one.
+    pub fn run_frame(&mut self)
+    {
+        loop {
+            let before = self.ppu_position();
+            self.clock();
+            if before <= VBLANK_START && self.ppu_position() > VBLANK_START {
+                return
+            }
+        }
+    }
+
+    pub fn run_cycles(&mut self, cycles: u64)
+    {
+        let end = self.cpu.cycles() + cycles;
+        while self.cpu.cycles() < end {
+            self.clock();
+        }
+    }
+
+    // checked before every CPU cycle, including the first one: a condition that is
+    // already true runs nothing
+    pub fn run_until<F: FnMut(&Nes) -> bool>(&mut self, mut condition: F)
+    {
+        while !condition(self) {
+            self.clock();
+        }
+    }
+
+    // The stop conditions of the frontends and test harnesses, checked on instruction
+    // boundaries, which only the CPU knows. A debug event stops the run too.
+    pub fn run_until_stopped(&mut self, condition: &StopCondition) -> StopReason { self.cpu.run_until(condition) }
+}
+
+impl Clocked for Nes
+{
+    // one CPU cycle, three PPU dots
+    fn clock(&mut self) { self.cpu.clock() }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::cpu::load_cartridge_from_bytes;
+
+    // NROM running JMP $8000 forever, with the PPU left off
+    fn nes() -> Nes
+    {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.resize(16 + 0x4000, 0);
+        rom[16..19].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        // the reset vector, at $FFFC in the mirrored bank
+        rom[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        Nes::new(load_cartridge_from_bytes(&rom).unwrap())
+    }
+
+    #[test]
+    fn test_run_frame()
+    {
+        let mut nes = nes();
+        nes.run_frame();
+        assert_eq!((nes.ppu().scanline(), nes.ppu().vblank()), (VBLANK_SCANLINE, true));
+
+        // 341 x 262 dots, at three a cycle the frames take 29780 or 29781 cycles
+        let start = nes.cycles();
+        let mut frame_cycles = Vec::new();
+        for _ in 0..3 {
+            let frame_start = nes.cycles();
+            nes.run_frame();
+            frame_cycles.push(nes.cycles() - frame_start);
+            assert_eq!(nes.ppu().vblank(), true);
+        }
+        assert_eq!(nes.cycles() - start, 341 * 262);
+        assert!(frame_cycles.iter().all(|&cycles| cycles == 29780 || cycles == 29781), "{:?}", frame_cycles);
+    }
+
+    #[test]
+    fn test_run_frame_counts_frames()
+    {
+        let mut nes = nes();
+        nes.run_frame();
+        let frame = nes.ppu().frame();
+        for i in 1..=5 {
+            nes.run_frame();
+            assert_eq!(nes.ppu().frame(), frame + i);
+        }
+    }
+
+    #[test]
+    fn test_run_cycles()
+    {
+        let mut nes = nes();
+        let start = nes.cycles();
+
+        nes.run_cycles(1000);
+        assert_eq!(nes.cycles(), start + 1000);
+        nes.run_cycles(0);
+        assert_eq!(nes.cycles(), start + 1000);
+    }
+
+    #[test]
+    fn test_run_until()
+    {
+        let mut nes = nes();
+        let start = nes.cycles();
+        let mut checks = Vec::new();
+
+        nes.run_until(|nes| {
+            checks.push(nes.cycles());
+            nes.cycles() == start + 10
+        });
+
+        // checked once per cycle, and stopped on the first true
+        assert_eq!(nes.cycles(), start + 10);
+        assert_eq!(checks, (start..=start + 10).collect::<Vec<u64>>());
+
+        nes.run_until(|_| true);
+        assert_eq!(nes.cycles(), start + 10);
+    }
+
+    #[test]
+    fn test_run_until_stopped()
+    {
+        let mut nes = nes();
+        let start = nes.cycles();
+
+        let reason = nes.run_until_stopped(&StopCondition::CycleCount(start + 100));
+        // JMP takes 3 cycles, the run stops on the first boundary reaching the count
+        assert_eq!(reason, StopReason::CycleCount(nes.cycles()));
+        assert!((start + 100..start + 103).contains(&nes.cycles()), "{}", nes.cycles() - start);
+
+        let cycles = nes.cycles();
+        assert_eq!(nes.run_until_stopped(&StopCondition::PcEquals(0x8000)), StopReason::PcEquals(0x8000));
+        assert_eq!(nes.cycles(), cycles);
+    }
+
+    #[test]
+    fn test_run_until_ppu_position()
+    {
+        let mut nes = nes();
+
+        nes.run_until(|nes| nes.ppu().scanline() == 100);
+
+        assert_eq!(nes.ppu().scanline(), 100);
+        // the cycle before was still on scanline 99
+        assert!(nes.ppu().dot() < 3);
+    }
+}
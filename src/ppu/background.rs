@@ -0,0 +1,159 @@
+use super::{
+    Ppu,
+    PRE_RENDER_SCANLINE,
+    SCREEN_WIDTH,
+    VramAddressParts,
+    decompose_v,
+};
+
+// Background pipeline: every 8 dots the next tile is fetched (nametable, attribute,
+// then both pattern planes, 2 dots each) and loaded into the low byte of the shift
+// registers, whose high byte holds the tile being drawn. The first two tiles of a
+// scanline are prefetched at the end of the previous one, from dot 321.
+impl Ppu
+{
+    pub(super) fn clock_background(&mut self)
+    {
+        if !self.rendering_enabled() {
+            return
+        }
+        let dot = self.dot;
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_background();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.fetch_tile();
+                },
+                2 => self.fetch_attribute(),
+                4 => self.next_pattern_low = self.fetch_pattern(0),
+                6 => self.next_pattern_high = self.fetch_pattern(8),
+                7 => self.increment_coarse_x(),
+                _ => {},
+            }
+        }
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.load_background_shifters();
+                self.copy_horizontal_position();
+            },
+            // for the whole pre-render scanline window
+            280..=304 if self.scanline == PRE_RENDER_SCANLINE => self.copy_vertical_position(),
+            _ => {},
+        }
+    }
+
+    // coarse X and the horizontal nametable bit, from t
+    fn copy_horizontal_position(&mut self)
+    {
+        let (v, t) = (decompose_v(self.v), decompose_v(self.t));
+        self.v = VramAddressParts {
+            coarse_x: t.coarse_x,
+            nametable: (v.nametable & 0b10) | (t.nametable & 0b01),
+            ..v
+        }.compose();
+    }
+
+    // coarse Y, fine Y and the vertical nametable bit, from t
+    fn copy_vertical_position(&mut self)
+    {
+        let (v, t) = (decompose_v(self.v), decompose_v(self.t));
+        self.v = VramAddressParts {
+            coarse_y: t.coarse_y,
+            fine_y: t.fine_y,
+            nametable: (t.nametable & 0b10) | (v.nametable & 0b01),
+            ..v
+        }.compose();
+    }
+
+    // the nametable byte under v, fine Y does not take part
+    fn fetch_tile(&mut self)
+    {
+        let address = VramAddressParts {fine_y: 0, ..decompose_v(self.v)}.compose();
+        self.next_tile = self.read_vram(0x2000 | address);
+    }
+
+    fn fetch_attribute(&mut self)
+    {
+        let parts = decompose_v(self.v);
+        // one attribute byte covers 4x4 tiles, split in four 2x2 quadrants of 2 bits
+        let address = 0x23C0 | (parts.nametable as u16) << 10 | (parts.coarse_y as u16 >> 2) << 3 | parts.coarse_x as u16 >> 2;
+        let shift = (parts.coarse_y & 0x02) << 1 | (parts.coarse_x & 0x02);
+        self.next_attribute = (self.read_vram(address) >> shift) & 0x03;
+    }
+
+    fn fetch_pattern(&self, plane: u16) -> u8
+    {
+        let table = if self.control & 0b0001_0000 != 0 {0x1000} else {0x0000};
+        self.read_vram(table + (self.next_tile as u16) * 16 + plane + decompose_v(self.v).fine_y as u16)
+    }
+
+    fn load_background_shifters(&mut self)
+    {
+        self.pattern_shift_low = (self.pattern_shift_low & 0xFF00) | self.next_pattern_low as u16;
+        self.pattern_shift_high = (self.pattern_shift_high & 0xFF00) | self.next_pattern_high as u16;
+        let attribute_low = if self.next_attribute & 0x01 != 0 {0xFF} else {0x00};
+        let attribute_high = if self.next_attribute & 0x02 != 0 {0xFF} else {0x00};
+        self.attribute_shift_low = (self.attribute_shift_low & 0xFF00) | attribute_low;
+        self.attribute_shift_high = (self.attribute_shift_high & 0xFF00) | attribute_high;
+    }
+
+    fn shift_background(&mut self)
+    {
+        self.pattern_shift_low <<= 1;
+        self.pattern_shift_high <<= 1;
+        self.attribute_shift_low <<= 1;
+        self.attribute_shift_high <<= 1;
+    }
+
+    // coarse X wraps into the horizontally adjacent nametable
+    fn increment_coarse_x(&mut self)
+    {
+        let mut parts = decompose_v(self.v);
+        if parts.coarse_x == 31 {
+            parts.coarse_x = 0;
+            parts.nametable ^= 0b01;
+        } else {
+            parts.coarse_x += 1;
+        }
+        self.v = parts.compose();
+    }
+
+    // fine Y, then coarse Y, which wraps into the vertically adjacent nametable after row 29
+    fn increment_y(&mut self)
+    {
+        let mut parts = decompose_v(self.v);
+        if parts.fine_y < 7 {
+            parts.fine_y += 1;
+        } else {
+            parts.fine_y = 0;
+            parts.coarse_y = match parts.coarse_y {
+                29 => {
+                    parts.nametable ^= 0b10;
+                    0
+                },
+                // rows 30 and 31 are the attribute table, reached by scrolling there
+                31 => 0,
+                coarse_y => coarse_y + 1,
+            };
+        }
+        self.v = parts.compose();
+    }
+
+    // pixel for dot - 1 of the current scanline
+    pub(super) fn render_pixel(&mut self)
+    {
+        let x = self.dot as usize - 1;
+        let show_background = self.mask & 0b0000_1000 != 0 && (x >= 8 || self.mask & 0b0000_0010 != 0);
+        let palette_address = if show_background {
+            let bit = 0x8000 >> self.fine_x;
+            let pixel = (self.pattern_shift_high & bit != 0) as u16 * 2 + (self.pattern_shift_low & bit != 0) as u16;
+            let palette = (self.attribute_shift_high & bit != 0) as u16 * 2 + (self.attribute_shift_low & bit != 0) as u16;
+            if pixel == 0 {0} else {palette * 4 + pixel}
+        } else {
+            0
+        };
+        self.frame_buffer[self.scanline as usize * SCREEN_WIDTH + x] = self.read_palette(0x3F00 + palette_address);
+    }
+}
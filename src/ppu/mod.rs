@@ -0,0 +1,656 @@
+mod registers;
+mod background;
+mod sprites;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cpu::Mapper;
+use crate::save_state::{
+    SaveStateError,
+    StateReader,
+    StateWriter,
+};
+use crate::utils::Clocked;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+pub const VISIBLE_SCANLINES: u16 = 240;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+// Scroll and sprite arithmetic shared by the rendering pipeline. The sprite fetches
+// subtract coordinates through sprite_row, and the fields of v and t are only read or
+// replaced through VramAddressParts. PPUADDR is the exception, it writes whole bytes.
+
+// Row of a sprite to fetch during the evaluation done on `scanline`, for display on
+// the next scanline. OAM stores the sprite top minus one, so the sprite is in range
+// when 0 <= scanline - oam_y < height. Sprites are only evaluated on visible scanlines.
+pub fn sprite_row(scanline: u16, oam_y: u8, height: u8, flip_vertical: bool) -> Option<u8>
+{
+    if scanline >= VISIBLE_SCANLINES {
+        return None
+    }
+    let row = scanline.checked_sub(oam_y as u16)?;
+    if row >= height as u16 {
+        return None
+    }
+    let row = row as u8;
+    Some(if flip_vertical {height - 1 - row} else {row})
+}
+
+// Fields of the 15 bits v and t registers: 0yyy NNYY YYYX XXXX
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VramAddressParts
+{
+    pub coarse_x: u8,
+    pub coarse_y: u8,
+    pub nametable: u8,
+    pub fine_y: u8,
+}
+
+impl VramAddressParts
+{
+    pub fn compose(self) -> u16
+    {
+        (self.coarse_x as u16 & 0x1F)
+            | (self.coarse_y as u16 & 0x1F) << 5
+            | (self.nametable as u16 & 0x03) << 10
+            | (self.fine_y as u16 & 0x07) << 12
+    }
+}
+
+pub fn decompose_v(v: u16) -> VramAddressParts
+{
+    VramAddressParts {
+        coarse_x: (v & 0x1F) as u8,
+        coarse_y: (v >> 5 & 0x1F) as u8,
+        nametable: (v >> 10 & 0x03) as u8,
+        fine_y: (v >> 12 & 0x07) as u8,
+    }
+}
+
+pub struct Ppu
+{
+    cartridge: Rc<RefCell<Box<dyn Mapper>>>,
+    // registers
+    control: u8,
+    mask: u8,
+    vblank: bool,
+    sprite_zero_hit: bool,
+    sprite_overflow: bool,
+    oam_address: u8,
+    // internal registers: current and temporary VRAM address (15 bits), fine X, write toggle
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
+    read_buffer: u8,
+    // last value driven on the CPU data bus, returned by write-only registers
+    io_latch: u8,
+    // memory
+    oam: [u8; 0x100],
+    nametables: [u8; 0x1000],
+    palette: [u8; 0x20],
+    // timing
+    scanline: u16,
+    dot: u16,
+    frame: u64,
+    // background pipeline
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_low: u8,
+    next_pattern_high: u8,
+    pattern_shift_low: u16,
+    pattern_shift_high: u16,
+    attribute_shift_low: u16,
+    attribute_shift_high: u16,
+    // palette indices, SCREEN_WIDTH x SCREEN_HEIGHT
+    frame_buffer: Vec<u8>,
+}
+
+impl Ppu
+{
+    pub fn new(cartridge: Rc<RefCell<Box<dyn Mapper>>>) -> Ppu
+    {
+        Ppu {
+            cartridge,
+            control: 0,
+            mask: 0,
+            vblank: false,
+            sprite_zero_hit: false,
+            sprite_overflow: false,
+            oam_address: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            oam: [0; 0x100],
+            nametables: [0; 0x1000],
+            palette: [0; 0x20],
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            next_tile: 0,
+            next_attribute: 0,
+            next_pattern_low: 0,
+            next_pattern_high: 0,
+            pattern_shift_low: 0,
+            pattern_shift_high: 0,
+            attribute_shift_low: 0,
+            attribute_shift_high: 0,
+            frame_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn scanline(&self) -> u16 { self.scanline }
+
+    pub fn dot(&self) -> u16 { self.dot }
+
+    // number of completed frames
+    pub fn frame(&self) -> u64 { self.frame }
+
+    pub fn frame_buffer(&self) -> &[u8] { &self.frame_buffer }
+
+    pub fn vblank(&self) -> bool { self.vblank }
+
+    pub fn oam(&self) -> &[u8] { &self.oam }
+
+    // v, the address PPUDATA reads and writes
+    pub fn vram_address(&self) -> u16 { self.v }
+
+    // /NMI is asserted while in vblank with PPUCTRL bit 7 set
+    pub fn nmi_output(&self) -> bool { self.vblank && self.control & 0b1000_0000 != 0 }
+
+    pub fn nmi_enabled(&self) -> bool { self.control & 0b1000_0000 != 0 }
+
+    // dots to go through before the one raising the vblank flag, not counting the dot
+    // the odd frames skip
+    pub fn dots_until_vblank(&self) -> u32
+    {
+        let frame_dots = SCANLINES_PER_FRAME as u32 * DOTS_PER_SCANLINE as u32;
+        let vblank_start = VBLANK_SCANLINE as u32 * DOTS_PER_SCANLINE as u32 + 1;
+        let position = self.scanline as u32 * DOTS_PER_SCANLINE as u32 + self.dot as u32;
+        (vblank_start + frame_dots - position) % frame_dots
+    }
+
+    fn rendering_enabled(&self) -> bool { self.mask & 0b0001_1000 != 0 }
+
+    // The reset line clears PPUCTRL, PPUMASK, the scroll latches and the read buffer.
+    // v, the status flags, the memories and the timing are left alone.
+    pub fn reset(&mut self)
+    {
+        self.control = 0;
+        self.mask = 0;
+        self.t = 0;
+        self.fine_x = 0;
+        self.w = false;
+        self.read_buffer = 0;
+    }
+
+    fn advance(&mut self)
+    {
+        self.dot += 1;
+        // with rendering enabled, odd frames skip the last dot of the pre-render scanline
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == DOTS_PER_SCANLINE - 1 && self.frame % 2 == 1 && self.rendering_enabled() {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+    }
+
+    // the cartridge is saved by the CPU, which owns it too
+    pub fn save_state(&self, state: &mut StateWriter)
+    {
+        for register in [self.control, self.mask, self.oam_address, self.fine_x, self.read_buffer, self.io_latch] {
+            state.write_u8(register);
+        }
+        for flag in [self.vblank, self.sprite_zero_hit, self.sprite_overflow, self.w] {
+            state.write_bool(flag);
+        }
+        state.write_u16(self.v);
+        state.write_u16(self.t);
+        state.write_bytes(&self.oam);
+        state.write_bytes(&self.nametables);
+        state.write_bytes(&self.palette);
+        state.write_u16(self.scanline);
+        state.write_u16(self.dot);
+        state.write_u64(self.frame);
+        for latch in [self.next_tile, self.next_attribute, self.next_pattern_low, self.next_pattern_high] {
+            state.write_u8(latch);
+        }
+        for shifter in [self.pattern_shift_low, self.pattern_shift_high, self.attribute_shift_low, self.attribute_shift_high] {
+            state.write_u16(shifter);
+        }
+        state.write_bytes(&self.frame_buffer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>
+    {
+        for register in [&mut self.control, &mut self.mask, &mut self.oam_address, &mut self.fine_x, &mut self.read_buffer, &mut self.io_latch] {
+            *register = state.read_u8()?;
+        }
+        for flag in [&mut self.vblank, &mut self.sprite_zero_hit, &mut self.sprite_overflow, &mut self.w] {
+            *flag = state.read_bool()?;
+        }
+        self.v = state.read_u16()?;
+        self.t = state.read_u16()?;
+        state.read_bytes(&mut self.oam)?;
+        state.read_bytes(&mut self.nametables)?;
+        state.read_bytes(&mut self.palette)?;
+        self.scanline = state.read_u16()?;
+        self.dot = state.read_u16()?;
+        self.frame = state.read_u64()?;
+        for latch in [&mut self.next_tile, &mut self.next_attribute, &mut self.next_pattern_low, &mut self.next_pattern_high] {
+            *latch = state.read_u8()?;
+        }
+        for shifter in [&mut self.pattern_shift_low, &mut self.pattern_shift_high, &mut self.attribute_shift_low, &mut self.attribute_shift_high] {
+            *shifter = state.read_u16()?;
+        }
+        state.read_bytes(&mut self.frame_buffer)
+    }
+}
+
+impl Clocked for Ppu
+{
+    fn clock(&mut self)
+    {
+        let visible = self.scanline < VISIBLE_SCANLINES;
+        if visible || self.scanline == PRE_RENDER_SCANLINE {
+            self.clock_background();
+            self.clock_sprite_fetches();
+        }
+        if visible && (1..=SCREEN_WIDTH as u16).contains(&self.dot) {
+            self.render_pixel();
+        }
+
+        match (self.scanline, self.dot) {
+            (VBLANK_SCANLINE, 1) => self.vblank = true,
+            (PRE_RENDER_SCANLINE, 1) => {
+                self.vblank = false;
+                self.sprite_zero_hit = false;
+                self.sprite_overflow = false;
+            },
+            _ => {},
+        }
+        self.advance();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sprite_row_full_domain()
+    {
+        for scanline in 0..SCANLINES_PER_FRAME {
+            for oam_y in 0..=0xFFu8 {
+                for height in [8u8, 16] {
+                    for flip_vertical in [false, true] {
+                        let distance = scanline as i32 - oam_y as i32;
+                        let in_range = scanline < VISIBLE_SCANLINES && distance >= 0 && distance < height as i32;
+                        let row = sprite_row(scanline, oam_y, height, flip_vertical);
+
+                        assert_eq!(row.is_some(), in_range, "scanline {} oam_y {} height {}", scanline, oam_y, height);
+                        if let Some(row) = row {
+                            assert_eq!(row < height, true);
+                            let expected = if flip_vertical {height as i32 - 1 - distance} else {distance};
+                            assert_eq!(row as i32, expected);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sprite_row_edges()
+    {
+        // a sprite at the top of the screen is stored with y = 0 and first shows on scanline 1
+        assert_eq!(sprite_row(0, 0, 8, false), Some(0));
+        assert_eq!(sprite_row(7, 0, 8, false), Some(7));
+        assert_eq!(sprite_row(8, 0, 8, false), None);
+        assert_eq!(sprite_row(15, 0, 16, true), Some(0));
+        // y >= $EF hides the sprite
+        assert_eq!(sprite_row(238, 0xEF, 8, false), None);
+        assert_eq!(sprite_row(0, 0xFF, 8, false), None);
+        // no evaluation on the post-render and pre-render scanlines
+        assert_eq!(sprite_row(241, 0xF0, 8, false), None);
+        assert_eq!(sprite_row(261, 0xFF, 8, false), None);
+    }
+
+    #[test]
+    fn test_sprite_pattern_address()
+    {
+        let mut ppu = ppu(0);
+        ppu.scanline = 20;
+        // 8x8 from $1000, row 5, flipped to row 2
+        ppu.write_register(0, 0x08);
+        assert_eq!(ppu.sprite_pattern_address([15, 0x42, 0x00, 0]), 0x1425);
+        assert_eq!(ppu.sprite_pattern_address([15, 0x42, 0x80, 0]), 0x1422);
+        // 8x16, row 10 is row 2 of the second tile, from the table of bit 0
+        ppu.write_register(0, 0x20);
+        assert_eq!(ppu.sprite_pattern_address([10, 0x03, 0x00, 0]), 0x1032);
+        assert_eq!(ppu.sprite_pattern_address([10, 0x03, 0x80, 0]), 0x1025);
+        // out of range, row 0
+        assert_eq!(ppu.sprite_pattern_address([0xFF; 4]), 0x1FE0);
+    }
+
+    #[test]
+    fn test_decompose_v_full_domain()
+    {
+        for v in 0..0x8000u16 {
+            let parts = decompose_v(v);
+
+            assert_eq!(parts.coarse_x < 32, true);
+            assert_eq!(parts.coarse_y < 32, true);
+            assert_eq!(parts.nametable < 4, true);
+            assert_eq!(parts.fine_y < 8, true);
+            assert_eq!(parts.compose(), v);
+        }
+    }
+
+    #[test]
+    fn test_decompose_v_fields()
+    {
+        assert_eq!(
+            decompose_v(0b0101_1001_0110_0011),
+            VramAddressParts {coarse_x: 0b00011, coarse_y: 0b01011, nametable: 0b10, fine_y: 0b101}
+        );
+        // bit 15 is not part of v
+        assert_eq!(decompose_v(0xFFFF), decompose_v(0x7FFF));
+    }
+
+    // NROM with CHR RAM, mirroring flag in header byte 6
+    fn ppu(flags_6: u8) -> Ppu
+    {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, flags_6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.resize(16 + 0x4000, 0);
+        let cartridge = crate::cpu::load_cartridge_from_bytes(&rom).unwrap();
+        Ppu::new(Rc::new(RefCell::new(cartridge)))
+    }
+
+    // NROM with 8 KB of CHR ROM
+    fn chr_rom_ppu(flags_6: u8, chr_rom: &[u8]) -> Ppu
+    {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, flags_6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.resize(16 + 0x4000, 0);
+        rom.extend_from_slice(chr_rom);
+        let cartridge = crate::cpu::load_cartridge_from_bytes(&rom).unwrap();
+        Ppu::new(Rc::new(RefCell::new(cartridge)))
+    }
+
+    fn set_address(ppu: &mut Ppu, address: u16)
+    {
+        ppu.write_register(6, (address >> 8) as u8);
+        ppu.write_register(6, address as u8);
+    }
+
+    #[test]
+    fn test_vblank_flag()
+    {
+        let mut ppu = ppu(0);
+        for _ in 0..VBLANK_SCANLINE as u32 * DOTS_PER_SCANLINE as u32 + 1 {
+            ppu.clock();
+        }
+        assert_eq!(ppu.vblank(), false);
+
+        ppu.clock();
+        assert_eq!((ppu.scanline(), ppu.dot()), (VBLANK_SCANLINE, 2));
+        assert_eq!(ppu.vblank(), true);
+        // reading PPUSTATUS clears the flag
+        assert_eq!(ppu.read_register(2) & 0x80, 0x80);
+        assert_eq!(ppu.read_register(2) & 0x80, 0x00);
+
+        while ppu.scanline() != PRE_RENDER_SCANLINE || ppu.dot() != 1 {
+            ppu.clock();
+        }
+        ppu.vblank = true;
+        ppu.clock();
+        assert_eq!(ppu.vblank(), false);
+    }
+
+    #[test]
+    fn test_frame_length()
+    {
+        let mut ppu = ppu(0);
+        for _ in 0..SCANLINES_PER_FRAME as u32 * DOTS_PER_SCANLINE as u32 {
+            ppu.clock();
+        }
+        assert_eq!((ppu.frame(), ppu.scanline(), ppu.dot()), (1, 0, 0));
+
+        // odd frames are one dot shorter when rendering
+        ppu.write_register(1, 0x08);
+        for _ in 0..SCANLINES_PER_FRAME as u32 * DOTS_PER_SCANLINE as u32 - 1 {
+            ppu.clock();
+        }
+        assert_eq!((ppu.frame(), ppu.scanline(), ppu.dot()), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_data_reads_are_buffered()
+    {
+        let mut ppu = ppu(0);
+        set_address(&mut ppu, 0x2000);
+        ppu.write_register(7, 0x11);
+        ppu.write_register(7, 0x22);
+
+        set_address(&mut ppu, 0x2000);
+        ppu.read_register(7);
+        assert_eq!(ppu.read_register(7), 0x11);
+        assert_eq!(ppu.read_register(7), 0x22);
+    }
+
+    #[test]
+    fn test_palette_reads_are_direct()
+    {
+        let mut ppu = ppu(0);
+        set_address(&mut ppu, 0x2F00);
+        ppu.write_register(7, 0x55);
+        set_address(&mut ppu, 0x3F00);
+        ppu.write_register(7, 0x2A);
+
+        set_address(&mut ppu, 0x3F00);
+        assert_eq!(ppu.read_register(7), 0x2A);
+        // the buffer got the nametable byte under the palette
+        assert_eq!(ppu.read_buffer, 0x55);
+    }
+
+    // Writes then reads back every VRAM address through $2006/$2007, with rendering
+    // off. The pattern tables answer with chr_rom when there is one.
+    fn sweep_vram(ppu: &mut Ppu, chr_rom: Option<&[u8]>)
+    {
+        for address in 0..0x4000u16 {
+            let data = (address ^ address >> 8) as u8;
+            set_address(ppu, address);
+            ppu.write_register(7, data);
+            set_address(ppu, address);
+            let read = match address {
+                // straight from the palette
+                0x3F00..=0x3FFF => ppu.read_register(7),
+                _ => {
+                    ppu.read_register(7);
+                    set_address(ppu, address);
+                    ppu.read_register(7)
+                },
+            };
+            let expected = match (address, chr_rom) {
+                (0x0000..=0x1FFF, Some(chr_rom)) => chr_rom[address as usize],
+                // the top two bits are open bus, last driven by the low address byte
+                (0x3F00..=0x3FFF, _) => data & 0x3F | address as u8 & 0xC0,
+                _ => data,
+            };
+            assert_eq!(read, expected, "address {:04X}", address);
+        }
+    }
+
+    #[test]
+    fn test_vram_sweep()
+    {
+        sweep_vram(&mut ppu(0), None);
+        sweep_vram(&mut ppu(0x01), None);
+        // four-screen
+        sweep_vram(&mut ppu(0x08), None);
+        let chr_rom: Vec<u8> = (0..0x2000u32).map(|i| ((i * 7) >> 3) as u8).collect();
+        sweep_vram(&mut chr_rom_ppu(0, &chr_rom), Some(&chr_rom));
+        sweep_vram(&mut chr_rom_ppu(0x08, &chr_rom), Some(&chr_rom));
+    }
+
+    // the bus only sees the low 14 bits of v, and $2007 wraps v from $7FFF to $0000
+    #[test]
+    fn test_vram_address_decode_15_bits()
+    {
+        let mut ppu = ppu(0x08);
+        for address in 0..0x4000u16 {
+            set_address(&mut ppu, address);
+            ppu.write_register(7, (address ^ address >> 8) as u8);
+        }
+        for v in 0..0x8000u16 {
+            assert_eq!(ppu.read_vram(v), ppu.read_vram(v & 0x3FFF), "v {:04X}", v);
+        }
+
+        ppu.v = 0x7FFF;
+        assert_eq!(ppu.read_register(7) & 0x3F, ppu.read_vram(0x3FFF));
+        assert_eq!(ppu.vram_address(), 0x0000);
+    }
+
+    // PPUADDR cannot set bit 14, only the increments of $2007 and the rendering reach it
+    #[test]
+    fn test_address_write_clears_bit_14()
+    {
+        let mut ppu = ppu(0);
+        set_address(&mut ppu, 0x3F1F);
+        ppu.write_register(7, 0x21);
+
+        set_address(&mut ppu, 0x7FFF);
+        assert_eq!(ppu.vram_address(), 0x3FFF);
+        // $3FFF mirrors $3F1F, under the open bus bits
+        assert_eq!(ppu.read_register(7) & 0x3F, 0x21);
+        assert_eq!(ppu.vram_address(), 0x4000);
+    }
+
+    // reading PPUSTATUS between the two writes starts the address over
+    #[test]
+    fn test_status_read_resets_address_latch()
+    {
+        let mut ppu = ppu(0);
+        ppu.write_register(6, 0x21);
+        ppu.read_register(2);
+        ppu.write_register(6, 0x23);
+        ppu.write_register(6, 0x45);
+        assert_eq!(ppu.vram_address(), 0x2345);
+
+        // shared with PPUSCROLL
+        ppu.write_register(5, 0x00);
+        ppu.read_register(2);
+        set_address(&mut ppu, 0x2678);
+        assert_eq!(ppu.vram_address(), 0x2678);
+    }
+
+    // the buffered byte under the palette comes out on the next read, wherever v points
+    #[test]
+    fn test_palette_read_then_nametable_reads()
+    {
+        let mut ppu = ppu(0);
+        set_address(&mut ppu, 0x2F05);
+        ppu.write_register(7, 0x55);
+        set_address(&mut ppu, 0x2000);
+        ppu.write_register(7, 0x11);
+        set_address(&mut ppu, 0x3F05);
+        ppu.write_register(7, 0x2A);
+
+        set_address(&mut ppu, 0x3F05);
+        assert_eq!(ppu.read_register(7), 0x2A);
+        assert_eq!(ppu.vram_address(), 0x3F06);
+
+        set_address(&mut ppu, 0x2000);
+        assert_eq!(ppu.read_register(7), 0x55);
+        assert_eq!(ppu.read_register(7), 0x11);
+        assert_eq!(ppu.vram_address(), 0x2002);
+
+        // going down with PPUCTRL bit 2
+        ppu.write_register(0, 0x04);
+        set_address(&mut ppu, 0x3F05);
+        assert_eq!(ppu.read_register(7), 0x2A);
+        assert_eq!(ppu.vram_address(), 0x3F25);
+    }
+
+    #[test]
+    fn test_reset()
+    {
+        let mut ppu = ppu(0);
+        ppu.write_register(0, 0x80);
+        ppu.write_register(1, 0x1E);
+        set_address(&mut ppu, 0x2123);
+        ppu.write_register(5, 0x7F);
+        ppu.vblank = true;
+
+        ppu.reset();
+        assert_eq!((ppu.control, ppu.mask, ppu.t, ppu.fine_x, ppu.w), (0, 0, 0, 0, false));
+        assert_eq!(ppu.nmi_output(), false);
+        assert_eq!(ppu.vram_address(), 0x2123);
+        assert_eq!(ppu.vblank(), true);
+    }
+
+    #[test]
+    fn test_palette_mirrors()
+    {
+        let mut ppu = ppu(0);
+        set_address(&mut ppu, 0x3F10);
+        ppu.write_register(7, 0x0F);
+
+        assert_eq!(ppu.read_vram(0x3F00), 0x0F);
+        assert_eq!(ppu.read_vram(0x3F20), 0x0F);
+        assert_eq!(ppu.read_vram(0x3F11), 0x00);
+    }
+
+    #[test]
+    fn test_nametable_mirroring()
+    {
+        let mut horizontal = ppu(0);
+        set_address(&mut horizontal, 0x2000);
+        horizontal.write_register(7, 0x42);
+        assert_eq!(horizontal.read_vram(0x2400), 0x42);
+        assert_eq!(horizontal.read_vram(0x2800), 0x00);
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(horizontal.read_vram(0x3000), 0x42);
+
+        let mut vertical = ppu(1);
+        set_address(&mut vertical, 0x2000);
+        vertical.write_register(7, 0x42);
+        assert_eq!(vertical.read_vram(0x2400), 0x00);
+        assert_eq!(vertical.read_vram(0x2800), 0x42);
+    }
+
+    #[test]
+    fn test_address_registers()
+    {
+        let mut ppu = ppu(0);
+        // the first PPUADDR write clears bit 14, reading PPUSTATUS resets the toggle
+        ppu.write_register(6, 0xFF);
+        ppu.read_register(2);
+        set_address(&mut ppu, 0x7F12);
+        assert_eq!(ppu.v, 0x3F12);
+
+        // increment by 32 with PPUCTRL bit 2
+        ppu.write_register(0, 0x04);
+        ppu.write_register(7, 0);
+        assert_eq!(ppu.v, 0x3F32);
+
+        ppu.write_register(5, 0b0111_1101);
+        ppu.write_register(5, 0b0101_1110);
+        assert_eq!(ppu.fine_x, 0b101);
+        assert_eq!(decompose_v(ppu.t), VramAddressParts {coarse_x: 0b01111, coarse_y: 0b01011, nametable: 0, fine_y: 0b110});
+    }
+}
@@ -0,0 +1,165 @@
+use super::{
+    Ppu,
+    VramAddressParts,
+    decompose_v,
+};
+use crate::cpu::Mirroring;
+
+impl Ppu
+{
+    // register is the CPU address modulo 8
+    pub fn read_register(&mut self, register: u16) -> u8
+    {
+        let data = match register {
+            2 => {
+                let status = self.peek_status();
+                self.vblank = false;
+                self.w = false;
+                status
+            },
+            // OAMDATA
+            4 => self.oam[self.oam_address as usize],
+            // PPUDATA
+            7 => self.read_data(),
+            // write-only registers
+            _ => self.io_latch,
+        };
+        self.io_latch = data;
+        data
+    }
+
+    // PPUSTATUS without clearing anything, the low bits are whatever was last on the bus
+    pub fn peek_status(&self) -> u8
+    {
+        (self.vblank as u8) << 7
+            | (self.sprite_zero_hit as u8) << 6
+            | (self.sprite_overflow as u8) << 5
+            | (self.io_latch & 0x1F)
+    }
+
+    pub fn write_register(&mut self, register: u16, data: u8)
+    {
+        self.io_latch = data;
+        match register {
+            // PPUCTRL, the nametable select bits go to t
+            0 => {
+                self.control = data;
+                self.t = VramAddressParts {nametable: data & 0x03, ..decompose_v(self.t)}.compose();
+            },
+            // PPUMASK
+            1 => self.mask = data,
+            // OAMADDR
+            3 => self.oam_address = data,
+            // OAMDATA
+            4 => {
+                self.oam[self.oam_address as usize] = data;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            },
+            // PPUSCROLL, X then Y
+            5 => {
+                let t = decompose_v(self.t);
+                if !self.w {
+                    self.t = VramAddressParts {coarse_x: data >> 3, ..t}.compose();
+                    self.fine_x = data & 0x07;
+                } else {
+                    self.t = VramAddressParts {coarse_y: data >> 3, fine_y: data & 0x07, ..t}.compose();
+                }
+                self.w = !self.w;
+            },
+            // PPUADDR, high byte then low byte. The first write clears bit 14 of t.
+            6 => {
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | (data as u16 & 0x3F) << 8;
+                } else {
+                    self.t = (self.t & 0x7F00) | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            },
+            // PPUDATA
+            7 => {
+                self.write_vram(self.v, data);
+                self.increment_v();
+            },
+            // PPUSTATUS is read-only
+            _ => {},
+        }
+    }
+
+    // Reads go through a buffer and return the previous read, except for the palette
+    // which answers directly. The buffer then gets the nametable byte under the palette.
+    fn read_data(&mut self) -> u8
+    {
+        let address = self.v & 0x3FFF;
+        let data = if address >= 0x3F00 {
+            self.read_buffer = self.read_vram(address - 0x1000);
+            self.read_palette(address) | (self.io_latch & 0xC0)
+        } else {
+            let data = self.read_buffer;
+            self.read_buffer = self.read_vram(address);
+            data
+        };
+        self.increment_v();
+        data
+    }
+
+    // PPUCTRL bit 2 selects going across (1) or down (32)
+    fn increment_v(&mut self)
+    {
+        let increment = if self.control & 0b0000_0100 != 0 {32} else {1};
+        self.v = (self.v + increment) & 0x7FFF;
+    }
+
+    // v keeps 15 bits, the bus only sees 14
+    pub(super) fn read_vram(&self, address: u16) -> u8
+    {
+        let address = address & 0x3FFF;
+        self.cartridge.borrow_mut().ppu_address(address);
+        match address {
+            0x0000..=0x1FFF => self.cartridge.borrow().chr_read(address),
+            0x2000..=0x3EFF => self.nametables[self.nametable_index(address)],
+            _ => self.read_palette(address),
+        }
+    }
+
+    fn write_vram(&mut self, address: u16, data: u8)
+    {
+        let address = address & 0x3FFF;
+        self.cartridge.borrow_mut().ppu_address(address);
+        match address {
+            0x0000..=0x1FFF => self.cartridge.borrow_mut().chr_write(address, data),
+            0x2000..=0x3EFF => self.nametables[self.nametable_index(address)] = data,
+            _ => self.palette[palette_index(address)] = data & 0x3F,
+        }
+    }
+
+    fn nametable_index(&self, address: u16) -> usize
+    {
+        let table = (address as usize >> 10) & 0x03;
+        let offset = address as usize & 0x03FF;
+        let physical_table = match self.cartridge.borrow().mirroring() {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        physical_table * 0x0400 + offset
+    }
+
+    // grayscale keeps only the column of gray colors
+    pub(super) fn read_palette(&self, address: u16) -> u8
+    {
+        let color = self.palette[palette_index(address)];
+        if self.mask & 0b0000_0001 != 0 {color & 0x30} else {color}
+    }
+}
+
+// $3F10, $3F14, $3F18 and $3F1C mirror the backdrop entries of the background palettes
+fn palette_index(address: u16) -> usize
+{
+    match address as usize & 0x1F {
+        index if index & 0x13 == 0x10 => index & 0x0F,
+        index => index,
+    }
+}
@@ -0,0 +1,46 @@
+use super::{
+    Ppu,
+    sprite_row,
+};
+
+// secondary OAM entry: y, tile, attributes, x
+type SpriteSlot = [u8; 4];
+
+// Secondary OAM is filled with $FF before the evaluation, and stays so until sprites
+// are evaluated: every slot is empty.
+const EMPTY_SLOT: SpriteSlot = [0xFF; 4];
+
+// Sprites are neither evaluated nor drawn yet, but their pattern fetches still happen
+// at dots 257-320, 8 dots per sprite slot. Mappers counting scanlines watch them on the
+// PPU bus. Empty slots fetch tile $FF.
+impl Ppu
+{
+    pub(super) fn clock_sprite_fetches(&mut self)
+    {
+        if !self.rendering_enabled() || !(257..=320).contains(&self.dot) {
+            return
+        }
+        match (self.dot - 257) % 8 {
+            4 => { self.read_vram(self.sprite_pattern_address(EMPTY_SLOT)); },
+            6 => { self.read_vram(self.sprite_pattern_address(EMPTY_SLOT) + 8); },
+            _ => {},
+        }
+    }
+
+    // low plane of the row the slot shows on the next scanline. Slots out of range, as
+    // the empty ones always are, fetch row 0.
+    pub(super) fn sprite_pattern_address(&self, slot: SpriteSlot) -> u16
+    {
+        let [y, tile, attributes, _] = slot;
+        let tall = self.control & 0b0010_0000 != 0;
+        let row = sprite_row(self.scanline, y, if tall {16} else {8}, attributes & 0x80 != 0).unwrap_or(0) as u16;
+        // 8x16 sprites pick the table with bit 0 of the tile, rows 8-15 are in the next tile
+        let tile_address = if tall {
+            (tile as u16 & 0x01) << 12 | (tile as u16 & 0xFE) << 4 | (row & 0x08) << 1
+        } else {
+            let table = if self.control & 0b0000_1000 != 0 {0x1000} else {0x0000};
+            table | (tile as u16) << 4
+        };
+        tile_address | (row & 0x07)
+    }
+}
@@ -0,0 +1,62 @@
+// How to run the test ROMs that do not follow the blargg status byte protocol,
+// keyed by the CRC32 of the ROM without its iNES header.
+
+use crate::cpu::StopCondition;
+
+pub struct RomProfile
+{
+    pub name: &'static str,
+    pub crc32: u32,
+    // automated mode entry point, the reset vector when None
+    pub entry: Option<u16>,
+    pub stop: fn() -> StopCondition,
+}
+
+pub const PROFILES: [RomProfile; 1] = [
+    RomProfile {
+        name: "nestest",
+        crc32: 0x158B_0388,
+        entry: Some(0xC000),
+        // last instruction of nestest.log
+        stop: || StopCondition::CycleCount(26554),
+    },
+];
+
+pub fn find_profile(rom: &[u8]) -> Option<&'static RomProfile>
+{
+    let crc = crc32(rom.get(16..).unwrap_or(&[]));
+    PROFILES.iter().find(|profile| profile.crc32 == crc)
+}
+
+pub fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xEDB8_8320} else {crc >> 1};
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32()
+    {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_find_profile()
+    {
+        let rom = std::fs::read("rom_tests/nestest/nestest.nes").unwrap();
+
+        assert_eq!(find_profile(&rom).map(|profile| profile.name), Some("nestest"));
+        assert_eq!(find_profile(&rom[..16]).is_none(), true);
+    }
+}
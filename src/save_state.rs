@@ -0,0 +1,239 @@
+use std::fmt;
+
+// "NQSS", then the version, little endian, then the state of every component in a
+// fixed order. Any change to what a component saves bumps the version: states are
+// for rewinding and replaying, not for keeping across releases.
+const MAGIC: [u8; 4] = *b"NQSS";
+pub const SAVE_STATE_VERSION: u16 = 3;
+const HEADER_SIZE: usize = 6;
+
+#[derive(Debug, PartialEq)]
+pub enum SaveStateError
+{
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingBytes(usize),
+    // a value the component would never have saved, as a boolean other than 0 and 1
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for SaveStateError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) =>
+                write!(f, "save state version {} is not supported, expected {}", version, SAVE_STATE_VERSION),
+            SaveStateError::Truncated => write!(f, "truncated save state"),
+            SaveStateError::TrailingBytes(count) => write!(f, "{} unexpected bytes at the end of the save state", count),
+            SaveStateError::Mismatch(what) => write!(f, "save state does not match the {}", what),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SaveState
+{
+    // header included
+    bytes: Vec<u8>,
+}
+
+impl SaveState
+{
+    pub fn as_bytes(&self) -> &[u8] { &self.bytes }
+
+    // checks the header, the rest is checked while loading
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<SaveState, SaveStateError>
+    {
+        if bytes.len() < HEADER_SIZE {
+            return Err(if MAGIC.starts_with(&bytes[..bytes.len().min(4)]) {SaveStateError::Truncated} else {SaveStateError::BadMagic})
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(SaveStateError::BadMagic)
+        }
+        match u16::from_le_bytes([bytes[4], bytes[5]]) {
+            SAVE_STATE_VERSION => Ok(SaveState {bytes}),
+            version => Err(SaveStateError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn reader(&self) -> StateReader<'_> { StateReader {bytes: &self.bytes[HEADER_SIZE..]} }
+}
+
+// Components append their fields in the order they read them back
+pub struct StateWriter
+{
+    bytes: Vec<u8>,
+}
+
+impl StateWriter
+{
+    pub fn new() -> StateWriter
+    {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&SAVE_STATE_VERSION.to_le_bytes());
+        StateWriter {bytes}
+    }
+
+    pub fn finish(self) -> SaveState { SaveState {bytes: self.bytes} }
+
+    pub fn write_u8(&mut self, value: u8) { self.bytes.push(value) }
+    pub fn write_bool(&mut self, value: bool) { self.bytes.push(value as u8) }
+    pub fn write_u16(&mut self, value: u16) { self.bytes.extend_from_slice(&value.to_le_bytes()) }
+    pub fn write_u32(&mut self, value: u32) { self.bytes.extend_from_slice(&value.to_le_bytes()) }
+    pub fn write_u64(&mut self, value: u64) { self.bytes.extend_from_slice(&value.to_le_bytes()) }
+    pub fn write_f32(&mut self, value: f32) { self.write_u32(value.to_bits()) }
+    pub fn write_bytes(&mut self, bytes: &[u8]) { self.bytes.extend_from_slice(bytes) }
+
+    pub fn write_option_u8(&mut self, value: Option<u8>)
+    {
+        self.write_bool(value.is_some());
+        self.write_u8(value.unwrap_or(0));
+    }
+
+    pub fn write_option_u64(&mut self, value: Option<u64>)
+    {
+        self.write_bool(value.is_some());
+        self.write_u64(value.unwrap_or(0));
+    }
+}
+
+impl Default for StateWriter
+{
+    fn default() -> StateWriter { StateWriter::new() }
+}
+
+pub struct StateReader<'a>
+{
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a>
+{
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SaveStateError>
+    {
+        if self.bytes.len() < count {
+            return Err(SaveStateError::Truncated)
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError>
+    {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> { Ok(self.take(1)?[0]) }
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> { Ok(u16::from_le_bytes(self.take_array()?)) }
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> { Ok(u32::from_le_bytes(self.take_array()?)) }
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> { Ok(u64::from_le_bytes(self.take_array()?)) }
+    pub fn read_f32(&mut self) -> Result<f32, SaveStateError> { Ok(f32::from_bits(self.read_u32()?)) }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError>
+    {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Mismatch("boolean encoding")),
+        }
+    }
+
+    // fills the whole slice
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), SaveStateError>
+    {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    pub fn read_option_u8(&mut self) -> Result<Option<u8>, SaveStateError>
+    {
+        let is_some = self.read_bool()?;
+        let value = self.read_u8()?;
+        Ok(if is_some {Some(value)} else {None})
+    }
+
+    pub fn read_option_u64(&mut self) -> Result<Option<u64>, SaveStateError>
+    {
+        let is_some = self.read_bool()?;
+        let value = self.read_u64()?;
+        Ok(if is_some {Some(value)} else {None})
+    }
+
+    // once every component is loaded
+    pub fn finish(self) -> Result<(), SaveStateError>
+    {
+        match self.bytes.len() {
+            0 => Ok(()),
+            count => Err(SaveStateError::TrailingBytes(count)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_round_trip()
+    {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789A_BCDE);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_f32(0.25);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_option_u8(None);
+        writer.write_option_u64(Some(7));
+        let state = SaveState::from_bytes(writer.finish().as_bytes().to_vec()).unwrap();
+
+        let mut reader = state.reader();
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0x789A_BCDE));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        assert_eq!(reader.read_f32(), Ok(0.25));
+        let mut bytes = [0; 3];
+        reader.read_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.read_option_u8(), Ok(None));
+        assert_eq!(reader.read_option_u64(), Ok(Some(7)));
+        assert_eq!(reader.read_u8(), Err(SaveStateError::Truncated));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_header()
+    {
+        let state = StateWriter::new().finish();
+        assert_eq!(state.as_bytes(), b"NQSS\x03\x00");
+
+        let mut bytes = state.as_bytes().to_vec();
+        bytes[4] = 1;
+        assert_eq!(SaveState::from_bytes(bytes), Err(SaveStateError::UnsupportedVersion(1)));
+        assert_eq!(SaveState::from_bytes(b"NES\x1A\x01\x00".to_vec()), Err(SaveStateError::BadMagic));
+        assert_eq!(SaveState::from_bytes(b"NQS".to_vec()), Err(SaveStateError::Truncated));
+        assert_eq!(SaveState::from_bytes(vec![]), Err(SaveStateError::Truncated));
+    }
+
+    #[test]
+    fn test_trailing_bytes()
+    {
+        let mut writer = StateWriter::new();
+        writer.write_u16(0);
+        let state = writer.finish();
+
+        let mut reader = state.reader();
+        reader.read_u8().unwrap();
+        assert_eq!(reader.finish(), Err(SaveStateError::TrailingBytes(1)));
+    }
+}
@@ -0,0 +1,132 @@
+// Runs the nesquick binary. The nestest runs need the fixtures, see src/fixtures.rs.
+#![allow(clippy::bool_assert_comparison)]
+
+use std::env;
+use std::fs;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process::{
+    Command,
+    Output,
+};
+
+fn nesquick(args: &[&str]) -> Output
+{
+    Command::new(env!("CARGO_BIN_EXE_nesquick")).args(args).output().expect("could not run nesquick")
+}
+
+fn nestest_path() -> Option<String>
+{
+    match env::var_os("NESQUICK_FIXTURES_DIR") {
+        Some(dir) => Some(PathBuf::from(dir).join("nestest/nestest.nes").to_string_lossy().into_owned()),
+        None if cfg!(feature = "require-fixtures") => panic!("NESQUICK_FIXTURES_DIR must be set to run fixture tests"),
+        None => {
+            eprintln!("skipped: NESQUICK_FIXTURES_DIR is not set, fixture nestest/nestest.nes unavailable");
+            None
+        },
+    }
+}
+
+#[test]
+fn test_missing_rom()
+{
+    let output = nesquick(&["does/not/exist.nes"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr).contains("could not read does/not/exist.nes"), true);
+}
+
+#[test]
+fn test_usage()
+{
+    assert_eq!(nesquick(&[]).status.code(), Some(2));
+    assert_eq!(nesquick(&["--frobnicate", "rom.nes"]).status.code(), Some(2));
+    assert_eq!(nesquick(&["rom.nes", "--max-cycles", "many"]).status.code(), Some(2));
+}
+
+#[test]
+fn test_no_trace_by_default()
+{
+    let path = match nestest_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let output = nesquick(&[&path]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"");
+}
+
+#[test]
+fn test_trace_with_cycle_limit()
+{
+    let path = match nestest_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let output = nesquick(&[&path, "--pc", "0xC000", "--max-cycles", "20", "--trace"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    let trace = String::from_utf8_lossy(&output.stdout);
+    let pcs: Vec<&str> = trace.lines().map(|line| &line[..4]).collect();
+    assert_eq!(pcs, vec!["C000", "C5F5", "C5F7", "C5F9", "C5FB"]);
+}
+
+#[test]
+fn test_trace_file()
+{
+    let path = match nestest_path() {
+        Some(path) => path,
+        None => return,
+    };
+    let trace_path = env::temp_dir().join(format!("nesquick-cli-{}.log", std::process::id()));
+    let output = nesquick(&[&path, "--max-cycles", "20", "--trace-file", &trace_path.to_string_lossy()]);
+    let trace = fs::read_to_string(&trace_path).unwrap();
+    fs::remove_file(&trace_path).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"");
+    assert_eq!(trace.lines().count(), 5);
+    assert_eq!(trace.starts_with("C000"), true);
+}
+
+// NROM with battery backed RAM, incrementing $6000 once
+fn battery_rom(path: &Path)
+{
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x02, 0];
+    rom.resize(16, 0);
+    let mut prg_rom = vec![0xEA; 0x4000];
+    // INC $6000 ; JMP *
+    prg_rom[..6].copy_from_slice(&[0xEE, 0x00, 0x60, 0x4C, 0x03, 0x80]);
+    prg_rom[0x3FFC] = 0x00;
+    prg_rom[0x3FFD] = 0x80;
+    rom.extend(prg_rom);
+    fs::write(path, rom).unwrap();
+}
+
+#[test]
+fn test_battery_save_file()
+{
+    let dir = env::temp_dir().join(format!("nesquick-cli-battery-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rom_path = dir.join("game.nes");
+    let save_path = dir.join("game.sav");
+    battery_rom(&rom_path);
+    let rom = rom_path.to_string_lossy();
+
+    // written on exit, then loaded on the next start
+    assert_eq!(nesquick(&[&rom, "--max-cycles", "100"]).status.code(), Some(0));
+    assert_eq!(fs::read(&save_path).unwrap()[0], 1);
+    assert_eq!(nesquick(&[&rom, "--max-cycles", "100"]).status.code(), Some(0));
+    let save = fs::read(&save_path).unwrap();
+    assert_eq!((save.len(), save[0]), (0x2000, 2));
+
+    fs::write(&save_path, [0; 16]).unwrap();
+    let output = nesquick(&[&rom, "--max-cycles", "100"]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr).contains("wrong save RAM size"), true);
+}